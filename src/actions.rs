@@ -0,0 +1,667 @@
+use crate::{App, builtins, lint, rename, utils::{self, Encoding, Var}};
+use lsp_types::*;
+use rnix::{
+    types::*,
+    SyntaxKind::{NODE_PAT_ENTRY, TOKEN_COMMENT, TOKEN_ELLIPSIS},
+    SyntaxNode,
+    TextRange,
+    TextUnit,
+};
+use std::{collections::HashMap, convert::TryFrom, rc::Rc};
+
+/// How many enclosing expressions to offer extracting at once
+const MAX_EXTRACT_ACTIONS: usize = 3;
+/// How many names a `with` may provide to be narrowed to an `inherit`
+const MAX_NARROWED_NAMES: usize = 8;
+/// Functions of `lib` that definitions of options are often wrapped in
+const OPTION_WRAPPERS: &[&str] = &["mkDefault", "mkForce", "mkIf"];
+
+impl App {
+    pub fn code_actions(&mut self, params: &CodeActionParams) -> Vec<CodeActionOrCommand> {
+        let Some((ast, code)) = self.files.get(&params.text_document.uri) else { return Vec::new() };
+        let (root, code) = (ast.node(), code.clone());
+        let code = &code;
+        let Some(offset) = utils::lookup_pos(code, params.range.start, self.encoding) else { return Vec::new() };
+        let file = Rc::new(params.text_document.uri.clone());
+        let Some(token) = root.token_at_offset(TextUnit::from_usize(offset)).right_biased() else { return Vec::new() };
+
+        let mut actions = Vec::new();
+        let with = token.parent().ancestors().filter_map(With::cast).find(|with| with.body().is_some_and(|body| is_list(&body)));
+        if let Some(with) = with {
+            let provided = self.provided_names(&file, &with);
+            if let Some(edits) = expand_with(&file, code, self.encoding, &with, provided.as_ref()) {
+                actions.push(action("Use explicit attributes instead of `with`", &file, edits));
+            }
+        }
+        if let Some(with) = token.parent().ancestors().find_map(With::cast) {
+            let provided = self.provided_names(&file, &with);
+            if let Some((title, edits)) = narrow_with(&file, code, self.encoding, &with, provided.as_ref()) {
+                actions.push(action(&title, &file, edits));
+            }
+        }
+        let list = token.parent().ancestors().find_map(List::cast);
+        if let Some((namespace, edits)) = list.and_then(|list| factor_with(&file, code, self.encoding, &list)) {
+            actions.push(action(&format!("Factor out `{namespace}.` into `with {namespace};`"), &file, edits));
+        }
+        for (title, edits) in wrap_definition(&file, &root, code, self.encoding, &token.parent()) {
+            actions.push(action(&title, &file, edits));
+        }
+        if let Some((title, edits)) = if_to_mk_if(&file, &root, code, self.encoding, &token.parent()) {
+            actions.push(action(&title, &file, edits));
+        }
+        if let Some((title, edits)) = inherit_self(code, self.encoding, &token.parent()) {
+            actions.push(action(&title, &file, edits));
+        }
+        let encoding = self.encoding;
+        let cursor = TextUnit::from_usize(offset);
+        for (interpol, name) in self.shell_variables(&file, &root) {
+            if !interpol.text_range().contains_inclusive(cursor) {
+                continue;
+            }
+            let start = interpol.text_range().start();
+            let edits = vec![TextEdit {
+                range: utils::range(code, TextRange::from_to(start, start), encoding),
+                new_text: "''".into(),
+            }];
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                kind: Some(code_action_kind::QUICKFIX.into()),
+                diagnostics: Some(vec![lint::shell_variable_diagnostic(code, encoding, &interpol, &name)]),
+                is_preferred: Some(true),
+                ..action_edit(&format!("Escape as `''${{{name}}}`"), &file, edits)
+            }));
+        }
+        if !self.is_large(code) {
+            let end = utils::lookup_pos(code, params.range.end, self.encoding).map_or(token.text_range().end(), TextUnit::from_usize);
+            let selection = TextRange::from_to(token.text_range().start().min(end), end.max(token.text_range().start()));
+            let extracts = token.parent().ancestors()
+                .filter(|node| selection.is_subrange(&node.text_range()))
+                .filter_map(|node| extract(&file, &root, code, self.encoding, &node))
+                .take(MAX_EXTRACT_ACTIONS);
+            for (title, edits) in extracts {
+                actions.push(action(&title, &file, edits));
+            }
+        }
+        actions
+    }
+    /// The names a `with` is known to provide, if it's inside another
+    /// `with` that names might come from instead
+    fn provided_names(&mut self, file: &Rc<Url>, with: &With) -> Option<HashMap<String, Var>> {
+        let namespace = with.namespace()?;
+        if withs_around(with.node()).is_empty() {
+            return None;
+        }
+        Some(self.scope_from_node(file, namespace).unwrap_or_default())
+    }
+}
+
+fn action(title: &str, file: &Url, edits: Vec<TextEdit>) -> CodeActionOrCommand {
+    CodeActionOrCommand::CodeAction(action_edit(title, file, edits))
+}
+fn action_edit(title: &str, file: &Url, edits: Vec<TextEdit>) -> CodeAction {
+    let mut changes = HashMap::new();
+    changes.insert(file.clone(), edits);
+    CodeAction {
+        title: title.into(),
+        kind: Some(code_action_kind::REFACTOR_REWRITE.into()),
+        diagnostics: None,
+        edit: Some(WorkspaceEdit {
+            changes: Some(changes),
+            ..WorkspaceEdit::default()
+        }),
+        command: None,
+        is_preferred: None,
+    }
+}
+
+fn is_list(node: &SyntaxNode) -> bool {
+    let mut node = node.clone();
+    while let Some(inner) = Paren::cast(node.clone()).and_then(|paren| paren.inner()) {
+        node = inner;
+    }
+    List::cast(node).is_some()
+}
+/// Whether a variable can come from a `with`. Lexical bindings and
+/// builtins like `toString` always take precedence.
+fn from_with(file: &Rc<Url>, ident: &Ident) -> bool {
+    let lexical = utils::scope_for(file, ident.node().clone()).is_none_or(|scope| scope.contains_key(ident.as_str()));
+    !lexical && builtins::get(ident.as_str()).is_none_or(|builtin| !builtin.global)
+}
+
+/// Rewrite `with pkgs; [ a b ]` to `[ pkgs.a pkgs.b ]`. If `provided`
+/// is given, every name has to be in it, like for `narrow_with`.
+fn expand_with(
+    file: &Rc<Url>,
+    code: &str,
+    encoding: Encoding,
+    with: &With,
+    provided: Option<&HashMap<String, Var>>,
+) -> Option<Vec<TextEdit>> {
+    let namespace = with.namespace()?;
+    let body = with.body()?;
+    let prefix = match ParsedType::try_from(namespace.clone()) {
+        Ok(ParsedType::Ident(_) | ParsedType::Select(_) | ParsedType::Paren(_)) => namespace.text().to_string(),
+        _ => format!("({})", namespace.text()),
+    };
+
+    let mut edits = vec![TextEdit {
+        range: utils::range(code, TextRange::from_to(with.node().text_range().start(), body.text_range().start()), encoding),
+        new_text: String::new(),
+    }];
+    for ident in body.descendants().filter_map(Ident::cast) {
+        if !utils::is_reference(&ident) || !from_with(file, &ident) {
+            continue;
+        }
+        // Inside another `with`, it's unclear which one provides it
+        let nested = ident.node().ancestors()
+            .take_while(|node| *node != body)
+            .any(|node| With::cast(node).is_some());
+        if nested || provided.is_some_and(|provided| !provided.contains_key(ident.as_str())) {
+            return None;
+        }
+        let name = ident.as_str();
+        match ident.node().parent().and_then(Inherit::cast) {
+            Some(inherit) => edits.extend(rename::split_inherit(code, encoding, &inherit, &ident, &format!("{name} = {prefix}.{name};"))),
+            None => edits.push(TextEdit {
+                range: utils::range(code, ident.node().text_range(), encoding),
+                new_text: format!("{prefix}.{name}"),
+            }),
+        }
+    }
+    Some(edits)
+}
+
+/// Rewrite `with pkgs; [ a b ]` to `let inherit (pkgs) a b; in [ a b ]`,
+/// so only the names that are used come into scope. If `provided` is
+/// given, every name has to be in it, since the others might come from
+/// an outer `with`.
+fn narrow_with(
+    file: &Rc<Url>,
+    code: &str,
+    encoding: Encoding,
+    with: &With,
+    provided: Option<&HashMap<String, Var>>,
+) -> Option<(String, Vec<TextEdit>)> {
+    let namespace = with.namespace()?;
+    let body = with.body()?;
+    let mut names = Vec::new();
+    for ident in body.descendants().filter_map(Ident::cast) {
+        if !utils::is_reference(&ident) || !from_with(file, &ident) {
+            continue;
+        }
+        // Inside another `with`, it's unclear which one provides it
+        if ident.node().ancestors().take_while(|node| node != with.node()).any(|node| With::cast(node).is_some()) {
+            return None;
+        }
+        if provided.is_some_and(|provided| !provided.contains_key(ident.as_str())) {
+            return None;
+        }
+        names.push(ident.as_str().to_owned());
+    }
+    names.sort();
+    names.dedup();
+    if names.is_empty() || names.len() > MAX_NARROWED_NAMES {
+        return None;
+    }
+
+    let inherit = format!("inherit ({}) {};", namespace.text(), names.join(" "));
+    let edits = vec![TextEdit {
+        range: utils::range(code, TextRange::from_to(with.node().text_range().start(), body.text_range().start()), encoding),
+        new_text: format!("let {inherit} in "),
+    }];
+    let shown = if inherit.len() <= 60 && !inherit.contains('\n') { format!("`{inherit}`") } else { "`inherit`".into() };
+    Some((format!("Narrow `with` to {shown}"), edits))
+}
+/// Rewrite `[ pkgs.a pkgs.b ]` to `with pkgs; [ a b ]`. Only offered if
+/// every item is an attribute of the same set, since other items might
+/// refer to something else once they're in the `with`.
+fn factor_with(file: &Rc<Url>, code: &str, encoding: Encoding, list: &List) -> Option<(String, Vec<TextEdit>)> {
+    let mut namespace = None;
+    let mut edits = Vec::new();
+    let mut count = 0;
+    for item in list.items() {
+        let select = Select::cast(item)?;
+        let set = select.set()?.text().to_string();
+        if namespace.get_or_insert_with(|| set.clone()) != &set {
+            return None;
+        }
+        let index = Ident::cast(select.index()?)?;
+        // The name would refer to the binding rather than the attribute
+        if !from_with(file, &index) {
+            return None;
+        }
+        edits.push(TextEdit {
+            range: utils::range(code, select.node().text_range(), encoding),
+            new_text: index.as_str().into(),
+        });
+        count += 1;
+    }
+    if count < 2 {
+        return None;
+    }
+    let namespace = namespace?;
+
+    // `with` reaches as far to the right as possible, so it needs
+    // parentheses unless the list already ends the expression
+    let start = list.node().text_range().start();
+    let end = list.node().text_range().end();
+    let parens = !list.node().parent().is_some_and(|parent| ends_expression(&parent, list.node()));
+    edits.insert(0, TextEdit {
+        range: utils::range(code, TextRange::from_to(start, start), encoding),
+        new_text: if parens { format!("(with {namespace}; ") } else { format!("with {namespace}; ") },
+    });
+    if parens {
+        edits.push(TextEdit {
+            range: utils::range(code, TextRange::from_to(end, end), encoding),
+            new_text: ")".into(),
+        });
+    }
+    Some((namespace, edits))
+}
+/// Wrap the value of an option definition in a module, like `a = 1;` in
+/// `lib.mkDefault` to get `a = lib.mkDefault 1;`. If `lib` isn't in
+/// scope, it's added to the module's arguments.
+fn wrap_definition(file: &Rc<Url>, root: &SyntaxNode, code: &str, encoding: Encoding, node: &SyntaxNode) -> Vec<(String, Vec<TextEdit>)> {
+    let Some((pattern, entry)) = definition(root, node) else { return Vec::new() };
+    let Some(value) = entry.value() else { return Vec::new() };
+    let Some(scope) = utils::scope_for(file, value.clone()) else { return Vec::new() };
+    let Some(lib) = lib_argument(code, encoding, &pattern, &scope) else { return Vec::new() };
+
+    // Priorities override each other, so only one makes sense
+    let priorities = ["mkDefault", "mkForce", "mkOverride"];
+    let wrapped = wrapper(&value);
+    let prioritized = wrapped.as_deref().is_some_and(|name| priorities.contains(&name));
+    let atomic = is_atomic(&value);
+    let (start, end) = (value.text_range().start(), value.text_range().end());
+    OPTION_WRAPPERS.iter()
+        .filter(|name| wrapped.as_deref() != Some(**name) && !(prioritized && priorities.contains(name)))
+        .map(|name| {
+            // The usual condition, if there's a `cfg` to take it from
+            let condition = match *name {
+                "mkIf" if scope.contains_key("cfg") => " cfg.enable",
+                "mkIf" => " true",
+                _ => "",
+            };
+            let mut edits = lib.clone();
+            edits.push(TextEdit {
+                range: utils::range(code, TextRange::from_to(start, start), encoding),
+                new_text: format!("lib.{name}{condition} {}", if atomic { "" } else { "(" }),
+            });
+            if !atomic {
+                edits.push(TextEdit {
+                    range: utils::range(code, TextRange::from_to(end, end), encoding),
+                    new_text: ")".into(),
+                });
+            }
+            (format!("Wrap in `lib.{name}`"), edits)
+        })
+        .collect()
+}
+/// Rewrite an option definition like `a = if cond then x else { };` to
+/// `a = lib.mkIf cond x;`. That's only the same if the other branch
+/// doesn't set anything, so it has to be `{ }`, `[ ]` or `null`. If
+/// that's the `then` branch, the condition is negated instead.
+fn if_to_mk_if(file: &Rc<Url>, root: &SyntaxNode, code: &str, encoding: Encoding, node: &SyntaxNode) -> Option<(String, Vec<TextEdit>)> {
+    let (pattern, if_else) = node.ancestors()
+        .filter_map(IfElse::cast)
+        .find_map(|if_else| {
+            let (pattern, entry) = definition(root, if_else.node())?;
+            let mut value = entry.value()?;
+            while let Some(inner) = Paren::cast(value.clone()).and_then(|paren| paren.inner()) {
+                value = inner;
+            }
+            (value == *if_else.node()).then_some((pattern, if_else))
+        })?;
+    // Comments around `then` and `else` would get lost
+    if if_else.node().children_with_tokens().any(|child| child.kind() == TOKEN_COMMENT) {
+        return None;
+    }
+    let (condition, body, else_body) = (if_else.condition()?, if_else.body()?, if_else.else_body()?);
+    let text = |node: &SyntaxNode| {
+        let text = code[node.text_range().start().to_usize()..node.text_range().end().to_usize()].to_owned();
+        if is_atomic(node) { text } else { format!("({text})") }
+    };
+    let (condition, value) = if is_unset(&else_body) {
+        (text(&condition), text(&body))
+    } else if is_unset(&body) {
+        (format!("(!{})", text(&condition)), text(&else_body))
+    } else {
+        return None;
+    };
+    let scope = utils::scope_for(file, if_else.node().clone())?;
+    let mut edits = lib_argument(code, encoding, &pattern, &scope)?;
+    edits.push(TextEdit {
+        range: utils::range(code, if_else.node().text_range(), encoding),
+        new_text: format!("lib.mkIf {condition} {value}"),
+    });
+    Some(("Rewrite as `lib.mkIf`".into(), edits))
+}
+/// Whether a value sets nothing when it's the definition of an option,
+/// like `{ }` for a set or `[ ]` for a list
+fn is_unset(node: &SyntaxNode) -> bool {
+    let mut node = node.clone();
+    while let Some(inner) = Paren::cast(node.clone()).and_then(|paren| paren.inner()) {
+        node = inner;
+    }
+    match ParsedType::try_from(node) {
+        Ok(ParsedType::Ident(ident)) => ident.as_str() == "null",
+        Ok(ParsedType::AttrSet(set)) => set.node().children().next().is_none(),
+        Ok(ParsedType::List(list)) => list.items().next().is_none(),
+        _ => false,
+    }
+}
+/// Rewrite a binding like `a = a;` to `inherit a;`, together with the
+/// ones right before and after it, so `a = a; b = b;` becomes
+/// `inherit a b;`. In `rec` sets and `let`, `a = a;` refers to itself
+/// instead of the outer `a`, so those are left alone.
+fn inherit_self(code: &str, encoding: Encoding, node: &SyntaxNode) -> Option<(String, Vec<TextEdit>)> {
+    let entry = node.ancestors().find_map(KeyValue::cast)?;
+    self_binding(&entry)?;
+    let set = entry.node().parent().and_then(AttrSet::cast).filter(|set| !utils::is_recursive(set))?;
+    let children: Vec<SyntaxNode> = set.node().children().collect();
+    let index = children.iter().position(|child| child == entry.node())?;
+
+    let is_self = |child: &SyntaxNode| KeyValue::cast(child.clone()).and_then(|entry| self_binding(&entry)).is_some();
+    // Comments in between would get lost
+    let adjacent = |a: &SyntaxNode, b: &SyntaxNode| code[a.text_range().end().to_usize()..b.text_range().start().to_usize()].trim().is_empty();
+    let mut start = index;
+    while start > 0 && is_self(&children[start - 1]) && adjacent(&children[start - 1], &children[start]) {
+        start -= 1;
+    }
+    let mut end = index;
+    while end + 1 < children.len() && is_self(&children[end + 1]) && adjacent(&children[end], &children[end + 1]) {
+        end += 1;
+    }
+
+    let names: Vec<String> = children[start..=end].iter()
+        .filter_map(|child| KeyValue::cast(child.clone()).and_then(|entry| self_binding(&entry)))
+        .collect();
+    let text = format!("inherit {};", names.join(" "));
+    let range = TextRange::from_to(children[start].text_range().start(), children[end].text_range().end());
+    Some((format!("Rewrite as `{text}`"), vec![TextEdit {
+        range: utils::range(code, range, encoding),
+        new_text: text,
+    }]))
+}
+/// The name of a binding to the variable of the same name, like `a = a;`
+fn self_binding(entry: &KeyValue) -> Option<String> {
+    let key = match entry.key()?.path().collect::<Vec<_>>().as_slice() {
+        [key] => Ident::cast(key.clone())?,
+        _ => return None,
+    };
+    let value = Ident::cast(entry.value()?)?;
+    (key.as_str() == value.as_str()).then(|| key.as_str().to_owned())
+}
+
+/// The arguments and the set of definitions of a NixOS module, like
+/// `{ config, lib, ... }: { ... }`
+fn module(root: &SyntaxNode) -> Option<(Pattern, SyntaxNode)> {
+    let lambda = Lambda::cast(Root::cast(root.clone())?.inner()?)?;
+    let pattern = Pattern::cast(lambda.arg()?)?;
+    let args = ["config", "lib", "pkgs", "options"];
+    if !pattern.ellipsis() && !pattern.entries().any(|entry| entry.name().is_some_and(|name| args.contains(&name.as_str()))) {
+        return None;
+    }
+    let mut body = lambda.body()?;
+    loop {
+        body = match ParsedType::try_from(body.clone()) {
+            Ok(ParsedType::LetIn(let_in)) => let_in.body()?,
+            Ok(ParsedType::With(with)) => with.body()?,
+            Ok(ParsedType::Paren(paren)) => paren.inner()?,
+            Ok(ParsedType::AttrSet(_)) => return Some((pattern, body)),
+            _ => return None,
+        };
+    }
+}
+/// The closest definition in a module whose value a node is in, only
+/// through nested sets like `services.nginx = { enable = true; };` and
+/// wrappers like `mkIf cond { ... }` or `mkMerge [ ... ]`, and the
+/// module's arguments
+fn definition(root: &SyntaxNode, node: &SyntaxNode) -> Option<(Pattern, KeyValue)> {
+    let (pattern, body) = module(root)?;
+    let (mut entry, mut top) = (None, None);
+    for ancestor in node.ancestors() {
+        if ancestor == body {
+            break;
+        }
+        if let Some(found) = KeyValue::cast(ancestor.clone()) {
+            let in_value = found.value().is_some_and(|value| node.ancestors().any(|inner| inner == value));
+            if entry.is_none() && !in_value {
+                return None;
+            }
+            entry.get_or_insert_with(|| found.clone());
+            top = Some(found);
+        } else if entry.is_some() {
+            let nested = match ParsedType::try_from(ancestor.clone()) {
+                Ok(ParsedType::AttrSet(_) | ParsedType::List(_) | ParsedType::Paren(_)) => true,
+                Ok(ParsedType::Apply(_)) => wrapper(&ancestor).is_some_and(|name| name.starts_with("mk")),
+                _ => false,
+            };
+            if !nested {
+                return None;
+            }
+        }
+    }
+    // Declarations and imports aren't definitions
+    let top = top.and_then(|top| top.key()?.path().next()).and_then(|key| utils::static_name(&key));
+    if top.is_some_and(|name| ["options", "imports", "disabledModules"].contains(&name.as_str())) {
+        return None;
+    }
+    Some((pattern, entry?))
+}
+/// The edit adding `lib` to the arguments of a module, if it isn't in
+/// scope already. Modules are passed `lib` too.
+fn lib_argument(code: &str, encoding: Encoding, pattern: &Pattern, scope: &HashMap<String, Var>) -> Option<Vec<TextEdit>> {
+    if scope.contains_key("lib") {
+        return Some(Vec::new());
+    }
+    let first = pattern.node().children_with_tokens()
+        .find(|child| child.kind() == NODE_PAT_ENTRY || child.kind() == TOKEN_ELLIPSIS)?;
+    let start = first.text_range().start();
+    Some(vec![TextEdit {
+        range: utils::range(code, TextRange::from_to(start, start), encoding),
+        new_text: "lib, ".into(),
+    }])
+}
+/// Whether an expression can be an argument of a function without
+/// parentheses
+fn is_atomic(node: &SyntaxNode) -> bool {
+    matches!(ParsedType::try_from(node.clone()),
+        Ok(ParsedType::Ident(_) | ParsedType::Value(_) | ParsedType::Str(_) | ParsedType::List(_)
+            | ParsedType::AttrSet(_) | ParsedType::Paren(_) | ParsedType::Select(_)))
+}
+/// The wrapper a value already has, like `mkIf` for `lib.mkIf cond {}`
+fn wrapper(value: &SyntaxNode) -> Option<String> {
+    let mut function = Apply::cast(value.clone())?.lambda()?;
+    while let Some(apply) = Apply::cast(function.clone()) {
+        function = apply.lambda()?;
+    }
+    let name = match Select::cast(function.clone()) {
+        Some(select) => Ident::cast(select.index()?)?,
+        None => Ident::cast(function)?,
+    };
+    Some(name.as_str().to_owned())
+}
+/// Whether `node` is the last part of `parent`, with nothing after it
+/// that a `with` in its place could swallow
+fn ends_expression(parent: &SyntaxNode, node: &SyntaxNode) -> bool {
+    match ParsedType::try_from(parent.clone()) {
+        Ok(ParsedType::Root(_) | ParsedType::Paren(_) | ParsedType::KeyValue(_)) => true,
+        Ok(ParsedType::LetIn(let_in)) => let_in.body().as_ref() == Some(node),
+        Ok(ParsedType::With(with)) => with.body().as_ref() == Some(node),
+        Ok(ParsedType::Lambda(lambda)) => lambda.body().as_ref() == Some(node),
+        Ok(ParsedType::Assert(assert)) => assert.body().as_ref() == Some(node),
+        _ => false,
+    }
+}
+
+/// The free variables of an expression with what they refer to: the
+/// identifier defining them, or the `with` expressions around them if
+/// they aren't bound lexically
+type Bindings = Vec<(String, Option<SyntaxNode>, Vec<SyntaxNode>)>;
+
+/// Rewrite an expression that occurs multiple times, like `f x` in
+/// `g (f x) (f x)`, to `let name = f x; in g name name`. Only
+/// occurrences that refer to the same variables count, and the `let`
+/// goes around all of them.
+fn extract(file: &Rc<Url>, root: &SyntaxNode, code: &str, encoding: Encoding, node: &SyntaxNode) -> Option<(String, Vec<TextEdit>)> {
+    let extractable = match ParsedType::try_from(node.clone()) {
+        Ok(ParsedType::Apply(_) | ParsedType::BinOp(_) | ParsedType::UnaryOp(_) | ParsedType::Select(_)
+            | ParsedType::IfElse(_) | ParsedType::List(_) | ParsedType::AttrSet(_) | ParsedType::OrDefault(_)) => true,
+        // Plain strings are more readable where they're used
+        Ok(ParsedType::Str(string)) => string.parts().iter().any(|part| matches!(part, rnix::value::StrPart::Ast(_))),
+        _ => false,
+    };
+    if !extractable {
+        return None;
+    }
+    let bindings = free_variables(file, node)?;
+    let hash = utils::structural_hash(node);
+    let occurrences: Vec<SyntaxNode> = root.descendants()
+        .filter(|other| other.kind() == node.kind() && utils::structural_hash(other) == hash && utils::normalized_eq(node, other))
+        .filter(|other| free_variables(file, other).as_ref() == Some(&bindings))
+        .collect();
+    if occurrences.len() < 2 {
+        return None;
+    }
+
+    // The closest expression around all of them, if it can see the
+    // same variables
+    let mut outer = occurrences[0].ancestors()
+        .find(|ancestor| occurrences.iter().all(|other| other.text_range().is_subrange(&ancestor.text_range())))?;
+    while !is_expression(&outer) {
+        outer = outer.parent()?;
+    }
+    let parent = outer.parent()?;
+    let scope = utils::scope_for(file, parent.clone())?;
+    let visible = bindings.iter().all(|(name, definition, withs)| {
+        let found = scope.get(name).map(|var| var.key.clone());
+        found == *definition && (definition.is_some() || *withs == withs_around(&outer))
+    });
+    if !visible {
+        return None;
+    }
+
+    let name = fresh_name(&outer, &suggest_name(node));
+    let wrap = !ends_expression(&parent, &outer);
+    let start = outer.text_range().start();
+    let end = outer.text_range().end();
+    let binding = format!("let {name} = {}; in ", node.text());
+    let mut edits = vec![TextEdit {
+        range: utils::range(code, TextRange::from_to(start, start), encoding),
+        new_text: if wrap { format!("({binding}") } else { binding },
+    }];
+    for occurrence in &occurrences {
+        // `(f x)` becomes `name`, not `(name)`
+        let replaced = occurrence.parent().filter(|parent| Paren::cast(parent.clone()).is_some()).unwrap_or_else(|| occurrence.clone());
+        edits.push(TextEdit {
+            range: utils::range(code, replaced.text_range(), encoding),
+            new_text: name.clone(),
+        });
+    }
+    if wrap {
+        edits.push(TextEdit {
+            range: utils::range(code, TextRange::from_to(end, end), encoding),
+            new_text: ")".into(),
+        });
+    }
+    let source = node.text().to_string();
+    let shown = if source.len() <= 40 && !source.contains('\n') { format!("`{source}`") } else { "expression".into() };
+    Some((format!("Extract {shown} into `let {name}` ({} occurrences)", occurrences.len()), edits))
+}
+/// What the variables used in an expression refer to, except for the
+/// ones defined inside of it
+fn free_variables(file: &Rc<Url>, node: &SyntaxNode) -> Option<Bindings> {
+    let mut bindings = Vec::new();
+    for ident in node.descendants().filter_map(Ident::cast).filter(utils::is_reference) {
+        let scope = utils::scope_for(file, ident.node().clone())?;
+        let definition = scope.get(ident.as_str()).map(|var| var.key.clone());
+        if definition.as_ref().is_some_and(|key| key.ancestors().any(|ancestor| ancestor == *node)) {
+            continue;
+        }
+        let withs = if definition.is_none() { withs_around(node) } else { Vec::new() };
+        bindings.push((ident.as_str().to_owned(), definition, withs));
+    }
+    Some(bindings)
+}
+/// The `with` expressions whose body contains a node
+fn withs_around(node: &SyntaxNode) -> Vec<SyntaxNode> {
+    node.ancestors()
+        .filter(|ancestor| With::cast(ancestor.clone()).and_then(|with| with.body()).is_some_and(|body| node.ancestors().any(|inner| inner == body)))
+        .collect()
+}
+fn is_expression(node: &SyntaxNode) -> bool {
+    matches!(ParsedType::try_from(node.clone()),
+        Ok(ParsedType::Apply(_) | ParsedType::Assert(_) | ParsedType::AttrSet(_) | ParsedType::BinOp(_)
+            | ParsedType::IfElse(_) | ParsedType::Lambda(_) | ParsedType::LetIn(_) | ParsedType::List(_)
+            | ParsedType::OrDefault(_) | ParsedType::Paren(_) | ParsedType::Select(_) | ParsedType::Str(_)
+            | ParsedType::UnaryOp(_) | ParsedType::With(_)))
+}
+/// A name for an extracted expression, like `hello` for `pkgs.hello`
+fn suggest_name(node: &SyntaxNode) -> String {
+    Select::cast(node.clone())
+        .and_then(|select| select.index())
+        .and_then(Ident::cast)
+        .map_or_else(|| "value".into(), |name| name.as_str().to_owned())
+}
+/// A variant of `name` that isn't used anywhere inside `node`, so the
+/// new binding doesn't change what anything refers to. Attribute names
+/// after a dot don't count.
+fn fresh_name(node: &SyntaxNode, name: &str) -> String {
+    let used = |candidate: &str| node.descendants().filter_map(Ident::cast).any(|ident| {
+        let index = ident.node().parent().and_then(Select::cast).and_then(|select| select.index()).as_ref() == Some(ident.node());
+        ident.as_str() == candidate && !index
+    });
+    if !used(name) {
+        return name.to_owned();
+    }
+    let mut i = 2;
+    while used(&format!("{name}{i}")) {
+        i += 1;
+    }
+    format!("{name}{i}")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use lsp_types::*;
+
+    /// The code after applying the action with this title at the cursor
+    fn apply(code: &str, title: &str) -> Option<String> {
+        let (mut app, _client) = testing::app(&serde_json::json!({}));
+        let position = app.open_at("default.nix", code);
+        let params = CodeActionParams {
+            text_document: position.text_document.clone(),
+            range: Range { start: position.position, end: position.position },
+            context: CodeActionContext { diagnostics: Vec::new(), only: None },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let code = &app.files[&position.text_document.uri].1.clone();
+        app.code_actions(&params).into_iter().find_map(|action| match action {
+            CodeActionOrCommand::CodeAction(action) if action.title == title => {
+                let changes = action.edit?.changes?;
+                Some(testing::apply(code, &changes[&position.text_document.uri]))
+            },
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn expand_with() {
+        assert_eq!(
+            apply("with pkgs; [ $0hello git ]", "Use explicit attributes instead of `with`").as_deref(),
+            Some("[ pkgs.hello pkgs.git ]"),
+        );
+    }
+    #[test]
+    fn expand_with_inside_with() {
+        let code = "let lib = { foo = 1; }; pkgs = { bar = 2; }; in with lib; with pkgs; [ $0foo bar ]";
+        assert_eq!(apply(code, "Use explicit attributes instead of `with`"), None);
+        let code = "let lib = { foo = 1; }; pkgs = { foo = 1; bar = 2; }; in with lib; with pkgs; [ $0foo bar ]";
+        assert_eq!(
+            apply(code, "Use explicit attributes instead of `with`").as_deref(),
+            Some("let lib = { foo = 1; }; pkgs = { foo = 1; bar = 2; }; in with lib; [ pkgs.foo pkgs.bar ]"),
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{App, builtins, utils};
+use lsp_types::*;
+use rnix::{
+    types::*,
+    value::{StrPart, Value as ParsedValue},
+    SyntaxKind::TOKEN_ASSERT,
+    SyntaxNode,
+    TextUnit,
+};
+use std::{convert::TryFrom, rc::Rc};
+
+/// How many variables to follow while folding a condition, so that
+/// `let a = !a; in assert a; ...` doesn't loop forever
+const MAX_FOLD_DEPTH: usize = 32;
+
+/// A value that's known without evaluating anything
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Constant {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Null,
+}
+
+/// The parts of `lib.assertMsg cond "message"`, which fails with the
+/// message instead of just "assertion failed"
+pub fn assert_msg(node: &SyntaxNode) -> Option<(SyntaxNode, SyntaxNode)> {
+    let outer = Apply::cast(node.clone())?;
+    let inner = Apply::cast(outer.lambda()?)?;
+    let function = inner.lambda()?;
+    let name = match Select::cast(function.clone()) {
+        Some(select) => select.index().and_then(Ident::cast),
+        None => Ident::cast(function),
+    }?;
+    if name.as_str() != "assertMsg" {
+        return None;
+    }
+    Some((inner.value()?, outer.value()?))
+}
+
+/// The builtin and message of a call like `throw "message"` or
+/// `builtins.abort "message"`, unless the function is shadowed
+pub fn throw_call(file: &Rc<Url>, node: &SyntaxNode) -> Option<(Ident, SyntaxNode)> {
+    let apply = Apply::cast(node.clone())?;
+    let function = apply.lambda()?;
+    // The name that has to be unbound, `builtins` or the function itself
+    let (name, global) = if let Some(select) = Select::cast(function.clone()) {
+        let set = select.set().and_then(Ident::cast).filter(|set| set.as_str() == "builtins")?;
+        (select.index().and_then(Ident::cast)?, set)
+    } else {
+        let ident = Ident::cast(function)?;
+        (ident.clone(), ident)
+    };
+    if !matches!(name.as_str(), "throw" | "abort") {
+        return None;
+    }
+    let scope = utils::scope_for(file, global.node().clone())?;
+    if scope.contains_key(global.as_str()) {
+        return None;
+    }
+    Some((name, apply.value()?))
+}
+/// A message for hovers, as its text if it's a plain string
+pub fn message_text(message: &SyntaxNode) -> String {
+    match Str::cast(message.clone()).map(|string| string.parts()).as_deref() {
+        Some([StrPart::Literal(text)]) => text.clone(),
+        _ => message.text().to_string(),
+    }
+}
+
+impl App {
+    /// Statically evaluate simple expressions, like `!false` or `a == 1`
+    /// where `a` is bound to a literal
+    pub fn constant(&mut self, file: &Rc<Url>, node: &SyntaxNode) -> Option<Constant> {
+        self.constant_depth(file, node, 0)
+    }
+    fn constant_depth(&mut self, file: &Rc<Url>, node: &SyntaxNode, depth: usize) -> Option<Constant> {
+        if depth > MAX_FOLD_DEPTH {
+            return None;
+        }
+        match ParsedType::try_from(node.clone()).ok()? {
+            ParsedType::Paren(paren) => self.constant_depth(file, &paren.inner()?, depth + 1),
+            ParsedType::Ident(ident) => {
+                if self.scope_at(file, node)?.contains_key(ident.as_str()) {
+                    let (file, value) = self.resolve_value(file, node.clone())?;
+                    return self.constant_depth(&file, &value, depth + 1);
+                }
+                match ident.as_str() {
+                    "true" => Some(Constant::Bool(true)),
+                    "false" => Some(Constant::Bool(false)),
+                    "null" => Some(Constant::Null),
+                    _ => None,
+                }
+            },
+            ParsedType::Select(_) => {
+                let (file, value) = self.resolve_value(file, node.clone())?;
+                self.constant_depth(&file, &value, depth + 1)
+            },
+            ParsedType::Value(value) => match value.to_value().ok()? {
+                ParsedValue::Integer(int) => Some(Constant::Int(int)),
+                _ => None,
+            },
+            ParsedType::Str(string) => match string.parts().as_slice() {
+                [] => Some(Constant::Str(String::new())),
+                [StrPart::Literal(text)] => Some(Constant::Str(text.clone())),
+                _ => None,
+            },
+            ParsedType::UnaryOp(op) => match (op.operator(), self.constant_depth(file, &op.value()?, depth + 1)?) {
+                (UnaryOpKind::Invert, Constant::Bool(b)) => Some(Constant::Bool(!b)),
+                (UnaryOpKind::Negate, Constant::Int(int)) => int.checked_neg().map(Constant::Int),
+                _ => None,
+            },
+            ParsedType::BinOp(op) => self.constant_op(file, &op, depth + 1),
+            _ => None,
+        }
+    }
+    fn constant_op(&mut self, file: &Rc<Url>, op: &BinOp, depth: usize) -> Option<Constant> {
+        let lhs = self.constant_depth(file, &op.lhs()?, depth);
+        // Like in Nix, the right side doesn't matter if the left side
+        // decides it
+        match (op.operator(), &lhs) {
+            (BinOpKind::And, Some(Constant::Bool(false))) => return Some(Constant::Bool(false)),
+            (BinOpKind::Or, Some(Constant::Bool(true))) | (BinOpKind::Implication, Some(Constant::Bool(false))) => {
+                return Some(Constant::Bool(true));
+            },
+            _ => (),
+        }
+        let rhs = self.constant_depth(file, &op.rhs()?, depth)?;
+        let lhs = lhs?;
+        let value = match (op.operator(), lhs, rhs) {
+            (BinOpKind::And, Constant::Bool(a), Constant::Bool(b)) => Constant::Bool(a && b),
+            (BinOpKind::Or, Constant::Bool(a), Constant::Bool(b)) => Constant::Bool(a || b),
+            (BinOpKind::Implication, Constant::Bool(a), Constant::Bool(b)) => Constant::Bool(!a || b),
+            (BinOpKind::Equal, a, b) => Constant::Bool(a == b),
+            (BinOpKind::NotEqual, a, b) => Constant::Bool(a != b),
+            (BinOpKind::Less, Constant::Int(a), Constant::Int(b)) => Constant::Bool(a < b),
+            (BinOpKind::LessOrEq, Constant::Int(a), Constant::Int(b)) => Constant::Bool(a <= b),
+            (BinOpKind::More, Constant::Int(a), Constant::Int(b)) => Constant::Bool(a > b),
+            (BinOpKind::MoreOrEq, Constant::Int(a), Constant::Int(b)) => Constant::Bool(a >= b),
+            (BinOpKind::Add, Constant::Int(a), Constant::Int(b)) => Constant::Int(a.checked_add(b)?),
+            (BinOpKind::Sub, Constant::Int(a), Constant::Int(b)) => Constant::Int(a.checked_sub(b)?),
+            (BinOpKind::Mul, Constant::Int(a), Constant::Int(b)) => Constant::Int(a.checked_mul(b)?),
+            (BinOpKind::Add, Constant::Str(a), Constant::Str(b)) => Constant::Str(a + &b),
+            _ => return None,
+        };
+        Some(value)
+    }
+    /// Whether an assertion certainly fails, with `assertMsg` or not
+    pub fn assert_fails(&mut self, file: &Rc<Url>, assert: &Assert) -> bool {
+        let Some(mut condition) = assert.condition() else { return false };
+        if let Some((inner, _)) = assert_msg(&condition) {
+            condition = inner;
+        }
+        self.constant(file, &condition) == Some(Constant::Bool(false))
+    }
+    /// Hover for the `assert` keyword, showing what's asserted and the
+    /// message it fails with
+    pub fn assert_hover(&mut self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let token = ast.node().token_at_offset(TextUnit::from_usize(offset))
+            .find(|token| token.kind() == TOKEN_ASSERT)?;
+        let assert = Assert::cast(token.parent())?;
+        let range = utils::range(content, token.text_range(), self.encoding);
+        let condition = assert.condition()?;
+
+        let mut value = match assert_msg(&condition) {
+            Some((inner, message)) => {
+                format!("Asserts\n```nix\n{}\n```\nOtherwise fails with: {}", inner.text(), message_text(&message))
+            },
+            None => format!("Asserts\n```nix\n{}\n```", condition.text()),
+        };
+        let file = Rc::new(params.text_document.uri.clone());
+        if self.assert_fails(&file, &assert) {
+            value.push_str("\n\nThis is always false, so the assertion always fails.");
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(range),
+        })
+    }
+    /// Hover for `throw` and `abort`, showing the message they fail with.
+    /// Names defined as one, like `a = throw "not implemented";`, are
+    /// marked as deliberate errors.
+    pub fn throw_hover(&mut self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+        let range = utils::range(content, info.ident.node().text_range(), self.encoding);
+        let file = Rc::new(params.text_document.uri.clone());
+
+        let mut node = info.ident.node().clone();
+        if let Some(select) = node.parent().and_then(Select::cast).filter(|select| select.index().as_ref() == Some(&node)) {
+            node = select.node().clone();
+        }
+        let call = node.parent().and_then(|parent| throw_call(&file, &parent))
+            .filter(|(name, _)| name.node() == info.ident.node());
+        let value = if let Some((name, message)) = call {
+            let summary = match name.as_str() {
+                "abort" => format!("Aborts evaluation with: {}", message_text(&message)),
+                _ => format!("Throws an error: {}", message_text(&message)),
+            };
+            match builtins::get(name.as_str()) {
+                Some(builtin) => format!("{summary}\n\n---\n\n{}", builtin.documentation()),
+                None => summary,
+            }
+        } else {
+            if node == *info.ident.node() && !utils::is_reference(&info.ident) {
+                return None;
+            }
+            let (file, value) = self.resolve_value(&file, node)?;
+            let (_, message) = throw_call(&file, &value)?;
+            format!("Deliberately fails with: {}", message_text(&message))
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(range),
+        })
+    }
+}
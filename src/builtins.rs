@@ -0,0 +1,181 @@
+/// A builtin function or constant provided by Nix itself
+#[derive(Clone, Copy, Debug)]
+pub struct Builtin {
+    pub name: &'static str,
+    /// Whether this builtin is also available without the `builtins.` prefix
+    pub global: bool,
+    pub signature: &'static str,
+    pub doc: &'static str,
+    /// Set if the builtin is deprecated, with a note on what to use instead
+    pub deprecated: Option<&'static str>,
+    /// The version of Nix that added this builtin, if it's newer than 2.0
+    pub since: Option<Version>,
+}
+impl Builtin {
+    const fn global(self) -> Self {
+        Self { global: true, ..self }
+    }
+    const fn deprecated(self, note: &'static str) -> Self {
+        Self { deprecated: Some(note), ..self }
+    }
+    const fn since(self, major: u16, minor: u16) -> Self {
+        Self { since: Some(Version(major, minor)), ..self }
+    }
+    /// Whether a version of Nix has this builtin. Without a version, the
+    /// latest one is assumed.
+    pub fn available(&self, version: Option<Version>) -> bool {
+        match (self.since, version) {
+            (Some(since), Some(version)) => since <= version,
+            _ => true,
+        }
+    }
+    pub fn documentation(&self) -> String {
+        let mut parts = vec![format!("```nix\nbuiltins.{}\n```", self.signature), self.doc.to_owned()];
+        if let Some(since) = self.since {
+            parts.push(format!("*Since Nix {since}*"));
+        }
+        if let Some(replacement) = self.deprecated {
+            parts.push(format!("**Deprecated:** {replacement}"));
+        }
+        parts.join("\n\n")
+    }
+}
+
+/// A version of Nix, like 2.4
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u16, pub u16);
+impl Version {
+    /// Parse a version like `2.4` or `2.4.1`, where the patch version is
+    /// ignored since builtins are only added in minor releases
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next().map_or(Some(0), |minor| minor.parse().ok())?;
+        Some(Self(major, minor))
+    }
+}
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
+
+const fn builtin(name: &'static str, signature: &'static str, doc: &'static str) -> Builtin {
+    Builtin {
+        name,
+        global: false,
+        signature,
+        doc,
+        deprecated: None,
+        since: None,
+    }
+}
+
+pub static BUILTINS: &[Builtin] = &[
+    builtin("abort", "abort s", "Abort Nix expression evaluation and print the error message `s`.").global(),
+    builtin("add", "add e1 e2", "Return the sum of the numbers `e1` and `e2`."),
+    builtin("all", "all pred list", "Return `true` if the function `pred` returns `true` for all elements of `list`."),
+    builtin("any", "any pred list", "Return `true` if the function `pred` returns `true` for at least one element of `list`."),
+    builtin("attrNames", "attrNames set", "Return the names of the attributes in the set `set` in an alphabetically sorted list."),
+    builtin("attrValues", "attrValues set", "Return the values of the attributes in the set `set` in the order corresponding to the sorted attribute names."),
+    builtin("baseNameOf", "baseNameOf s", "Return the base name of the string `s`, that is, everything following the final slash.").global(),
+    builtin("bitAnd", "bitAnd e1 e2", "Return the bitwise AND of the integers `e1` and `e2`."),
+    builtin("bitOr", "bitOr e1 e2", "Return the bitwise OR of the integers `e1` and `e2`."),
+    builtin("bitXor", "bitXor e1 e2", "Return the bitwise XOR of the integers `e1` and `e2`."),
+    builtin("break", "break v", "In debug mode, pause evaluation and enter the REPL, then return `v`.").since(2, 9),
+    builtin("builtins", "builtins", "The set of all builtin functions and values.").global(),
+    builtin("catAttrs", "catAttrs attr list", "Collect each attribute named `attr` from a list of attribute sets."),
+    builtin("ceil", "ceil double", "Round the number `double` up to the nearest integer.").since(2, 4),
+    builtin("compareVersions", "compareVersions s1 s2", "Compare two version strings. Returns -1, 0 or 1."),
+    builtin("concatLists", "concatLists lists", "Concatenate a list of lists into a single list."),
+    builtin("concatMap", "concatMap f list", "Map `f` over `list` and concatenate the resulting lists."),
+    builtin("concatStringsSep", "concatStringsSep separator list", "Concatenate a list of strings with `separator` between each element."),
+    builtin("convertHash", "convertHash args", "Convert a hash to another format or algorithm.").since(2, 19),
+    builtin("currentSystem", "currentSystem", "The platform identifier for the current system, e.g. `x86_64-linux`."),
+    builtin("currentTime", "currentTime", "The current time as seconds since the Unix epoch."),
+    builtin("deepSeq", "deepSeq e1 e2", "Like `seq e1 e2`, except that `e1` is evaluated deeply."),
+    builtin("derivation", "derivation attrs", "Construct a derivation from the attribute set `attrs`.").global(),
+    builtin("dirOf", "dirOf s", "Return the directory part of the string `s`, that is, everything before the final slash.").global(),
+    builtin("div", "div e1 e2", "Return the quotient of the numbers `e1` and `e2`."),
+    builtin("elem", "elem x xs", "Return `true` if a value equal to `x` occurs in the list `xs`."),
+    builtin("elemAt", "elemAt xs n", "Return element `n` from the list `xs`. Elements are counted starting from 0."),
+    builtin("false", "false", "The boolean false.").global(),
+    builtin("fetchClosure", "fetchClosure args", "Fetch a store path closure from a binary cache.").since(2, 8),
+    builtin("fetchGit", "fetchGit args", "Fetch a path from a git repository.").global(),
+    builtin("fetchTarball", "fetchTarball url", "Download the specified URL, unpack it and return the path of the unpacked tree.").global(),
+    builtin("fetchTree", "fetchTree input", "Fetch a file system tree or a plain file using one of the supported backends.").since(2, 4),
+    builtin("fetchurl", "fetchurl url", "Download the specified URL and return the path of the downloaded file."),
+    builtin("filter", "filter f list", "Return a list consisting of the elements of `list` for which `f` returns `true`."),
+    builtin("filterSource", "filterSource e1 e2", "Copy the path `e2` to the store, filtering files with the predicate `e1`."),
+    builtin("floor", "floor double", "Round the number `double` down to the nearest integer.").since(2, 4),
+    builtin("foldl'", "foldl' op nul list", "Reduce a list by applying a binary operator, from left to right."),
+    builtin("fromJSON", "fromJSON e", "Convert a JSON string to a Nix value."),
+    builtin("functionArgs", "functionArgs f", "Return a set containing the names of the formal arguments expected by the function `f`."),
+    builtin("genList", "genList generator length", "Generate a list of the given `length`, with element `i` being `generator i`."),
+    builtin("getAttr", "getAttr s set", "Return the attribute named `s` from `set`."),
+    builtin("getEnv", "getEnv s", "Return the value of the environment variable `s`, or an empty string if unset."),
+    builtin("getFlake", "getFlake args", "Fetch a flake from a flake reference, and return its output attributes.").since(2, 4),
+    builtin("groupBy", "groupBy f list", "Group the elements of `list` into a set by the string returned by `f` for each.").since(2, 5),
+    builtin("hasAttr", "hasAttr s set", "Return `true` if `set` has an attribute named `s`."),
+    builtin("hashString", "hashString type s", "Return a base-16 representation of the cryptographic hash of the string `s`."),
+    builtin("head", "head list", "Return the first element of a list."),
+    builtin("import", "import path", "Load, parse and return the Nix expression in the file `path`.").global(),
+    builtin("intersectAttrs", "intersectAttrs e1 e2", "Return a set consisting of the attributes in `e2` that also exist in `e1`."),
+    builtin("isAttrs", "isAttrs e", "Return `true` if `e` evaluates to a set."),
+    builtin("isBool", "isBool e", "Return `true` if `e` evaluates to a bool."),
+    builtin("isFloat", "isFloat e", "Return `true` if `e` evaluates to a float."),
+    builtin("isFunction", "isFunction e", "Return `true` if `e` evaluates to a function."),
+    builtin("isInt", "isInt e", "Return `true` if `e` evaluates to an integer."),
+    builtin("isList", "isList e", "Return `true` if `e` evaluates to a list."),
+    builtin("isNull", "isNull e", "Return `true` if `e` evaluates to `null`.").global().deprecated("use `e == null` instead"),
+    builtin("isPath", "isPath e", "Return `true` if `e` evaluates to a path."),
+    builtin("isString", "isString e", "Return `true` if `e` evaluates to a string."),
+    builtin("langVersion", "langVersion", "The current version of the Nix language."),
+    builtin("length", "length e", "Return the length of the list `e`."),
+    builtin("lessThan", "lessThan e1 e2", "Return `true` if the number `e1` is less than the number `e2`."),
+    builtin("listToAttrs", "listToAttrs e", "Construct a set from a list of `{ name, value }` sets."),
+    builtin("map", "map f list", "Apply the function `f` to each element in the list `list`.").global(),
+    builtin("mapAttrs", "mapAttrs f attrset", "Apply the function `f` to every attribute of `attrset`."),
+    builtin("match", "match regex str", "Match `str` against the POSIX regular expression `regex`."),
+    builtin("mul", "mul e1 e2", "Return the product of the numbers `e1` and `e2`."),
+    builtin("nixPath", "nixPath", "The list of search path entries used to resolve `<...>` paths."),
+    builtin("nixVersion", "nixVersion", "The version of Nix."),
+    builtin("null", "null", "The null value.").global(),
+    builtin("parseDrvName", "parseDrvName s", "Split the string `s` into a package name and version."),
+    builtin("partition", "partition pred list", "Split `list` into `right` and `wrong` by the result of `pred`."),
+    builtin("path", "path args", "Copy a path to the store, with optional filtering and naming."),
+    builtin("pathExists", "pathExists path", "Return `true` if the path `path` exists at evaluation time."),
+    builtin("placeholder", "placeholder output", "Return a placeholder string for the specified `output`.").global(),
+    builtin("readDir", "readDir path", "Return the contents of the directory `path` as a set mapping names to file types."),
+    builtin("readFile", "readFile path", "Return the contents of the file `path` as a string."),
+    builtin("readFileType", "readFileType p", "Determine the directory entry type of the path `p`.").since(2, 14),
+    builtin("removeAttrs", "removeAttrs set list", "Remove the attributes listed in `list` from `set`.").global(),
+    builtin("replaceStrings", "replaceStrings from to s", "Replace all occurrences of the strings in `from` with those in `to` in `s`."),
+    builtin("scopedImport", "scopedImport scope path", "Like `import`, but with the given extra scope in effect.").global(),
+    builtin("seq", "seq e1 e2", "Evaluate `e1`, then evaluate and return `e2`."),
+    builtin("sort", "sort comparator list", "Return `list` in sorted order, using `comparator` to compare elements."),
+    builtin("split", "split regex str", "Split `str` into a list of strings and match groups by the regex `regex`."),
+    builtin("splitVersion", "splitVersion s", "Split the version string `s` into its components."),
+    builtin("storeDir", "storeDir", "The path of the Nix store, usually `/nix/store`."),
+    builtin("storePath", "storePath path", "Return `path` as a store path, adding it as a dependency."),
+    builtin("stringLength", "stringLength e", "Return the length of the string `e`."),
+    builtin("sub", "sub e1 e2", "Return the difference between the numbers `e1` and `e2`."),
+    builtin("substring", "substring start len s", "Return the substring of `s` from position `start` with length `len`."),
+    builtin("tail", "tail list", "Return the second to last elements of a list."),
+    builtin("throw", "throw s", "Throw an error message `s`, which can be caught by `tryEval`.").global(),
+    builtin("toFile", "toFile name s", "Store the string `s` in a file in the Nix store and return its path."),
+    builtin("toJSON", "toJSON e", "Return a string containing a JSON representation of `e`."),
+    builtin("toPath", "toPath s", "Convert the string `s` to a path.").deprecated("use `/. + \"/path\"` to convert a string to a path"),
+    builtin("toString", "toString e", "Convert the expression `e` to a string.").global(),
+    builtin("toXML", "toXML e", "Return a string containing an XML representation of `e`."),
+    builtin("trace", "trace e1 e2", "Print `e1` to stderr and return `e2`."),
+    builtin("traceVerbose", "traceVerbose e1 e2", "Like `trace`, but only if `--trace-verbose` is enabled.").since(2, 10),
+    builtin("true", "true", "The boolean true.").global(),
+    builtin("tryEval", "tryEval e", "Try to shallowly evaluate `e`, returning `{ success, value }`."),
+    builtin("typeOf", "typeOf e", "Return a string representing the type of the value `e`."),
+    builtin("zipAttrsWith", "zipAttrsWith f list", "Merge the sets in `list`, calling `f` with each name and the list of its values.").since(2, 6),
+];
+
+pub fn get(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
@@ -0,0 +1,124 @@
+use crate::utils::{self, Encoding};
+use lsp_types::{Color, ColorInformation, ColorPresentation, Range, TextEdit};
+use rnix::{SyntaxKind::TOKEN_STRING_CONTENT, NodeOrToken, SyntaxNode, TextRange, TextUnit};
+
+/// Colors in strings, like `"#ff0000"`, `"rgb(255, 0, 0)"` or the ones
+/// in `''client.focused #4c7899 #285577''`, so editors can show them and
+/// offer a color picker
+pub fn document_colors(root: &SyntaxNode, code: &str, encoding: Encoding) -> Vec<ColorInformation> {
+    let mut colors = Vec::new();
+    let contents = root.descendants_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+        .filter(|token| token.kind() == TOKEN_STRING_CONTENT);
+    for token in contents {
+        let start = token.text_range().start().to_usize();
+        for (offset, len, color) in find_colors(token.text().as_str()) {
+            let range = TextRange::offset_len(TextUnit::from_usize(start + offset), TextUnit::from_usize(len));
+            colors.push(ColorInformation { range: utils::range(code, range, encoding), color });
+        }
+    }
+    colors
+}
+
+/// Ways to write a color the user picked. The notation of `current`,
+/// the text being replaced, comes first.
+pub fn presentations(color: &Color, range: Range, current: &str) -> Vec<ColorPresentation> {
+    let [red, green, blue] = [color.red, color.green, color.blue].map(channel);
+    let opaque = color.alpha >= 1.0;
+    let hex = if opaque {
+        format!("#{red:02x}{green:02x}{blue:02x}")
+    } else {
+        format!("#{red:02x}{green:02x}{blue:02x}{:02x}", channel(color.alpha))
+    };
+    let rgb = if opaque {
+        format!("rgb({red}, {green}, {blue})")
+    } else {
+        format!("rgba({red}, {green}, {blue}, {})", (color.alpha * 100.0).round() / 100.0)
+    };
+    let mut labels = vec![hex, rgb];
+    if current.starts_with("rgb") {
+        labels.reverse();
+    }
+    labels.into_iter()
+        .map(|label| ColorPresentation {
+            text_edit: Some(TextEdit { range, new_text: label.clone() }),
+            label,
+            additional_text_edits: None,
+        })
+        .collect()
+}
+
+/// The colors in the text of a string, as their offset, length and value
+fn find_colors(text: &str) -> Vec<(usize, usize, Color)> {
+    let mut colors = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        // Only at the start of a word, so `a#fff` isn't a color
+        let starts_word = i == 0 || !is_word(text.as_bytes()[i - 1]);
+        let found = if starts_word && text.is_char_boundary(i) {
+            hex_color(&text[i..]).or_else(|| rgb_color(&text[i..]))
+        } else {
+            None
+        };
+        match found {
+            Some((len, color)) => {
+                colors.push((i, len, color));
+                i += len;
+            },
+            None => i += 1,
+        }
+    }
+    colors
+}
+/// A color like `#f00`, `#ff000080` at the start of some text, and its
+/// length
+fn hex_color(text: &str) -> Option<(usize, Color)> {
+    let digits = text.strip_prefix('#')?;
+    let len = digits.bytes().take_while(u8::is_ascii_hexdigit).count();
+    if digits.as_bytes().get(len).is_some_and(|&next| is_word(next)) {
+        return None;
+    }
+    let digit = |i: usize| u8::from_str_radix(&digits[i..=i], 16).ok();
+    let byte = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).ok();
+    let channels = match len {
+        3 | 4 => (0..len).map(|i| digit(i).map(|digit| digit * 17)).collect::<Option<Vec<u8>>>()?,
+        6 | 8 => (0..len).step_by(2).map(byte).collect::<Option<Vec<u8>>>()?,
+        _ => return None,
+    };
+    let alpha = channels.get(3).map_or(1.0, |&alpha| f64::from(alpha) / 255.0);
+    Some((len + 1, Color {
+        red: f64::from(channels[0]) / 255.0,
+        green: f64::from(channels[1]) / 255.0,
+        blue: f64::from(channels[2]) / 255.0,
+        alpha,
+    }))
+}
+/// A color like `rgb(255, 0, 0)` or `rgba(255, 0, 0, 0.5)` at the start
+/// of some text, and its length. Channels can be percentages too.
+fn rgb_color(text: &str) -> Option<(usize, Color)> {
+    let args = text.strip_prefix("rgba(").or_else(|| text.strip_prefix("rgb("))?;
+    let end = args.find(')')?;
+    let parts: Vec<&str> = args[..end].split(',').map(str::trim).collect();
+    let channel = |part: &str| match part.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().ok().filter(|percent| (0.0..=100.0).contains(percent)).map(|percent| percent / 100.0),
+        None => part.parse::<u8>().ok().map(|value| f64::from(value) / 255.0),
+    };
+    let alpha = |part: &str| match part.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().ok().map(|percent| percent / 100.0),
+        None => part.parse::<f64>().ok(),
+    }.filter(|alpha| (0.0..=1.0).contains(alpha));
+    let (red, green, blue, alpha) = match parts.as_slice() {
+        [red, green, blue] => (channel(red)?, channel(green)?, channel(blue)?, 1.0),
+        [red, green, blue, opacity] => (channel(red)?, channel(green)?, channel(blue)?, alpha(opacity)?),
+        _ => return None,
+    };
+    Some((text.len() - args.len() + end + 1, Color { red, green, blue, alpha }))
+}
+fn is_word(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+/// A channel of a color in the range [0-1] as a byte
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // Clamped to a byte first
+fn channel(value: f64) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
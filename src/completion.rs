@@ -0,0 +1,1328 @@
+use crate::{
+    App,
+    builtins::{self, Builtin, Version},
+    config::Config,
+    imports,
+    meta::{self, MetaField},
+    nixlib,
+    options,
+    packages,
+    utils::{self, CompletionContext, Encoding, Var, VarKind},
+};
+use lsp_types::*;
+use rnix::{
+    types::*,
+    SyntaxKind::{NODE_ATTR_SET, NODE_LAMBDA, NODE_PAT_ENTRY, TOKEN_ASSIGN, TOKEN_CURLY_B_OPEN, TOKEN_IN, TOKEN_INTERPOL_START, TOKEN_WHITESPACE},
+    SyntaxNode,
+    TextRange,
+    TextUnit,
+};
+use serde_json::json;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    convert::TryFrom,
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+/// Characters that make the editor ask for completions by itself: `.`
+/// for attributes, `${` for interpolations, `/` for paths and `<` for
+/// search paths like `<nixpkgs>`
+pub const TRIGGER_CHARACTERS: &[&str] = &[".", "$", "{", "/", "<"];
+/// How many functions to look through for the attributes of a
+/// derivation, like `callPackage ./pkg.nix { }` to `mkDerivation { ... }`
+const MAX_CALL_DEPTH: usize = 8;
+/// How many lines of a value to show when resolving a completion
+const MAX_PREVIEW_LINES: usize = 12;
+
+/// The groups completions are sorted in, so that local variables aren't
+/// buried under everything `with pkgs;` brings into scope
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionGroup {
+    /// Bindings of a `let` or `rec` set, and attributes of a set
+    Local,
+    /// Function arguments
+    Argument,
+    /// Names brought into scope by `with`
+    With,
+    Builtin,
+    /// Functions of the nixpkgs library
+    Lib,
+    /// Names from the package list
+    Package,
+}
+impl CompletionGroup {
+    /// The default order
+    pub const ALL: [Self; 6] = [Self::Local, Self::Argument, Self::With, Self::Builtin, Self::Lib, Self::Package];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "local" => Some(Self::Local),
+            "argument" => Some(Self::Argument),
+            "with" => Some(Self::With),
+            "builtin" => Some(Self::Builtin),
+            "lib" => Some(Self::Lib),
+            "package" => Some(Self::Package),
+            _ => None,
+        }
+    }
+    fn of(kind: &VarKind) -> Self {
+        match kind {
+            VarKind::Let | VarKind::Rec | VarKind::Attr => Self::Local,
+            VarKind::Param => Self::Argument,
+            VarKind::With(_) => Self::With,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OverrideKind {
+    /// `pkg.override { ... }`, taking the arguments of the package
+    Override,
+    /// `pkg.overrideAttrs (old: { ... })`, taking the attributes of the
+    /// derivation
+    OverrideAttrs,
+}
+
+/// A set a key is being typed in
+struct KeySlot {
+    /// The set, or the function if it's parsed as a pattern
+    set: SyntaxNode,
+    /// Names which are already set, other than the one being typed
+    existing: Vec<String>,
+    /// The identifier being typed, if any
+    ident: Option<Ident>,
+}
+
+/// The set of arguments being written in an override
+struct OverrideSlot {
+    kind: OverrideKind,
+    /// The package being overridden
+    base: SyntaxNode,
+    /// Names which are already set
+    existing: Vec<String>,
+    /// The identifier being typed, if any
+    ident: Option<Ident>,
+}
+
+/// The completions of the last variable completed, which are filtered
+/// again while more of its name is typed. Typing changes the version of
+/// the file, so they're kept as long as the code around the name stays
+/// the same instead.
+#[derive(Debug)]
+pub struct CompletionCache {
+    file: Url,
+    /// Where the name starts
+    start: usize,
+    /// A hash of the code before and after the name
+    context: u64,
+    /// The name when the completions were computed
+    prefix: String,
+    items: Vec<CompletionItem>,
+}
+impl CompletionCache {
+    fn new(file: &Url, code: &str, ident: &Ident, items: &[CompletionItem]) -> Self {
+        let range = ident.node().text_range();
+        Self {
+            file: file.clone(),
+            start: range.start().to_usize(),
+            context: context_hash(code, range),
+            prefix: ident.as_str().to_owned(),
+            items: items.to_vec(),
+        }
+    }
+    /// Whether the completions were computed in a file. They can come
+    /// from other files, like ones that are imported, while edits of the
+    /// file itself are noticed by `get`.
+    pub fn is_for(&self, file: &Url) -> bool {
+        self.file == *file
+    }
+    /// The cached completions still matching a name, if the name is the
+    /// cached one with more typed after it
+    fn get(&self, file: &Url, code: &str, ident: &Ident, range: Range) -> Option<Vec<CompletionItem>> {
+        let name = ident.node().text_range();
+        let prefix = ident.as_str();
+        let reusable = self.file == *file
+            && self.start == name.start().to_usize()
+            && prefix.starts_with(&self.prefix)
+            && self.context == context_hash(code, name);
+        if !reusable {
+            return None;
+        }
+        // Definitions after the name moved by what was typed since
+        let end = self.start + self.prefix.len();
+        let moved = prefix.len() - self.prefix.len();
+        Some(self.items.iter()
+            .filter(|item| item.label.starts_with(prefix))
+            .cloned()
+            .map(|mut item| {
+                if let Some(edit) = &mut item.text_edit {
+                    edit.range = range;
+                }
+                if let Some(data) = &mut item.data {
+                    let offset = data["offset"].as_u64().and_then(|offset| usize::try_from(offset).ok());
+                    if let Some(offset) = offset.filter(|&offset| offset >= end && data["uri"].as_str() == Some(file.as_str())) {
+                        data["offset"] = json!(offset + moved);
+                    }
+                }
+                item
+            })
+            .collect())
+    }
+}
+fn context_hash(code: &str, name: TextRange) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    code[..name.start().to_usize()].hash(&mut hasher);
+    code[name.end().to_usize()..].hash(&mut hasher);
+    hasher.finish()
+}
+
+impl App {
+    /// Completions for a request the editor sent by itself, because one
+    /// of `TRIGGER_CHARACTERS` was typed. Only what that character
+    /// starts is completed, so typing `/` in `a / b` doesn't pop up
+    /// every variable in scope.
+    pub fn triggered_completions(&mut self, params: &TextDocumentPositionParams, trigger: &str) -> Option<Vec<CompletionItem>> {
+        let file = Rc::new(params.text_document.uri.clone());
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let node = ast.node();
+        match trigger {
+            "." => {
+                let select = utils::ident_at(&node, offset)
+                    .is_some_and(|info| utils::classify_completion(&info) == CompletionContext::Select);
+                if select {
+                    return self.completions(params);
+                }
+                if let Some(completions) = self.select_completions(&file, &node, offset) {
+                    return Some(completions);
+                }
+                let (_, content) = self.files.get(&params.text_document.uri)?;
+                self.option_key_completions(&node, content, offset)
+            },
+            "/" | "<" => imports::completions(&file, &node, content, offset, self.encoding)
+                .or_else(|| imports::search_path_completions(content, offset, self.encoding)),
+            // Nothing to complete until the `{` of `${`
+            "$" => None,
+            _ => self.completions(params),
+        }
+    }
+    pub fn completions(&mut self, params: &TextDocumentPositionParams) -> Option<Vec<CompletionItem>> {
+        let file = Rc::new(params.text_document.uri.clone());
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+
+        let node = ast.node();
+        let paths = imports::completions(&file, &node, content, offset, self.encoding)
+            .or_else(|| imports::search_path_completions(content, offset, self.encoding));
+        if paths.is_some() {
+            return paths;
+        }
+        let options = self.value_completions(&node, content, offset).or_else(|| self.option_key_completions(&node, content, offset));
+        if options.is_some() {
+            return options;
+        }
+        if let Some(completions) = self.license_completions(&file, &node, content, offset) {
+            return Some(completions);
+        }
+        if let Some(slot) = key_slot(&node, offset).filter(|slot| is_meta(&slot.set)) {
+            let range = key_range(content, slot.ident.as_ref(), offset, self.encoding);
+            return Some(self.field_completions(meta::META_FIELDS, &slot.existing, slot.ident.as_ref(), range));
+        }
+        if let Some(slot) = override_slot(&node, offset) {
+            let range = key_range(content, slot.ident.as_ref(), offset, self.encoding);
+            return Some(self.override_completions(&file, &slot, range));
+        }
+        if let Some((slot, function)) = argument_slot(&node, offset) {
+            let range = key_range(content, slot.ident.as_ref(), offset, self.encoding);
+            if let Some(completions) = self.argument_completions(&file, &slot, function, range) {
+                return Some(completions);
+            }
+        }
+        // Unknown functions take any names
+        let (_, content) = self.files.get(&params.text_document.uri)?;
+        let Some(info) = utils::ident_at(&node, offset) else {
+            let cursor = TextUnit::from_usize(offset);
+            let range = utils::range(content, TextRange::from_to(cursor, cursor), self.encoding);
+            return self.untyped_completions(&file, &node, offset, range);
+        };
+        let range = utils::range(content, info.ident.node().text_range(), self.encoding);
+        let prefix = info.ident.as_str();
+
+        let position = utils::classify_completion(&info);
+        match position {
+            CompletionContext::Inherit | CompletionContext::InheritFrom => {
+                let inherit = info.ident.node().parent().and_then(Inherit::cast)?;
+                return self.inherit_completions(&file, &inherit, prefix, range);
+            },
+            CompletionContext::Key
+            | CompletionContext::Select
+            | CompletionContext::Interpolation
+            | CompletionContext::Value => (),
+        }
+
+        if utils::builtin_path(&file, &info) == Some(true) {
+            return Some(builtins_completions(prefix, range, self.config.nix_version));
+        }
+        if let Some(completions) = self.option_completions(&file, &info.path, prefix, info.ident.node(), range) {
+            return Some(completions);
+        }
+        // Keys can't be cached, since the one being typed is part of the
+        // set it's completed in
+        let cacheable = position != CompletionContext::Key;
+        if let Some(cached) = self.completion_cache.as_ref().filter(|_| cacheable) {
+            if let Some(completions) = cached.get(&file, content, &info.ident, range) {
+                return Some(completions);
+            }
+        }
+        let mut lib = self.lib_completions(&file, &info, range);
+        if let Some(lib) = &mut lib {
+            rank(lib, CompletionGroup::Lib, &self.config.completion_order);
+        }
+        if !info.path.is_empty() && lib.is_some() {
+            return lib;
+        }
+
+        let (name, mut scope) = self.scope_for_ident(params.text_document.uri.clone(), &node, offset)?;
+        scope.retain(|_, var| !is_being_defined(var, &name));
+
+        let quote = !info.path.is_empty() || position == CompletionContext::Key;
+        let mut completions = var_completions(&scope, name.as_str(), range, quote, &self.config.completion_order);
+        if info.path.is_empty() {
+            add_global_completions(&mut completions, &scope, name.as_str(), range, &self.config);
+        }
+        // Names from `with lib;` are only suggested if they aren't
+        // something else
+        let version = self.config.nix_version;
+        completions.extend(lib.into_iter().flatten().filter(|function| !scope.contains_key(&function.label) && !is_global(&function.label, version)));
+        let in_package_list = name.node().parent().and_then(List::cast).is_some_and(|list| packages::in_package_list(&list));
+        if in_package_list {
+            let mut packages = self.package_completions(name.as_str(), range);
+            rank(&mut packages, CompletionGroup::Package, &self.config.completion_order);
+            // Without the duplicates from a `with pkgs;` we could resolve
+            let packages: Vec<_> = packages.into_iter().filter(|package| !scope.contains_key(&package.label)).collect();
+            completions.extend(packages);
+        }
+        if position == CompletionContext::Key && self.key_snippets() && !has_value(&name) {
+            for completion in &mut completions {
+                if let Some(edit) = &mut completion.text_edit {
+                    // Quoted keys may contain characters special to snippets
+                    edit.new_text = format!("{} = $0;", utils::escape_snippet(&edit.new_text));
+                    completion.insert_text_format = Some(InsertTextFormat::Snippet);
+                }
+            }
+        }
+        if cacheable {
+            let (_, content) = self.files.get(&params.text_document.uri)?;
+            self.completion_cache = Some(CompletionCache::new(&file, content, &info.ident, &completions));
+        }
+        Some(completions)
+    }
+    /// Completions for attributes `ident_at` can't find a path for, see
+    /// `utils::select_at`. The set is resolved from the expression before
+    /// the dot, so this works for imports too.
+    fn select_completions(&mut self, file: &Rc<Url>, root: &SyntaxNode, offset: usize) -> Option<Vec<CompletionItem>> {
+        let (select, ident) = utils::select_at(root, offset)?;
+        let set = select.set()?;
+        let cursor = TextUnit::from_usize(offset);
+        let range = ident.as_ref().map_or(TextRange::from_to(cursor, cursor), |ident| ident.node().text_range());
+        let (_, code) = self.files.get(file)?;
+        let range = utils::range(code, range, self.encoding);
+        let prefix = ident.as_ref().map_or("", Ident::as_str);
+
+        if let Some(path) = utils::select_path(set.clone()).filter(|_| ident.is_none()) {
+            let unbound = |name: &str| utils::scope_for(file, set.clone()).is_some_and(|scope| !scope.contains_key(name));
+            if path == ["builtins"] && unbound("builtins") {
+                return Some(builtins_completions("", range, self.config.nix_version));
+            }
+            if let Some(completions) = self.option_completions(file, &path, "", &set, range) {
+                return Some(completions);
+            }
+            if let Some(mut lib) = self.lib_member_completions(file, &path, &set, range) {
+                rank(&mut lib, CompletionGroup::Lib, &self.config.completion_order);
+                return Some(lib);
+            }
+        }
+        let scope = self.scope_from_node(file, set)?;
+        Some(var_completions(&scope, prefix, range, true, &self.config.completion_order))
+    }
+    /// Completions for keys in the sets of a module, from the options,
+    /// see `module_key_completions`
+    fn option_key_completions(&self, root: &SyntaxNode, code: &str, offset: usize) -> Option<Vec<CompletionItem>> {
+        let (before, slot) = dotted_key_slot(root, offset)?;
+        let range = key_range(code, slot.ident.as_ref(), offset, self.encoding);
+        let prefix = slot.ident.as_ref().map_or("", Ident::as_str);
+        let snippets = self.key_snippets() && slot.ident.as_ref().is_none_or(|ident| !has_value(ident));
+        self.module_key_completions(&slot.set, &before, prefix, &slot.existing, range, snippets)
+    }
+    /// Completions when nothing is typed yet, which only makes sense
+    /// after a dot, in an interpolation, an `inherit` or a list of
+    /// packages
+    fn untyped_completions(&mut self, file: &Rc<Url>, root: &SyntaxNode, offset: usize, range: Range) -> Option<Vec<CompletionItem>> {
+        if let Some(completions) = self.select_completions(file, root, offset) {
+            return Some(completions);
+        }
+        if packages::list_at(root, offset).is_some_and(|list| packages::in_package_list(&list)) {
+            let mut packages = self.package_completions("", range);
+            rank(&mut packages, CompletionGroup::Package, &self.config.completion_order);
+            return Some(packages);
+        }
+        if let Some(completions) = self.scope_completions(file, root, offset, range) {
+            return Some(completions);
+        }
+        let inherit = utils::inherit_at(root, offset)?;
+        self.inherit_completions(file, &inherit, "", range)
+    }
+    /// Completions for an empty interpolation, like `"${<cursor>}"`, or
+    /// the body of a `let` after `in `, which are all the names in scope.
+    /// While typing the body is often missing, which puts `in` into an
+    /// error node, but that's still inside the `let`.
+    fn scope_completions(&mut self, file: &Rc<Url>, root: &SyntaxNode, offset: usize, range: Range) -> Option<Vec<CompletionItem>> {
+        let mut token = root.token_at_offset(TextUnit::from_usize(offset)).left_biased()?;
+        let spaced = token.kind().is_trivia();
+        while token.kind().is_trivia() {
+            token = token.prev_token()?;
+        }
+        let parent = match token.kind() {
+            TOKEN_INTERPOL_START if !spaced => token.parent(),
+            TOKEN_IN if spaced => token.parent().ancestors().find(|node| LetIn::cast(node.clone()).is_some())?,
+            _ => return None,
+        };
+        let scope = self.scope_at(file, &parent)?;
+        let mut completions = var_completions(&scope, "", range, false, &self.config.completion_order);
+        add_global_completions(&mut completions, &scope, "", range, &self.config);
+        Some(completions)
+    }
+    /// Add the value of a variable to its completion, which is only done
+    /// once the editor shows the item. The item has the position of the
+    /// variable's name, which is found again here.
+    pub fn resolve_completion(&mut self, mut item: CompletionItem) -> CompletionItem {
+        let location = item.data.as_ref().and_then(|data| {
+            let uri = Url::parse(data.get("uri")?.as_str()?).ok()?;
+            let offset = usize::try_from(data.get("offset")?.as_u64()?).ok()?;
+            Some((uri, offset))
+        });
+        let Some((uri, offset)) = location else { return item };
+        if let Some(documentation) = self.var_documentation(uri, offset, &item.label) {
+            item.documentation = Some(Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: documentation,
+            }));
+        }
+        item
+    }
+    fn var_documentation(&mut self, uri: Url, offset: usize, name: &str) -> Option<String> {
+        let (ast, code) = self.files.get(&uri)?;
+        let token = ast.node().token_at_offset(TextUnit::from_usize(offset)).right_biased()?;
+        // The file may have changed since completing
+        let key = token.parent();
+        if utils::static_name(&key).as_deref() != Some(name) {
+            return None;
+        }
+        let line = utils::offset_to_pos(code, offset, self.encoding).line + 1;
+        let file_name = uri.path_segments().and_then(Iterator::last).unwrap_or_default().to_owned();
+        let file = Rc::new(uri);
+
+        let comment = utils::doc_comment(&key).map_or_else(String::new, |comment| format!("{comment}\n\n"));
+        let holder = key.parent()?;
+        let value = match ParsedType::try_from(holder.clone()).ok()? {
+            ParsedType::Key(key) => {
+                let entry = KeyValue::cast(holder.parent()?)?;
+                // For `a.b = 1;`, `a` is more than just the value
+                if key.path().nth(1).is_some() {
+                    Some(entry.node().text().to_string())
+                } else {
+                    Some(entry.value()?.text().to_string())
+                }
+            },
+            ParsedType::Inherit(_) => {
+                let var = self.definition(Var {
+                    file: Rc::clone(&file),
+                    set: holder.parent()?,
+                    key,
+                    value: None,
+                    kind: VarKind::Let,
+                });
+                var.value.map(|value| value.text().to_string())
+            },
+            ParsedType::PatEntry(entry) => entry.default().map(|default| format!("{name} ? {}", default.text())),
+            _ => None,
+        };
+        let preview = match value {
+            Some(value) => {
+                let mut lines: Vec<&str> = value.lines().take(MAX_PREVIEW_LINES + 1).collect();
+                if lines.len() > MAX_PREVIEW_LINES {
+                    lines.truncate(MAX_PREVIEW_LINES);
+                    lines.push("...");
+                }
+                format!("```nix\n{}\n```\n", lines.join("\n"))
+            },
+            None if matches!(holder.kind(), NODE_PAT_ENTRY | NODE_LAMBDA) => "Function argument\n\n".into(),
+            None => String::new(),
+        };
+        Some(format!("{comment}{preview}Defined on line {line} of `{file_name}`"))
+    }
+    /// Completions for the arguments of `.override` or the attributes of
+    /// `.overrideAttrs`. This only works for packages defined locally, the
+    /// names of anything else can't be known statically.
+    fn override_completions(&mut self, file: &Rc<Url>, slot: &OverrideSlot, range: Range) -> Vec<CompletionItem> {
+        let call = self.resolve_value(file, slot.base.clone())
+            .and_then(|(file, value)| Some((file, Apply::cast(utils::unwrap_parens(value))?)));
+        if slot.kind == OverrideKind::OverrideAttrs {
+            // Attributes every derivation can have, after the ones the
+            // package sets, documented if they're common ones
+            let names = call.and_then(|(file, call)| self.derivation_set(&file, call)).map_or_else(Vec::new, |(_, set)| set_keys(&set));
+            let mut completions = self.key_completions(names, "derivation attribute", &slot.existing, slot.ident.as_ref(), range);
+            for completion in &mut completions {
+                if let Some(field) = meta::DERIVATION_FIELDS.iter().find(|field| field.name == completion.label) {
+                    completion.documentation = Some(Documentation::MarkupContent(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value: field.doc.into(),
+                    }));
+                }
+            }
+            let existing: Vec<String> = slot.existing.iter().cloned().chain(completions.iter().map(|completion| completion.label.clone())).collect();
+            completions.extend(self.field_completions(meta::DERIVATION_FIELDS, &existing, slot.ident.as_ref(), range));
+            return completions;
+        }
+        let Some((file, call)) = call else { return Vec::new() };
+        let names = self.package_arguments(&file, &call);
+        self.key_completions(names, "override argument", &slot.existing, slot.ident.as_ref(), range)
+    }
+    /// Completions for the arguments of a call like `f { | }`, from the
+    /// pattern of the function. Sets with `__functor` are called with the
+    /// pattern of the function it returns. Returns `None` if the function
+    /// or the names it takes aren't known.
+    fn argument_completions(&mut self, file: &Rc<Url>, slot: &KeySlot, function: SyntaxNode, range: Range) -> Option<Vec<CompletionItem>> {
+        // `callPackage ./pkg.nix { }` passes what's left out from the
+        // package set, so everything in the argument set overrides that
+        let detail = if is_call_package(&function) { "callPackage argument" } else { "function argument" };
+        let (_, lambda) = match self.callable(file, function.clone()) {
+            Some(callable) => callable,
+            None => self.local_function(file, function)?,
+        };
+        let pattern = lambda.arg().and_then(Pattern::cast)?;
+        let mut defaults = HashMap::new();
+        let mut names = Vec::new();
+        for entry in pattern.entries() {
+            let Some(name) = entry.name() else { continue };
+            if let Some(default) = entry.default() {
+                defaults.insert(name.as_str().to_owned(), default.text().to_string());
+            }
+            names.push(name.as_str().to_owned());
+        }
+        if names.is_empty() {
+            return None;
+        }
+        let mut completions = self.key_completions(names, detail, &slot.existing, slot.ident.as_ref(), range);
+        for completion in &mut completions {
+            if let Some(default) = defaults.get(&completion.label) {
+                completion.detail = Some(format!("{detail}, optional"));
+                completion.documentation = Some(Documentation::MarkupContent(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: format!("Defaults to\n```nix\n{default}\n```"),
+                }));
+            }
+        }
+        Some(completions)
+    }
+    /// Completions for keys of a set out of a list of names, minus the
+    /// ones that are already set
+    fn key_completions(&self, names: Vec<String>, detail: &str, existing: &[String], ident: Option<&Ident>, range: Range) -> Vec<CompletionItem> {
+        let prefix = ident.map_or("", Ident::as_str);
+        let snippet = self.key_snippets() && ident.is_none_or(|ident| !has_value(ident));
+        let mut completions: Vec<CompletionItem> = names.into_iter()
+            .filter(|name| name.starts_with(prefix) && !existing.contains(name))
+            .map(|name| {
+                // Names like `"foo.bar"` are quoted, which may put
+                // characters special to snippets in them
+                let key = utils::escape_key(&name);
+                CompletionItem {
+                    label: name,
+                    kind: Some(CompletionItemKind::Property),
+                    detail: Some(detail.into()),
+                    insert_text_format: if snippet { Some(InsertTextFormat::Snippet) } else { None },
+                    text_edit: Some(TextEdit {
+                        range,
+                        new_text: if snippet { format!("{} = $0;", utils::escape_snippet(&key)) } else { key },
+                    }),
+                    ..CompletionItem::default()
+                }
+            })
+            .collect();
+        completions.sort_by(|a, b| a.label.cmp(&b.label));
+        completions.dedup_by(|a, b| a.label == b.label);
+        completions
+    }
+    /// Completions for documented attributes, like the standard ones of
+    /// `meta`, minus the ones that are already set
+    fn field_completions(&self, fields: &[MetaField], existing: &[String], ident: Option<&Ident>, range: Range) -> Vec<CompletionItem> {
+        let prefix = ident.map_or("", Ident::as_str);
+        let snippet = self.key_snippets() && ident.is_none_or(|ident| !has_value(ident));
+        fields.iter()
+            .filter(|field| field.name.starts_with(prefix) && !existing.iter().any(|name| name == field.name))
+            .map(|field| {
+                let value = match field.typ {
+                    "string" => "\"$0\"",
+                    "shell code" => "''$0''",
+                    typ if typ.starts_with("list") => "[ $0 ]",
+                    _ => "$0",
+                };
+                CompletionItem {
+                    label: field.name.into(),
+                    kind: Some(CompletionItemKind::Property),
+                    detail: Some(field.typ.into()),
+                    documentation: Some(Documentation::MarkupContent(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value: field.doc.into(),
+                    })),
+                    insert_text_format: if snippet { Some(InsertTextFormat::Snippet) } else { None },
+                    text_edit: Some(TextEdit {
+                        range,
+                        new_text: if snippet { format!("{} = {value};", field.name) } else { field.name.into() },
+                    }),
+                    ..CompletionItem::default()
+                }
+            })
+            .collect()
+    }
+    /// Completions for the value of `license` in `meta`, from
+    /// `lib.licenses`. Returns `None` anywhere else, or if `lib` isn't
+    /// known to be the library.
+    fn license_completions(&self, file: &Rc<Url>, root: &SyntaxNode, code: &str, offset: usize)
+        -> Option<Vec<CompletionItem>>
+    {
+        let (entry, range) = options::value_at(root, code, offset)?;
+        let path: Vec<_> = entry.key()?.path().map(|part| utils::static_name(&part)).collect();
+        let in_meta = match path.as_slice() {
+            [.., Some(meta), Some(license)] if meta == "meta" && license == "license" => true,
+            [.., Some(license)] if license == "license" => entry.node().parent().is_some_and(|set| is_meta(&set)),
+            _ => false,
+        };
+        let typed = code.get(range.start().to_usize()..offset)?;
+        if !in_meta || typed.contains('"') {
+            return None;
+        }
+        let prefix = nixlib::lib_prefix(file, entry.node())?;
+        let range = utils::range(code, range, self.encoding);
+        Some(meta::LICENSES.iter()
+            .map(|license| (license, format!("{prefix}licenses.{}", license.name)))
+            // Either the name, or the whole path is being typed
+            .filter(|(license, path)| license.name.starts_with(typed) || path.starts_with(typed))
+            .map(|(license, path)| CompletionItem {
+                label: license.name.into(),
+                kind: Some(CompletionItemKind::EnumMember),
+                detail: Some(license.full_name.into()),
+                documentation: license.spdx.map(|spdx| Documentation::String(format!("SPDX: {spdx}"))),
+                filter_text: Some(if typed.contains('.') { path.clone() } else { license.name.into() }),
+                text_edit: Some(TextEdit {
+                    range,
+                    new_text: path,
+                }),
+                ..CompletionItem::default()
+            })
+            .collect())
+    }
+    /// The arguments a package was called with, along with the ones its
+    /// function accepts
+    fn package_arguments(&mut self, file: &Rc<Url>, call: &Apply) -> Vec<String> {
+        let mut names = call.value().map(|arg| set_keys(&utils::unwrap_parens(arg))).unwrap_or_default();
+        if let Some((_, lambda)) = call.lambda().and_then(|function| self.local_function(file, function)) {
+            if let Some(pattern) = lambda.arg().and_then(Pattern::cast) {
+                names.extend(pattern.entries().filter_map(|entry| entry.name()).map(|name| name.as_str().to_owned()));
+            }
+        }
+        names
+    }
+    /// The set of attributes `old` stands for in `pkg.overrideAttrs (old:
+    /// { ... })`, or in `(finalAttrs: old: { ... })`, given the function
+    /// defining it. That's the set the package passes to `mkDerivation`,
+    /// if it's defined locally.
+    pub fn overridden_attrs(&mut self, file: &Rc<Url>, lambda: &SyntaxNode) -> Option<(Rc<Url>, SyntaxNode)> {
+        let mut node = lambda.clone();
+        while let Some(parent) = node.parent() {
+            let is_body = Lambda::cast(parent.clone()).is_some_and(|outer| outer.body().as_ref() == Some(&node));
+            if !is_body && Paren::cast(parent.clone()).is_none() {
+                break;
+            }
+            node = parent;
+        }
+        let apply = Apply::cast(node.parent()?).filter(|apply| apply.value().as_ref() == Some(&node))?;
+        let select = Select::cast(apply.lambda()?)?;
+        if Ident::cast(select.index()?)?.as_str() != "overrideAttrs" {
+            return None;
+        }
+        let (file, value) = self.resolve_value(file, select.set()?)?;
+        self.derivation_set(&file, Apply::cast(utils::unwrap_parens(value))?)
+    }
+    /// The set of attributes of the derivation a package evaluates to,
+    /// looking through local functions until a call like
+    /// `mkDerivation { ... }`
+    fn derivation_set(&mut self, file: &Rc<Url>, mut call: Apply) -> Option<(Rc<Url>, SyntaxNode)> {
+        let mut file = Rc::clone(file);
+        for _ in 0..MAX_CALL_DEPTH {
+            let Some(function) = call.lambda() else { break };
+            let Some((function_file, lambda)) = self.local_function(&file, function) else {
+                // Not ours, so probably `mkDerivation` or similar
+                let Some(mut arg) = call.value().map(utils::unwrap_parens) else { break };
+                // `mkDerivation (finalAttrs: { ... })`
+                while let Some(lambda) = Lambda::cast(arg.clone()) {
+                    let Some(body) = lambda.body() else { break };
+                    arg = utils::unwrap_parens(body);
+                }
+                return AttrSet::cast(arg.clone()).map(|_| (file, arg));
+            };
+            let mut body = lambda.body();
+            while let Some(node) = body.clone() {
+                body = match ParsedType::try_from(node) {
+                    Ok(ParsedType::LetIn(let_in)) => let_in.body(),
+                    Ok(ParsedType::With(with)) => with.body(),
+                    Ok(ParsedType::Assert(assert)) => assert.body(),
+                    Ok(ParsedType::Paren(paren)) => paren.inner(),
+                    _ => break,
+                };
+            }
+            let Some(next) = body.and_then(Apply::cast) else { break };
+            file = function_file;
+            call = next;
+        }
+        None
+    }
+    /// The function a node refers to, if it's defined locally. This also
+    /// looks through `import ./file.nix` and `callPackage ./file.nix`.
+    fn local_function(&mut self, file: &Rc<Url>, function: SyntaxNode) -> Option<(Rc<Url>, Lambda)> {
+        let (file, function) = self.resolve_value(file, function)?;
+        if let Some(apply) = Apply::cast(function.clone()) {
+            let name = apply.lambda().and_then(caller_name)?;
+            if name.as_str() != "import" && name.as_str() != "callPackage" {
+                return None;
+            }
+            let (file, root) = self.import(&file, &apply.value()?)?;
+            return Some((file, Lambda::cast(root)?));
+        }
+        Some((file, Lambda::cast(function)?))
+    }
+    fn key_snippets(&self) -> bool {
+        self.config.key_snippets && self.client.text_document.as_ref()
+            .and_then(|document| document.completion.as_ref())
+            .and_then(|completion| completion.completion_item.as_ref())
+            .and_then(|item| item.snippet_support)
+            .unwrap_or(false)
+    }
+    fn inherit_completions(&mut self, file: &Rc<Url>, inherit: &Inherit, prefix: &str, range: Range)
+        -> Option<Vec<CompletionItem>>
+    {
+        if let Some(from) = inherit.from() {
+            let scope = self.scope_from_node(file, from.inner()?)?;
+            return Some(var_completions(&scope, prefix, range, false, &self.config.completion_order));
+        }
+        // An inherit without a source takes from the surrounding scope,
+        // which doesn't include the bindings next to the inherit itself
+        let holder = inherit.node().parent()?;
+        let scope = self.scope_at(file, &holder.parent()?)?;
+        let mut completions = var_completions(&scope, prefix, range, false, &self.config.completion_order);
+        add_global_completions(&mut completions, &scope, prefix, range, &self.config);
+        Some(completions)
+    }
+}
+
+/// Find the set the cursor is typing a key in, if any. Sets with a
+/// single identifier, like `{ a }`, are parsed as function patterns, so
+/// those are handled too.
+fn key_slot(root: &SyntaxNode, offset: usize) -> Option<KeySlot> {
+    let token = root.token_at_offset(TextUnit::from_usize(offset)).left_biased()?;
+    let (ident, set) = if let Some(ident) = Ident::cast(token.parent()) {
+        let parent = ident.node().parent()?;
+        if let Some(key) = Key::cast(parent.clone()) {
+            if key.path().count() != 1 {
+                return None;
+            }
+            let set = key.node().parent()?.parent()?;
+            (Some(ident), set)
+        } else if parent.kind() == NODE_PAT_ENTRY {
+            let set = parent.parent()?.parent()?;
+            (Some(ident), set)
+        } else {
+            return None;
+        }
+    } else if token.kind() == TOKEN_WHITESPACE || token.kind() == TOKEN_CURLY_B_OPEN {
+        match token.parent() {
+            parent if AttrSet::cast(parent.clone()).is_some() => (None, parent),
+            parent if Pattern::cast(parent.clone()).is_some() => (None, parent.parent()?),
+            _ => return None,
+        }
+    } else {
+        return None;
+    };
+
+    let existing = if let Some(set) = AttrSet::cast(set.clone()) {
+        set_keys(set.node())
+    } else {
+        let pattern = Lambda::cast(set.clone())?.arg().and_then(Pattern::cast)?;
+        pattern.entries().filter_map(|entry| entry.name()).map(|name| name.as_str().to_owned()).collect()
+    };
+    Some(KeySlot {
+        set,
+        existing: existing.into_iter().filter(|name| Some(name.as_str()) != ident.as_ref().map(Ident::as_str)).collect(),
+        ident,
+    })
+}
+/// Like `key_slot`, but also for keys with a path, along with the names
+/// before the one being typed, like `a.b` for `a.b.|`
+fn dotted_key_slot(root: &SyntaxNode, offset: usize) -> Option<(Vec<String>, KeySlot)> {
+    if let Some(slot) = key_slot(root, offset) {
+        return Some((Vec::new(), slot));
+    }
+    let cursor = TextUnit::from_usize(offset);
+    let token = root.token_at_offset(cursor).left_biased()?;
+    let key = token.parent().ancestors().take(2).find_map(Key::cast)?;
+    let ident = Ident::cast(token.parent()).filter(|ident| ident.node().parent().as_ref() == Some(key.node()));
+    // Without a name after the dot, the key may go on with the next line
+    let end = ident.as_ref().map_or(cursor, |ident| ident.node().text_range().start());
+    let before = key.path()
+        .take_while(|part| part.text_range().end() <= end)
+        .map(|part| options::option_name(&part))
+        .collect::<Option<Vec<_>>>()?;
+    let set = key.node().parent()?.parent()?;
+    Some((before, KeySlot { set, existing: Vec::new(), ident }))
+}
+/// Find the override, if any, whose argument set the cursor is in
+fn override_slot(root: &SyntaxNode, offset: usize) -> Option<OverrideSlot> {
+    let KeySlot { set, existing, ident } = key_slot(root, offset)?;
+
+    // Skip through `old: { ... }` and parentheses
+    let mut node = set;
+    loop {
+        let parent = node.parent()?;
+        let is_body = Lambda::cast(parent.clone()).is_some_and(|lambda| lambda.body().as_ref() == Some(&node));
+        if !is_body && Paren::cast(parent.clone()).is_none() {
+            break;
+        }
+        node = parent;
+    }
+    let apply = Apply::cast(node.parent()?)?;
+    if apply.value().as_ref() != Some(&node) {
+        return None;
+    }
+    let select = Select::cast(apply.lambda()?)?;
+    let kind = match Ident::cast(select.index()?)?.as_str() {
+        "override" => OverrideKind::Override,
+        "overrideAttrs" => OverrideKind::OverrideAttrs,
+        _ => return None,
+    };
+    Some(OverrideSlot {
+        kind,
+        base: select.set()?,
+        existing,
+        ident,
+    })
+}
+/// The range a completion for a key replaces: the identifier being
+/// typed, or nothing at the cursor
+fn key_range(code: &str, ident: Option<&Ident>, offset: usize, encoding: Encoding) -> Range {
+    let cursor = TextUnit::from_usize(offset);
+    let range = ident.map_or(TextRange::from_to(cursor, cursor), |ident| ident.node().text_range());
+    utils::range(code, range, encoding)
+}
+/// Find the call, if any, whose argument set the cursor is in, like
+/// `f { | }`, along with the function
+fn argument_slot(root: &SyntaxNode, offset: usize) -> Option<(KeySlot, SyntaxNode)> {
+    let slot = key_slot(root, offset)?;
+    let mut node = slot.set.clone();
+    while let Some(parent) = node.parent().filter(|parent| Paren::cast(parent.clone()).is_some()) {
+        node = parent;
+    }
+    let apply = Apply::cast(node.parent()?)?;
+    if apply.value().as_ref() != Some(&node) {
+        return None;
+    }
+    let function = apply.lambda()?;
+    Some((slot, function))
+}
+/// The name of a function that's called, like `callPackage` for both
+/// `callPackage` and `pkgs.callPackage`
+fn caller_name(caller: SyntaxNode) -> Option<Ident> {
+    match Select::cast(caller.clone()) {
+        Some(select) => select.index().and_then(Ident::cast),
+        None => Ident::cast(caller),
+    }
+}
+/// Whether a function is `callPackage` given a path, like in
+/// `callPackage ./pkg.nix { }`
+fn is_call_package(function: &SyntaxNode) -> bool {
+    Apply::cast(function.clone())
+        .and_then(|apply| apply.lambda())
+        .and_then(caller_name)
+        .is_some_and(|name| name.as_str() == "callPackage")
+}
+/// Whether a set is the `meta` of a derivation, like in
+/// `meta = with lib; { ... };`
+fn is_meta(set: &SyntaxNode) -> bool {
+    let mut node = set.clone();
+    while let Some(parent) = node.parent() {
+        if let Some(entry) = KeyValue::cast(parent.clone()) {
+            let name = entry.key().and_then(|key| key.path().last()).and_then(|part| utils::static_name(&part));
+            return entry.value().as_ref() == Some(&node) && name.as_deref() == Some("meta");
+        }
+        let is_body = With::cast(parent.clone()).is_some_and(|with| with.body().as_ref() == Some(&node));
+        if !is_body && Paren::cast(parent.clone()).is_none() {
+            return false;
+        }
+        node = parent;
+    }
+    false
+}
+/// The names of the attributes a set literal defines
+fn set_keys(node: &SyntaxNode) -> Vec<String> {
+    let Some(set) = AttrSet::cast(node.clone()) else { return Vec::new() };
+    let keys = set.entries()
+        .filter_map(|entry| utils::static_name(&entry.key()?.path().next()?));
+    let inherits = set.inherits()
+        .flat_map(|inherit| inherit.idents())
+        .map(|ident| ident.as_str().to_owned());
+    keys.chain(inherits).collect()
+}
+
+/// Whether a variable is the one whose key or value an identifier is
+/// typed in, like `foo` in `let foo = fo|`, which would be an infinite
+/// recursion. In a function or set in the value, like `f = x: f x` or
+/// `a = { b = 1; c = a.b; }`, referring to itself is fine.
+fn is_being_defined(var: &Var, ident: &Ident) -> bool {
+    if var.key == *ident.node() {
+        return true;
+    }
+    let Some(entry) = var.key.parent().and_then(Key::cast).filter(|key| key.path().count() == 1).and_then(|key| key.node().parent()) else { return false };
+    let Some(value) = KeyValue::cast(entry).and_then(|entry| entry.value()) else { return false };
+    for node in ident.node().ancestors() {
+        if matches!(node.kind(), NODE_LAMBDA | NODE_ATTR_SET) {
+            return false;
+        }
+        if node == value {
+            return true;
+        }
+    }
+    false
+}
+/// Whether the key an identifier is part of is already followed by `=`,
+/// in which case completing it shouldn't insert another one
+fn has_value(ident: &Ident) -> bool {
+    let Some(key) = ident.node().parent() else { return false };
+    if key.last_child().as_ref() != Some(ident.node()) {
+        // Not the last part of the key, like `a` in `a.b`
+        return true;
+    }
+    let mut next = key.next_sibling_or_token();
+    while next.as_ref().is_some_and(|sibling| sibling.kind() == TOKEN_WHITESPACE) {
+        next = next.and_then(|sibling| sibling.next_sibling_or_token());
+    }
+    next.is_some_and(|sibling| sibling.kind() == TOKEN_ASSIGN)
+}
+
+/// Completions for the variables of a scope. Names like `"foo.bar"`
+/// are quoted if `quote` is set, for keys and after a `.`, and skipped
+/// otherwise since they can't be used as a variable.
+fn var_completions(scope: &HashMap<String, Var>, prefix: &str, range: Range, quote: bool, order: &[CompletionGroup])
+    -> Vec<CompletionItem>
+{
+    scope.iter()
+        .filter(|(name, _)| name.starts_with(prefix) && (quote || utils::is_ident(name)))
+        .map(|(name, var)| CompletionItem {
+            label: name.clone(),
+            sort_text: Some(sort_text(CompletionGroup::of(&var.kind), order, name)),
+            detail: match &var.kind {
+                VarKind::With(namespace) => Some(format!("from with {}", namespace.text())),
+                _ => None,
+            },
+            text_edit: Some(TextEdit {
+                range,
+                new_text: utils::escape_key(name),
+            }),
+            // The value is added by `App::resolve_completion`
+            data: Some(json!({ "uri": var.file.as_str(), "offset": var.key.text_range().start().to_usize() })),
+            ..CompletionItem::default()
+        })
+        .collect()
+}
+/// Completions for the attributes of `builtins`, unless they're too new
+/// for the targeted Nix version
+fn builtins_completions(prefix: &str, range: Range, version: Option<Version>) -> Vec<CompletionItem> {
+    builtins::BUILTINS.iter()
+        .filter(|builtin| builtin.name.starts_with(prefix) && builtin.available(version))
+        .map(|builtin| builtin_completion(builtin, range))
+        .collect()
+}
+/// Add completions for builtins that are available without `builtins.`,
+/// unless shadowed by the scope or too new for the targeted Nix version.
+/// Like in Nix, a `with` doesn't shadow them, so the builtin replaces a
+/// name from a `with` that's a builtin too.
+fn add_global_completions(completions: &mut Vec<CompletionItem>, scope: &HashMap<String, Var>, prefix: &str, range: Range, config: &Config) {
+    let mut globals = Vec::new();
+    let available = builtins::BUILTINS.iter()
+        .filter(|builtin| builtin.global && builtin.name.starts_with(prefix) && builtin.available(config.nix_version));
+    for builtin in available {
+        let mut completion = builtin_completion(builtin, range);
+        match scope.get(builtin.name).map(|var| &var.kind) {
+            None => (),
+            Some(VarKind::With(namespace)) => {
+                completions.retain(|other| other.label != builtin.name);
+                if let Some(Documentation::MarkupContent(markup)) = &mut completion.documentation {
+                    markup.value = format!("{}\n\n`with {};` provides `{}` too, but doesn't shadow builtins", markup.value, namespace.text(), builtin.name);
+                }
+            },
+            Some(_) => continue,
+        }
+        globals.push(completion);
+    }
+    rank(&mut globals, CompletionGroup::Builtin, &config.completion_order);
+    completions.extend(globals);
+}
+/// Whether a builtin with a name is available without `builtins.` in the
+/// targeted Nix version
+fn is_global(name: &str, version: Option<Version>) -> bool {
+    builtins::get(name).is_some_and(|builtin| builtin.global && builtin.available(version))
+}
+/// Keep the best `max` completions: the ones of the first groups, and in
+/// a group the shortest names, which are closest to what's typed so far.
+/// Returns whether any were left out.
+pub fn truncate(completions: &mut Vec<CompletionItem>, max: usize) -> bool {
+    if completions.len() <= max {
+        return false;
+    }
+    completions.sort_by_cached_key(|completion| {
+        // Unranked completions go last, rather than before every group
+        let group = completion.sort_text.as_ref().and_then(|sort| sort.chars().next()?.to_digit(10));
+        (group.unwrap_or(u32::MAX), completion.label.len(), completion.label.clone())
+    });
+    completions.truncate(max);
+    true
+}
+/// Sort completions of a group by where it is in `order`, and then by
+/// name
+fn rank(completions: &mut [CompletionItem], group: CompletionGroup, order: &[CompletionGroup]) {
+    for completion in completions {
+        completion.sort_text = Some(sort_text(group, order, &completion.label));
+    }
+}
+fn sort_text(group: CompletionGroup, order: &[CompletionGroup], label: &str) -> String {
+    let index = order.iter().position(|other| *other == group).unwrap_or(order.len());
+    format!("{index}{label}")
+}
+fn builtin_completion(builtin: &Builtin, range: Range) -> CompletionItem {
+    CompletionItem {
+        label: builtin.name.into(),
+        kind: Some(CompletionItemKind::Function),
+        detail: Some(format!("builtins.{}", builtin.signature)),
+        documentation: Some(Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: builtin.documentation(),
+        })),
+        deprecated: builtin.deprecated.map(|_| true),
+        tags: builtin.deprecated.map(|_| vec![CompletionItemTag::Deprecated]),
+        text_edit: Some(TextEdit {
+            range,
+            new_text: builtin.name.into(),
+        }),
+        ..CompletionItem::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::truncate;
+    use crate::testing;
+    use lsp_types::*;
+
+    fn labels(completions: &[CompletionItem]) -> Vec<&str> {
+        let mut labels: Vec<&str> = completions.iter().map(|item| item.label.as_str()).collect();
+        labels.sort_unstable();
+        labels
+    }
+
+    #[test]
+    fn cache_while_typing() {
+        let (mut app, _client) = testing::app(&serde_json::json!({}));
+        let mut position = app.open_at("default.nix", "let x = fo$0; foo = 1; fob = 2; in x");
+        let completions = app.completions(&position).unwrap();
+        assert_eq!(labels(&completions), ["fob", "foo"]);
+
+        let uri = position.text_document.uri.clone();
+        let code = "let x = foo; foo = 1; fob = 2; in x";
+        app.change_file(&uri, code);
+        position.position.character += 1;
+        let completions = app.completions(&position).unwrap();
+        assert_eq!(labels(&completions), ["foo"]);
+        // Filtered from the completions of `fo`
+        assert_eq!(app.completion_cache.as_ref().unwrap().prefix, "fo");
+        let data = completions[0].data.as_ref().unwrap();
+        assert_eq!(data["offset"], code.find("foo =").unwrap());
+        assert_eq!(completions[0].text_edit.as_ref().unwrap().range.end, position.position);
+    }
+    #[test]
+    fn cache_forgotten_after_other_changes() {
+        let (mut app, _client) = testing::app(&serde_json::json!({}));
+        let mut position = app.open_at("default.nix", "let x = fo$0; foo = 1; fob = 2; in x");
+        app.completions(&position).unwrap();
+
+        let uri = position.text_document.uri.clone();
+        app.change_file(&uri, "let x = foo; foo = 1; fob = 2; fooo = 3; in x");
+        position.position.character += 1;
+        assert_eq!(labels(&app.completions(&position).unwrap()), ["foo", "fooo"]);
+        assert_eq!(app.completion_cache.as_ref().unwrap().prefix, "foo");
+
+        app.open_file("other.nix", "{ }");
+        assert!(app.completion_cache.is_none());
+    }
+
+    /// A module using options in a string
+    const MODULE: &str = "{ config, lib, ... }: {\n  config.environment.etc.service.text = STRING;\n}";
+    /// The options of a service, like in `options.json`
+    const OPTIONS: &str = r#"{
+  "my.service.enable": { "type": "boolean" },
+  "my.service.\"data dir\"": { "loc": ["my", "service", "data dir"], "type": "string" }
+}"#;
+
+    /// The code after completing `label` in a string of `MODULE`
+    fn complete_in_string(string: &str, trigger: Option<&str>, label: &str) -> String {
+        let options = std::env::temp_dir().join(format!("rnix-lsp-string-options-{}.json", std::process::id()));
+        std::fs::write(&options, OPTIONS).unwrap();
+        let (mut app, _client) = testing::app(&serde_json::json!({ "optionsJson": options }));
+        app.load_options();
+        let _ = std::fs::remove_file(&options);
+        let position = app.open_at("module.nix", &MODULE.replace("STRING", string));
+        let completions = match trigger {
+            Some(trigger) => app.triggered_completions(&position, trigger),
+            None => app.completions(&position),
+        };
+        let completions = completions.unwrap();
+        let item = completions.iter().find(|item| item.label == label).unwrap_or_else(|| panic!("no {label} in {:?}", labels(&completions)));
+        let code = &app.files[&position.text_document.uri].1;
+        let code = testing::apply(code, &[item.text_edit.clone().unwrap()]);
+        assert!(rnix::parse(&code).errors().is_empty(), "{}", code);
+        code
+    }
+
+    #[test]
+    fn config_in_interpolations() {
+        let expected = MODULE.replace("STRING", r#""${config.my.service.enable} and more""#);
+        assert_eq!(complete_in_string(r#""${config.my.service.$0} and more""#, Some("."), "enable"), expected);
+        assert_eq!(complete_in_string(r#""${config.my.service.$0} and more""#, None, "enable"), expected);
+        assert_eq!(complete_in_string(r#""${config.my.service.en$0} and more""#, None, "enable"), expected);
+        assert_eq!(complete_in_string(r#""${config.my.service.en$0}""#, None, "enable"), MODULE.replace("STRING", r#""${config.my.service.enable}""#));
+        assert_eq!(
+            complete_in_string(r#""dir: ${config.my.service.$0}/bin""#, None, "data dir"),
+            MODULE.replace("STRING", r#""dir: ${config.my.service."data dir"}/bin""#),
+        );
+        assert_eq!(
+            complete_in_string("''\n    ${config.my.service.$0} ''${HOME}\n  ''", Some("."), "enable"),
+            MODULE.replace("STRING", "''\n    ${config.my.service.enable} ''${HOME}\n  ''"),
+        );
+    }
+
+    fn item(label: &str, sort_text: Option<&str>) -> CompletionItem {
+        CompletionItem { label: label.into(), sort_text: sort_text.map(Into::into), ..CompletionItem::default() }
+    }
+
+    #[test]
+    fn truncate_keeps_first_groups() {
+        let mut items = vec![
+            item("unranked", None),
+            item("builtin", Some("2builtin")),
+            item("longer", Some("0longer")),
+            item("x", None),
+            item("local", Some("0local")),
+            item("arg", Some("1arg")),
+        ];
+        assert!(!truncate(&mut items, 6));
+        assert_eq!(items.len(), 6);
+        assert!(truncate(&mut items, 4));
+        let order: Vec<&str> = items.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(order, ["local", "longer", "arg", "builtin"]);
+        assert!(truncate(&mut items, 1));
+        assert_eq!(items[0].label, "local");
+    }
+
+    /// The sort text of a local, an argument, a name from `with` and a
+    /// builtin, in that order
+    fn group_sort_texts(options: &serde_json::Value) -> Vec<String> {
+        let (mut app, _client) = testing::app(options);
+        let position = app.open_at("default.nix", "{ fArg }: let fLocal = 1; s = { fWith = 2; }; in with s; f$0");
+        let completions = app.completions(&position).unwrap();
+        ["fLocal", "fArg", "fWith", "fetchGit"].iter()
+            .map(|label| {
+                let item = completions.iter().find(|item| item.label == *label).unwrap_or_else(|| panic!("no {} in {:?}", label, labels(&completions)));
+                item.sort_text.clone().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_in_order() {
+        let sort_texts = group_sort_texts(&serde_json::json!({}));
+        assert_eq!(sort_texts, ["0fLocal", "1fArg", "2fWith", "3fetchGit"]);
+        assert!(sort_texts.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", sort_texts);
+
+        // Groups that are left out come last
+        let sort_texts = group_sort_texts(&serde_json::json!({ "completionOrder": ["builtin", "with", "local"] }));
+        assert_eq!(sort_texts, ["2fLocal", "3fArg", "1fWith", "0fetchGit"]);
+    }
+
+    #[test]
+    fn incomplete_lists() {
+        let code = "let sibling = 1; sib = 2; in si$0";
+        let completion_list = |max: u64| {
+            let (mut app, client) = testing::app(&serde_json::json!({ "maxCompletionItems": max }));
+            let position = app.open_at("file.nix", code);
+            let params = CompletionParams {
+                text_document_position: position,
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+                context: None,
+            };
+            testing::request::<lsp_types::request::Completion>(&mut app, &client, params)
+        };
+        let list = completion_list(200);
+        assert_eq!(list["isIncomplete"], false);
+        let list = completion_list(1);
+        assert_eq!(list["isIncomplete"], true);
+        assert_eq!(list["items"].as_array().unwrap().len(), 1);
+        assert_eq!(list["items"][0]["label"], "sib");
+    }
+
+    /// The completions at `$0`
+    fn complete(code: &str) -> Option<Vec<CompletionItem>> {
+        let (mut app, _client) = testing::app(&serde_json::json!({}));
+        let position = app.open_at("default.nix", code);
+        app.completions(&position)
+    }
+    /// The labels completed at `$0` that aren't builtins
+    fn local_labels(code: &str) -> Vec<String> {
+        let completions = complete(code).unwrap_or_default();
+        let mut labels: Vec<String> = completions.into_iter()
+            .filter(|item| item.detail.as_ref().is_none_or(|detail| !detail.starts_with("builtins.")))
+            .map(|item| item.label)
+            .collect();
+        labels.sort_unstable();
+        labels
+    }
+
+    #[test]
+    fn half_written_let() {
+        assert_eq!(local_labels("let a = 1; b = 2; in $0"), ["a", "b"]);
+        assert_eq!(local_labels("let a = 1; b = 2; in\n  $0"), ["a", "b"]);
+        assert_eq!(local_labels("x: let a = 1; in $0"), ["a", "x"]);
+        assert_eq!(local_labels("let a = 1; in let b = 2; in $0"), ["a", "b"]);
+        assert_eq!(local_labels("{ c = let a = 1; in $0 }"), ["a"]);
+        // Builtins are in scope too
+        assert!(labels(&complete("let a = 1; in $0").unwrap()).contains(&"map"));
+        // Still typing the keyword
+        assert!(local_labels("let a = 1; in$0").is_empty());
+    }
+
+    #[test]
+    fn partial_segments() {
+        let set = "{ b = { c = { d = 1; }; cd = 2; }; bx = 3; }";
+        let at = |path: &str| {
+            let completions = complete(&format!("let a = {set}; in {path}")).unwrap();
+            labels(&completions).into_iter().map(String::from).collect::<Vec<_>>()
+        };
+        assert_eq!(at("a.b$0"), ["b", "bx"]);
+        assert_eq!(at("a.b.c$0"), ["c", "cd"]);
+        assert_eq!(at("a.b.c.d$0"), ["d"]);
+        // Before a segment that's already written, with nothing typed of
+        // the one being completed
+        assert_eq!(at("a.b.$0c"), ["c", "cd"]);
+        assert_eq!(at("a.$0b.c"), ["b", "bx"]);
+    }
+
+    #[test]
+    fn inherit_names() {
+        let completions = complete("let s = { xa = 1; xb = 2; }; in with s; { inherit x$0; }").unwrap();
+        assert_eq!(labels(&completions), ["xa", "xb"]);
+        assert!(completions.iter().all(|item| item.detail.as_deref() == Some("from with s")), "{:?}", completions);
+        // Only the attributes of the source, without builtins
+        assert_eq!(labels(&complete("let s = { a = 1; b = 2; }; in { inherit (s) $0; }").unwrap()), ["a", "b"]);
+        assert_eq!(labels(&complete("let s = { a = 1; ab = 2; b = 3; }; in { inherit (s) a$0; }").unwrap()), ["a", "ab"]);
+    }
+
+    /// The text each completion at `$0` inserts, by label
+    fn inserted(completions: &[CompletionItem]) -> Vec<(&str, &str)> {
+        let mut inserted: Vec<(&str, &str)> = completions.iter()
+            .map(|item| (item.label.as_str(), item.text_edit.as_ref().map_or("", |edit| edit.new_text.as_str())))
+            .collect();
+        inserted.sort_unstable();
+        inserted
+    }
+
+    #[test]
+    fn quoted_keys() {
+        let set = r#"{ "foo.bar" = 1; "a b" = 2; "1x" = 3; "x-1'" = 4; "\${x}" = 5; }"#;
+        let expected = [("${x}", r#""\${x}""#), ("1x", r#""1x""#), ("a b", r#""a b""#), ("foo.bar", r#""foo.bar""#), ("x-1'", "x-1'")];
+        let code = format!("let s = {set}; in s.$0");
+        let (mut app, _client) = testing::app(&serde_json::json!({}));
+        let position = app.open_at("default.nix", &code);
+        assert_eq!(inserted(&app.triggered_completions(&position, ".").unwrap()), expected);
+        let code = format!("let s = {set}; in s.$0 + 1");
+        assert_eq!(inserted(&complete(&code).unwrap()), expected);
+    }
+
+    #[test]
+    fn quoting_by_context() {
+        // A name that needs quotes can't be used as a variable
+        assert_eq!(local_labels(r#"rec { "foo.bar" = 1; foo = 2; x = fo$0; }"#), ["foo"]);
+        // but can be after a dot, and as a key
+        assert_eq!(inserted(&complete(r#"let s = { "foo.bar" = 1; }; in s.fo$0"#).unwrap()), [("foo.bar", r#""foo.bar""#)]);
+        let code = r#"let pkg = stdenv.mkDerivation { "foo.bar" = 1; }; in pkg.overrideAttrs (old: { $0 })"#;
+        let completions = complete(code).unwrap();
+        assert!(inserted(&completions).contains(&("foo.bar", r#""foo.bar""#)), "{:?}", inserted(&completions));
+    }
+    #[test]
+    fn quoted_key_snippets() {
+        let (mut app, _client) = testing::app(&serde_json::json!({ "keySnippets": true }));
+        app.client.text_document = Some(TextDocumentClientCapabilities {
+            completion: Some(CompletionCapability {
+                completion_item: Some(CompletionItemCapability { snippet_support: Some(true), ..CompletionItemCapability::default() }),
+                ..CompletionCapability::default()
+            }),
+            ..TextDocumentClientCapabilities::default()
+        });
+        let code = r#"let pkg = stdenv.mkDerivation { "foo.bar" = 1; "\${x}" = 2; }; in pkg.overrideAttrs (old: { $0 })"#;
+        let position = app.open_at("default.nix", code);
+        let completions = app.completions(&position).unwrap();
+        let inserted = inserted(&completions);
+        // `$` and `}` are escaped for the snippet, after the `$` is for Nix
+        assert!(inserted.contains(&("${x}", r#""\\\${x\}" = $0;"#)), "{:?}", inserted);
+        assert!(inserted.contains(&("foo.bar", r#""foo.bar" = $0;"#)), "{:?}", inserted);
+    }
+
+    #[test]
+    fn with_against_builtins() {
+        let code = "let lib = { toString = x: x; toStrung = 1; }; in with lib; toStr$0";
+        let completions = complete(code).unwrap();
+        assert_eq!(labels(&completions), ["toString", "toStrung"]);
+        let builtin = completions.iter().find(|item| item.label == "toString").unwrap();
+        assert!(builtin.detail.as_ref().unwrap().starts_with("builtins."));
+        let Some(Documentation::MarkupContent(docs)) = &builtin.documentation else { panic!("{:?}", builtin.documentation) };
+        assert!(docs.value.ends_with("`with lib;` provides `toString` too, but doesn't shadow builtins"), "{}", docs.value);
+
+        // Also where everything in scope is completed
+        let completions = complete("let lib = { toString = x: x; }; in with lib; \"${$0}\"").unwrap();
+        assert_eq!(completions.iter().filter(|item| item.label == "toString").count(), 1);
+        // A variable does shadow the builtin
+        let completions = complete("let toString = 1; in toStr$0").unwrap();
+        assert_eq!(labels(&completions), ["toString"]);
+        assert!(completions[0].detail.as_ref().is_none_or(|detail| !detail.starts_with("builtins.")));
+    }
+
+    #[test]
+    fn not_the_binding_being_defined() {
+        assert_eq!(local_labels("let foo = fo$0; fob = 1; in foo"), ["fob"]);
+        assert_eq!(local_labels("rec { foo = fo$0; fob = 1; }"), ["fob"]);
+        assert_eq!(local_labels("let foo = 1 + fo$0; in foo"), Vec::<String>::new());
+        // Inside a function or set, it's not infinite recursion, and
+        // `foo.bar = foo.baz` is the same as a set
+        assert_eq!(local_labels("let foo.bar = fo$0; in foo"), ["foo"]);
+        assert_eq!(local_labels("let foo = x: fo$0; in foo"), ["foo"]);
+        assert_eq!(local_labels("let foo = { a = fo$0; }; in foo"), ["foo"]);
+        assert_eq!(local_labels("let foo = 1; bar = fo$0; in bar"), ["foo"]);
+    }
+}
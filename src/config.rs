@@ -0,0 +1,159 @@
+use crate::{builtins::Version, completion::CompletionGroup, format::Formatter};
+use serde_json::Value;
+use std::{collections::HashMap, convert::TryFrom, path::PathBuf};
+
+/// User settings, passed as `initializationOptions` by the client.
+/// Unknown or invalid settings are ignored and keep their default.
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)] // Independent settings, not states
+pub struct Config {
+    /// Files bigger than this (in bytes) skip expensive analysis like
+    /// diagnostics. Cheap features like completion still work.
+    pub max_file_size: usize,
+    /// Complete new attribute keys as `name = ;`, with the cursor before
+    /// the semicolon. Only used if the client supports snippets.
+    pub key_snippets: bool,
+    /// Show the value of the hovered expression, as evaluated by
+    /// `nix repl`. This runs arbitrary code, so it's off by default.
+    pub eval_hover: bool,
+    /// How long an evaluation may take (in milliseconds) before it's
+    /// killed
+    pub eval_timeout: u64,
+    /// A list of package names to complete in `with pkgs; [ ... ]`
+    pub package_list: Option<PathBuf>,
+    /// Complete and document the functions of the nixpkgs library, for
+    /// `lib.` and `with lib;` where `lib` is a function argument
+    pub lib_functions: bool,
+    /// The `options.json` of NixOS, for completing `config.` in modules
+    pub options_json: Option<PathBuf>,
+    /// Warn about bindings that are obviously infinitely recursive, like
+    /// `let a = b; b = a; in ...`
+    pub recursion_warnings: bool,
+    /// Files to skip when formatting the whole workspace, as patterns
+    /// like `generated/*.nix`
+    pub format_ignore: Vec<String>,
+    /// The version of Nix the code is written for, so newer builtins
+    /// aren't suggested. `None` is the latest version.
+    pub nix_version: Option<Version>,
+    /// The order completions are grouped in, like locals before builtins
+    pub completion_order: Vec<CompletionGroup>,
+    /// What formats files, nixpkgs-fmt or the flake's `nix fmt`
+    pub formatter: Formatter,
+    /// Show what `inherit (x) a;` expands to as an inlay hint
+    pub inherit_hints: bool,
+    /// Local checkouts of remote flakes, like `github:owner/repo`, whose
+    /// outputs are completed after `builtins.getFlake`
+    pub flake_mirrors: HashMap<String, PathBuf>,
+    /// How long lines may get before formatting wraps sets, lists and
+    /// `inherit`s
+    pub max_width: usize,
+    /// Tell the editor about colors in strings, like `"#ff0000"`, to show
+    /// them and offer a color picker
+    pub document_colors: bool,
+    /// How many aliases like `x = y;` hover follows to show what they
+    /// stand for
+    pub alias_depth: usize,
+    /// How many completions to send at most. If there are more, the
+    /// editor is told to ask again as more of the name is typed.
+    pub max_completion_items: usize,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_file_size: 4 * 1024 * 1024,
+            key_snippets: true,
+            eval_hover: false,
+            eval_timeout: 2000,
+            package_list: None,
+            lib_functions: false,
+            options_json: None,
+            recursion_warnings: false,
+            format_ignore: Vec::new(),
+            nix_version: None,
+            completion_order: CompletionGroup::ALL.to_vec(),
+            formatter: Formatter::NixpkgsFmt,
+            inherit_hints: true,
+            flake_mirrors: HashMap::new(),
+            max_width: 80,
+            document_colors: false,
+            alias_depth: 1,
+            max_completion_items: 200,
+        }
+    }
+}
+impl Config {
+    pub fn from_options(options: Option<&Value>) -> Self {
+        let mut config = Self::default();
+        if let Some(options) = options {
+            config.update(options);
+        }
+        config
+    }
+    pub fn update(&mut self, options: &Value) {
+        if let Some(size) = options.get("maxFileSize").and_then(Value::as_u64) {
+            self.max_file_size = usize::try_from(size).unwrap_or(usize::MAX);
+        }
+        if let Some(enabled) = options.get("keySnippets").and_then(Value::as_bool) {
+            self.key_snippets = enabled;
+        }
+        if let Some(enabled) = options.get("evalHover").and_then(Value::as_bool) {
+            self.eval_hover = enabled;
+        }
+        if let Some(timeout) = options.get("evalTimeout").and_then(Value::as_u64) {
+            self.eval_timeout = timeout;
+        }
+        if let Some(path) = options.get("packageList").and_then(Value::as_str) {
+            self.package_list = Some(PathBuf::from(path));
+        }
+        if let Some(enabled) = options.get("libFunctions").and_then(Value::as_bool) {
+            self.lib_functions = enabled;
+        }
+        if let Some(path) = options.get("optionsJson").and_then(Value::as_str) {
+            self.options_json = Some(PathBuf::from(path));
+        }
+        if let Some(enabled) = options.get("recursionWarnings").and_then(Value::as_bool) {
+            self.recursion_warnings = enabled;
+        }
+        if let Some(patterns) = options.get("formatIgnore").and_then(Value::as_array) {
+            self.format_ignore = patterns.iter().filter_map(Value::as_str).map(String::from).collect();
+        }
+        if let Some(version) = options.get("nixVersion").and_then(Value::as_str).and_then(Version::parse) {
+            self.nix_version = Some(version);
+        }
+        if let Some(groups) = options.get("completionOrder").and_then(Value::as_array) {
+            let mut order: Vec<CompletionGroup> = Vec::new();
+            for group in groups.iter().filter_map(Value::as_str).filter_map(CompletionGroup::from_name) {
+                if !order.contains(&group) {
+                    order.push(group);
+                }
+            }
+            // Groups that aren't mentioned keep their order, after the
+            // ones that are
+            order.extend(CompletionGroup::ALL.iter().filter(|group| !order.contains(group)).collect::<Vec<_>>());
+            self.completion_order = order;
+        }
+        if let Some(formatter) = options.get("formatter").and_then(Value::as_str).and_then(Formatter::from_name) {
+            self.formatter = formatter;
+        }
+        if let Some(enabled) = options.get("inheritHints").and_then(Value::as_bool) {
+            self.inherit_hints = enabled;
+        }
+        if let Some(mirrors) = options.get("flakeMirrors").and_then(Value::as_object) {
+            self.flake_mirrors = mirrors.iter()
+                .filter_map(|(reference, path)| Some((reference.clone(), PathBuf::from(path.as_str()?))))
+                .collect();
+        }
+        if let Some(width) = options.get("maxWidth").and_then(Value::as_u64) {
+            self.max_width = usize::try_from(width).unwrap_or(usize::MAX);
+        }
+        if let Some(enabled) = options.get("documentColors").and_then(Value::as_bool) {
+            self.document_colors = enabled;
+        }
+        if let Some(depth) = options.get("aliasDepth").and_then(Value::as_u64) {
+            self.alias_depth = usize::try_from(depth).unwrap_or(usize::MAX);
+        }
+        if let Some(max) = options.get("maxCompletionItems").and_then(Value::as_u64) {
+            self.max_completion_items = usize::try_from(max).unwrap_or(usize::MAX);
+        }
+    }
+}
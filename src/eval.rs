@@ -0,0 +1,253 @@
+use crate::{App, utils};
+use log::{info, warn};
+use lsp_types::*;
+use rnix::types::*;
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Printed by the repl after every expression, so we know where its
+/// output ends
+const SENTINEL: &str = "\"rnix-lsp-eval-done\"";
+/// Results longer than this are cut off
+const MAX_RESULT_LEN: usize = 1000;
+
+#[derive(Debug)]
+pub enum EvalError {
+    Io(io::Error),
+    /// Nix printed an error while evaluating the expression
+    Nix(String),
+    /// The evaluation didn't finish in time and was killed
+    Timeout,
+}
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "couldn't run nix repl: {err}"),
+            Self::Nix(err) => write!(f, "evaluation failed: {err}"),
+            Self::Timeout => write!(f, "evaluation timed out"),
+        }
+    }
+}
+impl From<io::Error> for EvalError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[derive(Debug)]
+enum Line {
+    Stdout(String),
+    Stderr(String),
+}
+
+/// A persistent `nix repl` process, restricted to the directory of the
+/// file it evaluates expressions for
+#[derive(Debug)]
+pub struct Evaluator {
+    dir: PathBuf,
+    child: Child,
+    stdin: ChildStdin,
+    output: Receiver<Line>,
+}
+impl Evaluator {
+    fn spawn(dir: &Path, timeout: Duration) -> Result<Self, EvalError> {
+        info!("Starting nix repl in {}", dir.display());
+        let mut child = Command::new("nix")
+            .arg("repl")
+            .args(["--option", "restrict-eval", "true"])
+            .arg("-I")
+            .arg(dir)
+            .current_dir(dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        let (sender, output) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            forward(stdout, sender.clone(), Line::Stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward(stderr, sender, Line::Stderr);
+        }
+
+        let mut evaluator = Self {
+            dir: dir.to_owned(),
+            child,
+            stdin,
+            output,
+        };
+        // Skip the welcome message, wherever it's printed
+        writeln!(evaluator.stdin, "{SENTINEL}")?;
+        match evaluator.read(Instant::now() + timeout) {
+            Ok(_) | Err(EvalError::Nix(_)) => Ok(evaluator),
+            Err(err) => Err(err),
+        }
+    }
+    /// Evaluate an expression and return what the repl printed
+    fn eval(&mut self, expr: &str, timeout: Duration) -> Result<String, EvalError> {
+        // Parentheses make the repl read multi-line expressions as a
+        // whole, since every line before the last one is incomplete
+        writeln!(self.stdin, "({expr})\n{SENTINEL}")?;
+        self.read(Instant::now() + timeout)
+    }
+    fn read(&mut self, deadline: Instant) -> Result<String, EvalError> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.output.recv_timeout(timeout) {
+                Ok(Line::Stdout(line)) if line.trim() == SENTINEL => break,
+                Ok(Line::Stdout(line)) => stdout.push(line),
+                Ok(Line::Stderr(line)) => stderr.push(line),
+                Err(RecvTimeoutError::Timeout) => return Err(EvalError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            }
+        }
+        // Errors are printed before the sentinel is read, but may be
+        // forwarded after it
+        while let Ok(line) = self.output.try_recv() {
+            if let Line::Stderr(line) = line {
+                stderr.push(line);
+            }
+        }
+
+        stdout.retain(|line| !line.trim().is_empty());
+        if let Some(start) = stderr.iter().position(|line| line.contains("error")) {
+            return Err(EvalError::Nix(stderr[start..].join("\n")));
+        }
+        if stdout.is_empty() {
+            // Every value prints something, so the error must still be
+            // on its way
+            return Err(EvalError::Nix(stderr.join("\n")));
+        }
+        Ok(stdout.join("\n"))
+    }
+}
+impl Drop for Evaluator {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn forward<R, F>(reader: R, sender: Sender<Line>, wrap: F)
+where
+    R: io::Read + Send + 'static,
+    F: Fn(String) -> Line + Send + 'static,
+{
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            let Ok(line) = line else { break };
+            if sender.send(wrap(strip_escapes(&line))).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Remove terminal color codes, like `\x1b[1m`
+fn strip_escapes(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip until the final byte of the sequence
+            for c in &mut chars {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+impl App {
+    /// Hover by sending the expression under the cursor to `nix repl`.
+    /// Names are resolved from the top level of the file, if it is a
+    /// set, since the repl can't see local bindings.
+    pub fn eval_hover(&mut self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+
+        let mut node = info.ident.node().clone();
+        let parent = node.parent();
+        if parent.clone().and_then(Key::cast).is_some() {
+            // Keys aren't expressions
+            return None;
+        }
+        if let Some(select) = parent.and_then(Select::cast) {
+            if select.index().as_ref() == Some(&node) {
+                node = select.node().clone();
+            }
+        }
+        let range = utils::range(content, node.text_range(), self.encoding);
+
+        let path = utils::uri_path(&params.text_document.uri)?;
+        // Files of the same project can share one repl
+        let dir = match self.root_for(&params.text_document.uri).and_then(utils::uri_path) {
+            Some(root) => root,
+            None => path.parent()?.to_owned(),
+        };
+        // A string instead of a path literal, which can't contain spaces
+        let file = path.to_string_lossy()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${");
+        let expr = format!(
+            "let file = import (/. + \"{}\"); in with (if builtins.isAttrs file then file else {{ }}); {}",
+            file,
+            node.text(),
+        );
+
+        let timeout = Duration::from_millis(self.config.eval_timeout);
+        let result = self.evaluator(&dir, timeout).and_then(|evaluator| evaluator.eval(&expr, timeout));
+        let mut value = match result {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("{}", err);
+                if let EvalError::Timeout | EvalError::Io(_) = err {
+                    // Kill it, it might be stuck in an infinite loop
+                    self.evaluator = None;
+                }
+                return None;
+            },
+        };
+        if value.len() > MAX_RESULT_LEN {
+            let mut end = MAX_RESULT_LEN;
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            value.truncate(end);
+            value.push('…');
+        }
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```nix\n{value}\n```"),
+            }),
+            range: Some(range),
+        })
+    }
+    /// The evaluator for a directory, started if it isn't running yet
+    fn evaluator(&mut self, dir: &Path, timeout: Duration) -> Result<&mut Evaluator, EvalError> {
+        if self.evaluator.as_ref().is_none_or(|evaluator| evaluator.dir != dir) {
+            self.evaluator = None;
+            self.evaluator = Some(Evaluator::spawn(dir, timeout)?);
+        }
+        Ok(self.evaluator.as_mut().unwrap())
+    }
+}
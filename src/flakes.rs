@@ -0,0 +1,108 @@
+use crate::{App, utils};
+use lsp_types::{Location, Range, Url};
+use rnix::{
+    types::*,
+    value::StrPart,
+    SyntaxNode,
+    TextUnit,
+};
+use std::{convert::TryFrom, rc::Rc};
+
+/// Where a flake reference points to, as far as completion cares
+#[derive(Debug, PartialEq, Eq)]
+pub enum FlakeRef<'a> {
+    /// A flake on disk, like `path:./dir`, `git+file:///dir` or `/dir`
+    Path(&'a str),
+    /// A flake that has to be fetched first, like `github:owner/repo`
+    Remote(&'a str),
+}
+
+/// Parse a flake reference, ignoring its `?query` and `#fragment`
+pub fn parse_ref(reference: &str) -> FlakeRef<'_> {
+    let end = reference.find(['?', '#']).unwrap_or(reference.len());
+    let reference = &reference[..end];
+    for scheme in &["path:", "git+file:"] {
+        if let Some(path) = reference.strip_prefix(scheme) {
+            // `path:///dir` is the same as `path:/dir`
+            return FlakeRef::Path(path.strip_prefix("//").unwrap_or(path));
+        }
+    }
+    if reference.starts_with('/') || reference.starts_with('.') {
+        FlakeRef::Path(reference)
+    } else {
+        FlakeRef::Remote(reference)
+    }
+}
+
+/// The flake reference of `builtins.getFlake "..."`, unless `builtins`
+/// is shadowed or the reference isn't a plain string
+pub fn get_flake(file: &Rc<Url>, node: &SyntaxNode) -> Option<String> {
+    let apply = Apply::cast(node.clone())?;
+    let select = Select::cast(apply.lambda()?)?;
+    let builtins = select.set().and_then(Ident::cast).filter(|set| set.as_str() == "builtins")?;
+    if select.index().and_then(Ident::cast)?.as_str() != "getFlake" {
+        return None;
+    }
+    if utils::scope_for(file, builtins.node().clone())?.contains_key("builtins") {
+        return None;
+    }
+    let string = Str::cast(apply.value()?)?;
+    match string.parts().as_slice() {
+        [StrPart::Literal(reference)] => Some(reference.clone()),
+        _ => None,
+    }
+}
+
+/// What the `outputs` function of a flake returns, past its arguments
+/// and any `let`, like `{ packages = ...; }` in
+/// `outputs = { self }: let ... in { packages = ...; }`
+fn outputs(root: &SyntaxNode) -> Option<SyntaxNode> {
+    let set = AttrSet::cast(root.clone())?;
+    let entry = set.entries().find(|entry| {
+        let path: Option<Vec<String>> = entry.key().and_then(|key| key.path().map(|part| utils::static_name(&part)).collect());
+        path.is_some_and(|path| path == ["outputs"])
+    })?;
+    let mut node = entry.value()?;
+    loop {
+        node = match ParsedType::try_from(node.clone()) {
+            Ok(ParsedType::Lambda(lambda)) => lambda.body()?,
+            Ok(ParsedType::LetIn(let_in)) => let_in.body()?,
+            Ok(ParsedType::Paren(paren)) => paren.inner()?,
+            _ => return Some(node),
+        };
+    }
+}
+
+impl App {
+    /// The `flake.nix` a flake reference points to. Paths are relative
+    /// to the file using them, and remote flakes are only found if they
+    /// have a local mirror configured.
+    pub fn flake_file(&self, file: &Url, reference: &str) -> Option<Url> {
+        match parse_ref(reference) {
+            FlakeRef::Path(path) => file.join(&format!("{}/flake.nix", path.trim_end_matches('/'))).ok(),
+            FlakeRef::Remote(name) => {
+                let dir = self.resolve_path(self.config.flake_mirrors.get(name)?);
+                Url::from_file_path(dir.join("flake.nix")).ok()
+            },
+        }
+    }
+    /// The set the outputs of the flake in `builtins.getFlake "..."`
+    /// evaluate to, if its `flake.nix` can be found
+    pub fn flake_outputs(&mut self, file: &Rc<Url>, node: &SyntaxNode) -> Option<(Rc<Url>, SyntaxNode)> {
+        let reference = get_flake(file, node)?;
+        let flake = Rc::new(self.flake_file(file, &reference)?);
+        let root = self.load(&flake)?;
+        Some((flake, outputs(&root)?))
+    }
+    /// Where the reference in `builtins.getFlake "..."` leads for goto
+    /// definition: the top of its `flake.nix`
+    pub fn flake_location(&mut self, file: &Url, root: &SyntaxNode, offset: usize) -> Option<Location> {
+        let file = Rc::new(file.clone());
+        let token = root.token_at_offset(TextUnit::from_usize(offset)).right_biased()?;
+        let string = token.parent().ancestors().find_map(Str::cast)?;
+        let reference = get_flake(&file, &string.node().parent()?)?;
+        let flake = self.flake_file(&file, &reference)?;
+        self.load(&flake)?;
+        Some(Location { uri: flake, range: Range::default() })
+    }
+}
@@ -0,0 +1,85 @@
+use crate::utils::{self, Encoding};
+use lsp_types::{FoldingRange, FoldingRangeKind};
+use rnix::{
+    types::*,
+    SyntaxKind::{self, TOKEN_CURLY_B_CLOSE, TOKEN_ELSE, TOKEN_IN, TOKEN_PAREN_CLOSE, TOKEN_SQUARE_B_CLOSE, TOKEN_THEN},
+    NodeOrToken,
+    SyntaxNode,
+    TextUnit,
+};
+use std::convert::TryFrom;
+
+/// Folds for every multi-line set, list and `let`, both branches of an
+/// `if`, and the bodies of functions and `with`, like a module's
+/// `with lib;` on its own line. Closing brackets and keywords like
+/// `else` stay visible, so folded code still reads as Nix.
+pub fn folding_ranges(root: &SyntaxNode, code: &str, encoding: Encoding) -> Vec<FoldingRange> {
+    let line = |offset: TextUnit| utils::offset_to_pos(code, offset.to_usize(), encoding).line;
+    let mut folds = Vec::new();
+    let mut add = |start: TextUnit, end_line: u64| {
+        let start_line = line(start);
+        if end_line > start_line {
+            folds.push(FoldingRange {
+                start_line,
+                start_character: None,
+                end_line,
+                end_character: None,
+                kind: Some(FoldingRangeKind::Region),
+            });
+        }
+    };
+    // The last line to fold away from a node, which is the one before its
+    // closing bracket
+    let last_line = |node: &SyntaxNode| {
+        let end = line(node.text_range().end());
+        let closed = node.last_token().is_some_and(|token| matches!(token.kind(), TOKEN_CURLY_B_CLOSE | TOKEN_SQUARE_B_CLOSE | TOKEN_PAREN_CLOSE));
+        if closed { end.saturating_sub(1) } else { end }
+    };
+
+    for node in root.descendants() {
+        match ParsedType::try_from(node.clone()) {
+            Ok(ParsedType::AttrSet(_) | ParsedType::List(_) | ParsedType::Pattern(_)) => {
+                add(node.text_range().start(), last_line(&node));
+            },
+            Ok(ParsedType::LetIn(_)) => {
+                if let Some(keyword) = token(&node, TOKEN_IN) {
+                    add(node.text_range().start(), line(keyword).saturating_sub(1));
+                }
+            },
+            Ok(ParsedType::IfElse(if_else)) => {
+                let (Some(then), Some(otherwise)) = (token(&node, TOKEN_THEN), token(&node, TOKEN_ELSE)) else { continue };
+                add(then, line(otherwise).saturating_sub(1));
+                // The folds of an `else if` are its own
+                let body = if_else.else_body();
+                if let Some(body) = body.filter(|body| IfElse::cast(body.clone()).is_none()) {
+                    add(otherwise, last_line(&body));
+                }
+            },
+            Ok(ParsedType::Lambda(lambda)) => {
+                if let Some(body) = lambda.body() {
+                    add(node.text_range().start(), last_line(&body));
+                }
+            },
+            Ok(ParsedType::With(with)) => {
+                if let Some(body) = with.body() {
+                    add(node.text_range().start(), last_line(&body));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    // Folds starting on the same line, like a function and the set it
+    // returns, would be shown as one anyway
+    folds.sort_by_key(|fold| (fold.start_line, std::cmp::Reverse(fold.end_line)));
+    folds.dedup_by_key(|fold| fold.start_line);
+    folds
+}
+
+/// Where the first token of a kind starts among a node's own tokens
+fn token(node: &SyntaxNode, kind: SyntaxKind) -> Option<TextUnit> {
+    node.children_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+        .find(|token| token.kind() == kind)
+        .map(|token| token.text_range().start())
+}
@@ -0,0 +1,595 @@
+use crate::{App, parse::Parsed, utils::{self, Encoding}};
+use lsp_types::{DocumentFormattingParams, FormattingOptions, TextEdit};
+use nixpkgs_fmt::AtomEdit;
+use rnix::{
+    types::{Inherit, TokenWrapper, TypedNode},
+    NodeOrToken,
+    SyntaxKind::{
+        NODE_ATTR_SET, NODE_ERROR, NODE_IDENT, NODE_INHERIT, NODE_INHERIT_FROM, NODE_LIST, TOKEN_COMMENT, TOKEN_CURLY_B_CLOSE,
+        TOKEN_CURLY_B_OPEN, TOKEN_SQUARE_B_CLOSE, TOKEN_SQUARE_B_OPEN, TOKEN_STRING_CONTENT, TOKEN_WHITESPACE,
+    },
+    SyntaxElement,
+    SyntaxNode,
+    TextRange,
+    TextUnit,
+};
+use std::{
+    convert::TryFrom,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How many spaces nixpkgs-fmt indents with
+const FMT_INDENT: usize = 2;
+/// How long `nix fmt` may take, which includes building the formatter
+/// the first time it's used
+const NIX_FMT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What formats a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Formatter {
+    /// The built-in nixpkgs-fmt
+    NixpkgsFmt,
+    /// `nix fmt`, running the `formatter` output of the project's flake
+    NixFmt,
+    /// No formatting, for projects using a formatter of their own
+    None,
+}
+impl Formatter {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nixpkgs-fmt" => Some(Self::NixpkgsFmt),
+            "nix-fmt" => Some(Self::NixFmt),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+}
+
+impl App {
+    /// Format an open file with the configured formatter. Files that
+    /// aren't part of a flake always use nixpkgs-fmt, since there's no
+    /// `formatter` for `nix fmt` to run.
+    pub fn format(&mut self, params: &DocumentFormattingParams) -> Result<Vec<TextEdit>, String> {
+        let uri = &params.text_document.uri;
+        let Some((ast, code)) = self.files.get(uri) else { return Ok(Vec::new()) };
+        let flake = match self.config.formatter {
+            Formatter::None => return Ok(Vec::new()),
+            Formatter::NixpkgsFmt => None,
+            Formatter::NixFmt => utils::uri_path(uri).and_then(|path| {
+                let root = self.root_for(uri).and_then(utils::uri_path);
+                Some((flake_dir(&path, root.as_deref())?, path))
+            }),
+        };
+        let Some((flake, path)) = flake else { return Ok(format(ast, code, &params.options, self.config.max_width, self.encoding)) };
+        let code = code.clone();
+        let formatted = nix_fmt(&flake, &path, &code, &mut || self.is_cancelled())?;
+        if formatted == code {
+            return Ok(Vec::new());
+        }
+        Ok(vec![replace_changed(&code, &formatted, self.encoding)])
+    }
+}
+
+/// The closest folder containing a file that has a `flake.nix`, up to
+/// the workspace folder if there is one
+pub fn flake_dir(path: &Path, root: Option<&Path>) -> Option<PathBuf> {
+    for dir in path.ancestors().skip(1) {
+        if dir.join("flake.nix").is_file() {
+            return Some(dir.to_owned());
+        }
+        if Some(dir) == root {
+            break;
+        }
+    }
+    None
+}
+
+/// Format code with `nix fmt` in the folder of a flake. Formatters work
+/// on files, and the editor's version may not be saved yet, so it's
+/// written to a hidden file next to the original first. Formatters like
+/// treefmt only format files in the project that their rules include, so
+/// it has to be there and keep the `.nix` extension.
+pub fn nix_fmt(flake: &Path, path: &Path, code: &str, cancelled: &mut dyn FnMut() -> bool) -> Result<String, String> {
+    let file = temp_file(path);
+    fs::write(&file, code).map_err(|err| format!("couldn't write {}: {err}", file.display()))?;
+    let result = run_nix_fmt(flake, &file, cancelled)
+        .and_then(|()| fs::read_to_string(&file).map_err(|err| format!("couldn't read {}: {err}", file.display())));
+    let _ = fs::remove_file(&file);
+    result
+}
+/// Where the code of a file is formatted by `nix fmt`, like
+/// `.default.nix.rnix-lsp-123.nix` for `default.nix`
+fn temp_file(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.rnix-lsp-{}.nix", process::id()))
+}
+/// Run `nix fmt` on a file. The server can't do anything else meanwhile,
+/// so it's given up if the request is cancelled or takes too long.
+fn run_nix_fmt(flake: &Path, file: &Path, cancelled: &mut dyn FnMut() -> bool) -> Result<(), String> {
+    let mut child = Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command flakes", "fmt", "--"])
+        .arg(file)
+        .current_dir(flake)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("couldn't run nix fmt: {err}"))?;
+    // Read it while waiting, so a full pipe doesn't block the formatter
+    let stderr = child.stderr.take().map(|mut stderr| thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stderr.read_to_string(&mut output);
+        output
+    }));
+
+    let deadline = Instant::now() + NIX_FMT_TIMEOUT;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline && !cancelled() => thread::sleep(Duration::from_millis(10)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                if Instant::now() < deadline {
+                    return Err("nix fmt was cancelled".into());
+                }
+                return Err(format!("nix fmt didn't finish within {} seconds", NIX_FMT_TIMEOUT.as_secs()));
+            },
+            Err(err) => return Err(format!("couldn't run nix fmt: {err}")),
+        }
+    };
+    if status.success() {
+        return Ok(());
+    }
+    let output = stderr.and_then(|stderr| stderr.join().ok()).unwrap_or_default();
+    Err(format!("nix fmt failed: {}", output.trim()))
+}
+
+/// Format a file using nixpkgs-fmt, and return the edits to apply to
+/// the original code. Sets and lists are laid out to fit in `width`,
+/// long `inherit`s are wrapped and the editor's options are applied
+/// afterwards, since nixpkgs-fmt itself doesn't do any of that.
+pub fn format(ast: &Parsed, code: &str, options: &FormattingOptions, width: usize, encoding: Encoding) -> Vec<TextEdit> {
+    let preserved = leading_comments_end(ast);
+    let edits = nixpkgs_fmt_edits(&ast.node(), preserved);
+    let formatted = apply_edits(code, &edits);
+    let laid_out = wrap_inherits(&lay_out(&formatted, preserved, width), width);
+    let adjusted = apply_options(&laid_out, preserved.to_usize(), options);
+    if adjusted != formatted {
+        // The edits from nixpkgs-fmt don't apply anymore, so replace
+        // everything that changed at once
+        return vec![replace_changed(code, &adjusted, encoding)];
+    }
+    edits.into_iter()
+        .map(|edit| TextEdit {
+            range: utils::range(code, edit.delete, encoding),
+            new_text: edit.insert.to_string()
+        })
+        .collect()
+}
+
+/// The edits nixpkgs-fmt makes to a file, sorted by where they start
+fn nixpkgs_fmt_edits(root: &SyntaxNode, preserved: TextUnit) -> Vec<AtomEdit> {
+    let fmt = nixpkgs_fmt::reformat_node(root);
+    let mut edits: Vec<_> = fmt.text_diff().into_iter()
+        .filter(|range| !range.delete.is_empty() || !range.insert.is_empty())
+        // Shebangs (and nix-shell directives) must stay untouched for
+        // the file to remain executable
+        .filter(|edit| edit.delete.start() >= preserved)
+        .collect();
+    edits.sort_by_key(|edit| edit.delete.start());
+    edits
+}
+fn apply_edits(code: &str, edits: &[AtomEdit]) -> String {
+    let mut output = code.to_owned();
+    for edit in edits.iter().rev() {
+        output.replace_range(edit.delete.start().to_usize()..edit.delete.end().to_usize(), &edit.insert);
+    }
+    output
+}
+
+/// Put sets and lists that fit in `width` characters on one line, and
+/// give every element of longer ones a line of its own. Outer sets are
+/// laid out first, so an inner set expands only if it doesn't fit even
+/// on its own line. Comments can't be moved around safely, so sets and
+/// lists with them are never collapsed. nixpkgs-fmt runs once more at
+/// the end, since it keeps line breaks and fixes indentation.
+fn lay_out(code: &str, preserved: TextUnit, width: usize) -> String {
+    let ast = rnix::parse(code);
+    if !ast.errors().is_empty() {
+        return code.to_owned();
+    }
+    let mut layout = Layout { code, width, output: String::with_capacity(code.len()) };
+    layout.node(&ast.node(), false);
+    if layout.output == code {
+        return layout.output;
+    }
+    let edits = nixpkgs_fmt_edits(&rnix::parse(&layout.output).node(), preserved);
+    apply_edits(&layout.output, &edits)
+}
+
+/// Prints a file again, changing only whitespace
+struct Layout<'a> {
+    code: &'a str,
+    width: usize,
+    output: String,
+}
+impl Layout<'_> {
+    fn line(&self) -> &str {
+        let start = self.output.rfind('\n').map_or(0, |i| i + 1);
+        &self.output[start..]
+    }
+    fn element(&mut self, element: SyntaxElement, collapse: bool) {
+        match element {
+            NodeOrToken::Node(node) => self.node(&node, collapse),
+            NodeOrToken::Token(token) if collapse && token.kind() == TOKEN_WHITESPACE => self.output.push(' '),
+            NodeOrToken::Token(token) => self.output.push_str(token.text()),
+        }
+    }
+    fn node(&mut self, node: &SyntaxNode, collapse: bool) {
+        let bracketed = node.kind() == NODE_ATTR_SET || node.kind() == NODE_LIST;
+        if collapse || !bracketed || node.children().next().is_none() {
+            for child in node.children_with_tokens() {
+                self.element(child, collapse);
+            }
+            return;
+        }
+
+        // What's stuck to the end has to fit too, like the `;` after
+        // `a = [ ... ];`
+        let end = node.text_range().end().to_usize();
+        let rest = self.code[end..].chars().take_while(|c| !c.is_whitespace()).count();
+        let space = self.width.saturating_sub(self.line().chars().count() + rest);
+        if flat_len(node, space).is_some() {
+            for child in node.children_with_tokens() {
+                self.element(child, true);
+            }
+            return;
+        }
+        let expand = !node.text().contains_char('\n');
+
+        let indent: String = self.line().chars().take_while(|&c| c == ' ').collect();
+        let children: Vec<SyntaxElement> = node.children_with_tokens().collect();
+        let mut open = false;
+        for (i, child) in children.iter().enumerate() {
+            let near_comment = |i: Option<usize>| i.and_then(|i| children.get(i)).is_some_and(|child| child.kind() == TOKEN_COMMENT);
+            if expand && open && child.kind() == TOKEN_WHITESPACE && !near_comment(i.checked_sub(1)) && !near_comment(Some(i + 1)) {
+                let closing = children.get(i + 1).is_some_and(|next| next.kind() == TOKEN_CURLY_B_CLOSE || next.kind() == TOKEN_SQUARE_B_CLOSE);
+                self.output.push('\n');
+                self.output.push_str(&indent);
+                if !closing {
+                    self.output.push_str(&" ".repeat(FMT_INDENT));
+                }
+                continue;
+            }
+            open |= child.kind() == TOKEN_CURLY_B_OPEN || child.kind() == TOKEN_SQUARE_B_OPEN;
+            self.element(child.clone(), false);
+        }
+    }
+}
+
+/// How long a node is on one line, unless that's more than `limit` or
+/// impossible, because it contains comments or multi-line strings
+fn flat_len(node: &SyntaxNode, limit: usize) -> Option<usize> {
+    let mut len = 0;
+    for token in node.descendants_with_tokens().filter_map(NodeOrToken::into_token) {
+        len += match token.kind() {
+            TOKEN_WHITESPACE => 1,
+            TOKEN_COMMENT => return None,
+            _ if token.text().contains('\n') => return None,
+            _ => token.text().chars().count(),
+        };
+        if len > limit {
+            return None;
+        }
+    }
+    Some(len)
+}
+
+/// Returns the end of the comments before the first expression, like a
+/// `#!/usr/bin/env nix-shell` line followed by `#!nix-shell` directives
+fn leading_comments_end(ast: &Parsed) -> TextUnit {
+    let mut end = TextUnit::from_usize(0);
+    for token in ast.node().descendants_with_tokens().filter_map(NodeOrToken::into_token) {
+        match token.kind() {
+            TOKEN_COMMENT => end = token.text_range().end(),
+            TOKEN_WHITESPACE => (),
+            _ => break,
+        }
+    }
+    end
+}
+
+/// Put every `inherit` that fits in `width` characters on one line, and
+/// the names of longer ones on a line each, like
+///
+/// ```nix
+/// inherit (lib)
+///   mkIf
+///   mkOption
+///   ;
+/// ```
+///
+/// Either way, the output doesn't depend on how the names were wrapped
+/// before. `inherit`s with comments or errors in them are left alone.
+fn wrap_inherits(code: &str, width: usize) -> String {
+    let ast = rnix::parse(code);
+    let mut edits = Vec::new();
+    for inherit in ast.node().descendants().filter_map(Inherit::cast) {
+        let node = inherit.node();
+        let untouchable = node.descendants_with_tokens().any(|child| child.kind() == TOKEN_COMMENT || child.kind() == NODE_ERROR);
+        if untouchable || node.ancestors().skip(1).any(|parent| parent.kind() == NODE_INHERIT) {
+            continue;
+        }
+        let head = match inherit.from() {
+            Some(from) => format!("inherit {}", from.node().text()),
+            None => "inherit".to_owned(),
+        };
+        let names: Vec<String> = inherit.idents().map(|ident| ident.as_str().to_owned()).collect();
+        let others = node.children().any(|child| child.kind() != NODE_IDENT && child.kind() != NODE_INHERIT_FROM);
+        if head.contains('\n') || names.len() < 2 || others {
+            continue;
+        }
+
+        let range = node.text_range();
+        let (start, end) = (range.start().to_usize(), range.end().to_usize());
+        let line_start = code[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = code[end..].find('\n').map_or(code.len(), |i| end + i);
+        let indent: String = code[line_start..].chars().take_while(|&c| c == ' ' || c == '\t').collect();
+
+        let one_line = format!("{head} {};", names.join(" "));
+        let length = code[line_start..start].chars().count()
+            + one_line.chars().count()
+            + code[end..line_end].trim_end().chars().count();
+        // Wrapping one in the middle of a line, like in `{ inherit a b; }`,
+        // would leave the rest of the line dangling
+        let starts_line = code[line_start..start].trim().is_empty();
+        let wrapped = if length <= width || !starts_line {
+            one_line
+        } else {
+            let inner = format!("\n{indent}{}", " ".repeat(FMT_INDENT));
+            format!("{head}{inner}{}{inner};", names.join(&inner))
+        };
+        if wrapped != code[start..end] {
+            edits.push((start..end, wrapped));
+        }
+    }
+
+    let mut output = code.to_owned();
+    for (range, text) in edits.into_iter().rev() {
+        output.replace_range(range, &text);
+    }
+    output
+}
+
+/// Change the indentation and whitespace of formatted code to match the
+/// editor's options. Whitespace inside strings is part of their value, so
+/// it's left alone, just like everything before `preserved`.
+fn apply_options(code: &str, preserved: usize, options: &FormattingOptions) -> String {
+    let tab_size = usize::try_from(options.tab_size).unwrap_or(FMT_INDENT);
+    let indent = if options.insert_spaces { " ".repeat(tab_size) } else { "\t".into() };
+    let reindent = indent != " ".repeat(FMT_INDENT);
+    let trim = options.trim_trailing_whitespace == Some(true);
+    if !reindent && !trim && options.insert_final_newline != Some(true) && options.trim_final_newlines != Some(true) {
+        return code.to_owned();
+    }
+
+    let strings: Vec<TextRange> = rnix::parse(code).node().descendants_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+        .filter(|token| token.kind() == TOKEN_STRING_CONTENT)
+        .map(|token| token.text_range())
+        .collect();
+    let in_string = |start: usize, end: usize| strings.iter()
+        .any(|range| range.start().to_usize() < end && start < range.end().to_usize());
+
+    let mut output = String::with_capacity(code.len());
+    let mut start = 0;
+    for line in code.split_inclusive('\n') {
+        let end = start + line.len();
+        let (mut body, newline) = match line.strip_suffix("\r\n").or_else(|| line.strip_suffix('\n')) {
+            Some(body) => (body, &line[body.len()..]),
+            None => (line, ""),
+        };
+        if start < preserved {
+            output.push_str(line);
+            start = end;
+            continue;
+        }
+        if trim {
+            let trimmed = body.trim_end_matches([' ', '\t']);
+            if !in_string(start + trimmed.len(), end) {
+                body = trimmed;
+            }
+        }
+        let content = body.trim_start_matches(' ');
+        let spaces = body.len() - content.len();
+        if reindent && spaces > 0 && !in_string(start, start + spaces) {
+            // Spaces that don't make a whole level are for alignment
+            #[allow(clippy::integer_division)]
+            let levels = spaces / FMT_INDENT;
+            output.push_str(&indent.repeat(levels));
+            output.push_str(&" ".repeat(spaces % FMT_INDENT));
+            output.push_str(content);
+        } else {
+            output.push_str(body);
+        }
+        output.push_str(newline);
+        start = end;
+    }
+
+    if options.trim_final_newlines == Some(true) {
+        let trimmed = output.trim_end_matches(['\n', '\r']).len();
+        if trimmed < output.len() {
+            // Keep the final newline itself
+            let newline = if output[trimmed..].starts_with("\r\n") { "\r\n" } else { "\n" };
+            output.truncate(trimmed);
+            output.push_str(newline);
+        }
+    }
+    if options.insert_final_newline == Some(true) && !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    output
+}
+
+/// A single edit turning `old` into `new`, covering everything between
+/// their common start and end
+pub fn replace_changed(old: &str, new: &str, encoding: Encoding) -> TextEdit {
+    let mut prefix = old.bytes().zip(new.bytes()).take_while(|(a, b)| a == b).count();
+    while !old.is_char_boundary(prefix) || !new.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let mut suffix = old.bytes().rev().zip(new.bytes().rev()).take(max_suffix).take_while(|(a, b)| a == b).count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+    let range = TextRange::from_to(TextUnit::from_usize(prefix), TextUnit::from_usize(old.len() - suffix));
+    TextEdit {
+        range: utils::range(old, range, encoding),
+        new_text: new[prefix..new.len() - suffix].to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse::Parsed, testing, utils::Encoding};
+    use lsp_types::FormattingOptions;
+    use std::{collections::HashMap, path::Path};
+
+    /// What nixpkgs-fmt is run with when formatting the workspace
+    fn options() -> FormattingOptions {
+        FormattingOptions {
+            tab_size: 2,
+            insert_spaces: true,
+            properties: HashMap::new(),
+            trim_trailing_whitespace: None,
+            insert_final_newline: None,
+            trim_final_newlines: None,
+        }
+    }
+    fn format_with(code: &str, width: usize, options: &FormattingOptions) -> String {
+        let edits = super::format(&Parsed::new(code), code, options, width, Encoding::Utf16);
+        testing::apply(code, &edits)
+    }
+    /// Formatted code, which formatting again doesn't change
+    fn format(code: &str, width: usize) -> String {
+        let formatted = format_with(code, width, &options());
+        assert_eq!(format_with(&formatted, width, &options()), formatted, "formatting {code:?} again changed it");
+        formatted
+    }
+
+    #[test]
+    fn temp_file_next_to_original() {
+        let file = super::temp_file(Path::new("/project/pkgs/default.nix"));
+        assert_eq!(file.parent(), Some(Path::new("/project/pkgs")));
+        assert_eq!(file.extension().and_then(|extension| extension.to_str()), Some("nix"));
+        assert!(file.file_name().unwrap().to_str().unwrap().starts_with(".default.nix."));
+    }
+
+    /// An entry keeping sets from fitting on one line
+    const LONG: &str = "  description = \"Something long enough to keep the set on more than one line\";\n";
+
+    #[test]
+    fn short_inherits() {
+        let code = format!("{{\n  inherit (lib) mkIf\n    mkOption;\n{LONG}}}\n");
+        assert_eq!(format(&code, 80), format!("{{\n  inherit (lib) mkIf mkOption;\n{LONG}}}\n"));
+        let code = format!("{{\n  inherit a b;\n{LONG}}}\n");
+        assert_eq!(format(&code, 80), code);
+    }
+    #[test]
+    fn long_inherits() {
+        let code = format!("{{\n  inherit (lib) mkIf mkOption mkEnableOption types;\n{LONG}}}\n");
+        let wrapped = format!("{{\n  inherit (lib)\n    mkIf\n    mkOption\n    mkEnableOption\n    types\n    ;\n{LONG}}}\n");
+        assert_eq!(format(&code, 40), wrapped);
+        // However they were wrapped before
+        let code = format!("{{\n  inherit (lib) mkIf\n    mkOption mkEnableOption\n    types;\n{LONG}}}\n");
+        assert_eq!(format(&code, 40), wrapped);
+        assert_eq!(format(&wrapped, 80), format!("{{\n  inherit (lib) mkIf mkOption mkEnableOption types;\n{LONG}}}\n"));
+    }
+
+    #[test]
+    fn sets_at_the_width() {
+        let flat = "{ a = 1; b = 2; }\n";
+        let expanded = "{\n  a = 1;\n  b = 2;\n}\n";
+        let width = flat.trim_end().len();
+        for code in [flat, expanded] {
+            assert_eq!(format(code, width - 1), expanded);
+            assert_eq!(format(code, width), flat);
+            assert_eq!(format(code, width + 1), flat);
+        }
+    }
+    #[test]
+    fn lists_at_the_width() {
+        // The `;` after the list has to fit too
+        let flat = format!("{{\n  list = [ 1 2 3 ];\n{LONG}}}\n");
+        let expanded = format!("{{\n  list = [\n    1\n    2\n    3\n  ];\n{LONG}}}\n");
+        let width = "  list = [ 1 2 3 ];".len();
+        for code in [&flat, &expanded] {
+            assert_eq!(format(code, width - 1), expanded);
+            assert_eq!(format(code, width), flat);
+            assert_eq!(format(code, width + 1), flat);
+        }
+    }
+
+    /// A set with an indented string and a comment, both with trailing
+    /// whitespace, which is only part of the value in the string
+    const STRING: &str = "{\n  a = ''\n    text  \n  '';\n  b = [\n    1 # one  \n  ];\n}\n";
+
+    fn apply(code: &str, options: &FormattingOptions) -> String {
+        super::apply_options(code, 0, options)
+    }
+
+    #[test]
+    fn tab_size() {
+        // The content of the string stays the same
+        let expected = "{\n    a = ''\n    text  \n  '';\n    b = [\n        1 # one  \n    ];\n}\n";
+        assert_eq!(apply(STRING, &FormattingOptions { tab_size: 4, ..options() }), expected);
+        assert_eq!(apply(STRING, &options()), STRING);
+        // Spaces that aren't a whole level are kept for alignment
+        assert_eq!(apply("[\n   1\n]\n", &FormattingOptions { tab_size: 4, ..options() }), "[\n     1\n]\n");
+    }
+    #[test]
+    fn insert_spaces() {
+        let expected = "{\n\ta = ''\n    text  \n  '';\n\tb = [\n\t\t1 # one  \n\t];\n}\n";
+        assert_eq!(apply(STRING, &FormattingOptions { insert_spaces: false, ..options() }), expected);
+        // Through nixpkgs-fmt and the layout too
+        let code = format!("{{\n  a = 1;\n{LONG}}}\n");
+        let expected = format!("{{\n\ta = 1;\n\t{}}}\n", LONG.trim_start());
+        assert_eq!(format_with(&code, 80, &FormattingOptions { insert_spaces: false, ..options() }), expected);
+    }
+    #[test]
+    fn trim_trailing_whitespace() {
+        let expected = "{\n  a = ''\n    text  \n  '';\n  b = [\n    1 # one\n  ];\n}\n";
+        assert_eq!(apply(STRING, &FormattingOptions { trim_trailing_whitespace: Some(true), ..options() }), expected);
+        assert_eq!(apply(STRING, &FormattingOptions { trim_trailing_whitespace: Some(false), ..options() }), STRING);
+    }
+    #[test]
+    fn insert_final_newline() {
+        assert_eq!(apply("{ }", &FormattingOptions { insert_final_newline: Some(true), ..options() }), "{ }\n");
+        assert_eq!(apply("{ }\n", &FormattingOptions { insert_final_newline: Some(true), ..options() }), "{ }\n");
+        assert_eq!(apply("{ }", &options()), "{ }");
+        assert_eq!(apply("", &FormattingOptions { insert_final_newline: Some(true), ..options() }), "");
+    }
+    #[test]
+    fn trim_final_newlines() {
+        assert_eq!(apply("{ }\n\n\n", &FormattingOptions { trim_final_newlines: Some(true), ..options() }), "{ }\n");
+        assert_eq!(apply("{ }\r\n\r\n", &FormattingOptions { trim_final_newlines: Some(true), ..options() }), "{ }\r\n");
+        assert_eq!(apply("{ }\n\n", &options()), "{ }\n\n");
+    }
+
+    #[test]
+    fn shebangs() {
+        let shebang = "#!/usr/bin/env nix-shell\n#!nix-shell -i bash  -p hello\n#!nix-shell   --pure  \n";
+        let code = format!("{shebang}{{a=1;\nb =  [ 1\n2 ];}}\n");
+        assert_eq!(format(&code, 80), format!("{shebang}{{ a = 1; b = [ 1 2 ]; }}\n"));
+        // Not even the editor's options change them
+        let options = FormattingOptions { tab_size: 4, trim_trailing_whitespace: Some(true), ..options() };
+        assert_eq!(format_with(&code, 20, &options), format!("{shebang}{{\n    a = 1;\n    b = [ 1 2 ];\n}}\n"));
+    }
+}
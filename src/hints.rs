@@ -0,0 +1,52 @@
+use crate::utils::{self, Encoding};
+use rnix::{
+    types::*,
+    SyntaxKind::{NODE_IDENT, NODE_SELECT},
+    SyntaxNode,
+    TextRange,
+};
+use serde_json::{json, Value};
+
+/// Sources longer than this are shortened to `…` in hints
+const MAX_SOURCE_LEN: usize = 40;
+
+/// Inlay hints after each `inherit (x) a b;` in a range, spelling out
+/// what it expands to: `a = x.a; b = x.b;`. These aren't supported by
+/// lsp-types yet, so they're plain JSON.
+pub fn inherit_hints(root: &SyntaxNode, code: &str, encoding: Encoding, span: TextRange) -> Vec<Value> {
+    root.descendants()
+        .filter_map(Inherit::cast)
+        .filter(|inherit| {
+            let end = inherit.node().text_range().end();
+            span.start() <= end && end <= span.end()
+        })
+        .filter_map(|inherit| {
+            let source = source(&inherit.from()?.inner()?);
+            let entries: Vec<String> = inherit.idents()
+                .map(|ident| format!("{name} = {source}.{name};", name = ident.as_str()))
+                .collect();
+            if entries.is_empty() {
+                return None;
+            }
+            let position = utils::offset_to_pos(code, inherit.node().text_range().end().to_usize(), encoding);
+            Some(json!({
+                "position": position,
+                "label": entries.join(" "),
+                "paddingLeft": true,
+            }))
+        })
+        .collect()
+}
+
+/// How the source of an `inherit` is shown: names and attribute paths as
+/// they are, anything else in parentheses
+fn source(node: &SyntaxNode) -> String {
+    let text = node.text().to_string();
+    if text.len() > MAX_SOURCE_LEN || text.contains('\n') {
+        return "(…)".into();
+    }
+    match node.kind() {
+        NODE_IDENT | NODE_SELECT => text,
+        _ => format!("({text})"),
+    }
+}
@@ -0,0 +1,272 @@
+use crate::utils::{self, Encoding};
+use lsp_types::*;
+use rnix::{
+    types::*,
+    value::{Anchor, StrPart, Value as ParsedValue},
+    SyntaxKind::{TOKEN_SQUARE_B_OPEN, TOKEN_WHITESPACE},
+    SyntaxNode,
+    TextRange,
+    TextUnit,
+};
+use std::{
+    env,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// Completions for the paths in `imports = [ ... ]` of a NixOS module,
+/// like `./hardware-configuration.nix`. Only directories and `.nix`
+/// files are offered, minus the ones already imported. Returns `None`
+/// unless the cursor is at such a path.
+pub fn completions(file: &Url, root: &SyntaxNode, code: &str, offset: usize, encoding: Encoding) -> Option<Vec<CompletionItem>> {
+    // Unfinished paths like `./dir/` aren't valid tokens, so this looks
+    // at the text instead
+    let before = code.get(..offset)?;
+    let start = before.char_indices().rev()
+        .find(|(_, c)| !is_path_char(*c))
+        .map_or(0, |(i, c)| i + c.len_utf8());
+    let typed = &before[start..];
+
+    let list = if typed.is_empty() {
+        let token = root.token_at_offset(TextUnit::from_usize(offset)).left_biased()?;
+        if token.kind() != TOKEN_WHITESPACE && token.kind() != TOKEN_SQUARE_B_OPEN {
+            return None;
+        }
+        List::cast(token.parent())?
+    } else {
+        if !["./", "../", "/", "~/"].iter().any(|anchor| typed.starts_with(anchor)) {
+            return None;
+        }
+        let token = root.token_at_offset(TextUnit::from_usize(start)).right_biased()?;
+        let parent = token.parent();
+        match List::cast(parent.clone()) {
+            Some(list) => list,
+            None => List::cast(parent.parent()?)?,
+        }
+    };
+    if !is_imports(&list) {
+        return None;
+    }
+
+    let current = utils::uri_path(file)?;
+    let base = current.parent()?;
+    let cursor = TextUnit::from_usize(offset);
+    let imported: Vec<PathBuf> = list.items()
+        // Not the one being typed
+        .filter(|item| !item.text_range().contains_inclusive(cursor))
+        .filter_map(|item| literal_path(&item))
+        .filter_map(|path| resolve(base, &path))
+        .chain(Some(current.clone()))
+        .map(|path| fs::canonicalize(&path).unwrap_or(path))
+        .collect();
+
+    // Everything after the last slash is the name being typed
+    let (dir, name) = match typed.rfind('/') {
+        Some(slash) => typed.split_at(slash + 1),
+        None => ("./", ""),
+    };
+    let insert_dir = if typed.is_empty() { dir } else { "" };
+    let name_start = TextUnit::from_usize(offset - name.len());
+    let range = utils::range(code, TextRange::from_to(name_start, cursor), encoding);
+
+    path_completions(&resolve(base, dir)?, name, insert_dir, range, &imported)
+}
+
+/// Completions for the directories and `.nix` files in `dir` whose name
+/// starts with `name`, except the ones in `skip`
+fn path_completions(dir: &Path, name: &str, insert_dir: &str, range: Range, skip: &[PathBuf]) -> Option<Vec<CompletionItem>> {
+    let mut completions = Vec::new();
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        let label = entry.file_name().to_string_lossy().into_owned();
+        if !label.starts_with(name) || (label.starts_with('.') && !name.starts_with('.')) {
+            continue;
+        }
+        let path = entry.path();
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if skip.contains(&canonical) || !label.chars().all(is_path_char) {
+            continue;
+        }
+        let (kind, detail, new_text) = if path.is_dir() {
+            let detail = if path.join("default.nix").is_file() { "directory with default.nix" } else { "directory" };
+            (CompletionItemKind::Folder, detail, format!("{insert_dir}{label}/"))
+        } else if path.extension().is_some_and(|extension| extension == "nix") {
+            (CompletionItemKind::File, "Nix file", format!("{insert_dir}{label}"))
+        } else {
+            continue;
+        };
+        completions.push(CompletionItem {
+            label,
+            kind: Some(kind),
+            detail: Some(detail.into()),
+            // Files before directories
+            sort_text: Some(format!("{}{new_text}", if kind == CompletionItemKind::File { "0" } else { "1" })),
+            text_edit: Some(TextEdit {
+                range,
+                new_text,
+            }),
+            ..CompletionItem::default()
+        });
+    }
+    Some(completions)
+}
+
+/// Completions for search paths like `<nixpkgs>` and `<nixpkgs/lib>`,
+/// from the entries of `NIX_PATH`. Returns `None` unless the cursor is
+/// after a `<` that starts a value, rather than comparing, like in
+/// `x = <` or `import <`.
+pub fn search_path_completions(code: &str, offset: usize, encoding: Encoding) -> Option<Vec<CompletionItem>> {
+    let before = code.get(..offset)?;
+    let start = before.char_indices().rev()
+        .find(|(_, c)| !is_path_char(*c) || *c == '~')
+        .map_or(0, |(i, c)| i + c.len_utf8());
+    let typed = &before[start..];
+    let value = before[..start].strip_suffix('<')?.trim_end();
+    let starts_value = value.is_empty()
+        || value.ends_with(['=', '(', '[', ':', ';'])
+        || ["import", "in", "then", "else", "assert", "with"].iter().any(|word| {
+            value.strip_suffix(word).is_some_and(|rest| !rest.ends_with(|c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '\'')))
+        });
+    if !starts_value {
+        return None;
+    }
+
+    let entries = search_path();
+    let (dir, name) = match typed.rfind('/') {
+        Some(slash) => typed.split_at(slash + 1),
+        None => ("", typed),
+    };
+    let name_start = TextUnit::from_usize(offset - name.len());
+    let range = utils::range(code, TextRange::from_to(name_start, TextUnit::from_usize(offset)), encoding);
+
+    let mut completions = Vec::new();
+    if dir.is_empty() {
+        for (prefix, path) in &entries {
+            match prefix {
+                Some(prefix) => if prefix.starts_with(name) {
+                    completions.push(CompletionItem {
+                        label: prefix.clone(),
+                        kind: Some(CompletionItemKind::Folder),
+                        detail: Some(path.display().to_string()),
+                        text_edit: Some(TextEdit {
+                            range,
+                            new_text: prefix.clone(),
+                        }),
+                        ..CompletionItem::default()
+                    });
+                },
+                // Anything in the directory can be looked up
+                None => completions.extend(path_completions(path, name, "", range, &[]).into_iter().flatten()),
+            }
+        }
+    } else {
+        // Like Nix, the first entry that has the path wins
+        let (first, rest) = dir.trim_end_matches('/').split_once('/').unwrap_or((dir.trim_end_matches('/'), ""));
+        let found = entries.iter().find_map(|(prefix, path)| {
+            let path = match prefix {
+                Some(prefix) if prefix == first => path.clone(),
+                Some(_) => return None,
+                None => path.join(first),
+            };
+            Some(path.join(rest)).filter(|path| path.is_dir())
+        })?;
+        completions.extend(path_completions(&found, name, "", range, &[]).into_iter().flatten());
+    }
+    completions.dedup_by(|a, b| a.label == b.label);
+    Some(completions)
+}
+/// The entries of `NIX_PATH` that are directories, like `nixpkgs=/path`
+/// as `(Some("nixpkgs"), "/path")`. Others, like URLs of tarballs,
+/// aren't anywhere to look yet.
+fn search_path() -> Vec<(Option<String>, PathBuf)> {
+    let Some(nix_path) = env::var_os("NIX_PATH") else { return Vec::new() };
+    let nix_path = nix_path.to_string_lossy().into_owned();
+    // Entries are separated by `:`, which URLs have too
+    let mut entries: Vec<String> = Vec::new();
+    for part in nix_path.split(':') {
+        match entries.last_mut() {
+            Some(last) if part.starts_with("//") => {
+                last.push(':');
+                last.push_str(part);
+            },
+            _ => entries.push(part.to_owned()),
+        }
+    }
+    entries.into_iter()
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((prefix, path)) => (Some(prefix.to_owned()), PathBuf::from(path)),
+            None => (None, PathBuf::from(entry)),
+        })
+        .filter(|(_, path)| path.is_dir())
+        .collect()
+}
+
+/// Where a path literal in a file points to, like the file's directory
+/// for `./.`. Search paths like `<nixpkgs/lib>` are looked up in
+/// `NIX_PATH`, and are `None` if no entry has them. Like in Nix, `..` is
+/// resolved without following symlinks.
+pub fn resolve_literal(file: &Url, anchor: &Anchor, path: &str) -> Option<PathBuf> {
+    let resolved = match anchor {
+        Anchor::Absolute => PathBuf::from(path),
+        Anchor::Relative => utils::uri_path(file)?.parent()?.join(path),
+        Anchor::Home => PathBuf::from(env::var_os("HOME")?).join(path),
+        Anchor::Store => lookup_search_path(path)?,
+    };
+    let mut normalized = PathBuf::new();
+    for component in resolved.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            _ => normalized.push(component),
+        }
+    }
+    Some(normalized)
+}
+/// The first entry of `NIX_PATH` that has a path, like Nix looks up
+/// `<nixpkgs/lib>`
+fn lookup_search_path(path: &str) -> Option<PathBuf> {
+    let (first, rest) = path.split_once('/').unwrap_or((path, ""));
+    search_path().into_iter().find_map(|(prefix, dir)| {
+        let found = match prefix {
+            Some(prefix) if prefix == first => dir.join(rest),
+            Some(_) => return None,
+            None => dir.join(path),
+        };
+        Some(found).filter(|found| found.exists())
+    })
+}
+
+/// Characters that can be part of a path literal, other than `~` at the
+/// start
+fn is_path_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+' | '/' | '~')
+}
+/// Whether a list is the value of `imports = [ ... ];`
+fn is_imports(list: &List) -> bool {
+    let Some(key) = list.node().parent().and_then(KeyValue::cast).and_then(|entry| entry.key()) else { return false };
+    let mut path = key.path();
+    let name = path.next().and_then(Ident::cast);
+    path.next().is_none() && name.is_some_and(|name| name.as_str() == "imports")
+}
+/// The text of a path literal, or a string without interpolation
+fn literal_path(node: &SyntaxNode) -> Option<String> {
+    if let Some(string) = Str::cast(node.clone()) {
+        return match string.parts().as_slice() {
+            [StrPart::Literal(text)] => Some(text.clone()),
+            _ => None,
+        };
+    }
+    match Value::cast(node.clone())?.to_value() {
+        Ok(ParsedValue::Path(..)) => Some(node.text().to_string()),
+        _ => None,
+    }
+}
+/// Resolve a path like `./a`, `/a`, or `~/a` relative to a directory
+fn resolve(base: &Path, path: &str) -> Option<PathBuf> {
+    if let Some(rest) = path.strip_prefix("~/") {
+        return Some(PathBuf::from(env::var_os("HOME")?).join(rest));
+    }
+    Some(base.join(path))
+}
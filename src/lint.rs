@@ -0,0 +1,335 @@
+use crate::{App, asserts, builtins::{self, Builtin, Version}, utils::{self, Encoding, Var}};
+use lsp_types::*;
+use rnix::{
+    types::*,
+    SyntaxKind::{NODE_STRING_INTERPOL, TOKEN_STRING_START},
+    SyntaxNode,
+};
+use std::{collections::{HashMap, HashSet}, convert::TryFrom, rc::Rc};
+
+impl App {
+    /// Warnings for mistakes that only show up when evaluating, found by
+    /// statically resolving the values involved
+    pub fn lint(&mut self, uri: &Url, root: &SyntaxNode, code: &str) -> Vec<Diagnostic> {
+        let file = Rc::new(uri.clone());
+        let mut diagnostics = Vec::new();
+        for interpol in root.descendants().filter(|node| node.kind() == NODE_STRING_INTERPOL) {
+            if self.is_cancelled() {
+                break;
+            }
+            let Some(inner) = interpol.children().next() else { continue };
+            let Some((_, value)) = self.resolve_value(&file, inner) else { continue };
+            let kind = if List::cast(value.clone()).is_some() {
+                "list"
+            } else if AttrSet::cast(value).is_some_and(|set| !is_coercible(&set)) {
+                "set"
+            } else {
+                continue;
+            };
+            diagnostics.push(Diagnostic {
+                range: utils::range(code, interpol.text_range(), self.encoding),
+                severity: Some(DiagnosticSeverity::Warning),
+                message: format!("interpolating a {kind} fails when evaluated: cannot coerce a {kind} to a string"),
+                ..Diagnostic::default()
+            });
+        }
+        for assert in root.descendants().filter_map(Assert::cast) {
+            if self.is_cancelled() {
+                break;
+            }
+            let Some(condition) = assert.condition() else { continue };
+            if self.assert_fails(&file, &assert) {
+                diagnostics.push(Diagnostic {
+                    range: utils::range(code, condition.text_range(), self.encoding),
+                    severity: Some(DiagnosticSeverity::Warning),
+                    message: "this condition is always false, so the assertion always fails".into(),
+                    ..Diagnostic::default()
+                });
+            }
+        }
+        if self.config.recursion_warnings {
+            for (key, message) in root.descendants().flat_map(|node| cycles(&node)) {
+                diagnostics.push(Diagnostic {
+                    range: utils::range(code, key.text_range(), self.encoding),
+                    severity: Some(DiagnosticSeverity::Warning),
+                    message,
+                    ..Diagnostic::default()
+                });
+            }
+        }
+        for (interpol, name) in self.shell_variables(&file, root) {
+            diagnostics.push(shell_variable_diagnostic(code, self.encoding, &interpol, &name));
+        }
+        for (name, var) in self.throw_uses(&file, root) {
+            let Some(value) = &var.value else { continue };
+            let Some((function, message)) = asserts::throw_call(&var.file, value) else { continue };
+            // The file being linted isn't stored yet
+            let definition_code = if *var.file == *uri {
+                code
+            } else {
+                let Some((_, code)) = self.files.get(&var.file) else { continue };
+                code.as_str()
+            };
+            let function = function.as_str();
+            diagnostics.push(Diagnostic {
+                range: utils::range(code, name.text_range(), self.encoding),
+                severity: Some(DiagnosticSeverity::Information),
+                message: format!("`{}` is defined as `{function}`, so this fails when evaluated: {}", name.text(), asserts::message_text(&message)),
+                related_information: Some(vec![DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: (*var.file).clone(),
+                        range: utils::range(definition_code, value.text_range(), self.encoding),
+                    },
+                    message: format!("defined as `{function}` here"),
+                }]),
+                ..Diagnostic::default()
+            });
+        }
+        for (value, message) in self.option_type_errors(&file, root) {
+            diagnostics.push(Diagnostic {
+                range: utils::range(code, value.text_range(), self.encoding),
+                severity: Some(DiagnosticSeverity::Error),
+                message,
+                ..Diagnostic::default()
+            });
+        }
+        if let Some(version) = self.config.nix_version {
+            for (node, builtin) in too_new(&file, root, version) {
+                let since = builtin.since.map_or_else(String::new, |since| since.to_string());
+                diagnostics.push(Diagnostic {
+                    range: utils::range(code, node.text_range(), self.encoding),
+                    severity: Some(DiagnosticSeverity::Warning),
+                    message: format!("`builtins.{}` was added in Nix {since}, but this targets Nix {version}", builtin.name),
+                    ..Diagnostic::default()
+                });
+            }
+        }
+        diagnostics
+    }
+}
+
+impl App {
+    /// Interpolations in `''` strings that were probably meant for the
+    /// shell, like `${HOME}` in a script. Only names in capitals that
+    /// aren't defined anywhere count.
+    pub fn shell_variables(&mut self, file: &Rc<Url>, root: &SyntaxNode) -> Vec<(SyntaxNode, String)> {
+        let mut found = Vec::new();
+        for interpol in root.descendants().filter(|node| node.kind() == NODE_STRING_INTERPOL) {
+            let indented = interpol.parent()
+                .and_then(|string| string.first_token())
+                .is_some_and(|start| start.kind() == TOKEN_STRING_START && start.text() == "''");
+            let Some(ident) = interpol.children().next().and_then(Ident::cast) else { continue };
+            let name = ident.as_str();
+            let shell_like = name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+                && name.chars().any(|c| c.is_ascii_uppercase());
+            if !indented || !shell_like || builtins::get(name).is_some() {
+                continue;
+            }
+            let defined = self.scope_at(file, ident.node()).is_none_or(|scope| scope.contains_key(name));
+            if !defined {
+                found.push((interpol, name.to_owned()));
+            }
+        }
+        found
+    }
+}
+impl App {
+    /// Attributes defined as `throw` or `abort` in this file that are
+    /// still selected or inherited, like `cfg.a` with
+    /// `a = throw "not implemented";`, along with their definition. Those
+    /// are usually stubs that were never filled in. Uses in
+    /// `builtins.tryEval` expect the error, so they don't count.
+    pub fn throw_uses(&mut self, file: &Rc<Url>, root: &SyntaxNode) -> Vec<(SyntaxNode, Var)> {
+        // Resolving every access is slow, so only the names of stubs are
+        let stubs: HashSet<String> = root.descendants()
+            .filter_map(KeyValue::cast)
+            .filter(|entry| entry.value().is_some_and(|value| asserts::throw_call(file, &value).is_some()))
+            .filter_map(|entry| utils::static_name(&entry.key()?.path().last()?))
+            .collect();
+        if stubs.is_empty() {
+            return Vec::new();
+        }
+
+        let mut found = Vec::new();
+        for node in root.descendants() {
+            if self.is_cancelled() {
+                break;
+            }
+            let uses: Vec<(SyntaxNode, Var)> = if let Some(select) = Select::cast(node.clone()) {
+                let Some((index, set)) = select.index().zip(select.set()) else { continue };
+                let Some(name) = utils::static_name(&index).filter(|name| stubs.contains(name)) else { continue };
+                self.scope_from_node(file, set)
+                    .and_then(|mut scope| scope.remove(&name))
+                    .map(|var| (index, var))
+                    .into_iter()
+                    .collect()
+            } else if let Some(inherit) = Inherit::cast(node.clone()) {
+                if !inherit.idents().any(|ident| stubs.contains(ident.as_str())) {
+                    continue;
+                }
+                let source = match inherit.from() {
+                    Some(from) => from.inner().and_then(|from| self.scope_from_node(file, from)),
+                    // `inherit a;` takes `a` from outside of the set
+                    None => inherit.node().parent().and_then(|set| set.parent()).and_then(|outside| self.scope_at(file, &outside)),
+                };
+                let Some(mut source) = source else { continue };
+                inherit.idents()
+                    .filter_map(|ident| Some((ident.node().clone(), source.remove(ident.as_str())?)))
+                    .collect()
+            } else {
+                continue;
+            };
+            for (name, var) in uses {
+                let throws = var.value.as_ref().is_some_and(|value| asserts::throw_call(&var.file, value).is_some());
+                if throws && !in_try_eval(&node) {
+                    found.push((name, var));
+                }
+            }
+        }
+        found
+    }
+}
+/// Whether a node is in the argument of `builtins.tryEval`, which
+/// catches `throw`
+fn in_try_eval(node: &SyntaxNode) -> bool {
+    node.ancestors()
+        .filter_map(Apply::cast)
+        .filter_map(|apply| apply.lambda())
+        .any(|function| function.text() == "builtins.tryEval")
+}
+pub fn shell_variable_diagnostic(code: &str, encoding: Encoding, interpol: &SyntaxNode, name: &str) -> Diagnostic {
+    Diagnostic {
+        range: utils::range(code, interpol.text_range(), encoding),
+        severity: Some(DiagnosticSeverity::Warning),
+        message: format!("`{name}` isn't defined, so this fails to evaluate. For a shell variable, escape it as `''${{{name}}}`"),
+        ..Diagnostic::default()
+    }
+}
+
+/// Uses of builtins that the targeted version of Nix doesn't have yet.
+/// Code often checks for those first, like `builtins ? getFlake` or
+/// `builtins.getFlake or ...`, so those uses are fine.
+fn too_new(file: &Rc<Url>, root: &SyntaxNode, version: Version) -> Vec<(SyntaxNode, &'static Builtin)> {
+    let unbound = |node: &SyntaxNode, name: &str| utils::scope_for(file, node.clone())
+        .is_some_and(|scope| !scope.contains_key(name));
+    let checked: HashSet<String> = root.descendants()
+        .filter_map(BinOp::cast)
+        .filter(|op| op.operator() == BinOpKind::IsSet)
+        .filter(|op| op.lhs().and_then(Ident::cast).is_some_and(|set| set.as_str() == "builtins"))
+        .filter_map(|op| op.rhs().and_then(Ident::cast))
+        .map(|name| name.as_str().to_owned())
+        .collect();
+    let too_new = |name: &str| builtins::get(name)
+        .filter(|builtin| !builtin.available(Some(version)) && !checked.contains(builtin.name));
+
+    let mut uses = Vec::new();
+    for node in root.descendants() {
+        if let Some(select) = Select::cast(node.clone()) {
+            let (Some(set), Some(index)) = (select.set().and_then(Ident::cast), select.index().and_then(Ident::cast))
+            else { continue };
+            if set.as_str() != "builtins" || node.parent().and_then(OrDefault::cast).is_some() {
+                continue;
+            }
+            if let Some(builtin) = too_new(index.as_str()).filter(|_| unbound(&node, "builtins")) {
+                uses.push((index.node().clone(), builtin));
+            }
+        } else if let Some(ident) = Ident::cast(node.clone()) {
+            let Some(builtin) = too_new(ident.as_str()).filter(|builtin| builtin.global) else { continue };
+            if utils::is_reference(&ident) && unbound(&node, builtin.name) {
+                uses.push((node, builtin));
+            }
+        }
+    }
+    uses
+}
+
+/// Bindings of a `let` or `rec` set that are just another name of the
+/// same set, like `a = b;`. If those form a cycle, evaluating any of them
+/// is an infinite recursion. Something like `f = x: f x` isn't an alias,
+/// so it's never reported.
+fn cycles(node: &SyntaxNode) -> Vec<(SyntaxNode, String)> {
+    let entries: Vec<KeyValue> = match ParsedType::try_from(node.clone()) {
+        Ok(ParsedType::LetIn(let_in)) => let_in.entries().collect(),
+        Ok(ParsedType::LegacyLet(let_)) => let_.entries().collect(),
+        Ok(ParsedType::AttrSet(set)) if utils::is_recursive(&set) => set.entries().collect(),
+        _ => return Vec::new(),
+    };
+    let mut aliases = HashMap::new();
+    for entry in &entries {
+        let Some(key) = entry.key() else { continue };
+        let mut path = key.path();
+        let (Some(name), None) = (path.next().and_then(Ident::cast), path.next()) else { continue };
+        let mut value = entry.value();
+        while let Some(paren) = value.clone().and_then(Paren::cast) {
+            value = paren.inner();
+        }
+        if let Some(target) = value.and_then(Ident::cast) {
+            aliases.insert(name.as_str().to_owned(), (name.node().clone(), target.as_str().to_owned()));
+        }
+    }
+
+    let mut cycles = Vec::new();
+    for (name, (key, _)) in &aliases {
+        let mut chain = vec![name.as_str()];
+        let mut current = name;
+        while let Some((_, target)) = aliases.get(current) {
+            if target == name {
+                let message = if chain.len() == 1 {
+                    format!("infinite recursion: `{name}` is defined as itself")
+                } else {
+                    let chain: Vec<String> = chain.iter().chain(Some(&name.as_str())).map(|name| format!("`{name}`")).collect();
+                    format!("infinite recursion: {}", chain.join(" -> "))
+                };
+                cycles.push((key.clone(), message));
+                break;
+            }
+            // Leads into a cycle that this binding isn't part of
+            if chain.contains(&target.as_str()) {
+                break;
+            }
+            chain.push(target);
+            current = target;
+        }
+    }
+    cycles.sort_by_key(|(key, _)| key.text_range().start());
+    cycles
+}
+
+/// Whether a set can be converted to a string, which is the case if it
+/// has `__toString` or `outPath`. Sets with dynamic keys might have them.
+fn is_coercible(set: &AttrSet) -> bool {
+    let special = |name: &str| name == "__toString" || name == "outPath";
+    let keys = set.entries().any(|entry| {
+        entry.key().and_then(|key| key.path().next()).and_then(Ident::cast).is_none_or(|ident| special(ident.as_str()))
+    });
+    keys || set.inherits().flat_map(|inherit| inherit.idents()).any(|ident| special(ident.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use lsp_types::*;
+    use serde_json::json;
+
+    /// The messages of the warnings for some code
+    fn warnings(options: &serde_json::Value, code: &str) -> Vec<String> {
+        let (mut app, _client) = testing::app(options);
+        let uri = app.open_file("default.nix", code);
+        let root = app.files[&uri].0.node();
+        app.lint(&uri, &root, code).into_iter()
+            .filter(|diagnostic| diagnostic.severity == Some(DiagnosticSeverity::Warning))
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    #[test]
+    fn builtins_by_version() {
+        let code = "builtins.getFlake \"nixpkgs\"";
+        assert_eq!(warnings(&json!({ "nixVersion": "2.3" }), code), ["`builtins.getFlake` was added in Nix 2.4, but this targets Nix 2.3"]);
+        assert!(warnings(&json!({ "nixVersion": "2.4" }), code).is_empty());
+        // Without a version, everything is assumed to be there
+        assert!(warnings(&json!({}), code).is_empty());
+        // Checked for first
+        assert!(warnings(&json!({ "nixVersion": "2.3" }), "if builtins ? getFlake then builtins.getFlake \"nixpkgs\" else null").is_empty());
+    }
+}
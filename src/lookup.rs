@@ -1,76 +1,619 @@
-use crate::{App, utils::{self, Var}};
-use lsp_types::Url;
+use crate::{App, builtins, parse::Parsed, utils::{self, Var, VarKind}};
+use lsp_types::{Hover, HoverContents, MarkupContent, MarkupKind, TextDocumentPositionParams, Url};
 use rnix::{
     types::*,
     value::Value as ParsedValue,
     SyntaxNode,
+    TextUnit,
 };
 use std::{
-    collections::{HashMap, hash_map::Entry},
-    fs,
+    collections::{HashMap, HashSet, hash_map::Entry},
+    convert::TryFrom,
+    path::{Path, PathBuf},
     rc::Rc,
 };
 
+/// How many aliases/selects to follow before giving up, so that
+/// `let a = a; in a.b` doesn't loop forever
+const MAX_RESOLVE_DEPTH: usize = 32;
+/// The command listing the names in scope at a position, for debugging
+pub const SCOPE_COMMAND: &str = "rnix-lsp.showScope";
+/// How many names of a `with` to list in `SCOPE_COMMAND`
+const MAX_SCOPE_NAMES: usize = 50;
+/// How many builtins to name in `SCOPE_COMMAND`
+const MAX_SCOPE_BUILTINS: usize = 5;
+/// How many lines of the value an alias leads to its hover shows
+const MAX_ALIAS_LINES: usize = 12;
+
+/// All keys defining a variable in its set. Attributes can be defined in
+/// parts, like `a.b = 1; a.c = 2;`, which all make up `a`.
+pub fn parts(var: &Var) -> Vec<SyntaxNode> {
+    fn in_holder<T: EntryHolder>(holder: &T, name: &str) -> Vec<SyntaxNode> {
+        let keys = holder.entries().filter_map(|entry| entry.key()?.path().next());
+        let inherited = holder.inherits().flat_map(|inherit| inherit.idents()).map(|ident| ident.node().clone());
+        keys.chain(inherited)
+            .filter(|key| utils::static_name(key).as_deref() == Some(name))
+            .collect()
+    }
+    let Some(name) = utils::static_name(&var.key) else { return vec![var.key.clone()] };
+    let parts = match ParsedType::try_from(var.set.clone()) {
+        Ok(ParsedType::AttrSet(set)) => in_holder(&set, &name),
+        Ok(ParsedType::LetIn(let_in)) => in_holder(&let_in, &name),
+        Ok(ParsedType::LegacyLet(let_)) => in_holder(&let_, &name),
+        _ => Vec::new(),
+    };
+    if parts.is_empty() { vec![var.key.clone()] } else { parts }
+}
+
+/// The entries of a set or `let`
+fn entries(set: &SyntaxNode) -> Vec<KeyValue> {
+    match ParsedType::try_from(set.clone()) {
+        Ok(ParsedType::AttrSet(set)) => set.entries().collect(),
+        Ok(ParsedType::LetIn(let_in)) => let_in.entries().collect(),
+        Ok(ParsedType::LegacyLet(let_)) => let_.entries().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// A path from the directory `base` to `path`, like `../lib/default.nix`
+fn relative_path(base: &Path, path: &Path) -> String {
+    let base: Vec<_> = base.components().collect();
+    let path: Vec<_> = path.components().collect();
+    let common = base.iter().zip(&path).take_while(|(a, b)| a == b).count();
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    relative.extend(&path[common..]);
+    let relative = relative.to_string_lossy().into_owned();
+    if relative.starts_with("..") { relative } else { format!("./{relative}") }
+}
+
 impl App {
+    /// Where the identifier at a position comes from, for the footer of
+    /// hovers: the comment documenting it, the kind of binding and the
+    /// line it's defined on, with the file if that's another one. Returns
+    /// `None` at the definition itself.
+    pub fn provenance(&mut self, params: &TextDocumentPositionParams) -> Option<String> {
+        let uri = &params.text_document.uri;
+        let (ast, code) = self.files.get(uri)?;
+        let offset = utils::lookup_pos(code, params.position, self.encoding)?;
+        let root = ast.node();
+        let info = utils::ident_at(&root, offset)?;
+        let var = self.scope_for_ident(uri.clone(), &root, offset)
+            .and_then(|(ident, mut scope)| scope.remove(ident.as_str()));
+        let Some(var) = var else {
+            let builtin = utils::builtin_path(&Rc::new(uri.clone()), &info)
+                .and_then(|path| builtins::get(info.ident.as_str()).filter(|builtin| path || builtin.global));
+            return builtin.map(|_| "Builtin".into());
+        };
+        if var.key == *info.ident.node() {
+            return None;
+        }
+        let var = self.definition(var);
+
+        let (_, definition_code) = self.files.get(&var.file)?;
+        let line = utils::offset_to_pos(definition_code, var.key.text_range().start().to_usize(), self.encoding).line + 1;
+        let kind = match &var.kind {
+            VarKind::Let => "Defined in `let`".into(),
+            VarKind::Rec => "Defined in a `rec` set".into(),
+            VarKind::Param => "Function argument".into(),
+            VarKind::Attr => "Defined in a set".into(),
+            VarKind::With(namespace) => format!("From `with {};`", namespace.text()),
+        };
+        let comment = utils::doc_comment(&var.key).map_or_else(String::new, |comment| format!("{comment}\n\n"));
+        if var.file == Rc::new(uri.clone()) {
+            return Some(format!("{comment}{kind}, line {line}"));
+        }
+        let paths = utils::uri_path(uri).zip(utils::uri_path(&var.file));
+        let file = match paths.as_ref().and_then(|(current, definition)| Some((current.parent()?, definition))) {
+            Some((dir, definition)) => relative_path(dir, definition),
+            None => var.file.to_string(),
+        };
+        Some(format!("{comment}{kind}, in `{file}:{line}`"))
+    }
+    /// Hover for a name whose value is just another name, like `x` in
+    /// `x = y;`, showing what the aliases lead to. Up to `aliasDepth` of
+    /// them are followed, stopping at cycles like `a = b; b = a;`.
+    pub fn alias_hover(&mut self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let depth = self.config.alias_depth.min(MAX_RESOLVE_DEPTH);
+        if depth == 0 {
+            return None;
+        }
+        let uri = &params.text_document.uri;
+        let (ast, code) = self.files.get(uri)?;
+        let offset = utils::lookup_pos(code, params.position, self.encoding)?;
+        let root = ast.node();
+        let range = utils::range(code, utils::ident_at(&root, offset)?.ident.node().text_range(), self.encoding);
+        let (ident, mut scope) = self.scope_for_ident(uri.clone(), &root, offset)?;
+        let mut var = self.definition(scope.remove(ident.as_str())?);
+
+        let mut chain = vec![format!("`{}`", ident.as_str())];
+        let mut seen = HashSet::from([var.key.clone()]);
+        let mut end = None;
+        let mut cycle = false;
+        while chain.len() <= depth {
+            let Some(value) = var.value.clone().map(utils::unwrap_parens) else { break };
+            let next = if let Some(name) = Ident::cast(value.clone()) {
+                let next = self.scope_at(&var.file, &value).and_then(|mut scope| scope.remove(name.as_str()));
+                if next.is_none() {
+                    let builtin = builtins::get(name.as_str())
+                        .filter(|builtin| builtin.global && builtin.available(self.config.nix_version));
+                    end = builtin.map(builtins::Builtin::documentation);
+                }
+                next
+            } else if let Some(select) = Select::cast(value.clone()) {
+                let index = select.index().and_then(|index| utils::static_name(&index));
+                let set = select.set().and_then(|set| self.scope_from_node(&var.file, set));
+                set.zip(index).and_then(|(mut set, index)| set.remove(&index))
+            } else {
+                break;
+            };
+            chain.push(format!("`{}`", value.text()));
+            let Some(next) = next else {
+                var.value = None;
+                break;
+            };
+            var = self.definition(next);
+            if !seen.insert(var.key.clone()) {
+                cycle = true;
+                var.value = None;
+                break;
+            }
+        }
+        if chain.len() == 1 {
+            return None;
+        }
+
+        let end = end.or_else(|| {
+            let value = var.value.as_ref()?.text().to_string();
+            let mut lines: Vec<&str> = value.lines().take(MAX_ALIAS_LINES + 1).collect();
+            if lines.len() > MAX_ALIAS_LINES {
+                lines.truncate(MAX_ALIAS_LINES);
+                lines.push("...");
+            }
+            Some(format!("```nix\n{}\n```", lines.join("\n")))
+        }).or_else(|| (var.kind == VarKind::Param).then(|| "A function argument".into()));
+        let cycle = if cycle { ", which is a cycle" } else { "" };
+        let chain = format!("Alias: {}{cycle}", chain.join(" → "));
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: end.map_or_else(|| chain.clone(), |end| format!("{chain}\n\n{end}")),
+            }),
+            range: Some(range),
+        })
+    }
     pub fn scope_for_ident(&mut self, file: Url, root: &SyntaxNode, offset: usize) -> Option<(Ident, HashMap<String, Var>)>
     {
-        let mut file = Rc::new(file);
-        let info = utils::ident_at(&root, offset)?;
+        let file = Rc::new(file);
+        let info = utils::ident_at(root, offset)?;
         let ident = info.ident;
-        let mut entries = utils::scope_for(&file, ident.node().clone())?;
+        let mut entries = self.scope_at(&file, ident.node())?;
         for var in info.path {
-            let node = entries.get(&var)?.value.clone()?;
-            entries = self.scope_from_node(&mut file, node)?;
+            let var = entries.remove(&var)?;
+            entries = self.resolve_var(&var, 0)?;
+        }
+        Some((ident, entries))
+    }
+    /// Like `utils::scope_for`, but also includes variables provided by
+    /// any `with` expressions that can be resolved statically. Just like
+    /// in Nix, lexical bindings always take precedence over `with`, and
+    /// inner `with` expressions take precedence over outer ones.
+    pub fn scope_at(&mut self, file: &Rc<Url>, node: &SyntaxNode) -> Option<HashMap<String, Var>> {
+        self.scope_at_depth(file, node, 0)
+    }
+    fn scope_at_depth(&mut self, file: &Rc<Url>, node: &SyntaxNode, depth: usize) -> Option<HashMap<String, Var>> {
+        let mut scope = utils::scope_for(file, node.clone())?;
+
+        for ancestor in node.ancestors() {
+            let Some(namespace) = With::cast(ancestor).and_then(|with| with.namespace()) else { continue };
+            // The namespace itself is not affected by its own with
+            if node.ancestors().any(|parent| parent == namespace) {
+                continue;
+            }
+            let Some(provided) = self.resolve_set(file, namespace.clone(), depth + 1) else { continue };
+            for (name, mut var) in provided {
+                if let Entry::Vacant(entry) = scope.entry(name) {
+                    var.kind = VarKind::With(namespace.clone());
+                    entry.insert(var);
+                }
+            }
+        }
+
+        Some(scope)
+    }
+    /// Describe the names in scope at a position, innermost first, with
+    /// where they come from and are defined. Names that an inner binding
+    /// or `with` hides are marked as shadowed. This is what
+    /// `SCOPE_COMMAND` shows, to find out why completion or goto
+    /// definition don't find something.
+    pub fn describe_scope(&mut self, params: &TextDocumentPositionParams) -> Option<String> {
+        let file = Rc::new(params.text_document.uri.clone());
+        let (ast, code) = self.files.get(&file)?;
+        let offset = utils::lookup_pos(code, params.position, self.encoding)?;
+        let node = ast.node().token_at_offset(TextUnit::from_usize(offset)).right_biased()?.parent();
+        let scope = self.scope_at(&file, &node)?;
+
+        let mut lines = vec![format!("Names in scope at {}:{}, innermost first:", params.position.line + 1, params.position.character + 1)];
+        for ancestor in node.ancestors() {
+            let (origin, names) = if let Some(with) = With::cast(ancestor.clone()) {
+                let Some(namespace) = with.namespace() else { continue };
+                // The namespace itself is not affected by its own with
+                if node.ancestors().any(|parent| parent == namespace) {
+                    continue;
+                }
+                let origin = format!("with {} (line {})", namespace.text(), self.line_of(&file, &ancestor));
+                let Some(provided) = self.scope_from_node(&file, namespace.clone()) else {
+                    lines.push(format!("{origin}: not known statically"));
+                    continue;
+                };
+                (origin, provided)
+            } else {
+                let origin = match ParsedType::try_from(ancestor.clone()) {
+                    Ok(ParsedType::LetIn(_) | ParsedType::LegacyLet(_)) => "let",
+                    Ok(ParsedType::AttrSet(_)) => "rec set",
+                    Ok(ParsedType::Lambda(_)) => "function arguments",
+                    _ => continue,
+                };
+                let mut own = HashMap::new();
+                if utils::populate_node(&file, &mut own, &ancestor).is_none() || own.is_empty() {
+                    continue;
+                }
+                (format!("{origin} (line {})", self.line_of(&file, &ancestor)), own)
+            };
+            let mut names: Vec<(String, Var)> = names.into_iter().collect();
+            names.sort_by(|a, b| a.0.cmp(&b.0));
+            let count = names.len();
+            let mut described: Vec<String> = names.iter()
+                .take(MAX_SCOPE_NAMES)
+                .map(|(name, var)| {
+                    let visible = scope.get(name).is_some_and(|found| found.key == var.key);
+                    let shadowed = if visible { "" } else { ", shadowed" };
+                    format!("`{name}` ({}{shadowed})", self.location_of(&file, var))
+                })
+                .collect();
+            if count > MAX_SCOPE_NAMES {
+                described.push(format!("and {} more", count - MAX_SCOPE_NAMES));
+            }
+            lines.push(format!("{origin}: {}", described.join(", ")));
+        }
+
+        let version = self.config.nix_version;
+        let builtins: Vec<&str> = builtins::BUILTINS.iter()
+            .filter(|builtin| builtin.global && builtin.available(version) && !scope.contains_key(builtin.name))
+            .map(|builtin| builtin.name)
+            .collect();
+        let mut named: Vec<String> = builtins.iter().take(MAX_SCOPE_BUILTINS).map(|name| format!("`{name}`")).collect();
+        if builtins.len() > MAX_SCOPE_BUILTINS {
+            named.push(format!("and {} more", builtins.len() - MAX_SCOPE_BUILTINS));
+        }
+        lines.push(format!("builtins: {}", named.join(", ")));
+        Some(lines.join("\n"))
+    }
+    /// The line a node starts on, counting from 1
+    fn line_of(&self, file: &Url, node: &SyntaxNode) -> u64 {
+        self.files.get(file).map_or(0, |(_, code)| utils::offset_to_pos(code, node.text_range().start().to_usize(), self.encoding).line + 1)
+    }
+    /// Where a variable is defined, as `line:column`, after the name of
+    /// its file if it isn't `file`
+    fn location_of(&self, file: &Url, var: &Var) -> String {
+        let Some((_, code)) = self.files.get(&var.file) else { return var.file.to_string() };
+        let pos = utils::offset_to_pos(code, var.key.text_range().start().to_usize(), self.encoding);
+        let place = format!("{}:{}", pos.line + 1, pos.character + 1);
+        if *var.file == *file {
+            return place;
         }
-        Some((Ident::cast(ident.node().clone()).unwrap(), entries))
+        let name = var.file.path_segments().and_then(Iterator::last).unwrap_or_default();
+        format!("{name}:{place}")
+    }
+    /// Statically resolve the attribute set that a node evaluates to,
+    /// and return its entries
+    pub fn scope_from_node(&mut self, file: &Rc<Url>, node: SyntaxNode)
+        -> Option<HashMap<String, Var>>
+    {
+        self.resolve_set(file, node, 0)
     }
-    pub fn scope_from_node(&mut self, file: &mut Rc<Url>, mut node: SyntaxNode)
+    fn resolve_set(&mut self, file: &Rc<Url>, mut node: SyntaxNode, depth: usize)
         -> Option<HashMap<String, Var>>
     {
+        if depth > MAX_RESOLVE_DEPTH {
+            return None;
+        }
+        let mut file = Rc::clone(file);
         let mut scope = HashMap::new();
 
         if let Some(entry) = KeyValue::cast(node.clone()) {
             node = entry.value()?;
         }
+        loop {
+            node = match ParsedType::try_from(node.clone()) {
+                Ok(ParsedType::Paren(paren)) => paren.inner()?,
+                Ok(ParsedType::LetIn(let_in)) => let_in.body()?,
+                _ => break,
+            };
+        }
+
+        if let Some(ident) = Ident::cast(node.clone()) {
+            let scope = self.scope_at_depth(&file, &node, depth + 1)?;
+            let var = scope.get(ident.as_str())?;
+            return self.resolve_var(var, depth + 1);
+        }
+        if let Some(select) = Select::cast(node.clone()) {
+            let index = utils::static_name(&select.index()?)?;
+            let set = self.resolve_set(&file, select.set()?, depth + 1)?;
+            let var = set.get(&index)?;
+            return self.resolve_var(var, depth + 1);
+        }
 
         // Resolve simple imports
         loop {
-            let apply = match Apply::cast(node.clone()) {
-                None => break,
-                Some(apply) => apply,
-            };
-            if Ident::cast(apply.lambda()?).map_or(true, |ident| ident.as_str() != "import") {
+            let Some(apply) = Apply::cast(node.clone()) else { break };
+            if Ident::cast(apply.lambda()?).is_none_or(|ident| ident.as_str() != "import") {
                 break;
             }
-            let (_anchor, path) = match Value::cast(apply.value()?) {
-                None => break,
-                Some(value) => match value.to_value() {
-                    Ok(ParsedValue::Path(anchor, path)) => (anchor, path),
-                    _ => break,
-                }
-            };
+            let Some((imported, root)) = self.import(&file, &apply.value()?) else { break };
+            file = imported;
+            node = root;
+        }
+        if let Some((flake, outputs)) = self.flake_outputs(&file, &node) {
+            return self.resolve_set(&flake, outputs, depth + 1);
+        }
+        if let Some((file, body)) = self.applied(&file, &node) {
+            return self.resolve_set(&file, body, depth + 1);
+        }
 
-            // TODO use anchor
-            *file = Rc::new(file.join(&path).ok()?);
-            let path = utils::uri_path(&file)?;
-            node = match self.files.entry((**file).clone()) {
-                Entry::Occupied(entry) => {
-                    let (ast, _code) = entry.get();
-                    ast.root().inner()?.clone()
-                },
-                Entry::Vacant(placeholder) => {
-                    let content = fs::read_to_string(&path).ok()?;
-                    let ast = rnix::parse(&content);
-                    let node = ast.root().inner()?.clone();
-                    placeholder.insert((ast, content));
-                    node
-                }
+        if let Some(set) = AttrSet::cast(node) {
+            utils::populate(&file, &mut scope, &set, &VarKind::Attr);
+        }
+        Some(scope)
+    }
+    /// What a function call like `f { ... }` or `import ./file.nix { ... }`
+    /// returns, which is the body of the function called. The arguments
+    /// aren't looked at, so this is only right for bodies that don't
+    /// depend on them, like most sets of packages.
+    fn applied(&mut self, file: &Rc<Url>, node: &SyntaxNode) -> Option<(Rc<Url>, SyntaxNode)> {
+        let mut function = Apply::cast(node.clone())?.lambda()?;
+        while let Some(paren) = Paren::cast(function.clone()) {
+            function = paren.inner()?;
+        }
+        let mut file = Rc::clone(file);
+        if let Some(apply) = Apply::cast(function.clone()) {
+            if Ident::cast(apply.lambda()?)?.as_str() != "import" {
+                return None;
+            }
+            let (imported, root) = self.import(&file, &apply.value()?)?;
+            file = imported;
+            function = root;
+        }
+        let (file, lambda) = self.callable(&file, function)?;
+        Some((file, lambda.body()?))
+    }
+    /// Parse the file a path points to, like in `import ./file.nix`, and
+    /// return its root expression. Returns `None` if the node isn't a
+    /// path, or reading the file fails.
+    pub fn import(&mut self, file: &Rc<Url>, path: &SyntaxNode) -> Option<(Rc<Url>, SyntaxNode)> {
+        let Ok(ParsedValue::Path(_anchor, path)) = Value::cast(path.clone())?.to_value() else { return None };
+
+        // TODO use anchor
+        let file = Rc::new(file.join(&path).ok()?);
+        let node = self.load(&file)?;
+        Some((file, node))
+    }
+    /// The root expression of a file, which is read and parsed unless
+    /// it's known already
+    pub fn load(&mut self, file: &Url) -> Option<SyntaxNode> {
+        // Reading and parsing files adds up, so stop if the result
+        // isn't wanted anymore
+        if self.is_cancelled() {
+            return None;
+        }
+
+        if !self.files.contains_key(file) {
+            let content = self.read(file)?;
+            self.files.insert(file.clone(), (Parsed::new(&content), content));
+        }
+        let (ast, _code) = self.files.get(file)?;
+        ast.root().inner()
+    }
+    /// Follow variables and attribute accesses to the expression they
+    /// stand for, like `b` in `let a = { b = 1; }; in a.b` to `1`
+    pub fn resolve_value(&mut self, file: &Rc<Url>, mut node: SyntaxNode) -> Option<(Rc<Url>, SyntaxNode)> {
+        let mut file = Rc::clone(file);
+        for _ in 0..MAX_RESOLVE_DEPTH {
+            let var = if let Some(ident) = Ident::cast(node.clone()) {
+                self.scope_at(&file, &node)?.remove(ident.as_str())?
+            } else if let Some(select) = Select::cast(node.clone()) {
+                let index = utils::static_name(&select.index()?)?;
+                self.scope_from_node(&file, select.set()?)?.remove(&index)?
+            } else if let Some(paren) = Paren::cast(node.clone()) {
+                node = paren.inner()?;
+                continue;
+            } else {
+                return Some((file, node));
             };
+            file = var.file;
+            node = var.value?;
+        }
+        None
+    }
+    /// The function that runs when a value is called: a lambda, or for a
+    /// set with `__functor`, the function that returns when given the set
+    /// itself, like `{ a }: ...` in `{ __functor = self: { a }: ...; }`.
+    /// Returns `None` if that can't be known statically.
+    pub fn callable(&mut self, file: &Rc<Url>, node: SyntaxNode) -> Option<(Rc<Url>, Lambda)> {
+        let (file, value) = self.resolve_value(file, node)?;
+        if let Some(lambda) = Lambda::cast(value.clone()) {
+            return Some((file, lambda));
+        }
+        let (file, functor) = self.functor(&file, &value)?;
+        let (file, functor) = self.resolve_value(&file, functor)?;
+        let body = Lambda::cast(functor)?.body()?;
+        let (file, function) = self.resolve_value(&file, body)?;
+        Some((file, Lambda::cast(function)?))
+    }
+    /// The `__functor` of a set, which makes it callable
+    pub fn functor(&mut self, file: &Rc<Url>, set: &SyntaxNode) -> Option<(Rc<Url>, SyntaxNode)> {
+        AttrSet::cast(set.clone())?;
+        let var = self.scope_from_node(file, set.clone())?.remove("__functor")?;
+        let var = self.definition(var);
+        Some((var.file, var.value?))
+    }
+    /// Follow a variable defined by `inherit` to the binding it's
+    /// inherited from, and so on, until reaching one with a value. If
+    /// the source can't be resolved, the last inherit found is returned.
+    pub fn definition(&mut self, mut var: Var) -> Var {
+        let mut seen = HashSet::new();
+        while var.value.is_none() && seen.insert(var.key.clone()) {
+            let Some(source) = self.inherited(&var) else { break };
+            var = source;
+        }
+        var
+    }
+    /// The variable an `inherit` takes its value from
+    fn inherited(&mut self, var: &Var) -> Option<Var> {
+        let inherit = var.key.parent().and_then(Inherit::cast)?;
+        let name = Ident::cast(var.key.clone())?;
+        let mut source = match inherit.from() {
+            Some(from) => self.scope_from_node(&var.file, from.inner()?)?,
+            None => self.scope_at(&var.file, &var.set.parent()?)?,
+        };
+        source.remove(name.as_str())
+    }
+    /// Resolve the set a variable's value evaluates to. Variables defined
+    /// by `inherit` don't have a value of their own, so it's looked up
+    /// where they're inherited from.
+    fn resolve_var(&mut self, var: &Var, depth: usize) -> Option<HashMap<String, Var>> {
+        if depth > MAX_RESOLVE_DEPTH {
+            return None;
+        }
+        let file = Rc::clone(&var.file);
+        if let Some(scope) = self.resolve_parts(var, depth) {
+            return Some(scope);
+        }
+        if let Some(value) = &var.value {
+            return self.resolve_set(&file, value.clone(), depth);
         }
+        if var.kind == VarKind::Param {
+            let (file, set) = self.overridden_attrs(&file, &var.set)?;
+            return self.resolve_set(&file, set, depth + 1);
+        }
+        let inherit = var.key.parent().and_then(Inherit::cast)?;
+        let name = Ident::cast(var.key.clone())?;
+        let source = match inherit.from() {
+            Some(from) => self.resolve_set(&file, from.inner()?, depth + 1)?,
+            // `inherit a;` takes `a` from outside of the set or let
+            None => self.scope_at_depth(&file, &var.set.parent()?, depth + 1)?,
+        };
+        self.resolve_var(source.get(name.as_str())?, depth + 1)
+    }
+    /// Resolve the set an attribute defined in parts makes up, like `a`
+    /// in `{ a.b = 1; a.c = { d = 2; }; }` to `b` and `c`. Returns `None`
+    /// unless the variable is (part of) a key.
+    fn resolve_parts(&mut self, var: &Var, depth: usize) -> Option<HashMap<String, Var>> {
+        let path: Vec<SyntaxNode> = var.key.parent().and_then(Key::cast)?.path().collect();
+        let index = path.iter().position(|part| *part == var.key)?;
+        let prefix: Vec<String> = path[..=index].iter().map(utils::static_name).collect::<Option<_>>()?;
 
-        if let Some(set) = AttrSet::cast(node) {
-            utils::populate(&file, &mut scope, &set);
+        let mut scope = HashMap::new();
+        for entry in entries(&var.set) {
+            let Some(key) = entry.key() else { continue };
+            let path: Vec<SyntaxNode> = key.path().collect();
+            let names = path.iter().take(prefix.len()).map(utils::static_name);
+            if path.len() < prefix.len() || !names.zip(&prefix).all(|(name, part)| name.as_ref() == Some(part)) {
+                continue;
+            }
+            if let Some(next) = path.get(prefix.len()) {
+                let Some(name) = utils::static_name(next) else { continue };
+                scope.entry(name).or_insert_with(|| Var {
+                    file: Rc::clone(&var.file),
+                    set: var.set.clone(),
+                    key: next.clone(),
+                    value: entry.value(),
+                    kind: VarKind::Attr,
+                });
+            } else if let Some(value) = entry.value() {
+                let set = self.resolve_set(&var.file, value, depth + 1).unwrap_or_default();
+                for (name, var) in set {
+                    scope.entry(name).or_insert(var);
+                }
+            }
         }
         Some(scope)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{testing, utils};
+    use lsp_types::request::*;
+    use rnix::types::TokenWrapper;
+    use serde_json::{json, Value};
+
+    /// Where goto definition at the cursor leads, as offsets in the code
+    fn definition(code: &str) -> Value {
+        let (mut app, client) = testing::app(&json!({}));
+        let position = app.open_at("default.nix", code);
+        testing::request::<GotoDefinition>(&mut app, &client, position)
+    }
+    fn hover(code: &str) -> Option<String> {
+        let (mut app, client) = testing::app(&json!({}));
+        let position = app.open_at("default.nix", code);
+        let hover = testing::request::<HoverRequest>(&mut app, &client, position);
+        hover["contents"]["value"].as_str().map(String::from)
+    }
+    fn range(line: u64, start: u64, end: u64) -> Value {
+        json!({ "start": { "line": line, "character": start }, "end": { "line": line, "character": end } })
+    }
+
+    #[test]
+    fn rec_siblings_from_values() {
+        let code = "rec {\n  a = 1;\n  b = { c = [ (x: { d = a$0; }) ]; };\n}";
+        assert_eq!(definition(code)["range"], range(1, 2, 3));
+        assert_eq!(hover(code).as_deref(), Some("Defined in a `rec` set, line 2"));
+    }
+    #[test]
+    fn no_siblings_in_non_rec_sets() {
+        assert_eq!(definition("{\n  a = 1;\n  b = { c = a$0; };\n}"), Value::Null);
+        let (mut app, _client) = testing::app(&json!({}));
+        let position = app.open_at("default.nix", "{\n  sibling = 1;\n  b = { c = sib$0; };\n}");
+        assert!(app.completions(&position).unwrap().is_empty());
+        let position = app.open_at("rec.nix", "rec {\n  sibling = 1;\n  b = { c = sib$0; };\n}");
+        let completions = app.completions(&position).unwrap();
+        assert_eq!(completions.iter().map(|item| item.label.as_str()).collect::<Vec<_>>(), ["sibling"]);
+    }
+    #[test]
+    fn rec_self_reference() {
+        let code = "rec {\n  a = { b = a$0.c; c = 1; };\n}";
+        assert_eq!(definition(code)["range"], range(1, 2, 3));
+        assert_eq!(hover(code).as_deref(), Some("Defined in a `rec` set, line 2"));
+    }
+    #[test]
+    fn callee_definition() {
+        assert_eq!(definition("let\n  f = x: x + 1;\nin f$0 3")["range"], range(1, 2, 3));
+        assert_eq!(definition("let\n  f = a: b: a + b;\nin f$0 1 2")["range"], range(1, 2, 3));
+        assert_eq!(definition("let\n  f = a: b: a + b;\n  a = 1;\nin f 1 a$0")["range"], range(2, 2, 3));
+        assert_eq!(definition("let\n  f = a: b: a + b;\nin (f$0 1) 2")["range"], range(1, 2, 3));
+    }
+    #[test]
+    fn callee_cursor_info() {
+        let (code, position) = testing::cursor("let f = a: b: a; in f$0 1 2");
+        let root = rnix::parse(&code).node();
+        let offset = utils::lookup_pos(&code, position, utils::Encoding::Utf16).unwrap();
+        let info = utils::ident_at(&root, offset).unwrap();
+        assert_eq!(info.ident.as_str(), "f");
+        assert!(info.path.is_empty());
+        assert!(utils::is_reference(&info.ident));
+    }
+
+    #[test]
+    fn builtin_docs_only_for_references() {
+        assert!(hover("map$0").is_some_and(|docs| docs.contains("map f list")));
+        assert!(hover("{ inherit map$0; }").is_some());
+        assert_eq!(hover("{ map$0 = 1; }"), None);
+        assert_eq!(hover("{ a.map$0 = 1; }"), None);
+        assert_eq!(hover("{ map$0 }: 1"), None);
+        assert_eq!(hover("x: x.map$0"), None);
+        assert!(hover("builtins.map$0").is_some());
+    }
+}
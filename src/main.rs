@@ -10,54 +10,145 @@
     clippy::decimal_literal_representation,
     clippy::float_cmp_const,
     clippy::get_unwrap,
-    clippy::integer_arithmetic,
+    clippy::arithmetic_side_effects,
     clippy::integer_division,
     clippy::pedantic,
 )]
 #![allow(
     // filter().map() can sometimes be more readable
-    clippy::filter_map,
+    clippy::manual_filter_map,
     // Most integer arithmetics are within an allocated region, so we know it's safe
-    clippy::integer_arithmetic,
+    clippy::arithmetic_side_effects,
+    // Glob imports of lsp_types and rnix::types are the norm here
+    clippy::wildcard_imports,
+    // Handlers return a Result even when they can't fail (yet)
+    clippy::unnecessary_wraps,
 )]
 
+mod actions;
+mod asserts;
+mod builtins;
+mod colors;
+mod completion;
+mod config;
+mod eval;
+mod flakes;
+mod folding;
+mod format;
+mod hints;
+mod imports;
+mod lint;
 mod lookup;
+mod meta;
+mod nixlib;
+mod options;
+mod packages;
+mod parse;
+mod rename;
+mod resolver;
+mod semantic;
+mod symbols;
+#[cfg(test)]
+mod testing;
+mod transport;
 mod utils;
+mod workspace;
 
-use log::{error, trace, warn};
+use config::Config;
+use format::Formatter;
+use log::{error, info, trace, warn};
 use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
 use lsp_types::{
     *,
     notification::{*, Notification as _},
     request::{*, Request as RequestTrait},
 };
-use rnix::{parser::*, types::*, SyntaxNode};
+use rnix::{
+    parser::*,
+    types::*,
+    value::{Anchor, Value as ParsedValue},
+    SyntaxKind::{TOKEN_FLOAT, TOKEN_INTEGER, TOKEN_PATH, TOKEN_SQUARE_B_CLOSE, TOKEN_SQUARE_B_OPEN, TOKEN_WITH},
+    SyntaxNode,
+    TextRange,
+    TextUnit,
+};
+use serde_json::json;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    env,
     panic,
     process,
     rc::Rc,
+    sync::Arc,
 };
 
+/// How many names the hover of a `with` lists
+const MAX_WITH_NAMES: usize = 30;
+
 type Error = Box<dyn std::error::Error>;
 
 fn main() {
-    if let Err(err) = real_main() {
-        error!("Error: {} ({:?})", err, err);
-        error!("A fatal error has occured and rnix-lsp will shut down.");
-        drop(err);
-        process::exit(libc::EXIT_FAILURE);
+    match real_main() {
+        Ok(status) => process::exit(status),
+        Err(err) => {
+            error!("Error: {} ({:?})", err, err);
+            error!("A fatal error has occured and rnix-lsp will shut down.");
+            drop(err);
+            process::exit(libc::EXIT_FAILURE);
+        },
     }
 }
-fn real_main() -> Result<(), Error> {
+/// Run the server, returning the status to exit with
+fn real_main() -> Result<i32, Error> {
     env_logger::init();
     panic::set_hook(Box::new(move |panic| {
         error!("----- Panic -----");
         error!("{}", panic);
     }));
 
-    let (connection, io_threads) = Connection::stdio();
-    let capabilities = serde_json::to_value(&ServerCapabilities {
+    let transport = transport::Transport::from_args(env::args().skip(1))?;
+    let (connection, io_threads) = transport.connect()?;
+    let mut encoding = utils::Encoding::Utf16;
+    let mut config = Config::default();
+    let params = initialize(&connection, |params| {
+        config = Config::from_options(params.get("initializationOptions"));
+        let mut capabilities = capabilities(&config);
+        // Not supported by lsp-types yet
+        let offered = params["capabilities"]["general"]["positionEncodings"].as_array();
+        let preferred = offered.into_iter().flatten()
+            .find_map(|name| utils::Encoding::from_name(name.as_str()?));
+        if let Some(preferred) = preferred {
+            encoding = preferred;
+            capabilities["positionEncoding"] = json!(encoding.name());
+        }
+        if params["capabilities"]["workspace"]["workspaceFolders"].as_bool() == Some(true) {
+            capabilities["workspace"] = json!({
+                "workspaceFolders": { "supported": true, "changeNotifications": true },
+            });
+        }
+        capabilities
+    })?;
+    let params: InitializeParams = serde_json::from_value(params)?;
+    let roots = workspace::roots(&params);
+
+    let status = App::new(connection, config, params.capabilities, encoding, roots).main();
+
+    // Messages still being written, like the last diagnostics, are sent
+    // before exiting
+    io_threads.join()?;
+
+    Ok(status)
+}
+
+/// What the server can do, leaving out what's turned off in the config so
+/// clients don't ask for it
+fn capabilities(config: &Config) -> serde_json::Value {
+    let formatting = config.formatter != Formatter::None;
+    let mut commands = vec![packages::RELOAD_COMMAND.into(), lookup::SCOPE_COMMAND.into()];
+    if formatting {
+        commands.push(workspace::FORMAT_COMMAND.into());
+    }
+    let mut capabilities = serde_json::to_value(&ServerCapabilities {
         text_document_sync: Some(TextDocumentSyncCapability::Options(
             TextDocumentSyncOptions {
                 open_close: Some(true),
@@ -66,33 +157,161 @@ fn real_main() -> Result<(), Error> {
             }
         )),
         completion_provider: Some(CompletionOptions {
+            resolve_provider: Some(true),
+            trigger_characters: Some(completion::TRIGGER_CHARACTERS.iter().map(|&c| c.into()).collect()),
             ..CompletionOptions::default()
         }),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        color_provider: config.document_colors.then_some(ColorProviderCapability::Simple(true)),
         definition_provider: Some(true),
-        document_formatting_provider: Some(true),
+        document_formatting_provider: formatting.then_some(true),
+        document_highlight_provider: Some(true),
+        document_symbol_provider: Some(true),
+        execute_command_provider: Some(ExecuteCommandOptions {
+            commands,
+            ..ExecuteCommandOptions::default()
+        }),
+        hover_provider: Some(true),
+        references_provider: Some(true),
         rename_provider: Some(RenameProviderCapability::Simple(true)),
         selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+        folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+        signature_help_provider: Some(SignatureHelpOptions {
+            trigger_characters: Some(vec![" ".into()]),
+            retrigger_characters: None,
+            work_done_progress_options: WorkDoneProgressOptions::default(),
+        }),
         ..ServerCapabilities::default()
     }).unwrap();
+    // Not supported by lsp-types yet
+    capabilities["semanticTokensProvider"] = json!({
+        "legend": {
+            "tokenTypes": semantic::TOKEN_TYPES,
+            "tokenModifiers": semantic::TOKEN_MODIFIERS,
+        },
+        "full": { "delta": true },
+        "range": true,
+    });
+    capabilities["linkedEditingRangeProvider"] = json!(true);
+    if config.inherit_hints {
+        capabilities["inlayHintProvider"] = json!(true);
+    }
+    if config.options_json.is_some() {
+        capabilities["typeHierarchyProvider"] = json!(true);
+    }
+    capabilities
 
-    connection.initialize(capabilities)?;
-
-    App {
-        files: HashMap::new(),
-        conn: connection,
-    }.main();
+}
 
-    io_threads.join()?;
+/// Like `Connection::initialize`, but the capabilities can depend on the
+/// initialize request, which is needed to negotiate the position encoding
+fn initialize<F>(connection: &Connection, capabilities: F) -> Result<serde_json::Value, Error>
+    where F: FnOnce(&serde_json::Value) -> serde_json::Value
+{
+    let (id, params) = match connection.receiver.recv() {
+        Ok(Message::Request(req)) if req.method == Initialize::METHOD => (req.id, req.params),
+        msg => return Err(format!("expected initialize request, got {msg:?}").into()),
+    };
+    let capabilities = capabilities(&params);
+    connection.sender.send(Response::new_ok(id, json!({ "capabilities": capabilities })).into()).unwrap();
+    match connection.receiver.recv() {
+        Ok(Message::Notification(notification)) if notification.method == Initialized::METHOD => (),
+        msg => return Err(format!("expected initialized notification, got {msg:?}").into()),
+    }
+    Ok(params)
+}
 
-    Ok(())
+/// Whether a message changes a file, after which what's computed for the
+/// old version of it is stale
+fn changes(msg: &Message, uri: &Url) -> bool {
+    match msg {
+        Message::Notification(notification) if notification.method == DidChangeTextDocument::METHOD => {
+            serde_json::from_value::<Url>(notification.params["textDocument"]["uri"].clone()).is_ok_and(|changed| changed == *uri)
+        },
+        _ => false,
+    }
+}
+fn cancelled(id: RequestId) -> Response {
+    Response::new_err(id, ErrorCode::RequestCanceled as i32, "request was cancelled".into())
 }
 
 struct App {
-    files: HashMap<Url, (AST, String)>,
+    files: HashMap<Url, (parse::Parsed, String)>,
     conn: Connection,
+    config: Config,
+    client: ClientCapabilities,
+    /// Messages received while checking for cancellation, which still
+    /// need to be handled
+    pending: VecDeque<Message>,
+    /// The request that is currently being handled
+    current: Option<RequestId>,
+    /// Whether the client cancelled the current request
+    cancelled: bool,
+    /// The file diagnostics are being computed for, which are given up
+    /// like a cancelled request once it changes again
+    linting: Option<Url>,
+    evaluator: Option<eval::Evaluator>,
+    /// The last semantic tokens sent for each file, with their result
+    /// id, for computing deltas
+    semantic_tokens: HashMap<Url, (String, Vec<u32>)>,
+    next_result_id: u64,
+    /// For requests sent to the client
+    next_request_id: u64,
+    packages: Option<packages::Packages>,
+    options: Option<options::OptionTree>,
+    /// Reads the files that aren't open
+    resolver: Arc<dyn resolver::FileResolver>,
+    /// How positions count characters
+    encoding: utils::Encoding,
+    /// The workspace folders open in the editor
+    roots: Vec<Url>,
+    /// The files open in the editor with their version, as opposed to
+    /// ones read from disk
+    open: HashMap<Url, i64>,
+    /// The last completions of a variable, reused while its name is typed
+    completion_cache: Option<completion::CompletionCache>,
+    /// Whether the client asked the server to shut down, after which
+    /// requests are refused until `exit`
+    shut_down: bool,
+}
+// The cached scopes hold nodes of the files, which rowan can't free once
+// its thread-local storage is gone at the end of the thread
+impl Drop for App {
+    fn drop(&mut self) {
+        for uri in self.files.keys() {
+            utils::forget_scopes(uri);
+        }
+    }
 }
 impl App {
-    fn reply(&mut self, response: Response) {
+    fn new(conn: Connection, config: Config, client: ClientCapabilities, encoding: utils::Encoding, roots: Vec<Url>) -> Self {
+        Self {
+            files: HashMap::new(),
+            conn,
+            config,
+            client,
+            pending: VecDeque::new(),
+            current: None,
+            cancelled: false,
+            linting: None,
+            evaluator: None,
+            semantic_tokens: HashMap::new(),
+            next_result_id: 0,
+            next_request_id: 0,
+            packages: None,
+            options: None,
+            resolver: Arc::new(resolver::Disk),
+            encoding,
+            roots,
+            open: HashMap::new(),
+            completion_cache: None,
+            shut_down: false,
+        }
+    }
+    fn reply(&mut self, mut response: Response) {
+        if self.cancelled && self.current.as_ref() == Some(&response.id) {
+            response = cancelled(response.id);
+        }
         trace!("Sending response: {:#?}", response);
         self.conn.sender.send(Message::Response(response)).unwrap();
     }
@@ -100,40 +319,115 @@ impl App {
         trace!("Sending notification: {:#?}", notification);
         self.conn.sender.send(Message::Notification(notification)).unwrap();
     }
+    fn log(&mut self, typ: MessageType, message: String) {
+        info!("{}", message);
+        self.notify(Notification::new(
+            LogMessage::METHOD.into(),
+            LogMessageParams { typ, message },
+        ));
+    }
     fn err<E>(&mut self, id: RequestId, err: E)
         where E: std::fmt::Display
     {
         warn!("{}", err);
         self.reply(Response::new_err(id, ErrorCode::UnknownErrorCode as i32, err.to_string()));
     }
-    fn main(&mut self) {
-        while let Ok(msg) = self.conn.receiver.recv() {
+    /// Handle messages until `exit`, or until the client goes away.
+    /// Returns the status to exit with, which is only a success if the
+    /// client asked to shut down first.
+    fn main(&mut self) -> i32 {
+        self.load_packages();
+        self.load_options();
+        for root in self.roots.clone() {
+            self.index(&root);
+        }
+        loop {
+            let msg = match self.pending.pop_front() {
+                Some(msg) => msg,
+                None => match self.conn.receiver.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+            };
             trace!("Message: {:#?}", msg);
             match msg {
+                Message::Request(req) if self.shut_down => {
+                    self.reply(Response::new_err(req.id, ErrorCode::InvalidRequest as i32, "the server is shutting down".into()));
+                },
+                Message::Request(req) if req.method == Shutdown::METHOD => self.shutdown(req.id),
+                Message::Notification(notification) if notification.method == Exit::METHOD => break,
+                Message::Notification(_) | Message::Response(_) if self.shut_down => (),
                 Message::Request(req) => {
                     let id = req.id.clone();
-                    match self.conn.handle_shutdown(&req) {
-                        Ok(true) => break,
-                        Ok(false) => if let Err(err) = self.handle_request(req) {
-                            self.err(id, err);
-                        },
-                        Err(err) => {
-                            // This only fails if a shutdown was
-                            // requested in the first place, so it
-                            // should definitely break out of the
-                            // loop.
-                            self.err(id, err);
-                            break;
-                        },
+                    self.current = Some(id.clone());
+                    self.cancelled = false;
+                    if self.is_cancelled() {
+                        self.reply(cancelled(id));
+                    } else {
+                        // A bug in one handler shouldn't take the
+                        // whole server down with it
+                        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| self.handle_request(req)));
+                        match result {
+                            Ok(Ok(())) => (),
+                            Ok(Err(err)) => self.err(id, err),
+                            Err(_) => self.err(id, "request handler panicked"),
+                        }
                     }
+                    self.current = None;
+                    self.cancelled = false;
                 },
                 Message::Notification(notification) => {
-                    let _ = self.handle_notification(notification);
+                    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| self.handle_notification(notification)));
                 },
                 Message::Response(_) => (),
             }
         }
+        if self.shut_down { libc::EXIT_SUCCESS } else { libc::EXIT_FAILURE }
+    }
+    /// Reply to a shutdown request, after stopping what could still be
+    /// running in the background. Messages might already have been queued
+    /// while checking for cancellation, so this can't use
+    /// `Connection::handle_shutdown`, and `exit` is waited for by `main`.
+    fn shutdown(&mut self, id: RequestId) {
+        // Kills `nix repl`
+        self.evaluator = None;
+        self.shut_down = true;
+        self.reply(Response::new_ok(id, ()));
+    }
+    /// Returns true if the client has cancelled the request that is being
+    /// handled, or changed the file being linted. Expensive handlers should
+    /// check this regularly and stop early, their response is then
+    /// replaced with a cancellation error.
+    fn is_cancelled(&mut self) -> bool {
+        while let Ok(msg) = self.conn.receiver.try_recv() {
+            match msg {
+                Message::Notification(notification) if notification.method == Cancel::METHOD => {
+                    if let Ok(id) = serde_json::from_value::<RequestId>(notification.params["id"].clone()) {
+                        self.cancel(&id);
+                    }
+                },
+                msg => self.pending.push_back(msg),
+            }
+        }
+        if let Some(uri) = &self.linting {
+            self.cancelled |= self.pending.iter().any(|msg| changes(msg, uri));
+        }
+        self.cancelled
     }
+    fn cancel(&mut self, id: &RequestId) {
+        if self.current.as_ref() == Some(id) {
+            self.cancelled = true;
+            return;
+        }
+        // Requests that haven't been started yet are answered right away
+        let queued = self.pending.len();
+        self.pending.retain(|msg| !matches!(msg, Message::Request(req) if req.id == *id));
+        if self.pending.len() < queued {
+            self.reply(cancelled(id.clone()));
+        }
+    }
+    // One branch per request, so this grows with every feature
+    #[allow(clippy::too_many_lines)]
     fn handle_request(&mut self, req: Request) -> Result<(), Error> {
         fn cast<Kind>(req: &mut Option<Request>) -> Option<(RequestId, Kind::Params)>
         where
@@ -148,6 +442,16 @@ impl App {
                 },
             }
         }
+        /// For requests lsp-types doesn't know about yet
+        fn cast_raw(req: &mut Option<Request>, method: &str) -> Option<(RequestId, serde_json::Value)> {
+            match req.take().unwrap().extract::<serde_json::Value>(method) {
+                Ok(value) => Some(value),
+                Err(owned) => {
+                    *req = Some(owned);
+                    None
+                },
+            }
+        }
         let mut req = Some(req);
         if let Some((id, params)) = cast::<GotoDefinition>(&mut req) {
             if let Some(pos) = self.lookup_definition(params) {
@@ -156,120 +460,542 @@ impl App {
                 self.reply(Response::new_ok(id, ()));
             }
         } else if let Some((id, params)) = cast::<Completion>(&mut req) {
-            let completions = self.completions(&params.text_document_position).unwrap_or_default();
-            self.reply(Response::new_ok(id, completions));
+            let trigger = params.context.as_ref()
+                .filter(|context| context.trigger_kind == CompletionTriggerKind::TriggerCharacter)
+                .and_then(|context| context.trigger_character.as_deref());
+            let completions = match trigger {
+                Some(trigger) => self.triggered_completions(&params.text_document_position, trigger),
+                None => self.completions(&params.text_document_position),
+            };
+            let mut items = completions.unwrap_or_default();
+            let is_incomplete = completion::truncate(&mut items, self.config.max_completion_items);
+            self.reply(Response::new_ok(id, CompletionList { is_incomplete, items }));
+        } else if let Some((id, item)) = cast::<ResolveCompletionItem>(&mut req) {
+            let item = self.resolve_completion(item);
+            self.reply(Response::new_ok(id, item));
+        } else if let Some((id, params)) = cast::<HoverRequest>(&mut req) {
+            let mut hover = self.throw_hover(&params)
+                .or_else(|| self.declaration_hover(&params))
+                .or_else(|| self.with_hover(&params))
+                .or_else(|| self.hover(&params))
+                .or_else(|| self.package_hover(&params))
+                .or_else(|| self.lib_hover(&params))
+                .or_else(|| self.functor_hover(&params))
+                .or_else(|| self.alias_hover(&params))
+                .or_else(|| self.assert_hover(&params))
+                .or_else(|| self.number_hover(&params))
+                .or_else(|| self.path_hover(&params))
+                .or_else(|| self.list_hover(&params));
+            if hover.is_none() && self.config.eval_hover {
+                hover = self.eval_hover(&params);
+            }
+            if let Some(provenance) = self.provenance(&params) {
+                hover = self.with_provenance(&params, hover, provenance);
+            }
+            if let Some(hover) = hover {
+                self.reply(Response::new_ok(id, hover));
+            } else {
+                self.reply(Response::new_ok(id, ()));
+            }
+        } else if let Some((id, params)) = cast::<SignatureHelpRequest>(&mut req) {
+            let help = self.lib_signature_help(&params);
+            self.reply(Response::new_ok(id, help));
         } else if let Some((id, params)) = cast::<Rename>(&mut req) {
-            let changes = self.rename(params);
-            self.reply(Response::new_ok(id, WorkspaceEdit {
-                changes,
-                ..WorkspaceEdit::default()
-            }));
+            let edit = self.rename(params)?.map_or_else(WorkspaceEdit::default, |changes| self.workspace_edit(changes));
+            self.reply(Response::new_ok(id, edit));
+        } else if let Some((id, params)) = cast::<DocumentHighlightRequest>(&mut req) {
+            let highlights = self.document_highlight(&params);
+            self.reply(Response::new_ok(id, highlights));
+        } else if let Some((id, params)) = cast::<References>(&mut req) {
+            let locations = self.references(&params);
+            self.reply(Response::new_ok(id, locations));
         } else if let Some((id, params)) = cast::<Formatting>(&mut req) {
-            let changes = if let Some((ast, code)) = self.files.get(&params.text_document.uri) {
-                let fmt = nixpkgs_fmt::reformat_node(&ast.node());
-                fmt.text_diff().iter()
-                    .filter(|range| !range.delete.is_empty() || !range.insert.is_empty())
-                    .map(|edit| TextEdit {
-                        range: utils::range(&code, edit.delete),
-                        new_text: edit.insert.to_string()
-                    })
-                    .collect()
+            match self.format(&params) {
+                Ok(changes) => self.reply(Response::new_ok(id, changes)),
+                Err(err) => self.err(id, err),
+            }
+        } else if let Some((id, params)) = cast::<DocumentSymbolRequest>(&mut req) {
+            let uri = &params.text_document.uri;
+            let symbols = self.files.get(uri)
+                .filter(|(_, code)| !self.is_large(code))
+                .map(|(ast, code)| symbols::document_symbols(uri, &ast.node(), code, self.encoding))
+                .unwrap_or_default();
+            let hierarchical = self.client.text_document.as_ref()
+                .and_then(|document| document.document_symbol.as_ref())
+                .and_then(|symbol| symbol.hierarchical_document_symbol_support)
+                .unwrap_or(false);
+            let symbols = if hierarchical {
+                DocumentSymbolResponse::Nested(symbols)
             } else {
-                Vec::new()
+                DocumentSymbolResponse::Flat(symbols::flatten(uri, symbols))
             };
-            self.reply(Response::new_ok(id, changes));
+            self.reply(Response::new_ok(id, symbols));
+        } else if let Some((id, params)) = cast_raw(&mut req, "textDocument/linkedEditingRange") {
+            let params: TextDocumentPositionParams = serde_json::from_value(params)?;
+            match self.linked_editing(&params) {
+                Some(ranges) => self.reply(Response::new_ok(id, json!({ "ranges": ranges, "wordPattern": rename::IDENT_PATTERN }))),
+                None => self.reply(Response::new_ok(id, ())),
+            }
+        } else if let Some((id, params)) = cast_raw(&mut req, "textDocument/semanticTokens/full") {
+            let document: TextDocumentIdentifier = serde_json::from_value(params["textDocument"].clone())?;
+            let tokens = self.semantic_tokens_full(document.uri);
+            self.reply(Response::new_ok(id, tokens));
+        } else if let Some((id, params)) = cast_raw(&mut req, "textDocument/semanticTokens/full/delta") {
+            let document: TextDocumentIdentifier = serde_json::from_value(params["textDocument"].clone())?;
+            let previous = params["previousResultId"].as_str().unwrap_or_default();
+            let tokens = self.semantic_tokens_delta(document.uri, previous);
+            self.reply(Response::new_ok(id, tokens));
+        } else if let Some((id, params)) = cast_raw(&mut req, "textDocument/semanticTokens/range") {
+            let document: TextDocumentIdentifier = serde_json::from_value(params["textDocument"].clone())?;
+            let range: Range = serde_json::from_value(params["range"].clone())?;
+            let encoding = self.encoding;
+            let data = self.files.get(&document.uri)
+                .filter(|(_, code)| !self.is_large(code))
+                .and_then(|(ast, code)| {
+                    let span = semantic::span(code, range, encoding)?;
+                    Some(semantic::tokens(&Rc::new(document.uri.clone()), &ast.node(), code, encoding, Some(span)))
+                })
+                .unwrap_or_default();
+            self.reply(Response::new_ok(id, json!({ "data": data })));
+        } else if let Some((id, params)) = cast_raw(&mut req, "textDocument/inlayHint") {
+            let document: TextDocumentIdentifier = serde_json::from_value(params["textDocument"].clone())?;
+            let range: Range = serde_json::from_value(params["range"].clone())?;
+            let encoding = self.encoding;
+            let hints = self.files.get(&document.uri)
+                .filter(|_| self.config.inherit_hints)
+                .and_then(|(ast, code)| Some(hints::inherit_hints(&ast.node(), code, encoding, semantic::span(code, range, encoding)?)))
+                .unwrap_or_default();
+            self.reply(Response::new_ok(id, hints));
+        } else if let Some((id, params)) = cast_raw(&mut req, "textDocument/prepareTypeHierarchy") {
+            let params: TextDocumentPositionParams = serde_json::from_value(params)?;
+            let items = self.prepare_type_hierarchy(&params);
+            self.reply(Response::new_ok(id, items));
+        } else if let Some((id, params)) = cast_raw(&mut req, "typeHierarchy/supertypes") {
+            let items = self.type_hierarchy(&params["item"], true);
+            self.reply(Response::new_ok(id, items));
+        } else if let Some((id, params)) = cast_raw(&mut req, "typeHierarchy/subtypes") {
+            let items = self.type_hierarchy(&params["item"], false);
+            self.reply(Response::new_ok(id, items));
+        } else if let Some((id, params)) = cast::<CodeActionRequest>(&mut req) {
+            let actions = self.code_actions(&params);
+            self.reply(Response::new_ok(id, actions));
+        } else if let Some((id, params)) = cast::<ExecuteCommand>(&mut req) {
+            if params.command == packages::RELOAD_COMMAND {
+                self.load_packages();
+                self.reply(Response::new_ok(id, ()));
+            } else if params.command == lookup::SCOPE_COMMAND {
+                let position = params.arguments.first().cloned().map(serde_json::from_value::<TextDocumentPositionParams>).transpose()?;
+                let Some(message) = position.and_then(|position| self.describe_scope(&position)) else {
+                    self.err(id, format!("{} takes a document and a position in it", lookup::SCOPE_COMMAND));
+                    return Ok(());
+                };
+                self.notify(Notification::new(ShowMessage::METHOD.into(), ShowMessageParams { typ: MessageType::Info, message: message.clone() }));
+                self.reply(Response::new_ok(id, message));
+            } else if params.command == workspace::FORMAT_COMMAND && self.config.formatter != Formatter::None {
+                self.format_workspace(&params);
+                self.reply(Response::new_ok(id, ()));
+            } else {
+                self.err(id, format!("unknown command {}", params.command));
+            }
         } else if let Some((id, params)) = cast::<SelectionRangeRequest>(&mut req) {
             let mut selections = Vec::new();
             if let Some((ast, code)) = self.files.get(&params.text_document.uri) {
                 for pos in params.positions {
-                    selections.push(utils::selection_ranges(&ast.node(), code, pos));
+                    selections.push(utils::selection_ranges(&ast.node(), code, pos, self.encoding));
                 }
             }
             self.reply(Response::new_ok(id, selections));
+        } else if let Some((id, params)) = cast::<DocumentColor>(&mut req) {
+            let colors = match self.files.get(&params.text_document.uri) {
+                Some((ast, code)) if self.config.document_colors && !self.is_large(code) => colors::document_colors(&ast.node(), code, self.encoding),
+                _ => Vec::new(),
+            };
+            self.reply(Response::new_ok(id, colors));
+        } else if let Some((id, params)) = cast::<ColorPresentationRequest>(&mut req) {
+            let current = self.files.get(&params.text_document.uri).and_then(|(_, code)| {
+                let start = utils::lookup_pos(code, params.range.start, self.encoding)?;
+                let end = utils::lookup_pos(code, params.range.end, self.encoding)?;
+                code.get(start..end)
+            });
+            let presentations = colors::presentations(&params.color, params.range, current.unwrap_or_default());
+            self.reply(Response::new_ok(id, presentations));
+        } else if let Some((id, params)) = cast::<FoldingRangeRequest>(&mut req) {
+            let folds = match self.files.get(&params.text_document.uri) {
+                Some((ast, code)) => folding::folding_ranges(&ast.node(), code, self.encoding),
+                None => Vec::new(),
+            };
+            self.reply(Response::new_ok(id, folds));
         }
         Ok(())
     }
     fn handle_notification(&mut self, req: Notification) -> Result<(), Error> {
         match &*req.method {
+            Cancel::METHOD => {
+                let id = serde_json::from_value::<RequestId>(req.params["id"].clone())?;
+                // The request might still be waiting in the channel
+                self.is_cancelled();
+                self.cancel(&id);
+            },
             DidOpenTextDocument::METHOD => {
                 let params: DidOpenTextDocumentParams = serde_json::from_value(req.params)?;
-                let text = params.text_document.text;
-                let parsed = rnix::parse(&text);
-                self.send_diagnostics(params.text_document.uri.clone(), &text, &parsed)?;
-                self.files.insert(params.text_document.uri, (parsed, text));
+                self.open.insert(params.text_document.uri.clone(), params.text_document.version);
+                self.update_file(params.text_document.uri, params.text_document.text)?;
             },
             DidChangeTextDocument::METHOD => {
                 let params: DidChangeTextDocumentParams = serde_json::from_value(req.params)?;
+                if let (Some(version), Some(open)) = (params.text_document.version, self.open.get_mut(&params.text_document.uri)) {
+                    *open = version;
+                }
                 if let Some(change) = params.content_changes.into_iter().last() {
-                    let parsed = rnix::parse(&change.text);
-                    self.send_diagnostics(params.text_document.uri.clone(), &change.text, &parsed)?;
-                    self.files.insert(params.text_document.uri, (parsed, change.text));
+                    self.update_file(params.text_document.uri, change.text)?;
                 }
             },
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = serde_json::from_value(req.params)?;
+                self.open.remove(&params.text_document.uri);
+            },
+            DidChangeWorkspaceFolders::METHOD => {
+                let params: DidChangeWorkspaceFoldersParams = serde_json::from_value(req.params)?;
+                self.change_folders(params);
+            },
             _ => (),
         }
         Ok(())
     }
-    fn lookup_definition(&mut self, params: TextDocumentPositionParams) -> Option<Location> {
+    fn update_file(&mut self, uri: Url, text: String) -> Result<(), Error> {
+        let parsed = match self.files.get(&uri) {
+            Some((old, code)) => old.reparse(code, &text),
+            None => parse::Parsed::new(&text),
+        };
+        if self.is_large(&text) {
+            let was_large = self.files.get(&uri).is_some_and(|(_, old)| self.is_large(old));
+            if !was_large {
+                self.log(MessageType::Info, format!(
+                    "{} is larger than {} bytes, skipping diagnostics, semantic tokens, document symbols, colors and refactorings",
+                    uri, self.config.max_file_size
+                ));
+                self.publish_diagnostics(uri.clone(), Vec::new());
+            }
+        } else {
+            self.send_diagnostics(uri.clone(), &text, &parsed)?;
+        }
+        utils::forget_scopes(&uri);
+        if self.completion_cache.as_ref().is_some_and(|cache| !cache.is_for(&uri)) {
+            self.completion_cache = None;
+        }
+        self.files.insert(uri, (parsed, text));
+        Ok(())
+    }
+    /// Returns true if expensive analysis should be skipped for this code
+    fn is_large(&self, code: &str) -> bool {
+        code.len() > self.config.max_file_size
+    }
+    fn lookup_definition(&mut self, params: TextDocumentPositionParams) -> Option<GotoDefinitionResponse> {
         let (current_ast, current_content) = self.files.get(&params.text_document.uri)?;
-        let offset = utils::lookup_pos(current_content, params.position)?;
+        let offset = utils::lookup_pos(current_content, params.position, self.encoding)?;
         let node = current_ast.node();
-        let (name, scope) = self.scope_for_ident(params.text_document.uri, &node, offset)?;
-
-        let var = scope.get(name.as_str())?;
+        let var = if let Some((name, mut scope)) = self.scope_for_ident(params.text_document.uri.clone(), &node, offset) {
+            scope.remove(name.as_str())?
+        } else if let Some(location) = self.flake_location(&params.text_document.uri, &node, offset) {
+            return Some(GotoDefinitionResponse::Scalar(location));
+        } else {
+            // A quoted access, like `set."a b"`
+            let name = utils::quoted_name_at(&node, offset)?;
+            let select = name.parent().and_then(Select::cast)?;
+            let file = Rc::new(params.text_document.uri);
+            self.scope_from_node(&file, select.set()?)?.remove(&utils::static_name(&name)?)?
+        };
+        let var = self.definition(var);
         let (_definition_ast, definition_content) = self.files.get(&var.file)?;
-        Some(Location {
-            uri: (*var.file).clone(),
-            range: utils::range(definition_content, var.key.text_range())
+        let mut locations: Vec<Location> = lookup::parts(&var).iter()
+            .map(|key| Location {
+                uri: (*var.file).clone(),
+                range: utils::range(definition_content, key.text_range(), self.encoding)
+            })
+            .collect();
+        locations.dedup();
+        match locations.len() {
+            1 => locations.pop().map(GotoDefinitionResponse::Scalar),
+            _ => Some(GotoDefinitionResponse::Array(locations)),
+        }
+    }
+    /// Add where a name comes from to its hover, or make that the hover
+    /// if there's nothing else to show
+    fn with_provenance(&self, params: &TextDocumentPositionParams, hover: Option<Hover>, provenance: String) -> Option<Hover> {
+        match hover {
+            Some(Hover { contents: HoverContents::Markup(mut markup), range }) => {
+                markup.value = format!("{}\n\n{provenance}", markup.value);
+                Some(Hover { contents: HoverContents::Markup(markup), range })
+            },
+            Some(hover) => Some(hover),
+            None => {
+                let (ast, code) = self.files.get(&params.text_document.uri)?;
+                let offset = utils::lookup_pos(code, params.position, self.encoding)?;
+                let info = utils::ident_at(&ast.node(), offset)?;
+                Some(Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value: provenance,
+                    }),
+                    range: Some(utils::range(code, info.ident.node().text_range(), self.encoding)),
+                })
+            },
+        }
+    }
+    fn hover(&mut self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+        let range = utils::range(content, info.ident.node().text_range(), self.encoding);
+
+        let builtin_path = utils::builtin_path(&Rc::new(params.text_document.uri.clone()), &info)?;
+        let builtin = builtins::get(info.ident.as_str())
+            .filter(|builtin| builtin_path || builtin.global)
+            .filter(|builtin| builtin.available(self.config.nix_version))?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: builtin.documentation(),
+            }),
+            range: Some(range),
         })
     }
-    #[allow(clippy::shadow_unrelated)] // false positive
-    fn completions(&mut self, params: &TextDocumentPositionParams) -> Option<Vec<CompletionItem>> {
+    /// Hover for sets that can be called like functions because of their
+    /// `__functor`, showing the arguments they take if they're known
+    fn functor_hover(&mut self, params: &TextDocumentPositionParams) -> Option<Hover> {
         let (ast, content) = self.files.get(&params.text_document.uri)?;
-        let offset = utils::lookup_pos(content, params.position)?;
-
-        let node = ast.node();
-        let (name, scope) = self.scope_for_ident(params.text_document.uri.clone(), &node, offset)?;
-
-        // Re-open, because scope_for_ident may mutably borrow
-        let (_, content) = self.files.get(&params.text_document.uri)?;
-
-        let mut completions = Vec::new();
-        for var in scope.keys() {
-            if var.starts_with(&name.as_str()) {
-                completions.push(CompletionItem {
-                    label: var.clone(),
-                    text_edit: Some(TextEdit {
-                        range: utils::range(content, name.node().text_range()),
-                        new_text: var.clone()
-                    }),
-                    ..CompletionItem::default()
-                });
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+        let range = utils::range(content, info.ident.node().text_range(), self.encoding);
+        let file = Rc::new(params.text_document.uri.clone());
+
+        let mut node = info.ident.node().clone();
+        if let Some(select) = node.parent().and_then(Select::cast).filter(|select| select.index().as_ref() == Some(&node)) {
+            node = select.node().clone();
+        }
+        if node == *info.ident.node() && !utils::is_reference(&info.ident) {
+            return None;
+        }
+        let (file, set) = self.resolve_value(&file, node)?;
+        self.functor(&file, &set)?;
+        let value = match self.callable(&file, set).and_then(|(_, lambda)| lambda.arg()) {
+            Some(arg) => format!("Callable set, through `__functor`, taking\n```nix\n{}\n```", arg.text()),
+            None => "Callable set, through `__functor`".into(),
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(range),
+        })
+    }
+    /// Hover for the `with` keyword and what follows it, listing the names
+    /// it brings into scope if they can be known statically
+    fn with_hover(&mut self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let token = ast.node().token_at_offset(TextUnit::from_usize(offset)).right_biased()?;
+        let (with, range) = if token.kind() == TOKEN_WITH {
+            (With::cast(token.parent())?, token.text_range())
+        } else {
+            token.parent().ancestors().filter_map(With::cast).find_map(|with| {
+                let namespace = with.namespace()?.text_range();
+                namespace.contains_inclusive(token.text_range().start()).then_some((with, namespace))
+            })?
+        };
+        let range = utils::range(content, range, self.encoding);
+        let namespace = with.namespace()?;
+
+        let file = Rc::new(params.text_document.uri.clone());
+        let mut names: Vec<String> = self.scope_from_node(&file, namespace.clone())
+            .map(|scope| scope.into_keys().collect())
+            .unwrap_or_default();
+        let value = if names.is_empty() {
+            format!("Brings the attributes of\n```nix\n{}\n```\ninto scope, which aren't known statically", namespace.text())
+        } else {
+            names.sort();
+            let count = names.len();
+            let mut list: Vec<String> = names.iter().take(MAX_WITH_NAMES).map(|name| format!("`{name}`")).collect();
+            if count > MAX_WITH_NAMES {
+                list.push(format!("and {} more", count - MAX_WITH_NAMES));
             }
+            let plural = if count == 1 { "name" } else { "names" };
+            format!("Brings {count} {plural} into scope: {}", list.join(", "))
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(range),
+        })
+    }
+    /// Hover for number literals, showing integers in hex and binary and
+    /// the value of floats like `1.5e3`
+    fn number_hover(&self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let token = ast.node().token_at_offset(TextUnit::from_usize(offset))
+            .find(|token| matches!(token.kind(), TOKEN_INTEGER | TOKEN_FLOAT))?;
+        let value = if token.kind() == TOKEN_INTEGER {
+            let number: i64 = token.text().parse().ok()?;
+            format!("Hex `{number:#x}`, binary `{number:#b}`")
+        } else {
+            let number: f64 = token.text().parse().ok()?;
+            format!("Float `{number:?}`")
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(utils::range(content, token.text_range(), self.encoding)),
+        })
+    }
+    /// Hover for path literals, showing the absolute path they point to
+    /// and whether it exists
+    fn path_hover(&self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let token = ast.node().token_at_offset(TextUnit::from_usize(offset)).find(|token| token.kind() == TOKEN_PATH)?;
+        let Ok(ParsedValue::Path(anchor, path)) = ParsedValue::from_token(TOKEN_PATH, token.text()) else { return None };
+
+        let value = match imports::resolve_literal(&params.text_document.uri, &anchor, &path) {
+            Some(resolved) => {
+                let kind = if resolved.is_dir() {
+                    "a directory"
+                } else if resolved.exists() {
+                    "a file"
+                } else {
+                    "which doesn't exist"
+                };
+                format!("`{}`, {kind}", resolved.display())
+            },
+            None if anchor == Anchor::Store => format!("`<{path}>` isn't in `NIX_PATH`"),
+            None => return None,
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(utils::range(content, token.text_range(), self.encoding)),
+        })
+    }
+    /// Hover for the brackets of a list, showing what type its elements
+    /// have if they're all literals of the same type
+    fn list_hover(&self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let token = ast.node().token_at_offset(TextUnit::from_usize(offset))
+            .find(|token| matches!(token.kind(), TOKEN_SQUARE_B_OPEN | TOKEN_SQUARE_B_CLOSE))?;
+        let list = List::cast(token.parent())?;
+
+        let file = Rc::new(params.text_document.uri.clone());
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: utils::list_summary(&file, &list),
+            }),
+            range: Some(utils::range(content, list.node().text_range(), self.encoding)),
+        })
+    }
+    fn document_highlight(&mut self, params: &TextDocumentPositionParams) -> Vec<DocumentHighlight> {
+        let uri = &params.text_document.uri;
+        let Some((ast, code)) = self.files.get(uri) else { return Vec::new() };
+        let Some(offset) = utils::lookup_pos(code, params.position, self.encoding) else { return Vec::new() };
+        let (root, code) = (ast.node(), code.clone());
+        let name = utils::ident_at(&root, offset)
+            .map(|info| info.ident.node().clone())
+            .or_else(|| utils::quoted_name_at(&root, offset));
+        let Some(name) = name else { return Vec::new() };
+        let file = Rc::new(uri.clone());
+        match self.rename_target(&file, &name) {
+            Ok(Some(target)) => self.attr_highlights(&file, &root, &code, &target),
+            _ => Vec::new(),
         }
-        Some(completions)
     }
-    fn rename(&mut self, params: RenameParams) -> Option<HashMap<Url, Vec<TextEdit>>> {
+    fn references(&mut self, params: &ReferenceParams) -> Vec<Location> {
+        let position = &params.text_document_position;
+        let uri = &position.text_document.uri;
+        let Some((ast, code)) = self.files.get(uri) else { return Vec::new() };
+        let Some(offset) = utils::lookup_pos(code, position.position, self.encoding) else { return Vec::new() };
+        let (root, code) = (ast.node(), code.clone());
+        let name = utils::ident_at(&root, offset)
+            .map(|info| info.ident.node().clone())
+            .or_else(|| utils::quoted_name_at(&root, offset));
+        let Some(name) = name else { return Vec::new() };
+        self.name_references(&Rc::new(uri.clone()), &root, &code, &name, params.context.include_declaration)
+    }
+    fn linked_editing(&mut self, params: &TextDocumentPositionParams) -> Option<Vec<Range>> {
+        let uri = &params.text_document.uri;
+        let (ast, code) = self.files.get(uri)?;
+        let offset = utils::lookup_pos(code, params.position, self.encoding)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+        let (root, code) = (ast.node(), code.clone());
+        self.linked_ranges(&Rc::new(uri.clone()), &root, &code, &info.ident)
+    }
+    fn rename(&mut self, params: RenameParams) -> Result<Option<HashMap<Url, Vec<TextEdit>>>, Error> {
         struct Rename<'a> {
             edits: Vec<TextEdit>,
+            file: &'a Rc<Url>,
             code: &'a str,
+            encoding: utils::Encoding,
             old: &'a str,
             new_name: String,
+            /// The set or let the variable is defined in
+            set: SyntaxNode,
+            /// The identifier the variable is defined by
+            definition: SyntaxNode,
         }
         fn rename_in_node(rename: &mut Rename, node: &SyntaxNode) -> Option<()> {
             if let Some(ident) = Ident::cast(node.clone()) {
-                if ident.as_str() == rename.old {
+                // Not the ones a closer binding of the same name shadows
+                if ident.as_str() == rename.old && rename::binding(rename.file, &ident).as_ref() == Some(&rename.definition) {
                     rename.edits.push(TextEdit {
-                        range: utils::range(rename.code, node.text_range()),
+                        range: utils::range(rename.code, node.text_range(), rename.encoding),
                         new_text: rename.new_name.clone()
                     });
                 }
             } else if let Some(index) = Select::cast(node.clone()) {
                 rename_in_node(rename, &index.set()?);
             } else if let Some(attr) = Key::cast(node.clone()) {
+                // Only the keys defining the variable, not attributes of
+                // other sets which happen to have the same name
+                let holder = node.parent().and_then(|entry| entry.parent());
                 let mut path = attr.path();
-                if let Some(ident) = path.next() {
-                    rename_in_node(rename, &ident);
+                if let Some(part) = path.next().filter(|_| holder.as_ref() == Some(&rename.set)) {
+                    if Ident::cast(part.clone()).is_none() && utils::static_name(&part).as_deref() == Some(rename.old) {
+                        // A quoted key, like `"a" = 1;`
+                        rename.edits.push(TextEdit {
+                            range: utils::range(rename.code, part.text_range(), rename.encoding),
+                            new_text: rename.new_name.clone()
+                        });
+                    } else {
+                        rename_in_node(rename, &part);
+                    }
+                }
+            } else if let Some(inherit) = Inherit::cast(node.clone()) {
+                // An inherit both uses and defines a name, so only one side
+                // of it can be renamed
+                let from = inherit.from();
+                let source = from.as_ref().map(|from| format!("{}.", from.node().text())).unwrap_or_default();
+                let mut defines = false;
+                let old = rename.old;
+                for ident in inherit.idents().filter(|ident| ident.as_str() == old) {
+                    let binding = if *ident.node() == rename.definition {
+                        defines = true;
+                        format!("{} = {}{};", rename.new_name, source, rename.old)
+                    } else if from.is_none() {
+                        format!("{} = {};", rename.old, rename.new_name)
+                    } else {
+                        continue;
+                    };
+                    rename.edits.extend(rename::split_inherit(rename.code, rename.encoding, &inherit, &ident, &binding));
+                }
+                if let Some(from) = from.filter(|_| !defines) {
+                    rename_in_node(rename, from.node());
                 }
             } else {
                 for child in node.children() {
@@ -280,42 +1006,111 @@ impl App {
         }
 
         let uri = params.text_document_position.text_document.uri;
-        let (ast, code) = self.files.get(&uri)?;
-        let offset = utils::lookup_pos(code, params.text_document_position.position)?;
-        let info = utils::ident_at(&ast.node(), offset)?;
+        let Some((ast, code)) = self.files.get(&uri) else { return Ok(None) };
+        let Some(offset) = utils::lookup_pos(code, params.text_document_position.position, self.encoding) else { return Ok(None) };
+        let root = ast.node();
+        let info = utils::ident_at(&root, offset);
+        // Quoted names like `"a b"` can only be attributes
+        let name = match &info {
+            Some(info) => info.ident.node().clone(),
+            None => match utils::quoted_name_at(&root, offset) {
+                Some(name) => name,
+                None => return Ok(None),
+            },
+        };
+        let file = Rc::new(uri.clone());
+
+        if let Some(target) = self.rename_target(&file, &name)? {
+            return Ok(Some(self.rename_attr(&target, &params.new_name)?));
+        }
+        let Some(info) = info else { return Ok(None) };
+        let old = info.ident;
         if !info.path.is_empty() {
             // Renaming within a set not supported
-            return None;
+            return Ok(None);
         }
-        let old = info.ident;
-        let scope = utils::scope_for(&Rc::new(uri.clone()), old.node().clone())?;
+        let Some((_, code)) = self.files.get(&uri) else { return Ok(None) };
+        let Some(scope) = utils::scope_for(&file, old.node().clone()) else { return Ok(None) };
 
+        let Some(definition) = scope.get(old.as_str()) else { return Ok(None) };
+        if !utils::is_ident(&params.new_name) {
+            return Err(format!("can't rename `{}` to `{}`, variables have to be identifiers", old.as_str(), params.new_name).into());
+        }
+        rename::check_variable_rename(&file, code, definition, old.as_str(), &params.new_name)?;
         let mut rename = Rename {
             edits: Vec::new(),
+            file: &file,
             code,
+            encoding: self.encoding,
             old: old.as_str(),
-            new_name: params.new_name
+            new_name: params.new_name,
+            set: definition.set.clone(),
+            definition: definition.key.clone(),
         };
-        let definition = scope.get(old.as_str())?;
         rename_in_node(&mut rename, &definition.set);
 
-        let mut changes = HashMap::new();
-        changes.insert(uri, rename.edits);
-        Some(changes)
+        Ok(Some(HashMap::from([(uri, rename.edits)])))
+    }
+    /// Edits to several files, as versioned document changes if the
+    /// client supports them, so it refuses to apply edits to an open
+    /// file that changed in the meantime. Files that aren't open have no
+    /// version and are edited as they are on disk.
+    fn workspace_edit(&self, changes: HashMap<Url, Vec<TextEdit>>) -> WorkspaceEdit {
+        let versioned = self.client.workspace.as_ref()
+            .and_then(|workspace| workspace.workspace_edit.as_ref())
+            .and_then(|edit| edit.document_changes)
+            .unwrap_or(false);
+        if !versioned {
+            return WorkspaceEdit {
+                changes: Some(changes),
+                ..WorkspaceEdit::default()
+            };
+        }
+        let mut edits: Vec<TextDocumentEdit> = changes.into_iter()
+            .map(|(uri, edits)| TextDocumentEdit {
+                text_document: VersionedTextDocumentIdentifier { version: self.open.get(&uri).copied(), uri },
+                edits,
+            })
+            .collect();
+        edits.sort_by(|a, b| a.text_document.uri.cmp(&b.text_document.uri));
+        WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Edits(edits)),
+            ..WorkspaceEdit::default()
+        }
     }
-    fn send_diagnostics(&mut self, uri: Url, code: &str, ast: &AST) -> Result<(), Error> {
+    fn send_diagnostics(&mut self, uri: Url, code: &str, ast: &parse::Parsed) -> Result<(), Error> {
         let errors = ast.errors();
         let mut diagnostics = Vec::with_capacity(errors.len());
         for err in errors {
-            if let ParseError::Unexpected(node) = err {
-                diagnostics.push(Diagnostic {
-                    range: utils::range(code, node),
-                    severity: Some(DiagnosticSeverity::Error),
-                    message: err.to_string(),
-                    ..Diagnostic::default()
-                });
-            }
+            let range = match err {
+                ParseError::Unexpected(node) => node,
+                // Unexpected EOF errors don't carry a location, so they
+                // are put at the very end of the file
+                ParseError::UnexpectedEOF | ParseError::UnexpectedEOFWanted(_) => {
+                    let end = TextUnit::of_str(code);
+                    TextRange::from_to(end, end)
+                },
+            };
+            diagnostics.push(Diagnostic {
+                range: utils::range(code, range, self.encoding),
+                severity: Some(DiagnosticSeverity::Error),
+                message: err.to_string(),
+                ..Diagnostic::default()
+            });
+        }
+        // Linting resolves a lot, so it's given up if the file changes
+        // again, and the diagnostics of the newer version are sent instead
+        self.linting = Some(uri.clone());
+        let lints = self.lint(&uri, &ast.node(), code);
+        self.linting = None;
+        if std::mem::take(&mut self.cancelled) {
+            return Ok(());
         }
+        diagnostics.extend(lints);
+        self.publish_diagnostics(uri, diagnostics);
+        Ok(())
+    }
+    fn publish_diagnostics(&mut self, uri: Url, diagnostics: Vec<Diagnostic>) {
         self.notify(Notification::new(
             "textDocument/publishDiagnostics".into(),
             PublishDiagnosticsParams {
@@ -324,6 +1119,315 @@ impl App {
                 version: None,
             }
         ));
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{capabilities, initialize, testing, utils, workspace, App, Config};
+    use lsp_server::{ErrorCode, Message, Notification, Request, RequestId};
+    use lsp_types::{
+        notification::{Notification as _, *},
+        request::{Request as _, *},
+        *,
+    };
+    use serde_json::json;
+
+    /// A file using most of the syntax, to be cut off anywhere
+    const SAMPLE: &str = r#"{ lib, pkgs ? import <nixpkgs> { }, ... }@args:
+# The package
+let
+  inherit (lib) mkIf optional;
+  version = "1.0";
+  src = ./src;
+  f = a: { b ? 1, ... }: a + b;
+  set = rec {
+    a = 1;
+    "quoted key" = a;
+    ${"dynamic"} = [ 1 2.5 "three" ];
+    nested.path.here = f 1 { };
+  };
+in
+with pkgs;
+assert version != "";
+stdenv.mkDerivation {
+  pname = "example-${version}";
+  inherit version src;
+  buildInputs = [ hello git ] ++ optional (set ? a) curl;
+  script = ''
+    echo "${toString set.a}" ''${HOME} ü
+  '';
+  meta = with lib; { license = licenses.mit; };
+  passthru = { x = if true then set.nested.path else null; };
+  value = (builtins.map (x: x * 2) [ 1 2 ]).0 or set."quoted key";
+}
+"#;
+
+    /// Positions in the code, spread over it like random ones would be
+    fn positions(code: &str, seed: usize) -> Vec<Position> {
+        let mut state = seed.wrapping_mul(2_654_435_761) | 1;
+        let mut offsets = vec![0, code.len()];
+        for _ in 0..4 {
+            // xorshift
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            offsets.push(state % (code.len() + 1));
+        }
+        offsets.into_iter()
+            .filter(|&offset| code.is_char_boundary(offset))
+            .map(|offset| utils::offset_to_pos(code, offset, utils::Encoding::Utf16))
+            .collect()
+    }
+
+    #[test]
+    fn truncated_files_dont_panic() {
+        let (mut app, client) = testing::app(&json!({}));
+        let uri = app.open_file("default.nix", SAMPLE);
+        for end in (0..=SAMPLE.len()).filter(|&end| SAMPLE.is_char_boundary(end)) {
+            let code = &SAMPLE[..end];
+            // Both parsed again after an edit and from scratch
+            app.change_file(&uri, code);
+            let fresh = app.open_file("fresh.nix", code);
+            for position in positions(code, end) {
+                for uri in [&uri, &fresh] {
+                    let position = TextDocumentPositionParams { text_document: TextDocumentIdentifier { uri: uri.clone() }, position };
+                    let completion = CompletionParams {
+                        text_document_position: position.clone(),
+                        work_done_progress_params: WorkDoneProgressParams::default(),
+                        partial_result_params: PartialResultParams::default(),
+                        context: None,
+                    };
+                    let requests = [
+                        (Completion::METHOD, serde_json::to_value(completion).unwrap()),
+                        (HoverRequest::METHOD, serde_json::to_value(&position).unwrap()),
+                        (GotoDefinition::METHOD, serde_json::to_value(&position).unwrap()),
+                    ];
+                    for (method, params) in requests {
+                        // Errors are fine, panics aren't
+                        let _ = app.handle_request(Request::new(RequestId::from(0), method.into(), params));
+                    }
+                }
+            }
+            client.receiver.try_iter().for_each(drop);
+        }
+    }
+
+    fn cancel(id: u64) -> Message {
+        Notification::new(Cancel::METHOD.into(), CancelParams { id: NumberOrString::Number(id) }).into()
+    }
+    /// Run a request like `App::main` does, while the client cancels it
+    /// and sends a hover request. Returns the responses the server sent.
+    fn cancel_in_flight<R>(code: &str, params: impl FnOnce(TextDocumentPositionParams) -> R::Params) -> Vec<lsp_server::Response>
+    where
+        R: lsp_types::request::Request,
+        R::Params: serde::Serialize,
+    {
+        let (mut app, client) = testing::app(&json!({}));
+        let position = app.open_at("default.nix", code);
+        let _ = testing::responses(&client);
+
+        app.current = Some(RequestId::from(1));
+        client.sender.send(cancel(1)).unwrap();
+        client.sender.send(Request::new(RequestId::from(2), HoverRequest::METHOD.into(), &position).into()).unwrap();
+        app.handle_request(Request::new(RequestId::from(1), R::METHOD.into(), params(position))).unwrap();
+        app.current = None;
+        app.cancelled = false;
+        // The hover is waiting to be handled next
+        client.sender.send(Notification::new(Exit::METHOD.into(), ()).into()).unwrap();
+        app.main();
+        testing::responses(&client)
+    }
+
+    #[test]
+    fn cancel_references() {
+        let responses = cancel_in_flight::<References>("let s = { a = 1; }; in s.a$0", |position| ReferenceParams {
+            text_document_position: position,
+            context: ReferenceContext { include_declaration: true },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        });
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].id, RequestId::from(1));
+        assert_eq!(responses[0].error.as_ref().map(|err| err.code), Some(ErrorCode::RequestCanceled as i32));
+        assert_eq!(responses[1].id, RequestId::from(2));
+        assert!(responses[1].error.is_none());
+    }
+    #[test]
+    fn cancel_rename() {
+        let responses = cancel_in_flight::<Rename>("let s = { a = 1; }; in s.a$0", |position| RenameParams {
+            text_document_position: position,
+            new_name: "b".into(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        });
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].error.as_ref().map(|err| err.code), Some(ErrorCode::RequestCanceled as i32));
+        assert_eq!(responses[1].id, RequestId::from(2));
+    }
+    #[test]
+    fn cancel_queued_request() {
+        let (mut app, client) = testing::app(&json!({}));
+        let position = app.open_at("default.nix", "let s = { a = 1; }; in s.a$0");
+        client.sender.send(Request::new(RequestId::from(1), HoverRequest::METHOD.into(), &position).into()).unwrap();
+        client.sender.send(cancel(1)).unwrap();
+        client.sender.send(Notification::new(Exit::METHOD.into(), ()).into()).unwrap();
+        app.main();
+        let responses = testing::responses(&client);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].error.as_ref().map(|err| err.code), Some(ErrorCode::RequestCanceled as i32));
+    }
+    #[test]
+    fn stale_lints_are_given_up() {
+        let (mut app, client) = testing::app(&json!({}));
+        let uri = app.open_file("default.nix", "let a = [ ]; in \"${a}\"");
+        let published = |client: &lsp_server::Connection| client.receiver.try_iter()
+            .filter(|msg| matches!(msg, Message::Notification(notification) if notification.method == PublishDiagnostics::METHOD))
+            .count();
+        assert_eq!(published(&client), 1);
+
+        let change = |text: &str| -> Message {
+            Notification::new(DidChangeTextDocument::METHOD.into(), DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier { uri: uri.clone(), version: Some(2) },
+                content_changes: vec![TextDocumentContentChangeEvent { range: None, range_length: None, text: text.into() }],
+            }).into()
+        };
+        client.sender.send(change("let a = [ 1 ]; in \"${a}\"")).unwrap();
+        app.change_file(&uri, "let a = [ 2 ]; in \"${a}\"");
+        assert_eq!(published(&client), 0);
+        assert_eq!(app.pending.len(), 1);
+
+        // The newer version is linted as usual
+        client.sender.send(Notification::new(Exit::METHOD.into(), ()).into()).unwrap();
+        app.main();
+        assert_eq!(published(&client), 1);
+    }
+
+    /// Run the server like `real_main` does, for a client that sends
+    /// `messages` after initializing. Returns the status it exits with and
+    /// the responses it sent.
+    fn lifecycle(messages: Vec<Message>) -> (i32, Vec<lsp_server::Response>) {
+        let (server, lsp_server::Connection { sender, receiver }) = lsp_server::Connection::memory();
+        sender.send(Request::new(RequestId::from(0), Initialize::METHOD.into(), json!({ "capabilities": {} })).into()).unwrap();
+        sender.send(Notification::new(Initialized::METHOD.into(), json!({})).into()).unwrap();
+        for msg in messages {
+            sender.send(msg).unwrap();
+        }
+        // The client stops sending after these, like it's gone
+        drop(sender);
+        let config = Config::default();
+        initialize(&server, |_| capabilities(&config)).unwrap();
+        let status = App::new(server, config, ClientCapabilities::default(), utils::Encoding::Utf16, Vec::new()).main();
+        let responses = receiver.try_iter()
+            .filter_map(|msg| match msg {
+                Message::Response(response) => Some(response),
+                _ => None,
+            })
+            .collect();
+        (status, responses)
+    }
+
+    #[test]
+    fn shutdown_then_exit() {
+        let position = json!({ "textDocument": { "uri": testing::uri("default.nix") }, "position": { "line": 0, "character": 0 } });
+        let (status, responses) = lifecycle(vec![
+            Request::new(RequestId::from(1), Shutdown::METHOD.into(), ()).into(),
+            Request::new(RequestId::from(2), HoverRequest::METHOD.into(), position).into(),
+            Notification::new(Exit::METHOD.into(), ()).into(),
+        ]);
+        assert_eq!(status, libc::EXIT_SUCCESS);
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].result.as_ref().is_some_and(|result| result["capabilities"].is_object()));
+        assert_eq!(responses[1].id, RequestId::from(1));
+        assert!(responses[1].error.is_none());
+        assert_eq!(responses[2].id, RequestId::from(2));
+        assert_eq!(responses[2].error.as_ref().map(|err| err.code), Some(ErrorCode::InvalidRequest as i32));
+    }
+    #[test]
+    fn exit_without_shutdown() {
+        let (status, responses) = lifecycle(vec![Notification::new(Exit::METHOD.into(), ()).into()]);
+        assert_eq!(status, libc::EXIT_FAILURE);
+        assert_eq!(responses.len(), 1);
+    }
+    #[test]
+    fn client_goes_away() {
+        let (status, _) = lifecycle(Vec::new());
+        assert_eq!(status, libc::EXIT_FAILURE);
+    }
+
+    #[test]
+    fn capabilities_follow_config() {
+        let offered = |options: serde_json::Value| capabilities(&Config::from_options(Some(&options)));
+        let commands = |capabilities: &serde_json::Value| capabilities["executeCommandProvider"]["commands"].clone();
+
+        let defaults = offered(json!({}));
+        assert_eq!(defaults["documentFormattingProvider"], true);
+        assert_eq!(defaults["inlayHintProvider"], true);
+        assert!(defaults["colorProvider"].is_null());
+        assert!(defaults["typeHierarchyProvider"].is_null());
+        assert!(commands(&defaults).as_array().unwrap().contains(&json!(workspace::FORMAT_COMMAND)));
+
+        let changed = offered(json!({
+            "formatter": "none",
+            "inheritHints": false,
+            "documentColors": true,
+            "optionsJson": "/nix/options.json",
+        }));
+        assert!(changed["documentFormattingProvider"].is_null());
+        assert!(changed["inlayHintProvider"].is_null());
+        assert_eq!(changed["colorProvider"], true);
+        assert_eq!(changed["typeHierarchyProvider"], true);
+        assert!(!commands(&changed).as_array().unwrap().contains(&json!(workspace::FORMAT_COMMAND)));
+
+        // Other formatters are offered like the default one
+        assert_eq!(offered(json!({ "formatter": "nix-fmt" }))["documentFormattingProvider"], true);
+    }
+
+    #[test]
+    fn rename_across_three_files() {
+        let files = [
+            ("a.nix", "{ x = 1; }"),
+            ("b.nix", "(import ./a.nix).x"),
+            ("c.nix", "let a = import ./a.nix; in a.x + import ./b.nix"),
+        ];
+        let (mut app, client) = testing::app_with_files(&json!({}), &files);
+        app.roots = vec![Url::parse("file:///test/").unwrap()];
+        // Only `a.nix` is open, the others are known like indexed ones
+        app.refresh(&testing::uri("b.nix"));
+        app.refresh(&testing::uri("c.nix"));
+        let position = app.open_at("a.nix", "{ x$0 = 1; }");
+        let params = RenameParams {
+            text_document_position: position,
+            new_name: "y".into(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let renamed = |edits: &[TextEdit], name: &str| {
+            let code = files.iter().find(|(file, _)| *file == name).unwrap().1;
+            testing::apply(code, edits)
+        };
+        let expected = [
+            ("a.nix", "{ y = 1; }"),
+            ("b.nix", "(import ./a.nix).y"),
+            ("c.nix", "let a = import ./a.nix; in a.y + import ./b.nix"),
+        ];
+
+        // Clients without versioned edits get the changes of each file
+        let edit: WorkspaceEdit = serde_json::from_value(testing::request::<Rename>(&mut app, &client, params.clone())).unwrap();
+        assert!(edit.document_changes.is_none());
+        let changes = edit.changes.unwrap();
+        assert_eq!(changes.len(), 3);
+        for (name, code) in expected {
+            assert_eq!(renamed(&changes[&testing::uri(name)], name), code);
+        }
+
+        // The others get document changes, versioned for the open file
+        app.client = serde_json::from_value(json!({ "workspace": { "workspaceEdit": { "documentChanges": true } } })).unwrap();
+        let edit: WorkspaceEdit = serde_json::from_value(testing::request::<Rename>(&mut app, &client, params)).unwrap();
+        assert!(edit.changes.is_none());
+        let Some(DocumentChanges::Edits(edits)) = edit.document_changes else { panic!("no document changes") };
+        let uris: Vec<Url> = edits.iter().map(|edit| edit.text_document.uri.clone()).collect();
+        assert_eq!(uris, expected.map(|(name, _)| testing::uri(name)));
+        for (edit, (name, code)) in edits.iter().zip(expected) {
+            assert_eq!(renamed(&edit.edits, name), code);
+            assert_eq!(edit.text_document.version, (name == "a.nix").then_some(0));
+        }
     }
 }
@@ -0,0 +1,138 @@
+/// A standard attribute of the `meta` of a derivation
+#[derive(Clone, Copy, Debug)]
+pub struct MetaField {
+    pub name: &'static str,
+    /// What the value is, like `string` or `list of platforms`
+    pub typ: &'static str,
+    pub doc: &'static str,
+}
+
+const fn field(name: &'static str, typ: &'static str, doc: &'static str) -> MetaField {
+    MetaField { name, typ, doc }
+}
+
+pub static META_FIELDS: &[MetaField] = &[
+    field("badPlatforms", "list of platforms", "Platforms the package is known not to work on, even though they match `platforms`."),
+    field("broken", "boolean", "Whether the package is known not to build. Evaluating it fails unless broken packages are allowed."),
+    field("changelog", "string", "A link to the changelog of the packaged version."),
+    field("description", "string", "A short, one-line description of the package. It doesn't start with an article or the name of the package, and doesn't end with a period."),
+    field("downloadPage", "string", "The page where the source of the package can be downloaded."),
+    field("homepage", "string", "The homepage of the project."),
+    field("hydraPlatforms", "list of platforms", "The platforms Hydra builds the package for, which defaults to `platforms`. `[ ]` means it isn't built at all."),
+    field("knownVulnerabilities", "list of strings", "Security issues of the packaged version. Packages with any are insecure, and evaluating them fails unless they're allowed."),
+    field("license", "license or list of licenses", "The license of the package, from `lib.licenses`. Use a list if parts are licensed differently."),
+    field("longDescription", "string", "A longer description of the package, which can span multiple paragraphs."),
+    field("mainProgram", "string", "The name of the main executable in `bin/`, used by `lib.getExe` and `nix run`."),
+    field("maintainers", "list of maintainers", "The maintainers of the package in nixpkgs, from `lib.maintainers`."),
+    field("maxSilent", "integer", "How many seconds a build on Hydra may go without output before it's killed."),
+    field("outputsToInstall", "list of strings", "The outputs that are installed when the package is, like `[ \"out\" \"man\" ]`."),
+    field("pkgConfigModules", "list of strings", "The pkg-config modules the package provides, checked by `testers.hasPkgConfigModules`."),
+    field("platforms", "list of platforms", "The platforms the package is supported on, like `lib.platforms.linux`."),
+    field("priority", "integer", "Which package wins when installed packages have colliding files. Lower numbers win, the default is 5."),
+    field("sourceProvenance", "list of source types", "Whether the package is built from source or from binaries, from `lib.sourceTypes`."),
+    field("teams", "list of teams", "The teams maintaining the package in nixpkgs, from `lib.teams`."),
+    field("timeout", "integer", "How many seconds a build on Hydra may take before it's killed."),
+];
+
+/// Common attributes of `stdenv.mkDerivation`, completed in
+/// `overrideAttrs`. The typ `shell code` is for phases and hooks.
+pub static DERIVATION_FIELDS: &[MetaField] = &[
+    field("buildInputs", "list of packages", "Dependencies for the platform the package runs on, like libraries it links against."),
+    field("buildPhase", "shell code", "Replaces the default build phase, which runs `make`."),
+    field("checkPhase", "shell code", "Replaces the default check phase, which runs `make check`."),
+    field("cmakeFlags", "list of strings", "Extra flags for `cmake`, if it's in `nativeBuildInputs`."),
+    field("configureFlags", "list of strings", "Extra flags for the `configure` script."),
+    field("configurePhase", "shell code", "Replaces the default configure phase, which runs `./configure`."),
+    field("doCheck", "boolean", "Whether to run the tests in the check phase."),
+    field("doInstallCheck", "boolean", "Whether to run the tests of the installed package in the install check phase."),
+    field("dontBuild", "boolean", "Skips the build phase."),
+    field("dontConfigure", "boolean", "Skips the configure phase."),
+    field("dontStrip", "boolean", "Keeps debugging symbols in the installed binaries."),
+    field("enableParallelBuilding", "boolean", "Whether to build with multiple jobs, like `make -j`."),
+    field("env", "set of strings", "Environment variables of the build."),
+    field("hardeningDisable", "list of strings", "Hardening flags to turn off, like `[ \"fortify\" ]`, or `[ \"all\" ]`."),
+    field("installPhase", "shell code", "Replaces the default install phase, which runs `make install`."),
+    field("makeFlags", "list of strings", "Extra flags for `make`, in every phase that runs it."),
+    field("mesonFlags", "list of strings", "Extra flags for `meson`, if it's in `nativeBuildInputs`."),
+    field("meta", "set", "Information about the package, like its description, license and maintainers."),
+    field("name", "string", "The name of the derivation, which is `${pname}-${version}` if those are set."),
+    field("nativeBuildInputs", "list of packages", "Dependencies for the platform the package is built on, like compilers and build tools."),
+    field("nativeCheckInputs", "list of packages", "Dependencies that are only needed to run the tests."),
+    field("outputs", "list of strings", "The outputs of the derivation, like `[ \"out\" \"dev\" ]`. The first one is the default."),
+    field("passthru", "set", "Attributes of the package that aren't passed to the builder, like `tests` or `updateScript`."),
+    field("patches", "list of paths", "Patches applied to the source in the patch phase."),
+    field("pname", "string", "The name of the package, without its version."),
+    field("postBuild", "shell code", "Runs at the end of the build phase."),
+    field("postConfigure", "shell code", "Runs at the end of the configure phase."),
+    field("postFixup", "shell code", "Runs at the end of the fixup phase, after binaries are stripped and patched."),
+    field("postInstall", "shell code", "Runs at the end of the install phase."),
+    field("postPatch", "shell code", "Runs at the end of the patch phase, after `patches` are applied."),
+    field("preBuild", "shell code", "Runs at the start of the build phase."),
+    field("preConfigure", "shell code", "Runs at the start of the configure phase."),
+    field("preInstall", "shell code", "Runs at the start of the install phase."),
+    field("propagatedBuildInputs", "list of packages", "Like `buildInputs`, but also dependencies of packages depending on this one."),
+    field("separateDebugInfo", "boolean", "Puts debugging symbols in a `debug` output."),
+    field("sourceRoot", "string", "The directory of the unpacked source to build in."),
+    field("src", "path or derivation", "The source to build, usually from a fetcher like `fetchurl` or `fetchFromGitHub`."),
+    field("strictDeps", "boolean", "Whether to keep `buildInputs` and `nativeBuildInputs` strictly apart, which matters for cross-compiling."),
+    field("version", "string", "The version of the package."),
+];
+
+/// A license from `lib.licenses`
+#[derive(Clone, Copy, Debug)]
+pub struct License {
+    pub name: &'static str,
+    pub full_name: &'static str,
+    /// The SPDX identifier, if it's a license SPDX knows
+    pub spdx: Option<&'static str>,
+}
+
+const fn license(name: &'static str, full_name: &'static str, spdx: &'static str) -> License {
+    License { name, full_name, spdx: Some(spdx) }
+}
+const fn unlisted(name: &'static str, full_name: &'static str) -> License {
+    License { name, full_name, spdx: None }
+}
+
+pub static LICENSES: &[License] = &[
+    license("agpl3Only", "GNU Affero General Public License v3.0 only", "AGPL-3.0-only"),
+    license("agpl3Plus", "GNU Affero General Public License v3.0 or later", "AGPL-3.0-or-later"),
+    license("artistic2", "Artistic License 2.0", "Artistic-2.0"),
+    license("asl20", "Apache License 2.0", "Apache-2.0"),
+    license("boost", "Boost Software License 1.0", "BSL-1.0"),
+    license("bsd0", "BSD Zero Clause License", "0BSD"),
+    license("bsd2", "BSD 2-clause \"Simplified\" License", "BSD-2-Clause"),
+    license("bsd3", "BSD 3-clause \"New\" or \"Revised\" License", "BSD-3-Clause"),
+    license("bsl11", "Business Source License 1.1", "BUSL-1.1"),
+    license("cc-by-40", "Creative Commons Attribution 4.0", "CC-BY-4.0"),
+    license("cc-by-sa-40", "Creative Commons Attribution Share Alike 4.0", "CC-BY-SA-4.0"),
+    license("cc0", "Creative Commons Zero v1.0 Universal", "CC0-1.0"),
+    license("epl20", "Eclipse Public License 2.0", "EPL-2.0"),
+    unlisted("free", "Unspecified free software license"),
+    license("gpl2Only", "GNU General Public License v2.0 only", "GPL-2.0-only"),
+    license("gpl2Plus", "GNU General Public License v2.0 or later", "GPL-2.0-or-later"),
+    license("gpl3Only", "GNU General Public License v3.0 only", "GPL-3.0-only"),
+    license("gpl3Plus", "GNU General Public License v3.0 or later", "GPL-3.0-or-later"),
+    license("isc", "ISC License", "ISC"),
+    license("lgpl21Only", "GNU Lesser General Public License v2.1 only", "LGPL-2.1-only"),
+    license("lgpl21Plus", "GNU Lesser General Public License v2.1 or later", "LGPL-2.1-or-later"),
+    license("lgpl3Only", "GNU Lesser General Public License v3.0 only", "LGPL-3.0-only"),
+    license("lgpl3Plus", "GNU Lesser General Public License v3.0 or later", "LGPL-3.0-or-later"),
+    license("mit", "MIT License", "MIT"),
+    license("mit0", "MIT No Attribution", "MIT-0"),
+    license("mpl20", "Mozilla Public License 2.0", "MPL-2.0"),
+    license("ncsa", "University of Illinois/NCSA Open Source License", "NCSA"),
+    license("ofl", "SIL Open Font License 1.1", "OFL-1.1"),
+    license("openssl", "OpenSSL License", "OpenSSL"),
+    license("postgresql", "PostgreSQL License", "PostgreSQL"),
+    license("psfl", "Python Software Foundation License version 2", "Python-2.0"),
+    unlisted("publicDomain", "Public Domain"),
+    unlisted("unfree", "Unfree"),
+    unlisted("unfreeRedistributable", "Unfree redistributable"),
+    license("unlicense", "The Unlicense", "Unlicense"),
+    license("upl", "Universal Permissive License", "UPL-1.0"),
+    license("vim", "Vim License", "Vim"),
+    license("wtfpl", "Do What The F*ck You Want To Public License", "WTFPL"),
+    license("zlib", "zlib License", "Zlib"),
+    license("zpl21", "Zope Public License 2.1", "ZPL-2.1"),
+];
@@ -0,0 +1,387 @@
+use crate::{App, meta, utils::{self, CursorInfo, VarKind}};
+use lsp_types::*;
+use rnix::{
+    types::*,
+    SyntaxKind::{TOKEN_COMMENT, TOKEN_WHITESPACE},
+    SyntaxNode,
+    TextUnit,
+};
+use std::{convert::TryFrom, rc::Rc};
+
+/// A function or value of the nixpkgs library, `lib`
+#[derive(Clone, Copy, Debug)]
+pub struct LibFunction {
+    /// The attribute of `lib` it's defined in, like `attrsets`
+    pub module: &'static str,
+    pub name: &'static str,
+    /// Whether this is also available directly as `lib.name`
+    pub exported: bool,
+    pub signature: &'static str,
+    pub doc: &'static str,
+}
+impl LibFunction {
+    const fn unexported(self) -> Self {
+        Self { exported: false, ..self }
+    }
+    pub fn documentation(&self) -> String {
+        format!("```nix\nlib.{}.{}\n```\n\n{}", self.module, self.signature, self.doc)
+    }
+    /// The names of the arguments, as listed in the signature
+    fn params(&self) -> impl Iterator<Item = &'static str> {
+        self.signature.split_whitespace().skip(1)
+    }
+}
+
+const fn function(module: &'static str, name: &'static str, signature: &'static str, doc: &'static str) -> LibFunction {
+    LibFunction {
+        module,
+        name,
+        exported: true,
+        signature,
+        doc,
+    }
+}
+
+pub static LIB: &[LibFunction] = &[
+    function("attrsets", "attrByPath", "attrByPath attrPath default set", "Return the attribute at the list of names `attrPath` in `set`, or `default` if it doesn't exist."),
+    function("attrsets", "collect", "collect pred attrs", "Recursively collect the values of `attrs` for which `pred` returns `true`."),
+    function("attrsets", "filterAttrs", "filterAttrs pred set", "Keep the attributes of `set` for which `pred name value` returns `true`."),
+    function("attrsets", "filterAttrsRecursive", "filterAttrsRecursive pred set", "Like `filterAttrs`, but also filters nested sets."),
+    function("attrsets", "foldAttrs", "foldAttrs op nul list_of_attrs", "Fold the values of the attributes with the same name in a list of sets."),
+    function("attrsets", "genAttrs", "genAttrs names f", "Generate a set with an attribute `name = f name` for each of the `names`."),
+    function("attrsets", "getAttrFromPath", "getAttrFromPath attrPath set", "Like `attrByPath`, but throws an error if the attribute doesn't exist."),
+    function("attrsets", "hasAttrByPath", "hasAttrByPath attrPath e", "Return `true` if the attribute at the list of names `attrPath` exists in `e`."),
+    function("attrsets", "isDerivation", "isDerivation value", "Return `true` if `value` is a derivation."),
+    function("attrsets", "mapAttrs'", "mapAttrs' f set", "Map each attribute to a new `nameValuePair`, changing both names and values."),
+    function("attrsets", "mapAttrsRecursive", "mapAttrsRecursive f set", "Like `mapAttrs`, but recurses into nested sets, passing the path of names to `f`."),
+    function("attrsets", "mapAttrsToList", "mapAttrsToList f attrs", "Call `f name value` for each attribute and return the results as a list."),
+    function("attrsets", "nameValuePair", "nameValuePair name value", "Create `{ name = name; value = value; }`, as used by `listToAttrs`."),
+    function("attrsets", "optionalAttrs", "optionalAttrs cond as", "Return `as` if `cond` is `true`, and an empty set otherwise."),
+    function("attrsets", "recursiveUpdate", "recursiveUpdate lhs rhs", "Like `lhs // rhs`, but merges nested sets instead of replacing them."),
+    function("attrsets", "setAttrByPath", "setAttrByPath attrPath value", "Create a nested set with `value` at the list of names `attrPath`."),
+    function("attrsets", "zipAttrsWith", "zipAttrsWith f sets", "Merge a list of sets, calling `f name values` with the values of each name."),
+    function("customisation", "callPackageWith", "callPackageWith autoArgs fn args", "Call the package function `fn`, passing the arguments it wants from `autoArgs`."),
+    function("customisation", "makeOverridable", "makeOverridable f origArgs", "Call `f origArgs`, adding an `override` attribute to the result."),
+    function("fixedPoints", "extends", "extends f rattrs", "Extend the function `rattrs` with the overlay `f`."),
+    function("fixedPoints", "fix", "fix f", "Compute the fixed point of `f`, that is `let x = f x; in x`."),
+    function("lists", "all", "all pred list", "Return `true` if `pred` returns `true` for all elements of `list`."),
+    function("lists", "any", "any pred list", "Return `true` if `pred` returns `true` for at least one element of `list`."),
+    function("lists", "count", "count pred list", "Count the elements of `list` for which `pred` returns `true`."),
+    function("lists", "drop", "drop count list", "Remove the first `count` elements of `list`."),
+    function("lists", "findFirst", "findFirst pred default list", "Return the first element of `list` for which `pred` returns `true`, or `default`."),
+    function("lists", "flatten", "flatten x", "Flatten nested lists into a single list."),
+    function("lists", "foldl", "foldl op nul list", "Reduce `list` with the binary operator `op`, from left to right."),
+    function("lists", "foldr", "foldr op nul list", "Reduce `list` with the binary operator `op`, from right to left."),
+    function("lists", "forEach", "forEach xs f", "Like `map`, but with the arguments flipped."),
+    function("lists", "imap0", "imap0 f list", "Like `map`, but also passes the index of each element, counting from 0."),
+    function("lists", "imap1", "imap1 f list", "Like `map`, but also passes the index of each element, counting from 1."),
+    function("lists", "last", "last list", "Return the last element of `list`."),
+    function("lists", "optional", "optional cond elem", "Return `[ elem ]` if `cond` is `true`, and an empty list otherwise."),
+    function("lists", "optionals", "optionals cond elems", "Return `elems` if `cond` is `true`, and an empty list otherwise."),
+    function("lists", "partition", "partition pred list", "Split `list` into `{ right, wrong }` depending on what `pred` returns."),
+    function("lists", "range", "range first last", "Return the list of integers from `first` to `last`, inclusive."),
+    function("lists", "remove", "remove e list", "Remove all elements equal to `e` from `list`."),
+    function("lists", "reverseList", "reverseList xs", "Reverse the order of the elements of `xs`."),
+    function("lists", "subtractLists", "subtractLists e list", "Remove the elements of `e` from `list`."),
+    function("lists", "take", "take count list", "Return the first `count` elements of `list`."),
+    function("lists", "toList", "toList x", "Return `x` if it's a list, and `[ x ]` otherwise."),
+    function("lists", "unique", "unique list", "Remove duplicate elements from `list`, keeping the first occurrence."),
+    function("modules", "mkAfter", "mkAfter value", "Order `value` after other definitions of a list option."),
+    function("modules", "mkAliasOptionModule", "mkAliasOptionModule from to", "Create a module that makes the option `from` an alias of `to`."),
+    function("modules", "mkBefore", "mkBefore value", "Order `value` before other definitions of a list option."),
+    function("modules", "mkDefault", "mkDefault value", "Set an option with a lower priority than normal definitions, so it can be overridden."),
+    function("modules", "mkForce", "mkForce value", "Set an option with a higher priority than normal definitions."),
+    function("modules", "mkIf", "mkIf condition content", "Only apply the definitions in `content` if `condition` is `true`."),
+    function("modules", "mkMerge", "mkMerge contents", "Merge a list of definitions into one."),
+    function("modules", "mkOverride", "mkOverride priority content", "Set an option with the given `priority`. Lower numbers take precedence."),
+    function("modules", "mkRemovedOptionModule", "mkRemovedOptionModule optionName replacementInstructions", "Create a module that throws an error if the removed option is used."),
+    function("modules", "mkRenamedOptionModule", "mkRenamedOptionModule from to", "Create a module that forwards the renamed option `from` to `to`, with a warning."),
+    function("options", "literalExpression", "literalExpression text", "Mark `text` as a Nix expression for an option's documentation."),
+    function("options", "mkEnableOption", "mkEnableOption name", "Create a boolean option, `false` by default, that enables `name`."),
+    function("options", "mkOption", "mkOption attrs", "Declare an option, with a `type`, `default`, `description` and so on."),
+    function("options", "mkPackageOption", "mkPackageOption pkgs name attrs", "Create an option for the package to use, `pkgs.${name}` by default."),
+    function("strings", "concatMapStrings", "concatMapStrings f list", "Map `f` over `list` and concatenate the resulting strings."),
+    function("strings", "concatMapStringsSep", "concatMapStringsSep sep f list", "Map `f` over `list` and concatenate the resulting strings with `sep` in between."),
+    function("strings", "concatStrings", "concatStrings list", "Concatenate a list of strings."),
+    function("strings", "concatStringsSep", "concatStringsSep sep list", "Concatenate a list of strings with `sep` in between."),
+    function("strings", "escapeShellArg", "escapeShellArg arg", "Quote `arg` so that it's passed to a shell command as a single argument."),
+    function("strings", "escapeShellArgs", "escapeShellArgs args", "Quote each of `args` with `escapeShellArg` and join them with spaces."),
+    function("strings", "fileContents", "fileContents file", "Read `file`, removing a trailing newline."),
+    function("strings", "getName", "getName x", "Return the name of the package or derivation name `x`, without its version."),
+    function("strings", "getVersion", "getVersion x", "Return the version of the package or derivation name `x`."),
+    function("strings", "hasPrefix", "hasPrefix pref str", "Return `true` if `str` starts with `pref`."),
+    function("strings", "hasSuffix", "hasSuffix suffix content", "Return `true` if `content` ends with `suffix`."),
+    function("strings", "makeBinPath", "makeBinPath packages", "Create a `PATH`-style string of the `bin` directories of `packages`."),
+    function("strings", "makeLibraryPath", "makeLibraryPath packages", "Create a search path of the `lib` directories of `packages`."),
+    function("strings", "optionalString", "optionalString cond string", "Return `string` if `cond` is `true`, and an empty string otherwise."),
+    function("strings", "removePrefix", "removePrefix prefix str", "Remove `prefix` from the start of `str`, if it's there."),
+    function("strings", "removeSuffix", "removeSuffix suffix str", "Remove `suffix` from the end of `str`, if it's there."),
+    function("strings", "splitString", "splitString sep s", "Split `s` at each occurrence of `sep`."),
+    function("strings", "stringToCharacters", "stringToCharacters s", "Split `s` into a list of its characters."),
+    function("strings", "toLower", "toLower str", "Convert ASCII letters in `str` to lower case."),
+    function("strings", "toUpper", "toUpper str", "Convert ASCII letters in `str` to upper case."),
+    function("strings", "versionAtLeast", "versionAtLeast v1 v2", "Return `true` if version `v1` is at least `v2`."),
+    function("strings", "versionOlder", "versionOlder v1 v2", "Return `true` if version `v1` is older than `v2`."),
+    function("trivial", "boolToString", "boolToString b", "Convert a boolean to `\"true\"` or `\"false\"`."),
+    function("trivial", "const", "const x y", "Ignore `y` and return `x`."),
+    function("trivial", "flip", "flip f a b", "Call `f b a`."),
+    function("trivial", "id", "id x", "Return `x`."),
+    function("trivial", "importJSON", "importJSON path", "Read and parse the JSON file at `path`."),
+    function("trivial", "importTOML", "importTOML path", "Read and parse the TOML file at `path`."),
+    function("trivial", "max", "max x y", "Return the greater of `x` and `y`."),
+    function("trivial", "min", "min x y", "Return the lesser of `x` and `y`."),
+    function("trivial", "pipe", "pipe value fns", "Pass `value` through the list of functions `fns`, from first to last."),
+    function("trivial", "throwIf", "throwIf cond msg", "Throw `msg` if `cond` is `true`, and return the identity function otherwise."),
+    function("trivial", "throwIfNot", "throwIfNot cond msg", "Throw `msg` if `cond` is `false`, and return the identity function otherwise."),
+    function("trivial", "warn", "warn msg val", "Print the warning `msg` and return `val`."),
+    function("types", "anything", "anything", "A type that accepts any value, merging sets recursively.").unexported(),
+    function("types", "attrsOf", "attrsOf elemType", "A set with values of `elemType`.").unexported(),
+    function("types", "bool", "bool", "A boolean.").unexported(),
+    function("types", "either", "either t1 t2", "A value of either type `t1` or `t2`.").unexported(),
+    function("types", "enum", "enum values", "One of the given `values`.").unexported(),
+    function("types", "int", "int", "A signed integer.").unexported(),
+    function("types", "lines", "lines", "A string, where multiple definitions are joined with newlines.").unexported(),
+    function("types", "listOf", "listOf elemType", "A list with elements of `elemType`.").unexported(),
+    function("types", "nullOr", "nullOr elemType", "Either `null` or a value of `elemType`.").unexported(),
+    function("types", "package", "package", "A derivation or a store path.").unexported(),
+    function("types", "path", "path", "An absolute path.").unexported(),
+    function("types", "port", "port", "A port number, between 0 and 65535.").unexported(),
+    function("types", "str", "str", "A string, which can't be defined more than once.").unexported(),
+    function("types", "submodule", "submodule options", "A set of options of its own, declared by the module `options`.").unexported(),
+    function("versions", "majorMinor", "majorMinor v", "Return the major and minor part of the version `v`, like `\"1.2\"`.").unexported(),
+];
+
+/// The function at a path of attributes below `lib`, like `["mkIf"]` or
+/// `["attrsets", "mapAttrs'"]`
+pub fn get(path: &[&str]) -> Option<&'static LibFunction> {
+    match path {
+        [name] => LIB.iter().find(|function| function.exported && function.name == *name),
+        [module, name] => LIB.iter().find(|function| function.module == *module && function.name == *name),
+        _ => None,
+    }
+}
+/// What's available in the set at the path below `lib`: functions, and
+/// modules for the top level
+fn members(path: &[String]) -> Vec<CompletionItem> {
+    let mut members: Vec<CompletionItem> = match path {
+        [] => {
+            let mut modules: Vec<_> = LIB.iter().map(|function| function.module).collect();
+            modules.dedup();
+            let modules = modules.into_iter().map(|module| CompletionItem {
+                label: module.into(),
+                kind: Some(CompletionItemKind::Module),
+                detail: Some(format!("lib.{module}")),
+                ..CompletionItem::default()
+            });
+            let licenses = CompletionItem {
+                label: "licenses".into(),
+                kind: Some(CompletionItemKind::Module),
+                detail: Some("lib.licenses".into()),
+                ..CompletionItem::default()
+            };
+            LIB.iter().filter(|function| function.exported).map(function_completion).chain(modules).chain(Some(licenses)).collect()
+        },
+        [module] if module == "licenses" => meta::LICENSES.iter()
+            .map(|license| CompletionItem {
+                label: license.name.into(),
+                kind: Some(CompletionItemKind::EnumMember),
+                detail: Some(license.full_name.into()),
+                documentation: license.spdx.map(|spdx| Documentation::String(format!("SPDX: {spdx}"))),
+                ..CompletionItem::default()
+            })
+            .collect(),
+        [module] => LIB.iter().filter(|function| function.module == module).map(function_completion).collect(),
+        _ => Vec::new(),
+    };
+    members.sort_by(|a, b| a.label.cmp(&b.label));
+    members
+}
+fn function_completion(function: &LibFunction) -> CompletionItem {
+    CompletionItem {
+        label: function.name.into(),
+        kind: Some(CompletionItemKind::Function),
+        detail: Some(format!("lib.{}.{}", function.module, function.signature)),
+        documentation: Some(Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: function.documentation(),
+        })),
+        ..CompletionItem::default()
+    }
+}
+
+/// Whether `lib` at this node is the library, which is assumed when it's
+/// a function argument like in `{ lib, ... }:`. Other bindings of `lib`
+/// could be anything.
+fn is_lib(file: &Rc<Url>, node: &SyntaxNode) -> bool {
+    utils::scope_for(file, node.clone())
+        .and_then(|mut scope| scope.remove("lib"))
+        .is_some_and(|var| var.kind == VarKind::Param)
+}
+/// Whether a node is inside a `with lib;`, where `lib` is the library
+pub fn in_with_lib(file: &Rc<Url>, node: &SyntaxNode) -> bool {
+    node.ancestors().filter_map(With::cast).any(|with| {
+        let namespace = with.namespace();
+        namespace.as_ref().and_then(|namespace| Ident::cast(namespace.clone())).is_some_and(|ident| ident.as_str() == "lib")
+            && namespace.is_some_and(|namespace| is_lib(file, &namespace))
+    })
+}
+/// Whether an identifier is provided by a `with lib;`, and not bound by
+/// anything else
+fn from_with_lib(file: &Rc<Url>, ident: &Ident) -> bool {
+    let bound = utils::scope_for(file, ident.node().clone()).is_none_or(|scope| scope.contains_key(ident.as_str()));
+    !bound && in_with_lib(file, ident.node())
+}
+/// What to write before a member of `lib` at a node: nothing inside
+/// `with lib;`, `lib.` if it's a function argument, and `None` if the
+/// library isn't available
+pub fn lib_prefix(file: &Rc<Url>, node: &SyntaxNode) -> Option<&'static str> {
+    if in_with_lib(file, node) {
+        Some("")
+    } else if is_lib(file, node) {
+        Some("lib.")
+    } else {
+        None
+    }
+}
+/// The path below `lib` an identifier refers to, like `["attrsets"]` and
+/// `mapAttrs` for `lib.attrsets.mapAttrs`, if `lib` is the library
+fn lib_path(file: &Rc<Url>, info: &CursorInfo) -> Option<Vec<String>> {
+    match info.path.split_first() {
+        Some((base, rest)) if base == "lib" && is_lib(file, info.ident.node()) => Some(rest.to_vec()),
+        None if from_with_lib(file, &info.ident) => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+fn member_completions(path: &[String], prefix: &str, range: Range) -> Vec<CompletionItem> {
+    members(path).into_iter()
+        .filter(|member| member.label.starts_with(prefix))
+        .map(|member| CompletionItem {
+            text_edit: Some(TextEdit {
+                range,
+                new_text: member.label.clone(),
+            }),
+            ..member
+        })
+        .collect()
+}
+
+impl App {
+    /// Completions for `lib.` and `with lib;`. Returns `None` unless the
+    /// identifier can be in `lib`.
+    pub fn lib_completions(&self, file: &Rc<Url>, info: &CursorInfo, range: Range) -> Option<Vec<CompletionItem>> {
+        if !self.config.lib_functions {
+            return None;
+        }
+        let path = lib_path(file, info)?;
+        Some(member_completions(&path, info.ident.as_str(), range))
+    }
+    /// Completions right after a dot, like `lib.strings.`, where nothing
+    /// of the name is typed yet. `path` is everything before the dot.
+    pub fn lib_member_completions(&self, file: &Rc<Url>, path: &[String], node: &SyntaxNode, range: Range)
+        -> Option<Vec<CompletionItem>>
+    {
+        if !self.config.lib_functions {
+            return None;
+        }
+        match path.split_first() {
+            Some((base, rest)) if base == "lib" && is_lib(file, node) => Some(member_completions(rest, "", range)),
+            _ => None,
+        }
+    }
+    pub fn lib_hover(&self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        if !self.config.lib_functions {
+            return None;
+        }
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+        let file = Rc::new(params.text_document.uri.clone());
+        let mut path = lib_path(&file, &info)?;
+        path.push(info.ident.as_str().into());
+        let function = get(&path.iter().map(String::as_str).collect::<Vec<_>>())?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: function.documentation(),
+            }),
+            range: Some(utils::range(content, info.ident.node().text_range(), self.encoding)),
+        })
+    }
+    /// The signature of the `lib` function being called at the cursor,
+    /// with the argument being typed highlighted
+    pub fn lib_signature_help(&self, params: &TextDocumentPositionParams) -> Option<SignatureHelp> {
+        if !self.config.lib_functions {
+            return None;
+        }
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let file = Rc::new(params.text_document.uri.clone());
+        let (head, args) = call_at(&ast.node(), offset)?;
+
+        // Flatten `lib.attrsets.mapAttrs` into its names
+        let mut names = Vec::new();
+        let mut node = head;
+        while let Some(select) = Select::cast(node.clone()) {
+            names.push(Ident::cast(select.index()?)?);
+            node = select.set()?;
+        }
+        let base = Ident::cast(node)?;
+        names.reverse();
+        let path = if base.as_str() == "lib" && is_lib(&file, base.node()) {
+            names
+        } else if names.is_empty() && from_with_lib(&file, &base) {
+            vec![base]
+        } else {
+            return None;
+        };
+        let function = get(&path.iter().map(Ident::as_str).collect::<Vec<_>>())?;
+
+        let active = function.params().count().saturating_sub(1).min(args);
+        Some(SignatureHelp {
+            signatures: vec![SignatureInformation {
+                label: function.signature.into(),
+                documentation: Some(Documentation::MarkupContent(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: function.doc.into(),
+                })),
+                parameters: Some(function.params().map(|param| ParameterInformation {
+                    label: ParameterLabel::Simple(param.into()),
+                    documentation: None,
+                }).collect()),
+            }],
+            active_signature: Some(0),
+            active_parameter: i64::try_from(active).ok(),
+        })
+    }
+}
+
+/// The function being called at the cursor, and how many of its
+/// arguments come before the cursor. The cursor has to be after the
+/// function or one of its arguments, like `f a <cursor>`.
+fn call_at(root: &SyntaxNode, offset: usize) -> Option<(SyntaxNode, usize)> {
+    let mut token = root.token_at_offset(TextUnit::from_usize(offset)).left_biased()?;
+    let mut after_space = false;
+    while matches!(token.kind(), TOKEN_WHITESPACE | TOKEN_COMMENT) {
+        token = token.prev_token()?;
+        after_space = true;
+    }
+    // The biggest expression that ends right before the cursor
+    let mut node = token.parent();
+    while let Some(parent) = node.parent().filter(|parent| parent.text_range().end() == node.text_range().end()) {
+        if Apply::cast(parent.clone()).is_none() && Select::cast(parent.clone()).is_none() {
+            break;
+        }
+        node = parent;
+    }
+    let mut args: usize = 0;
+    while let Some(apply) = Apply::cast(node.clone()) {
+        args += 1;
+        node = apply.lambda()?;
+    }
+    if !after_space {
+        // Still typing the last argument, or the function itself
+        args = args.checked_sub(1)?;
+    }
+    Some((node, args))
+}
@@ -0,0 +1,788 @@
+use crate::{App, Error, utils::{self, VarKind}};
+use lsp_types::*;
+use rnix::{
+    types::{Apply, AttrSet, EntryHolder, Key, KeyValue, List, ParsedType, Pattern, Root, Str, TokenWrapper, TypedNode, With, Wrapper},
+    value::StrPart,
+    NodeOrToken,
+    SyntaxKind::{NODE_DYNAMIC, NODE_LITERAL, NODE_ROOT, NODE_STRING, TOKEN_ASSIGN},
+    SyntaxNode,
+    TextRange,
+    TextUnit,
+};
+use serde_json::{json, Deserializer, Value};
+use std::{collections::BTreeMap, convert::TryFrom, fs, path::Path, rc::Rc};
+
+/// The name NixOS uses in option paths for attributes that can have
+/// any name, like `services.nginx.virtualHosts.<name>.root`
+const ANY_NAME: &str = "<name>";
+
+/// An option declared by a NixOS module
+#[derive(Debug, Default)]
+pub struct NixOption {
+    /// The description of the type, like `boolean` or `one of "a", "b"`
+    pub typ: String,
+    pub description: Option<String>,
+    /// The default value, as Nix code
+    pub default: Option<String>,
+    pub read_only: bool,
+}
+impl NixOption {
+    fn from_json(value: &Value) -> Self {
+        Self {
+            typ: value.get("type").and_then(Value::as_str).unwrap_or("unspecified").to_owned(),
+            description: value.get("description").and_then(text),
+            default: value.get("default").and_then(text),
+            read_only: value.get("readOnly").and_then(Value::as_bool).unwrap_or(false),
+        }
+    }
+    pub fn documentation(&self) -> String {
+        let mut parts = vec![format!("*Type:* {}", self.typ)];
+        match &self.default {
+            Some(default) if default.contains('\n') => parts.push(format!("*Default:*\n```nix\n{default}\n```")),
+            Some(default) => parts.push(format!("*Default:* `{default}`")),
+            None => (),
+        }
+        if self.read_only {
+            parts.push("*Read only*".into());
+        }
+        parts.extend(self.description.clone());
+        parts.join("\n\n")
+    }
+    /// The values allowed by an enum type, like `one of "a", "b"` from
+    /// `types.enum [ "a" "b" ]`, as Nix code
+    pub fn enum_values(&self) -> Option<Vec<String>> {
+        let mut rest = self.typ.strip_prefix("null or ").unwrap_or(&self.typ).strip_prefix("one of ")?;
+        let mut values = Vec::new();
+        // The values are written as JSON, and strings may contain commas
+        loop {
+            let mut stream = Deserializer::from_str(rest).into_iter::<Value>();
+            let value = stream.next()?.ok()?;
+            values.push(match value {
+                Value::String(s) => nix_string(&s),
+                Value::Number(_) | Value::Bool(_) => value.to_string(),
+                _ => return None,
+            });
+            match rest[stream.byte_offset()..].strip_prefix(", ") {
+                Some(next) => rest = next,
+                None if stream.byte_offset() == rest.len() => return Some(values),
+                None => return None,
+            }
+        }
+    }
+    /// Whether the type is `types.bool`, or `null or` it
+    pub fn is_bool(&self) -> bool {
+        matches!(self.typ.as_str(), "boolean" | "null or boolean")
+    }
+    /// The types of literals, as named by `utils::literal_type`, that the
+    /// option allows, if it has one of the basic types like a boolean,
+    /// integer or string. `None` for every other type.
+    fn literal_types(&self) -> Option<Vec<&'static str>> {
+        let (typ, nullable) = match self.typ.strip_prefix("null or ") {
+            Some(typ) => (typ, true),
+            None => (self.typ.as_str(), false),
+        };
+        let integer = ["signed integer", "unsigned integer, ", "positive integer, ", "integer between "]
+            .iter()
+            .any(|prefix| typ.starts_with(prefix))
+            || typ.contains(" bit unsigned integer") || typ.contains(" bit signed integer");
+        let string = matches!(typ, "string" | "non-empty string" | "single-line string")
+            || typ.starts_with("strings concatenated with ")
+            || typ.starts_with("string matching the pattern ");
+        let mut types = if typ.contains(" or ") {
+            return None;
+        } else if typ == "boolean" {
+            vec!["bool"]
+        } else if integer {
+            vec!["int"]
+        } else if string {
+            // Paths are coerced to strings in some places, so they're
+            // left alone
+            vec!["string", "path"]
+        } else {
+            return None;
+        };
+        if nullable {
+            types.push("null");
+        }
+        Some(types)
+    }
+}
+/// A string as a Nix literal
+fn nix_string(s: &str) -> String {
+    format!("\"{}\"", utils::escape_string(s))
+}
+/// Descriptions and defaults are either plain values, or wrapped like
+/// `{ "_type": "literalExpression", "text": "..." }`
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Object(map) if map.contains_key("_type") => map.get("text").and_then(Value::as_str).map(String::from),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+/// One level of the option tree. Options like `services.nginx` with
+/// options below them don't have an option of their own.
+#[derive(Debug, Default)]
+pub struct OptionTree {
+    pub option: Option<NixOption>,
+    pub children: BTreeMap<String, OptionTree>,
+}
+impl OptionTree {
+    /// Read the `options.json` NixOS generates for its manual, at
+    /// `share/doc/nixos/options.json`
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let Value::Object(map) = serde_json::from_str(&fs::read_to_string(path)?)? else {
+            return Err("the options should be an object".into());
+        };
+        let mut tree = Self::default();
+        for (name, value) in &map {
+            // `loc` is split correctly even if names contain dots
+            let loc: Option<Vec<&str>> = value.get("loc").and_then(Value::as_array)
+                .map(|loc| loc.iter().filter_map(Value::as_str).collect());
+            let path = loc.unwrap_or_else(|| name.split('.').collect());
+            let mut node = &mut tree;
+            for name in path {
+                node = node.children.entry(name.to_owned()).or_default();
+            }
+            node.option = Some(NixOption::from_json(value));
+        }
+        Ok(tree)
+    }
+    pub fn len(&self) -> usize {
+        self.children.values().map(|child| usize::from(child.option.is_some()) + child.len()).sum()
+    }
+    /// The options a module declares itself in `options`, with
+    /// `mkOption` or `mkEnableOption`. Types are described like in
+    /// `options.json`, and the options of submodules are below theirs.
+    pub fn declared(root: &SyntaxNode) -> Self {
+        let mut tree = Self::default();
+        if let Some(module) = Root::cast(root.clone()).and_then(|root| root.inner()).and_then(module_set) {
+            tree.declare_module(&module, &[]);
+        }
+        tree
+    }
+    /// Add the options a module declares, below `path`
+    fn declare_module(&mut self, module: &AttrSet, path: &[String]) {
+        for entry in module.entries() {
+            let (Some(names), Some(value)) = (key_names(&entry), entry.value()) else { continue };
+            if names.first().is_some_and(|first| first == "options") {
+                self.declare(value, [path, &names[1..]].concat());
+            }
+        }
+    }
+    /// Add the options of a declaration or a set of them at a path
+    fn declare(&mut self, value: SyntaxNode, path: Vec<String>) {
+        let value = utils::unwrap_parens(value);
+        if let Some(set) = AttrSet::cast(value.clone()) {
+            for entry in set.entries() {
+                let (Some(names), Some(value)) = (key_names(&entry), entry.value()) else { continue };
+                self.declare(value, [path.as_slice(), &names].concat());
+            }
+            return;
+        }
+        let Some((option, submodule)) = declaration(&value) else { return };
+        let mut node = &mut *self;
+        for name in &path {
+            node = node.children.entry(name.clone()).or_default();
+        }
+        node.option = Some(option);
+        let Some((below, module)) = submodule else { return };
+        // `submodule { options = ...; }` or `submodule ({ name, ... }: { ... })`
+        if let Some(module) = module_set(module) {
+            self.declare_module(&module, &[path, below].concat());
+        }
+    }
+    /// The options at a path, where any name matches `<name>`
+    pub fn get<S: AsRef<str>>(&self, path: &[S]) -> Option<&Self> {
+        let mut node = self;
+        for name in path {
+            node = node.children.get(name.as_ref()).or_else(|| node.children.get(ANY_NAME))?;
+        }
+        Some(node)
+    }
+}
+
+
+/// How NixOS puts the description of a type into the one of another, see
+/// `optionDescriptionPhrase` in `lib/types.nix`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Noun,
+    Composite,
+    Conjunction,
+    Other,
+}
+/// The module of a submodule, and the names below the option its options
+/// are at, like `<name>` for `attrsOf (submodule m)`
+type Submodule = (Vec<String>, SyntaxNode);
+/// A type of `lib.types`, as written in a declaration
+struct Type {
+    /// What it's called in `options.json`, like `list of string`
+    description: String,
+    class: Class,
+    submodule: Option<Submodule>,
+}
+impl Type {
+    fn noun(description: &str) -> Self {
+        Self { description: description.into(), class: Class::Noun, submodule: None }
+    }
+    /// A type written some way that isn't known, described by its code
+    fn unknown(node: &SyntaxNode) -> Self {
+        Self { description: node.text().to_string(), class: Class::Other, submodule: None }
+    }
+    /// The description of this type in the one of another, in parentheses
+    /// unless its class is one of `bare`
+    fn phrase(&self, bare: &[Class]) -> String {
+        if bare.contains(&self.class) { self.description.clone() } else { format!("({})", self.description) }
+    }
+    /// A type made of another, like `list of string`. The options of a
+    /// submodule in it are below `name`.
+    fn wrap(prefix: &str, inner: Self, bare: &[Class], class: Class, name: Option<&str>) -> Self {
+        let description = format!("{prefix}{}", inner.phrase(bare));
+        let submodule = inner.submodule.map(|(mut path, module)| {
+            path.splice(0..0, name.map(String::from));
+            (path, module)
+        });
+        Self { description, class, submodule }
+    }
+    fn parse(node: &SyntaxNode) -> Self {
+        use Class::{Composite, Conjunction, Noun};
+        // `with types; listOf str`
+        if let Some(body) = With::cast(node.clone()).and_then(|with| with.body()) {
+            return Self::parse(&body);
+        }
+        let (function, args) = call(node);
+        let Some(path) = utils::select_path(function) else { return Self::unknown(node) };
+        let path: Vec<&str> = match without_lib(&path) {
+            [types, rest @ ..] if types == "types" => rest,
+            path => path,
+        }.iter().map(String::as_str).collect();
+        let description = match (path.as_slice(), args.as_slice()) {
+            (["bool"], []) => "boolean",
+            (["int"], []) => "signed integer",
+            (["ints", "unsigned"], []) => "unsigned integer, meaning >=0",
+            (["ints", "positive"], []) => "positive integer, meaning >0",
+            (["ints", "u8"], []) => "8 bit unsigned integer; between 0 and 255 (both inclusive)",
+            (["port"] | ["ints", "u16"], []) => "16 bit unsigned integer; between 0 and 65535 (both inclusive)",
+            (["ints", "u32"], []) => "32 bit unsigned integer; between 0 and 4294967295 (both inclusive)",
+            (["float"], []) => "floating point number",
+            (["number"], []) => "signed integer or floating point number",
+            (["str"], []) => "string",
+            (["nonEmptyStr"], []) => "non-empty string",
+            (["singleLineStr"], []) => "(optionally newline-terminated) single-line string",
+            (["lines"], []) => "strings concatenated with \"\\n\"",
+            (["commas"], []) => "strings concatenated with \",\"",
+            (["envVar"], []) => "strings concatenated with \":\"",
+            (["path"], []) => "path",
+            (["package"], []) => "package",
+            (["attrs"], []) => "attribute set",
+            (["anything"], []) => "anything",
+            (["unspecified"], []) => "unspecified value",
+            (["raw"], []) => "raw value",
+            (["listOf"], [inner]) => return Self::wrap("list of ", Self::parse(inner), &[Noun, Composite], Composite, Some("*")),
+            (["attrsOf"], [inner]) => return Self::wrap("attribute set of ", Self::parse(inner), &[Noun, Composite], Composite, Some(ANY_NAME)),
+            (["lazyAttrsOf"], [inner]) => return Self::wrap("lazy attribute set of ", Self::parse(inner), &[Noun, Composite], Composite, Some(ANY_NAME)),
+            (["nullOr"], [inner]) => return Self::wrap("null or ", Self::parse(inner), &[Noun, Conjunction], Conjunction, None),
+            (["functionTo"], [inner]) => {
+                let inner = Self::parse(inner);
+                return Self { description: format!("function that evaluates to a(n) {}", inner.phrase(&[Noun, Composite])), class: Composite, submodule: None };
+            },
+            (["either"], [left, right]) => return Self::either(&[left.clone(), right.clone()]),
+            (["oneOf"], [list]) => match List::cast(list.clone()) {
+                Some(list) => return Self::either(&list.items().collect::<Vec<_>>()),
+                None => return Self::unknown(node),
+            },
+            (["enum"], [list]) => return Self::enumeration(list).unwrap_or_else(|| Self::unknown(node)),
+            (["submodule"], [module]) => return Self { description: "submodule".into(), class: Class::Other, submodule: Some((Vec::new(), module.clone())) },
+            (["strMatching"], [pattern]) => match string(pattern) {
+                Some(pattern) => return Self::noun(&format!("string matching the pattern {pattern}")),
+                None => return Self::unknown(node),
+            },
+            (["separatedString"], [separator]) => match string(separator) {
+                Some(separator) => return Self::noun(&format!("strings concatenated with {}", Value::String(separator))),
+                None => return Self::unknown(node),
+            },
+            _ => return Self::unknown(node),
+        };
+        Self::noun(description)
+    }
+    /// `either a b` or `oneOf [ a b ]`
+    fn either(types: &[SyntaxNode]) -> Self {
+        let types: Vec<Self> = types.iter().map(Self::parse).collect();
+        let description = types.iter().map(|typ| typ.phrase(&[Class::Noun, Class::Conjunction])).collect::<Vec<_>>().join(" or ");
+        let submodule = types.into_iter().find_map(|typ| typ.submodule);
+        Self { description, class: Class::Conjunction, submodule }
+    }
+    /// `enum [ "a" "b" ]`, with the values written like in `options.json`.
+    /// `None` unless they're all literals.
+    fn enumeration(list: &SyntaxNode) -> Option<Self> {
+        let values = List::cast(list.clone())?.items()
+            .map(|item| match string(&item) {
+                Some(string) => Some(Value::String(string).to_string()),
+                None if matches!(item.text().to_string().as_str(), "true" | "false") || item.kind() == NODE_LITERAL => Some(item.text().to_string()),
+                None => None,
+            })
+            .collect::<Option<Vec<String>>>()?;
+        Some(match values.as_slice() {
+            [] => Self::noun("impossible (empty enumeration)"),
+            [value] => Self::noun(&format!("value {value} (singular enum)")),
+            values => Self { description: format!("one of {}", values.join(", ")), class: Class::Conjunction, submodule: None },
+        })
+    }
+}
+/// A call like `f a b`, as `f` and its arguments
+fn call(node: &SyntaxNode) -> (SyntaxNode, Vec<SyntaxNode>) {
+    let mut node = utils::unwrap_parens(node.clone());
+    let mut args = Vec::new();
+    while let Some(apply) = Apply::cast(node.clone()) {
+        let (Some(function), Some(arg)) = (apply.lambda(), apply.value()) else { break };
+        args.push(utils::unwrap_parens(arg));
+        node = utils::unwrap_parens(function);
+    }
+    args.reverse();
+    (node, args)
+}
+/// The text of a string literal without interpolations
+fn string(node: &SyntaxNode) -> Option<String> {
+    let parts = Str::cast(node.clone())?.parts();
+    parts.into_iter()
+        .map(|part| match part {
+            StrPart::Literal(text) => Some(text),
+            StrPart::Ast(_) => None,
+        })
+        .collect()
+}
+/// The option a call like `mkOption { ... }` or `mkEnableOption "name"`
+/// declares, and the module of a submodule in its type
+fn declaration(node: &SyntaxNode) -> Option<(NixOption, Option<Submodule>)> {
+    let (function, args) = call(node);
+    let function = utils::select_path(function)?;
+    match (without_lib(&function), args.as_slice()) {
+        ([name], [description]) if name == "mkEnableOption" => {
+            let description = string(description).map(|name| format!("Whether to enable {name}."));
+            Some((NixOption { typ: "boolean".into(), description, default: Some("false".into()), read_only: false }, None))
+        },
+        ([name], [set]) if name == "mkOption" => {
+            let mut option = NixOption { typ: "unspecified".into(), ..NixOption::default() };
+            let mut submodule = None;
+            for entry in AttrSet::cast(set.clone())?.entries() {
+                let (Some(key), Some(value)) = (key_names(&entry), entry.value()) else { continue };
+                match key.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+                    ["type"] => {
+                        let typ = Type::parse(&value);
+                        option.typ = typ.description;
+                        submodule = typ.submodule;
+                    },
+                    // `lib.mdDoc "..."`
+                    ["description"] => option.description = string(&value).or_else(|| string(call(&value).1.last()?)),
+                    ["default"] => option.default = Some(dedent(&value.text().to_string())),
+                    ["readOnly"] => option.read_only = value.text() == "true",
+                    _ => (),
+                }
+            }
+            Some((option, submodule))
+        },
+        _ => None,
+    }
+}
+/// Code spanning lines without the indentation its lines after the first
+/// have in common, which is the one of the code around it
+fn dedent(code: &str) -> String {
+    let mut lines = code.lines();
+    let first = lines.next().unwrap_or_default();
+    let rest: Vec<&str> = lines.collect();
+    let indent = rest.iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let rest = rest.iter().map(|line| line.get(indent..).unwrap_or_else(|| line.trim_start()));
+    std::iter::once(first).chain(rest).collect::<Vec<_>>().join("\n")
+}
+/// The set of a module, like the body of `{ lib, ... }: { ... }`
+fn module_set(node: SyntaxNode) -> Option<AttrSet> {
+    let mut node = node;
+    loop {
+        node = match ParsedType::try_from(node.clone()).ok()? {
+            ParsedType::Lambda(lambda) => lambda.body()?,
+            ParsedType::LetIn(let_in) => let_in.body()?,
+            ParsedType::Paren(paren) => paren.inner()?,
+            ParsedType::With(with) => with.body()?,
+            ParsedType::AttrSet(set) => return Some(set),
+            _ => return None,
+        };
+    }
+}
+/// Whether `config` at this node is the configuration of a module,
+/// passed to it like `{ config, ... }:`
+fn is_config(file: &Rc<Url>, node: &rnix::SyntaxNode) -> bool {
+    utils::scope_for(file, node.clone())
+        .and_then(|mut scope| scope.remove("config"))
+        .is_some_and(|var| var.kind == VarKind::Param)
+}
+
+/// The name a part of a key or path stands for in option paths. Parts
+/// that aren't static, like `${name}` or `"${name}.conf"`, can only be
+/// names of options that take any name, like `environment.etc.<name>`.
+pub fn option_name(part: &SyntaxNode) -> Option<String> {
+    utils::static_name(part).or_else(|| matches!(part.kind(), NODE_DYNAMIC | NODE_STRING).then(|| ANY_NAME.to_owned()))
+}
+/// The names of the key of an entry, like `a.b` for `a.b = ...;`
+fn key_names(entry: &KeyValue) -> Option<Vec<String>> {
+    entry.key()?.path().map(|part| option_name(&part)).collect()
+}
+/// The static path of attribute names leading to a key, through the
+/// sets it's nested in, like `a.b.c` for `{ a.b = { c = ...; }; }`
+fn key_path(entry: &KeyValue) -> Option<Vec<String>> {
+    let mut path = Vec::new();
+    let mut entry = entry.clone();
+    loop {
+        path.splice(0..0, key_names(&entry)?);
+        let set = entry.node().parent().and_then(AttrSet::cast)?;
+        match set.node().parent().and_then(KeyValue::cast) {
+            Some(parent) => entry = parent,
+            None => return Some(path),
+        }
+    }
+}
+/// A type as it reads in a sentence, like "a set" for `attrs`
+fn describe(typ: &str) -> &str {
+    match typ {
+        "attrs" => "a set",
+        "bool" => "a boolean",
+        "float" => "a float",
+        "function" => "a function",
+        "int" => "an integer",
+        "list" => "a list",
+        "path" => "a path",
+        "string" => "a string",
+        _ => typ,
+    }
+}
+/// A path like `lib.types.bool` without the `lib.`
+fn without_lib(path: &[String]) -> &[String] {
+    match path {
+        [lib, rest @ ..] if lib == "lib" => rest,
+        _ => path,
+    }
+}
+/// The option path a set in a module defines, like `services.nginx` for
+/// `services.nginx = { ... };`, through `let`, `with`, `lib.mkIf` and
+/// `lib.mkMerge`. `None` if the set isn't part of the value of a module,
+/// which is a function taking `...`.
+fn module_path(set: &SyntaxNode) -> Option<Vec<String>> {
+    let is_call = |function: Option<SyntaxNode>, name: &str| {
+        function.and_then(utils::select_path).is_some_and(|path| without_lib(&path) == [name])
+    };
+    let mut path = Vec::new();
+    let mut node = set.clone();
+    loop {
+        let parent = node.parent()?;
+        node = match ParsedType::try_from(parent.clone()).ok()? {
+            ParsedType::KeyValue(entry) if entry.value().as_ref() == Some(&node) => {
+                path.splice(0..0, key_names(&entry)?);
+                // Not a set in a `let`
+                entry.node().parent().filter(|holder| AttrSet::cast(holder.clone()).is_some())?
+            },
+            ParsedType::LetIn(_) | ParsedType::Paren(_) => parent,
+            ParsedType::With(with) if with.body().as_ref() == Some(&node) => parent,
+            // `lib.mkIf cond { ... }`
+            ParsedType::Apply(apply) if apply.value().as_ref() == Some(&node) && is_call(apply.lambda().and_then(Apply::cast).and_then(|apply| apply.lambda()), "mkIf") => parent,
+            // `lib.mkMerge [ { ... } ]`
+            ParsedType::List(_) => {
+                let apply = parent.parent().and_then(Apply::cast).filter(|apply| is_call(apply.lambda(), "mkMerge"))?;
+                apply.node().clone()
+            },
+            ParsedType::Lambda(lambda) if lambda.body().as_ref() == Some(&node) => {
+                let at_root = parent.parent().is_some_and(|root| root.kind() == NODE_ROOT);
+                let takes_rest = lambda.arg().and_then(Pattern::cast).is_some_and(|pattern| pattern.ellipsis());
+                return (at_root && takes_rest).then_some(path);
+            },
+            _ => return None,
+        };
+    }
+}
+/// Completions for the options below nodes of the trees, from the first
+/// one having an option of a name. With
+/// `snippets`, options without options below them are completed as
+/// `name = ;`, with the cursor before the semicolon.
+fn option_items(nodes: &[&OptionTree], prefix: &str, existing: &[String], range: Range, snippets: bool) -> Vec<CompletionItem> {
+    let mut children = BTreeMap::new();
+    for node in nodes {
+        for (name, child) in &node.children {
+            children.entry(name).or_insert(child);
+        }
+    }
+    children.into_iter()
+        .filter(|(name, _)| name.starts_with(prefix) && *name != ANY_NAME && !existing.contains(name))
+        .map(|(name, child)| {
+            let key = utils::escape_key(name);
+            let snippet = snippets && child.children.is_empty();
+            CompletionItem {
+                label: name.clone(),
+                kind: Some(if child.option.is_some() { CompletionItemKind::Property } else { CompletionItemKind::Module }),
+                detail: Some(child.option.as_ref().map_or_else(|| "options".into(), |option| option.typ.clone())),
+                documentation: child.option.as_ref().map(|option| Documentation::MarkupContent(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: option.documentation(),
+                })),
+                insert_text_format: snippet.then_some(InsertTextFormat::Snippet),
+                text_edit: Some(TextEdit {
+                    range,
+                    // Quoted keys may contain characters special to snippets
+                    new_text: if snippet { format!("{} = $0;", utils::escape_snippet(&key)) } else { key },
+                }),
+                ..CompletionItem::default()
+            }
+        })
+        .collect()
+}
+/// An item of the type hierarchy of the options, for the options at
+/// `path`. Options aren't declared in the code, so the item points at
+/// `range` of the document it was asked for in.
+fn hierarchy_item(node: &OptionTree, path: &[String], uri: &Url, range: Range) -> Value {
+    let (kind, detail) = match &node.option {
+        Some(option) => (SymbolKind::Property, option.typ.clone()),
+        None => (SymbolKind::Module, "options".into()),
+    };
+    json!({
+        "name": path.last().map_or("options", String::as_str),
+        "kind": kind,
+        "detail": format!("{}: {detail}", path.join(".")),
+        "uri": uri,
+        "range": range,
+        "selectionRange": range,
+        "data": { "path": path },
+    })
+}
+/// The entry whose value is being typed, with the range of what's typed
+/// so far. That's nothing, part of a string, or part of an identifier.
+pub fn value_at(root: &SyntaxNode, code: &str, offset: usize) -> Option<(KeyValue, TextRange)> {
+    let cursor = TextUnit::from_usize(offset);
+    let token = root.token_at_offset(cursor).left_biased()?;
+    let entry = token.parent().ancestors().find_map(KeyValue::cast)?;
+    let assign = entry.node().children_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+        .find(|token| token.kind() == TOKEN_ASSIGN)?;
+    let value_start = assign.text_range().end();
+    if cursor < value_start {
+        return None;
+    }
+    let typed = code.get(value_start.to_usize()..offset)?;
+    let start = value_start + TextUnit::of_str(&typed[..typed.len() - typed.trim_start().len()]);
+    let typed = typed.trim_start();
+    if !typed.trim_start_matches('"').chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return None;
+    }
+    // Replace the closing quote too, if the editor already added it
+    let end = match entry.value().and_then(Str::cast) {
+        Some(string) if string.node().text_range().start() == start => string.node().text_range().end().max(cursor),
+        _ => cursor,
+    };
+    Some((entry, TextRange::from_to(start, end)))
+}
+/// The nodes at a path of option trees, see `OptionTree::get`
+fn nodes_at<'a>(trees: &[&'a OptionTree], path: &[String]) -> Vec<&'a OptionTree> {
+    trees.iter().filter_map(|tree| tree.get(path)).collect()
+}
+/// The option at a path, from the first tree that has one there
+fn option_at<'a>(trees: &[&'a OptionTree], path: &[String]) -> Option<&'a NixOption> {
+    trees.iter().find_map(|tree| tree.get(path)?.option.as_ref())
+}
+
+impl App {
+    /// The options a file can use: the ones it declares itself, if any,
+    /// before the ones of `options.json`
+    fn option_trees<'a>(&'a self, declared: &'a OptionTree) -> Vec<&'a OptionTree> {
+        let declared = (!declared.children.is_empty()).then_some(declared);
+        declared.into_iter().chain(&self.options).collect()
+    }
+    /// Read the options again, if there's a file configured
+    pub fn load_options(&mut self) {
+        let Some(path) = self.config.options_json.as_deref().map(|path| self.resolve_path(path)) else { return };
+        match OptionTree::load(&path) {
+            Ok(options) => {
+                self.log(MessageType::Info, format!("Loaded {} options from {}", options.len(), path.display()));
+                self.options = Some(options);
+            },
+            Err(err) => {
+                self.log(MessageType::Warning, format!("Couldn't load the options {}: {}", path.display(), err));
+            },
+        }
+    }
+    /// Completions for `config.` in a module, from the options. Returns
+    /// `None` if this isn't the `config` of a module.
+    pub fn option_completions(&self, file: &Rc<Url>, path: &[String], prefix: &str, node: &SyntaxNode, range: Range)
+        -> Option<Vec<CompletionItem>>
+    {
+        let (base, path) = path.split_first()?;
+        if base != "config" || !is_config(file, node) {
+            return None;
+        }
+        let declared = OptionTree::declared(&node.ancestors().last()?);
+        let nodes = nodes_at(&self.option_trees(&declared), path);
+        (!nodes.is_empty()).then(|| option_items(&nodes, prefix, &[], range, false))
+    }
+    /// Completions for a key in a set of a module, like `serviceConfig`
+    /// in `systemd.services.foo = { | };`, from the options below the path
+    /// of the set and the names `before` the key in the same key, like
+    /// `foo` in `systemd.services.foo.|`. Names like `foo` match `<name>`,
+    /// so this completes the options of submodules too. Returns `None`
+    /// if there are no options to complete here.
+    pub fn module_key_completions(&self, set: &SyntaxNode, before: &[String], prefix: &str, existing: &[String], range: Range, snippets: bool)
+        -> Option<Vec<CompletionItem>>
+    {
+        let declared = OptionTree::declared(&set.ancestors().last()?);
+        let trees = self.option_trees(&declared);
+        let mut path = module_path(set)?;
+        path.extend_from_slice(before);
+        // Modules that declare options put the rest in `config = { ... }`
+        if path.first().is_some_and(|name| name == "config") && nodes_at(&trees, &path).is_empty() {
+            path.remove(0);
+        }
+        let completions = option_items(&nodes_at(&trees, &path), prefix, existing, range, snippets);
+        (!completions.is_empty()).then_some(completions)
+    }
+    /// Definitions of options with a literal of the wrong type, like a set
+    /// for a boolean option. Values that aren't literals, like `mkIf c
+    /// true`, are never wrong since their type isn't obvious.
+    pub fn option_type_errors(&self, file: &Rc<Url>, root: &SyntaxNode) -> Vec<(SyntaxNode, String)> {
+        let declared = OptionTree::declared(root);
+        let trees = self.option_trees(&declared);
+        if trees.is_empty() {
+            return Vec::new();
+        }
+        let mut errors = Vec::new();
+        for entry in root.descendants().filter_map(KeyValue::cast) {
+            let Some(value) = entry.value() else { continue };
+            let Some(actual) = utils::literal_type(file, value.clone()) else { continue };
+            // Sets with a `_type` are things like `mkIf` already applied
+            if AttrSet::cast(value.clone()).is_some_and(|set| set.entries().any(|entry| key_names(&entry).is_some_and(|key| key == ["_type"]))) {
+                continue;
+            }
+            let Some(mut path) = key_path(&entry) else { continue };
+            if path.first().is_some_and(|name| name == "config") && nodes_at(&trees, &path).is_empty() {
+                path.remove(0);
+            }
+            let Some(option) = option_at(&trees, &path) else { continue };
+            let Some(expected) = option.literal_types() else { continue };
+            if !expected.contains(&actual) {
+                errors.push((value, format!("`{}` expects `{}`, but this is {}", path.join("."), option.typ, describe(actual))));
+            }
+        }
+        errors
+    }
+    /// Completions for the value of an option with an enum or boolean
+    /// type, like `services.nginx.package = |` or `enable = |`, also if
+    /// the module declares the option itself. Returns
+    /// `None` if the cursor isn't at the value of such an option.
+    pub fn value_completions(&self, root: &SyntaxNode, code: &str, offset: usize) -> Option<Vec<CompletionItem>> {
+        let (entry, range) = value_at(root, code, offset)?;
+        let declared = OptionTree::declared(root);
+        let trees = self.option_trees(&declared);
+        let mut path = key_path(&entry)?;
+        // Modules that declare options put the rest in `config = { ... }`
+        if path.first().is_some_and(|name| name == "config") && option_at(&trees, &path).is_none() {
+            path.remove(0);
+        }
+        let option = option_at(&trees, &path)?;
+        let (values, kind) = match option.enum_values() {
+            Some(values) => (values, CompletionItemKind::EnumMember),
+            None if option.is_bool() => (vec!["true".into(), "false".into()], CompletionItemKind::Value),
+            None => return None,
+        };
+
+        let typed = code.get(range.start().to_usize()..offset)?.trim_start_matches('"');
+        let range = utils::range(code, range, self.encoding);
+        Some(values.into_iter()
+            .filter(|value| value.trim_start_matches('"').starts_with(typed))
+            .map(|value| CompletionItem {
+                label: value.clone(),
+                kind: Some(kind),
+                detail: Some(option.typ.clone()),
+                documentation: option.description.clone().map(|value| Documentation::MarkupContent(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value,
+                })),
+                text_edit: Some(TextEdit {
+                    range,
+                    new_text: value,
+                }),
+                ..CompletionItem::default()
+            })
+            .collect())
+    }
+    /// Hover for the name of an option a module declares, like `enable` in
+    /// `enable = mkEnableOption "foo";`, showing its type, default and
+    /// description like for options of `options.json`
+    pub fn declaration_hover(&self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let (ast, code) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(code, params.position, self.encoding)?;
+        let ident = utils::ident_at(&ast.node(), offset)?.ident;
+        let key = ident.node().parent().and_then(Key::cast)?;
+        // Only the last name of `options.foo.enable` is the option
+        if key.path().last().as_ref() != Some(ident.node()) {
+            return None;
+        }
+        let (option, _) = declaration(&KeyValue::cast(key.node().parent()?)?.value()?)?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: option.documentation(),
+            }),
+            range: Some(utils::range(code, ident.node().text_range(), self.encoding)),
+        })
+    }
+    /// The option at a position, as an item of the type hierarchy, for a
+    /// key of a module like `services.nginx` or a path like
+    /// `config.services.nginx`. Returns `None` anywhere else.
+    pub fn prepare_type_hierarchy(&self, params: &TextDocumentPositionParams) -> Option<Value> {
+        let options = self.options.as_ref()?;
+        let uri = &params.text_document.uri;
+        let (ast, code) = self.files.get(uri)?;
+        let offset = utils::lookup_pos(code, params.position, self.encoding)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+        let ident = info.ident.node();
+
+        let mut path = if ident.parent().and_then(Key::cast).is_some() {
+            let set = ident.ancestors().find_map(KeyValue::cast)?.node().parent()?;
+            let mut path = module_path(&set)?;
+            path.extend(info.path);
+            path
+        } else {
+            let (base, path) = info.path.split_first()?;
+            if base != "config" || !is_config(&Rc::new(uri.clone()), ident) {
+                return None;
+            }
+            path.to_vec()
+        };
+        path.push(info.ident.as_str().to_owned());
+        if path.first().is_some_and(|name| name == "config") && options.get(&path).is_none() {
+            path.remove(0);
+        }
+        let range = utils::range(code, ident.text_range(), self.encoding);
+        Some(json!([hierarchy_item(options.get(&path)?, &path, uri, range)]))
+    }
+    /// The options directly below an item of the type hierarchy, like the
+    /// ones of a submodule, or with `parent` the options it's part of
+    pub fn type_hierarchy(&self, item: &Value, parent: bool) -> Option<Value> {
+        let options = self.options.as_ref()?;
+        let path: Vec<String> = item["data"]["path"].as_array()?.iter().map(|name| name.as_str().map(String::from)).collect::<Option<_>>()?;
+        let uri: Url = serde_json::from_value(item["uri"].clone()).ok()?;
+        let range: Range = serde_json::from_value(item["range"].clone()).ok()?;
+        if parent {
+            let (_, parent) = path.split_last().filter(|(_, parent)| !parent.is_empty())?;
+            return Some(json!([hierarchy_item(options.get(parent)?, parent, &uri, range)]));
+        }
+        let items: Vec<Value> = options.get(&path)?.children.iter()
+            .map(|(name, child)| {
+                let mut path = path.clone();
+                path.push(name.clone());
+                hierarchy_item(child, &path, &uri, range)
+            })
+            .collect();
+        Some(json!(items))
+    }
+}
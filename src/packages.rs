@@ -0,0 +1,217 @@
+use crate::{App, Error, utils};
+use lsp_types::*;
+use rnix::{
+    types::*,
+    SyntaxKind::{TOKEN_SQUARE_B_OPEN, TOKEN_WHITESPACE},
+    SyntaxNode,
+    TextUnit,
+};
+use serde_json::Value;
+use std::{collections::BTreeMap, convert::TryFrom, fs, path::Path, rc::Rc};
+
+/// The command that reads the package list again, after it changed
+pub const RELOAD_COMMAND: &str = "rnix-lsp.reloadPackages";
+
+/// What's known about a package, if the list includes it
+#[derive(Debug, Default)]
+pub struct Meta {
+    pub version: Option<String>,
+    pub description: Option<String>,
+}
+impl Meta {
+    fn from_json(value: &Value) -> Self {
+        let field = |name| value.get(name).and_then(Value::as_str).filter(|s| !s.is_empty()).map(String::from);
+        Self {
+            version: field("version"),
+            description: field("description"),
+        }
+    }
+    /// Parse the name and description columns of `nix-env -qaP`, like
+    /// `hello-2.10  A program that produces a familiar, friendly greeting`
+    fn from_columns<'a>(mut columns: impl Iterator<Item = &'a str>) -> Self {
+        // Like Nix, the version starts at the first dash that isn't
+        // followed by a letter
+        let version = columns.next().and_then(|name| {
+            let (start, _) = name.match_indices('-')
+                .find(|(i, _)| name[i + 1..].chars().next().is_some_and(|c| !c.is_alphabetic()))?;
+            Some(name[start + 1..].to_owned())
+        });
+        let description = columns.collect::<Vec<_>>().join(" ");
+        Self {
+            version,
+            description: Some(description).filter(|description| !description.is_empty()),
+        }
+    }
+}
+
+/// Names of the packages in nixpkgs, as read from the `packageList`
+/// setting. This is the output of either `nix search --json`,
+/// `nix-env -qaP` (optionally with `--description`), or just one name
+/// per line.
+#[derive(Debug, Default)]
+pub struct Packages {
+    packages: BTreeMap<String, Meta>,
+}
+impl Packages {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)?;
+        let mut packages = Self::default();
+        if content.trim_start().starts_with(['{', '[']) {
+            match serde_json::from_str(&content)? {
+                Value::Object(map) => map.iter().for_each(|(path, value)| packages.insert(strip_prefix(path), Meta::from_json(value))),
+                Value::Array(names) => names.iter().filter_map(Value::as_str).for_each(|name| packages.insert(name, Meta::default())),
+                _ => return Err("the package list should be an object or an array".into()),
+            }
+        } else {
+            for line in content.lines() {
+                let mut fields = line.split_whitespace().peekable();
+                let Some(path) = fields.next() else { continue };
+                // `nix-env -qaP` prints the channel too, like
+                // `nixpkgs.hello  hello-2.10`
+                let path = if fields.peek().is_some() { path.split_once('.').map_or(path, |(_, rest)| rest) } else { path };
+                packages.insert(path, Meta::from_columns(fields));
+            }
+        }
+        Ok(packages)
+    }
+    /// Add a package by its attribute path. Only the first part can be
+    /// typed as a single identifier, so that's what's kept. The meta of
+    /// nested packages like `python3Packages.requests` doesn't describe
+    /// the first part, so it's dropped.
+    fn insert(&mut self, path: &str, meta: Meta) {
+        match path.split_once('.') {
+            Some((name, _)) if !name.is_empty() => {
+                self.packages.entry(name.to_owned()).or_default();
+            },
+            None if !path.is_empty() => {
+                self.packages.insert(path.to_owned(), meta);
+            },
+            _ => (),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.packages.len()
+    }
+    pub fn get(&self, name: &str) -> Option<&Meta> {
+        self.packages.get(name)
+    }
+    pub fn matching<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a String, &'a Meta)> + 'a {
+        self.packages.range(prefix.to_owned()..).take_while(move |(name, _)| name.starts_with(prefix))
+    }
+}
+/// Remove the part of a `nix search --json` attribute path before the
+/// package, `legacyPackages.x86_64-linux.` for flakes or `nixpkgs.` for
+/// channels
+fn strip_prefix(path: &str) -> &str {
+    if let Some(rest) = path.strip_prefix("legacyPackages.") {
+        return rest.split_once('.').map_or(rest, |(_system, rest)| rest);
+    }
+    path.split_once('.').map_or(path, |(_channel, rest)| rest)
+}
+
+/// Whether a list is in the body of a `with pkgs;`, where its items are
+/// likely packages. Lists that are concatenated are included.
+pub fn in_package_list(list: &List) -> bool {
+    let mut node = list.node().clone();
+    loop {
+        let Some(parent) = node.parent() else { return false };
+        match ParsedType::try_from(parent.clone()) {
+            Ok(ParsedType::Paren(_)) => (),
+            Ok(ParsedType::BinOp(op)) if op.operator() == BinOpKind::Concat => (),
+            Ok(ParsedType::With(with)) => {
+                if with.body().as_ref() != Some(&node) {
+                    return false;
+                }
+                return with.namespace().is_some_and(|namespace| is_pkgs(&namespace));
+            },
+            _ => return false,
+        }
+        node = parent;
+    }
+}
+/// `pkgs`, or something ending in it like `config.pkgs`
+fn is_pkgs(node: &SyntaxNode) -> bool {
+    let name = match Select::cast(node.clone()) {
+        Some(select) => select.index().and_then(Ident::cast),
+        None => Ident::cast(node.clone()),
+    };
+    name.is_some_and(|name| name.as_str() == "pkgs")
+}
+/// The list the cursor is in when nothing has been typed yet, like in
+/// `[ a <cursor> ]`
+pub fn list_at(root: &SyntaxNode, offset: usize) -> Option<List> {
+    let token = root.token_at_offset(TextUnit::from_usize(offset)).left_biased()?;
+    if token.kind() != TOKEN_WHITESPACE && token.kind() != TOKEN_SQUARE_B_OPEN {
+        return None;
+    }
+    List::cast(token.parent())
+}
+
+impl App {
+    /// Read the package list again, if one is configured
+    pub fn load_packages(&mut self) {
+        let Some(path) = self.config.package_list.as_deref().map(|path| self.resolve_path(path)) else { return };
+        match Packages::load(&path) {
+            Ok(packages) => {
+                self.log(MessageType::Info, format!("Loaded {} packages from {}", packages.len(), path.display()));
+                self.packages = Some(packages);
+                self.completion_cache = None;
+            },
+            Err(err) => {
+                self.log(MessageType::Warning, format!("Couldn't load the package list {}: {}", path.display(), err));
+            },
+        }
+    }
+    /// Completions for package names, for items of a list in `with pkgs;`
+    pub fn package_completions(&self, prefix: &str, range: Range) -> Vec<CompletionItem> {
+        let Some(packages) = &self.packages else { return Vec::new() };
+        packages.matching(prefix)
+            .map(|(name, meta)| CompletionItem {
+                label: name.clone(),
+                kind: Some(CompletionItemKind::Module),
+                detail: Some(meta.version.as_ref().map_or_else(|| "package".into(), |version| format!("package {version}"))),
+                documentation: meta.description.clone().map(Documentation::String),
+                text_edit: Some(TextEdit {
+                    range,
+                    new_text: name.clone(),
+                }),
+                ..CompletionItem::default()
+            })
+            .collect()
+    }
+    /// Hover for a package in a list in `with pkgs;`, showing what the
+    /// package list knows about it
+    pub fn package_hover(&self, params: &TextDocumentPositionParams) -> Option<Hover> {
+        let packages = self.packages.as_ref()?;
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position, self.encoding)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+        let list = info.ident.node().parent().and_then(List::cast)?;
+        if !in_package_list(&list) {
+            return None;
+        }
+        // Bindings outside of the `with` take precedence
+        let file = Rc::new(params.text_document.uri.clone());
+        let name = info.ident.as_str();
+        if utils::scope_for(&file, info.ident.node().clone())?.contains_key(name) {
+            return None;
+        }
+        let meta = packages.get(name)?;
+
+        let mut value = match &meta.version {
+            Some(version) => format!("`pkgs.{name}` {version}"),
+            None => format!("`pkgs.{name}`"),
+        };
+        if let Some(description) = &meta.description {
+            value.push_str("\n\n");
+            value.push_str(description);
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(utils::range(content, info.ident.node().text_range(), self.encoding)),
+        })
+    }
+}
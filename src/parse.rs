@@ -0,0 +1,177 @@
+use rnix::{
+    parser::ParseError,
+    types::*,
+    SyntaxKind::{
+        NODE_ATTR_SET, NODE_LIST, NODE_PAREN, NODE_STRING, TOKEN_CURLY_B_CLOSE, TOKEN_CURLY_B_OPEN, TOKEN_PAREN_CLOSE,
+        TOKEN_PAREN_OPEN, TOKEN_SQUARE_B_CLOSE, TOKEN_SQUARE_B_OPEN, TOKEN_STRING_END, TOKEN_STRING_START,
+    },
+    NodeOrToken,
+    SyntaxNode,
+    TextRange,
+    TextUnit,
+};
+
+/// A parsed file, like `rnix::AST`, but a new version of it can reuse
+/// the parts of the tree an edit didn't touch
+#[derive(Clone)]
+pub struct Parsed {
+    root: SyntaxNode,
+    /// The errors of the parser itself, not including error nodes
+    errors: Vec<ParseError>,
+}
+impl Parsed {
+    pub fn new(code: &str) -> Self {
+        let ast = rnix::parse(code);
+        Self { root: ast.node(), errors: ast.root_errors().to_vec() }
+    }
+    pub fn node(&self) -> SyntaxNode {
+        self.root.clone()
+    }
+    pub fn root(&self) -> Root {
+        Root::cast(self.node()).unwrap()
+    }
+    /// All errors of the file, like `rnix::AST::errors`
+    pub fn errors(&self) -> Vec<ParseError> {
+        let mut errors = self.errors.clone();
+        errors.extend(self.root().errors().into_iter().map(|node| ParseError::Unexpected(node.text_range())));
+        errors
+    }
+    /// Parse a new version of the code this was parsed from. If the
+    /// change is inside one list, set, parenthesized expression or
+    /// string, and its new code is still one of those on its own, only
+    /// that is parsed again and put into the old tree. The result is the
+    /// same as parsing everything again, which is what's done otherwise.
+    pub fn reparse(&self, old: &str, new: &str) -> Self {
+        self.reparse_changed(old, new).unwrap_or_else(|| Self::new(new))
+    }
+    fn reparse_changed(&self, old: &str, new: &str) -> Option<Self> {
+        // Error positions after the change would have to be moved
+        if !self.errors.is_empty() {
+            return None;
+        }
+        let (start, old_end, new_end) = changed(old, new);
+        let change = TextRange::from_to(TextUnit::from_usize(start), TextUnit::from_usize(old_end));
+        let node = match self.root.covering_element(change) {
+            NodeOrToken::Node(node) => node,
+            NodeOrToken::Token(token) => token.parent(),
+        };
+        let node = node.ancestors().find(|node| encloses(node, change))?;
+
+        let range = node.text_range();
+        let end = range.end().to_usize() - old_end + new_end;
+        let fragment = rnix::parse(&new[range.start().to_usize()..end]);
+        let mut children = fragment.node().children_with_tokens();
+        let (Some(NodeOrToken::Node(replacement)), None) = (children.next(), children.next()) else { return None };
+        let valid = replacement.kind() == node.kind()
+            && fragment.root_errors().is_empty()
+            && fragment.root().errors().is_empty()
+            && !is_empty_set(&replacement);
+        if !valid {
+            return None;
+        }
+        let root = SyntaxNode::new_root(node.replace_with(replacement.green().clone()));
+        Some(Self { root, errors: Vec::new() })
+    }
+}
+
+/// Where two versions of code differ, as the start of the change and
+/// where it ends in the old and the new code
+fn changed(old: &str, new: &str) -> (usize, usize, usize) {
+    let mut start = old.bytes().zip(new.bytes()).take_while(|(a, b)| a == b).count();
+    while !old.is_char_boundary(start) || !new.is_char_boundary(start) {
+        start -= 1;
+    }
+    let max_suffix = old.len().min(new.len()) - start;
+    let mut suffix = old.bytes().rev().zip(new.bytes().rev()).take(max_suffix).take_while(|(a, b)| a == b).count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+    (start, old.len() - suffix, new.len() - suffix)
+}
+
+/// Whether a node can be parsed again on its own and the change is
+/// strictly between its brackets or quotes. What's around a change there
+/// can't change how it's parsed, and it can't change what's around it
+/// as long as the brackets still match.
+fn encloses(node: &SyntaxNode, change: TextRange) -> bool {
+    let (open, close) = match node.kind() {
+        NODE_LIST => (TOKEN_SQUARE_B_OPEN, TOKEN_SQUARE_B_CLOSE),
+        NODE_ATTR_SET => (TOKEN_CURLY_B_OPEN, TOKEN_CURLY_B_CLOSE),
+        NODE_PAREN => (TOKEN_PAREN_OPEN, TOKEN_PAREN_CLOSE),
+        NODE_STRING => (TOKEN_STRING_START, TOKEN_STRING_END),
+        _ => return false,
+    };
+    // The first token of `rec { ... }` is `rec`
+    let open = node.children_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+        .find(|token| token.kind() == open);
+    let close = node.last_token().filter(|token| token.kind() == close && token.parent() == *node);
+    match (open, close) {
+        (Some(open), Some(close)) => open.text_range().end() <= change.start() && change.end() <= close.text_range().start(),
+        _ => false,
+    }
+}
+
+/// Whether a node is `{ }`, which is a set on its own but the pattern of
+/// a function if `:` follows it
+fn is_empty_set(node: &SyntaxNode) -> bool {
+    node.kind() == NODE_ATTR_SET && node.children_with_tokens().filter(|child| !child.kind().is_trivia()).count() <= 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Parsed;
+    use rnix::{NodeOrToken, SyntaxNode};
+
+    /// Code with lists, sets, parentheses and strings to edit inside
+    const CODE: &[&str] = &[
+        "[ 1 (2) \"three\" { a = 4; } ]",
+        "{ a = [ 1 2 ]; b = { c = \"${d}\"; }; e = (f 1); }",
+        "rec { a = ''\n  x ${y}\n''; b = a; }",
+        "let f = { x ? { }, ... }: x; in f { }",
+        "{ inherit (a) b; c = \"d\"; } // { }",
+    ];
+    /// What's inserted, including ones that unbalance brackets or quotes
+    /// and start comments
+    const INSERTED: &[&str] = &["x", " ", ";", "[", "]", "{", "}", "(", ")", "\"", "''", "${", "#", "/*", "=", "a = 1;"];
+
+    /// Every node and token, with its range and text
+    fn dump(node: &SyntaxNode) -> String {
+        node.descendants_with_tokens()
+            .map(|element| match element {
+                NodeOrToken::Node(node) => format!("{:?}@{:?}\n", node.kind(), node.text_range()),
+                NodeOrToken::Token(token) => format!("{:?}@{:?} {:?}\n", token.kind(), token.text_range(), token.text()),
+            })
+            .collect()
+    }
+    fn assert_same(old: &str, new: &str) {
+        let reparsed = Parsed::new(old).reparse(old, new);
+        let parsed = Parsed::new(new);
+        assert_eq!(dump(&reparsed.node()), dump(&parsed.node()), "{old:?} to {new:?}");
+        assert_eq!(format!("{:?}", reparsed.errors()), format!("{:?}", parsed.errors()), "{old:?} to {new:?}");
+    }
+
+    #[test]
+    fn same_as_parsing_again() {
+        for &old in CODE {
+            for (offset, c) in old.char_indices() {
+                assert_same(old, &format!("{}{}", &old[..offset], &old[offset + c.len_utf8()..]));
+                for inserted in INSERTED {
+                    assert_same(old, &format!("{}{inserted}{}", &old[..offset], &old[offset..]));
+                }
+            }
+        }
+    }
+    #[test]
+    fn reuses_unchanged_parts() {
+        let old = "{ a = [ 1 2 ]; b = 3; }";
+        let parsed = Parsed::new(old);
+        let new = "{ a = [ 1 2 5 ]; b = 3; }";
+        assert!(parsed.reparse_changed(old, new).is_some());
+        // Unbalanced, so it's parsed again from scratch
+        assert!(parsed.reparse_changed(old, "{ a = [ 1 2 } ]; b = 3; }").is_none());
+        // `{ }` followed by `:` would be a pattern
+        let old = "f { a = 1; }";
+        assert!(Parsed::new(old).reparse_changed(old, "f { }").is_none());
+    }
+}
@@ -0,0 +1,684 @@
+use crate::{App, Error, builtins, utils::{self, Encoding, Var, VarKind}};
+use lsp_types::*;
+use rnix::{
+    types::*,
+    SyntaxKind::{NODE_IDENT, NODE_SELECT, TOKEN_WHITESPACE},
+    SyntaxNode,
+    TextRange,
+    TextUnit,
+};
+use std::{collections::{HashMap, HashSet}, convert::TryFrom, rc::Rc};
+
+/// What a Nix identifier looks like, so editors know when typing stops
+/// being a rename
+pub const IDENT_PATTERN: &str = "[a-zA-Z_][a-zA-Z0-9_'-]*";
+
+/// The attribute of a set literal that is being renamed
+pub struct Target {
+    /// The file the set is defined in
+    file: Rc<Url>,
+    set: AttrSet,
+    name: String,
+}
+
+/// The variables and selects of a file that evaluate to set literals,
+/// see `set_values`
+struct FileValues {
+    file: Rc<Url>,
+    root: SyntaxNode,
+    values: Vec<(SyntaxNode, SyntaxNode)>,
+}
+
+/// Where a value ends up, as far as renaming is concerned
+enum Flow {
+    /// Only used in ways we know all the uses of
+    Known,
+    /// Stored as an attribute of another set, which means that set's
+    /// uses have to be known too
+    Container(SyntaxNode),
+    /// The value of its file, which other files can import
+    Exported,
+    /// Passed somewhere we can't follow, like a function
+    Opaque,
+}
+
+/// Follow a value up the tree until it's used or bound to a name
+fn flow(node: &SyntaxNode) -> Flow {
+    let mut node = node.clone();
+    loop {
+        let Some(parent) = node.parent() else { return Flow::Opaque };
+        match ParsedType::try_from(parent.clone()) {
+            // Expressions that evaluate to the value
+            Ok(ParsedType::Paren(_) | ParsedType::Assert(_) | ParsedType::IfElse(_) | ParsedType::LetIn(_)) => (),
+            Ok(ParsedType::With(with)) => if with.namespace().as_ref() == Some(&node) {
+                // Uses in the body are found by looking up their scope
+                return Flow::Known;
+            },
+            Ok(ParsedType::Select(select)) => {
+                let dynamic = select.index().and_then(|index| utils::static_name(&index)).is_none();
+                return if select.set().as_ref() == Some(&node) && !dynamic { Flow::Known } else { Flow::Opaque };
+            },
+            Ok(ParsedType::InheritFrom(_)) => return Flow::Known,
+            Ok(ParsedType::KeyValue(entry)) => {
+                if entry.value().as_ref() != Some(&node) {
+                    return Flow::Opaque;
+                }
+                return container(entry.node().parent());
+            },
+            Ok(ParsedType::Inherit(inherit)) => return container(inherit.node().parent()),
+            Ok(ParsedType::Root(_)) => return Flow::Exported,
+            _ => return Flow::Opaque,
+        }
+        node = parent;
+    }
+}
+/// Where an attribute or `let` binding in `holder` ends up
+fn container(holder: Option<SyntaxNode>) -> Flow {
+    match holder.map(ParsedType::try_from) {
+        Some(Ok(ParsedType::LetIn(_) | ParsedType::LegacyLet(_))) => Flow::Known,
+        Some(Ok(ParsedType::AttrSet(set))) => Flow::Container(set.node().clone()),
+        _ => Flow::Opaque,
+    }
+}
+
+impl App {
+    /// The attribute at the cursor, if it's one of a set literal that
+    /// can be renamed. The name is an identifier or a quoted name like
+    /// `"a b"`. Returns `None` for variables, which are renamed the usual
+    /// way.
+    pub fn rename_target(&mut self, file: &Rc<Url>, node: &SyntaxNode) -> Result<Option<Target>, Error> {
+        let (Some(parent), Some(name)) = (node.parent(), utils::static_name(node)) else { return Ok(None) };
+
+        if let Some(key) = Key::cast(parent.clone()) {
+            if key.path().next().as_ref() != Some(node) {
+                return Err("renaming nested attributes like the `b` in `a.b = ...;` isn't supported".into());
+            }
+            let set = key.node().parent().and_then(|entry| entry.parent()).and_then(AttrSet::cast);
+            return Ok(set.filter(|set| !utils::is_recursive(set)).map(|set| Target { file: Rc::clone(file), set, name }));
+        }
+
+        if let Some(inherit) = Inherit::cast(parent.clone()) {
+            // Inherits in a set define attributes, too
+            let set = inherit.node().parent().and_then(AttrSet::cast);
+            return Ok(set.filter(|set| !utils::is_recursive(set)).map(|set| Target { file: Rc::clone(file), set, name }));
+        }
+
+        let Some(select) = Select::cast(parent) else { return Ok(None) };
+        if select.index().as_ref() != Some(node) {
+            return Ok(None);
+        }
+        let var = select.set()
+            .and_then(|set| self.scope_from_node(file, set))
+            .and_then(|mut scope| scope.remove(&name))
+            .ok_or("couldn't find where this attribute is defined")?;
+        match AttrSet::cast(var.set) {
+            Some(set) if !utils::is_recursive(&set) => Ok(Some(Target { file: var.file, set, name })),
+            _ => Err("renaming attributes of recursive sets isn't supported".into()),
+        }
+    }
+    /// Rename an attribute, along with all accesses to it like `set.a`
+    /// and `inherit (set) a;`. Refuses if the set is used somewhere we
+    /// can't follow, since some accesses might be missed. A set that is
+    /// the value of its file can be imported anywhere, so it's only
+    /// renamed in workspace folders, along with its uses in other files
+    /// of the workspace.
+    pub fn rename_attr(&mut self, target: &Target, new_name: &str) -> Result<HashMap<Url, Vec<TextEdit>>, Error> {
+        let old = target.name.as_str();
+        let set = target.set.node();
+        let defines = |name: &str| {
+            target.set.entries().any(|entry| {
+                entry.key().and_then(|key| key.path().next()).and_then(|key| utils::static_name(&key)).as_deref() == Some(name)
+            }) || target.set.inherits().flat_map(|inherit| inherit.idents()).any(|ident| ident.as_str() == name)
+        };
+        if defines(new_name) {
+            return Err(format!("the set already has an attribute called `{new_name}`").into());
+        }
+
+        // Find everything that evaluates to the set, or to a set
+        // containing it. Containers matter because `outer.inner.a`
+        // accesses `a` as well.
+        let Some(root) = self.files.get(&target.file).map(|(ast, _)| ast.node()) else { return Ok(HashMap::new()) };
+        let Some(values) = self.set_values(&target.file, &root) else { return Ok(HashMap::new()) };
+        let mut files = vec![FileValues { file: Rc::clone(&target.file), root, values }];
+
+        let mut watched = HashSet::new();
+        let mut queue = vec![set.clone()];
+        let mut imported = false;
+        'watch: while let Some(watch) = queue.pop() {
+            if !watched.insert(watch.clone()) {
+                continue;
+            }
+            let uses: Vec<SyntaxNode> = files.iter()
+                .flat_map(|file| &file.values)
+                .filter(|(_, resolved)| *resolved == watch)
+                .map(|(node, _)| node.clone())
+                .collect();
+            for node in std::iter::once(watch).chain(uses) {
+                match flow(&node) {
+                    Flow::Known => (),
+                    Flow::Container(container) => queue.push(container),
+                    Flow::Exported if imported => (),
+                    Flow::Exported => {
+                        if self.root_for(&target.file).is_none() {
+                            return Err(format!("can't rename `{old}`, the set is the value of this file and could be imported anywhere").into());
+                        }
+                        let Some(others) = self.workspace_values(&target.file, old) else { return Ok(HashMap::new()) };
+                        files.extend(others);
+                        imported = true;
+                        // Start over, so the uses in other files are
+                        // followed too
+                        watched.clear();
+                        queue = vec![set.clone()];
+                        continue 'watch;
+                    },
+                    Flow::Opaque => {
+                        let parent = node.parent().map(|parent| parent.text().to_string()).unwrap_or_default();
+                        return Err(format!("can't rename `{old}`, the set is used in a way that can't be followed: `{parent}`").into());
+                    },
+                }
+            }
+        }
+
+        let mut changes = HashMap::new();
+        for file in &files {
+            let edits = self.attr_edits(file, target, new_name)?;
+            if !edits.is_empty() {
+                changes.insert((*file.file).clone(), edits);
+            }
+        }
+        Ok(changes)
+    }
+    /// The edits renaming an attribute in one file: its definitions if
+    /// they're in the file, its accesses, and uses through `with`
+    fn attr_edits(&mut self, file: &FileValues, target: &Target, new_name: &str) -> Result<Vec<TextEdit>, Error> {
+        let Some((_, code)) = self.files.get(&file.file) else { return Ok(Vec::new()) };
+        let code = code.clone();
+        let code = code.as_str();
+        let old = target.name.as_str();
+        let set = target.set.node();
+        let encoding = self.encoding;
+        // Keys and accesses can be quoted, but not uses through `with`
+        let quoted = utils::escape_key(new_name);
+
+        let mut edits = Vec::new();
+        let rename = |edits: &mut Vec<TextEdit>, node: &SyntaxNode| edits.push(TextEdit {
+            range: utils::range(code, node.text_range(), encoding),
+            new_text: quoted.clone(),
+        });
+
+        // The definitions themselves
+        if file.file == target.file {
+            for entry in target.set.entries() {
+                let first = entry.key().and_then(|key| key.path().next());
+                if let Some(key) = first.filter(|key| utils::static_name(key).as_deref() == Some(old)) {
+                    rename(&mut edits, &key);
+                }
+            }
+            for inherit in target.set.inherits() {
+                let from = inherited_from(&inherit).map(|from| format!("{from}."));
+                for ident in inherit.idents().filter(|ident| ident.as_str() == old) {
+                    let binding = format!("{quoted} = {}{old};", from.as_deref().unwrap_or(""));
+                    edits.extend(split_inherit(code, encoding, &inherit, &ident, &binding));
+                }
+            }
+        }
+
+        // Accesses of the set
+        for (node, resolved) in &file.values {
+            if resolved != set {
+                continue;
+            }
+            let Some(parent) = node.parent() else { continue };
+            let parent = match Paren::cast(parent.clone()) {
+                // Like `(import ./file.nix).a`
+                Some(paren) => paren.node().parent().unwrap_or(parent),
+                None => parent,
+            };
+            if let Some(select) = Select::cast(parent.clone()) {
+                if let Some(index) = select.index().filter(|index| utils::static_name(index).as_deref() == Some(old)) {
+                    rename(&mut edits, &index);
+                }
+            } else if let Some(inherit) = InheritFrom::cast(parent).and_then(|from| from.node().parent()).and_then(Inherit::cast) {
+                let from = inherited_from(&inherit).unwrap_or_default();
+                for ident in inherit.idents().filter(|ident| ident.as_str() == old) {
+                    let binding = format!("{old} = {from}.{quoted};");
+                    edits.extend(split_inherit(code, encoding, &inherit, &ident, &binding));
+                }
+            }
+        }
+
+        // Uses brought into scope by `with`
+        for node in file.root.descendants() {
+            let Some(ident) = Ident::cast(node.clone()).filter(|ident| ident.as_str() == old && utils::is_reference(ident)) else { continue };
+            let Some(scope) = self.scope_at(&file.file, &node) else { continue };
+            let from_set = scope.get(old).is_some_and(|var| matches!(var.kind, VarKind::With(_)) && var.set == *set);
+            if !from_set {
+                continue;
+            }
+            if scope.contains_key(new_name) {
+                return Err(format!("can't rename `{old}`, its use in `with` would refer to a different `{new_name}`").into());
+            }
+            if !utils::is_ident(new_name) {
+                return Err(format!("can't rename `{old}` to `{new_name}`, it's used through `with`, where it has to be an identifier").into());
+            }
+            match node.parent().and_then(Inherit::cast) {
+                Some(inherit) => edits.extend(split_inherit(code, encoding, &inherit, &ident, &format!("{old} = {new_name};"))),
+                None => rename(&mut edits, ident.node()),
+            }
+        }
+
+        Ok(edits)
+    }
+    /// The values of the other files in the workspace that mention
+    /// `name`, since only those can access an attribute of that name.
+    /// Returns `None` if the request was cancelled.
+    fn workspace_values(&mut self, file: &Url, name: &str) -> Option<Vec<FileValues>> {
+        let others: Vec<Url> = self.files.keys()
+            .filter(|uri| *uri != file && self.root_for(uri).is_some())
+            .cloned()
+            .collect();
+        let mut files = Vec::new();
+        for uri in others {
+            self.refresh(&uri);
+            let Some((ast, code)) = self.files.get(&uri) else { continue };
+            if !code.contains(name) {
+                continue;
+            }
+            let root = ast.node();
+            let file = Rc::new(uri);
+            let values = self.set_values(&file, &root)?;
+            files.push(FileValues { file, root, values });
+        }
+        Some(files)
+    }
+}
+
+impl App {
+    /// Every variable, select and import in the file that evaluates to a
+    /// set literal, along with that set. Returns `None` if the request was
+    /// cancelled.
+    fn set_values(&mut self, file: &Rc<Url>, root: &SyntaxNode) -> Option<Vec<(SyntaxNode, SyntaxNode)>> {
+        let mut values = Vec::new();
+        for node in root.descendants() {
+            if self.is_cancelled() {
+                return None;
+            }
+            let is_value = match ParsedType::try_from(node.clone()) {
+                Ok(ParsedType::Ident(ident)) => utils::is_reference(&ident),
+                Ok(ParsedType::Select(_)) => true,
+                // Imports of the file a set is the value of
+                Ok(ParsedType::Apply(apply)) => apply.lambda().and_then(Ident::cast).is_some_and(|ident| ident.as_str() == "import"),
+                _ => false,
+            };
+            if !is_value {
+                continue;
+            }
+            let resolved = self.scope_from_node(file, node.clone())
+                .and_then(|scope| scope.into_iter().next())
+                .map(|(_, var)| var.set);
+            if let Some(resolved) = resolved {
+                values.push((node, resolved));
+            }
+        }
+        Some(values)
+    }
+    /// The definitions of an attribute and the accesses to it that can
+    /// be resolved, for highlighting. Unlike renaming, this doesn't need
+    /// to know every use of the set.
+    pub fn attr_highlights(&mut self, file: &Rc<Url>, root: &SyntaxNode, code: &str, target: &Target) -> Vec<DocumentHighlight> {
+        let name = target.name.as_str();
+        let set = target.set.node();
+        let encoding = self.encoding;
+        let mut highlights = Vec::new();
+        let mut highlight = |node: &SyntaxNode, kind| highlights.push(DocumentHighlight {
+            range: utils::range(code, node.text_range(), encoding),
+            kind: Some(kind),
+        });
+
+        if target.file == *file {
+            let keys = target.set.entries().filter_map(|entry| entry.key()?.path().next());
+            let inherited = target.set.inherits().flat_map(|inherit| inherit.idents()).map(|ident| ident.node().clone());
+            for key in keys.chain(inherited).filter(|key| utils::static_name(key).as_deref() == Some(name)) {
+                highlight(&key, DocumentHighlightKind::Write);
+            }
+        }
+
+        let Some(values) = self.set_values(file, root) else { return Vec::new() };
+        for (node, resolved) in &values {
+            if resolved != set {
+                continue;
+            }
+            let Some(parent) = node.parent() else { continue };
+            if let Some(select) = Select::cast(parent.clone()) {
+                if let Some(index) = select.index().filter(|index| utils::static_name(index).as_deref() == Some(name)) {
+                    highlight(&index, DocumentHighlightKind::Read);
+                }
+            } else if let Some(inherit) = InheritFrom::cast(parent).and_then(|from| from.node().parent()).and_then(Inherit::cast) {
+                for ident in inherit.idents().filter(|ident| ident.as_str() == name) {
+                    highlight(ident.node(), DocumentHighlightKind::Read);
+                }
+            }
+        }
+
+        // Uses brought into scope by `with`. Nested sets or bindings of
+        // the same name shadow it, which the scope takes care of.
+        for node in root.descendants() {
+            let Some(ident) = Ident::cast(node.clone()).filter(|ident| ident.as_str() == name && utils::is_reference(ident)) else { continue };
+            let from_set = self.scope_at(file, &node)
+                .and_then(|mut scope| scope.remove(name))
+                .is_some_and(|var| matches!(var.kind, VarKind::With(_)) && var.set == *set);
+            if from_set {
+                highlight(ident.node(), DocumentHighlightKind::Read);
+            }
+        }
+        highlights
+    }
+    /// Where a variable or an attribute of a set literal is defined and
+    /// used. Uses of an attribute through `with` count, unless a closer
+    /// binding of the same name shadows it. For attributes, that's in the
+    /// file of the set and the one asked about, since other files are
+    /// only searched by renaming.
+    pub fn name_references(&mut self, file: &Rc<Url>, root: &SyntaxNode, code: &str, name: &SyntaxNode, declarations: bool) -> Vec<Location> {
+        let target = match self.rename_target(file, name) {
+            Ok(Some(target)) => Some(target),
+            Ok(None) => Ident::cast(name.clone()).and_then(|ident| {
+                // A bare name from `with`, which is an attribute too
+                let var = self.scope_at(file, name)?.remove(ident.as_str())?;
+                if !matches!(var.kind, VarKind::With(_)) || !utils::is_reference(&ident) {
+                    return None;
+                }
+                let set = AttrSet::cast(var.set)?;
+                Some(Target { file: var.file, set, name: ident.as_str().into() })
+            }),
+            Err(_) => return Vec::new(),
+        };
+        let Some(target) = target else {
+            // A variable, whose uses are all in this file
+            let Some(ident) = Ident::cast(name.clone()) else { return Vec::new() };
+            let Some(definition) = binding(file, &ident) else { return Vec::new() };
+            return root.descendants()
+                .filter_map(Ident::cast)
+                .filter(|other| other.as_str() == ident.as_str() && binding(file, other).as_ref() == Some(&definition))
+                .filter(|other| declarations || *other.node() != definition)
+                .map(|other| Location::new((**file).clone(), utils::range(code, other.node().text_range(), self.encoding)))
+                .collect();
+        };
+
+        let mut files = vec![Rc::clone(&target.file)];
+        if target.file != *file {
+            files.push(Rc::clone(file));
+        }
+        let mut locations = Vec::new();
+        for uri in files {
+            let Some((root, code)) = self.files.get(&uri).map(|(ast, code)| (ast.node(), code.clone())) else { continue };
+            let highlights = self.attr_highlights(&uri, &root, &code, &target);
+            locations.extend(highlights.into_iter()
+                .filter(|highlight| declarations || highlight.kind != Some(DocumentHighlightKind::Write))
+                .map(|highlight| Location::new((*uri).clone(), highlight.range)));
+        }
+        locations
+    }
+    /// The ranges of a name that can be edited together, as it's typed.
+    /// Those are all uses of the same variable or attribute in the file.
+    /// Returns `None` if that isn't known, or if one of them is in an
+    /// `inherit`, where changing the name changes where it comes from.
+    pub fn linked_ranges(&mut self, file: &Rc<Url>, root: &SyntaxNode, code: &str, ident: &Ident) -> Option<Vec<Range>> {
+        let nodes = match self.rename_target(file, ident.node()) {
+            // Editing the uses in this file only would break the others
+            Ok(Some(target)) if target.file != *file => return None,
+            Ok(Some(target)) => {
+                let highlights = self.attr_highlights(file, root, code, &target);
+                highlights.iter()
+                    .map(|highlight| {
+                        let offset = utils::lookup_pos(code, highlight.range.start, self.encoding)?;
+                        root.token_at_offset(TextUnit::from_usize(offset)).right_biased().map(|token| token.parent())
+                    })
+                    .collect::<Option<Vec<_>>>()?
+            },
+            Ok(None) => {
+                // A quoted key like `"a" = 1;` isn't written like its uses
+                let definition = binding(file, ident).filter(|key| Ident::cast(key.clone()).is_some())?;
+                root.descendants()
+                    .filter_map(Ident::cast)
+                    .filter(|other| other.as_str() == ident.as_str() && binding(file, other).as_ref() == Some(&definition))
+                    .map(|other| other.node().clone())
+                    .collect()
+            },
+            Err(_) => return None,
+        };
+        if nodes.iter().any(|node| node.parent().and_then(Inherit::cast).is_some()) {
+            return None;
+        }
+        // Quoted names like `"a"` aren't written like `a`
+        if nodes.iter().any(|node| Ident::cast(node.clone()).is_none()) {
+            return None;
+        }
+        Some(nodes.iter().map(|node| utils::range(code, node.text_range(), self.encoding)).collect())
+    }
+}
+
+/// The key of the variable an identifier refers to or defines
+pub fn binding(file: &Rc<Url>, ident: &Ident) -> Option<SyntaxNode> {
+    let var = utils::scope_for(file, ident.node().clone())?.remove(ident.as_str())?;
+    if utils::is_reference(ident) || var.key == *ident.node() {
+        Some(var.key)
+    } else {
+        None
+    }
+}
+
+/// Check that renaming a variable to `new_name` doesn't make any name
+/// refer to something else, and list all that would if it does. That's
+/// uses of the variable that a closer binding of `new_name` would
+/// capture, like in `let a = 1; f = b: a;` when renaming `a` to `b`,
+/// and uses of `new_name` inside the scope of the variable that it would
+/// capture, like ones from an outer scope, `with` or builtins.
+pub fn check_variable_rename(file: &Rc<Url>, code: &str, definition: &Var, old: &str, new_name: &str) -> Result<(), Error> {
+    let line = |node: &SyntaxNode| utils::offset_to_pos(code, node.text_range().start().to_usize(), Encoding::Utf8).line + 1;
+    let inside = |node: &SyntaxNode| *node != definition.set && node.ancestors().any(|ancestor| ancestor == definition.set);
+    let mut conflicts = Vec::new();
+
+    let existing = utils::scope_for(file, definition.key.clone())
+        .and_then(|mut scope| scope.remove(new_name))
+        .filter(|var| var.set == definition.set);
+    if let Some(existing) = existing {
+        conflicts.push(format!("`{new_name}` is already defined on line {}", line(&existing.key)));
+    }
+    for ident in definition.set.descendants().filter_map(Ident::cast) {
+        if !utils::is_reference(&ident) || (ident.as_str() != old && ident.as_str() != new_name) {
+            continue;
+        }
+        let Some(scope) = utils::scope_for(file, ident.node().clone()) else { continue };
+        if ident.as_str() == old {
+            if scope.get(old).is_none_or(|var| var.key != definition.key) {
+                continue;
+            }
+            if let Some(closer) = scope.get(new_name).filter(|var| inside(&var.set)) {
+                conflicts.push(format!("the use on line {} would refer to the `{new_name}` on line {}", line(ident.node()), line(&closer.key)));
+            }
+            continue;
+        }
+        // Builtins like `toString` win over `with`
+        let builtin = builtins::get(new_name).is_some_and(|builtin| builtin.global);
+        let instead = match scope.get(new_name) {
+            Some(var) if var.set == definition.set || inside(&var.set) => continue,
+            Some(var) => format!(" instead of the one on line {}", line(&var.key)),
+            None if builtin => " instead of the builtin".into(),
+            None => match ident.node().ancestors().find_map(With::cast) {
+                Some(with) => format!(" instead of coming from the `with` on line {}", line(with.node())),
+                None => String::new(),
+            },
+        };
+        conflicts.push(format!("the `{new_name}` on line {} would refer to the renamed `{old}`{instead}", line(ident.node())));
+    }
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("can't rename `{old}` to `{new_name}`: {}", conflicts.join("; ")).into())
+    }
+}
+
+/// The set of an `inherit (set) ...;`, as it's written before `.name`,
+/// without the parentheses unless they're needed, like for `(import ./a.nix)`
+fn inherited_from(inherit: &Inherit) -> Option<String> {
+    let set = inherit.from()?.inner()?;
+    Some(match set.kind() {
+        NODE_IDENT | NODE_SELECT => set.text().to_string(),
+        _ => format!("({})", set.text()),
+    })
+}
+
+/// Take an identifier out of an `inherit` and add `binding` in its place,
+/// for when it can't be inherited under the same name anymore
+pub fn split_inherit(code: &str, encoding: Encoding, inherit: &Inherit, ident: &Ident, binding: &str) -> Vec<TextEdit> {
+    if inherit.idents().count() == 1 {
+        return vec![TextEdit {
+            range: utils::range(code, inherit.node().text_range(), encoding),
+            new_text: binding.into(),
+        }];
+    }
+    // Remove the identifier along with the whitespace before it
+    let start = ident.node().prev_sibling_or_token()
+        .filter(|token| token.kind() == TOKEN_WHITESPACE)
+        .map_or(ident.node().text_range().start(), |token| token.text_range().start());
+    let removed = TextRange::from_to(start, ident.node().text_range().end());
+    let end = TextRange::from_to(inherit.node().text_range().end(), inherit.node().text_range().end());
+    vec![
+        TextEdit {
+            range: utils::range(code, removed, encoding),
+            new_text: String::new(),
+        },
+        TextEdit {
+            range: utils::range(code, end, encoding),
+            new_text: format!(" {binding}"),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use lsp_types::{request::References, *};
+    use serde_json::json;
+
+    /// Code with what's found marked by `[` and `]`
+    fn marked(code: &str, ranges: &[Range]) -> String {
+        let edits: Vec<TextEdit> = ranges.iter()
+            .flat_map(|range| [
+                TextEdit { range: Range::new(range.start, range.start), new_text: "[".into() },
+                TextEdit { range: Range::new(range.end, range.end), new_text: "]".into() },
+            ])
+            .collect();
+        testing::apply(code, &edits)
+    }
+    /// The code with the references of the name at `$0` marked
+    fn references(code: &str) -> String {
+        let (mut app, client) = testing::app(&json!({}));
+        let position = app.open_at("default.nix", code);
+        let locations = testing::request::<References>(&mut app, &client, ReferenceParams {
+            text_document_position: position,
+            context: ReferenceContext { include_declaration: true },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        });
+        let locations: Vec<Location> = serde_json::from_value(locations).unwrap();
+        let ranges: Vec<Range> = locations.into_iter().map(|location| location.range).collect();
+        marked(&testing::cursor(code).0, &ranges)
+    }
+
+    #[test]
+    fn references_through_with() {
+        assert_eq!(references("let s = { x = 1; }; in with s; x$0 + s.x"), "let s = { [x] = 1; }; in with s; [x] + s.[x]");
+        assert_eq!(references("let s = { x$0 = 1; }; in with s; x + s.x"), "let s = { [x] = 1; }; in with s; [x] + s.[x]");
+        // Variables come before names from `with`, wherever they're bound
+        assert_eq!(references("let s = { x = 1; }; in with s; let x = 2; in x$0"), "let s = { x = 1; }; in with s; let [x] = 2; in [x]");
+        assert_eq!(references("let x = 2; s = { x = 1; }; in with s; x$0"), "let [x] = 2; s = { x = 1; }; in with s; [x]");
+        assert_eq!(references("let s = { x = 1; }; in with s; (x: x$0) x"), "let s = { x = 1; }; in with s; ([x]: [x]) x");
+        assert_eq!(references("let s = { x = 1; }; in with s; (x: x) x$0"), "let s = { [x] = 1; }; in with s; (x: x) [x]");
+        // The innermost `with` is the one names come from
+        assert_eq!(
+            references("let a = { x = 1; }; b = { x = 2; }; in with a; with b; x$0 + a.x"),
+            "let a = { x = 1; }; b = { [x] = 2; }; in with a; with b; [x] + a.x",
+        );
+    }
+
+    /// The code after renaming the variable at `$0`, or why it can't be
+    fn rename(code: &str, new_name: &str) -> Result<String, String> {
+        let (mut app, _client) = testing::app(&json!({}));
+        let position = app.open_at("default.nix", code);
+        let uri = position.text_document.uri.clone();
+        let changes = app.rename(RenameParams {
+            text_document_position: position,
+            new_name: new_name.into(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        });
+        let mut changes = changes.map_err(|err| err.to_string())?.expect("nothing to rename");
+        Ok(testing::apply(&testing::cursor(code).0, &changes.remove(&uri).unwrap_or_default()))
+    }
+    fn refused(code: &str, new_name: &str) -> String {
+        rename(code, new_name).expect_err("rename wasn't refused")
+    }
+
+    #[test]
+    fn renames_without_capture() {
+        assert_eq!(rename("let a$0 = 1; in a + (b: b)", "c").as_deref(), Ok("let c = 1; in c + (b: b)"));
+        // The `b` of the function is its own
+        assert_eq!(rename("let a$0 = 1; in a + (b: b)", "b").as_deref(), Ok("let b = 1; in b + (b: b)"));
+        assert_eq!(rename("let a = 1; in a$0 + (a: a)", "b").as_deref(), Ok("let b = 1; in b + (a: a)"));
+    }
+    #[test]
+    fn renames_with_capture() {
+        let err = refused("let a = 1; f = b: a$0; in f", "b");
+        assert!(err.contains("the use on line 1 would refer to the `b` on line 1"), "{}", err);
+        let err = refused("let a$0 = 1; b = 2; in a + b", "b");
+        assert!(err.contains("`b` is already defined on line 1"), "{}", err);
+        let err = refused("let b = 1; in\nlet a$0 = 2; in\na + b", "b");
+        assert!(err.contains("the `b` on line 3 would refer to the renamed `a` instead of the one on line 1"), "{}", err);
+        let err = refused("let a$0 = 1; in a + toString 1", "toString");
+        assert!(err.contains("instead of the builtin"), "{}", err);
+        let err = refused("with pkgs;\nlet a$0 = 1; in a + hello", "hello");
+        assert!(err.contains("instead of coming from the `with` on line 1"), "{}", err);
+    }
+
+    #[test]
+    fn attribute_renames() {
+        assert_eq!(rename("let s = { a$0 = 1; }; in s.a + s.a", "b").as_deref(), Ok("let s = { b = 1; }; in s.b + s.b"));
+        assert_eq!(rename("let s = { a = 1; }; in s.a$0", "b").as_deref(), Ok("let s = { b = 1; }; in s.b"));
+        assert_eq!(rename("let s = { a$0 = 1; }; in { inherit (s) a; }", "b").as_deref(), Ok("let s = { b = 1; }; in { a = s.b; }"));
+        assert_eq!(
+            rename("let s = { a$0 = 1; c = 2; }; in { inherit (s) a c; }", "b").as_deref(),
+            Ok("let s = { b = 1; c = 2; }; in { inherit (s) c; a = s.b; }"),
+        );
+        assert_eq!(
+            rename("let s = { a$0 = 1; }; in { inherit ((s)) a; }", "b").as_deref(),
+            Ok("let s = { b = 1; }; in { a = ((s)).b; }"),
+        );
+        assert_eq!(rename("let s = { a$0 = 1; }; in with s; a", "b").as_deref(), Ok("let s = { b = 1; }; in with s; b"));
+        // Sets containing the set are followed too
+        assert_eq!(rename("let s = { a$0 = 1; }; t = { inherit s; }; in t.s.a", "b").as_deref(), Ok("let s = { b = 1; }; t = { inherit s; }; in t.s.b"));
+    }
+    #[test]
+    fn quoted_renames() {
+        assert_eq!(rename("let s = { a$0 = 1; }; in s.a", "foo.bar").as_deref(), Ok(r#"let s = { "foo.bar" = 1; }; in s."foo.bar""#));
+        assert_eq!(
+            rename("let s = { a$0 = 1; }; in { inherit (s) a; }", "foo.bar").as_deref(),
+            Ok(r#"let s = { "foo.bar" = 1; }; in { a = s."foo.bar"; }"#),
+        );
+        assert_eq!(rename(r#"let s = { "foo$0.bar" = 1; }; in s."foo.bar""#, "b").as_deref(), Ok("let s = { b = 1; }; in s.b"));
+        assert_eq!(rename(r#"let s = { "foo.bar" = 1; }; in s."foo$0.bar""#, "a b").as_deref(), Ok(r#"let s = { "a b" = 1; }; in s."a b""#));
+    }
+    #[test]
+    fn refused_attribute_renames() {
+        let refused = |code, new_name, message: &str| {
+            let err = refused(code, new_name);
+            assert!(err.contains(message), "{} for {}", err, code);
+        };
+        refused("{ a.b$0 = 1; }", "c", "renaming nested attributes like the `b` in `a.b = ...;` isn't supported");
+        refused("let s = rec { a = 1; b = a; }; in s.a$0", "c", "renaming attributes of recursive sets isn't supported");
+        refused("x: x.a$0", "b", "couldn't find where this attribute is defined");
+        refused("let s = { a$0 = 1; b = 2; }; in s.a", "b", "the set already has an attribute called `b`");
+        refused("{ a$0 = 1; }", "b", "can't rename `a`, the set is the value of this file and could be imported anywhere");
+        refused("let s = { a$0 = 1; }; in f s", "b", "can't rename `a`, the set is used in a way that can't be followed: `f s`");
+        refused("let s = { a$0 = 1; }; b = 2; in with s; a + b", "b", "can't rename `a`, its use in `with` would refer to a different `b`");
+        refused("let s = { a$0 = 1; }; in with s; a", "a b", "can't rename `a` to `a b`, it's used through `with`, where it has to be an identifier");
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{App, parse::Parsed, utils};
+use lsp_types::Url;
+use std::fs;
+
+/// Where files that aren't open in the editor are read from
+pub trait FileResolver: Send + Sync {
+    fn read(&self, url: &Url) -> Option<String>;
+}
+
+/// Reads files from disk
+pub struct Disk;
+impl FileResolver for Disk {
+    fn read(&self, url: &Url) -> Option<String> {
+        fs::read_to_string(utils::uri_path(url)?).ok()
+    }
+}
+
+impl App {
+    /// The contents of a file, from the editor if it's open there since
+    /// it might have unsaved changes
+    pub fn read(&self, url: &Url) -> Option<String> {
+        if self.open.contains_key(url) {
+            if let Some((_, content)) = self.files.get(url) {
+                return Some(content.clone());
+            }
+        }
+        self.resolver.read(url)
+    }
+    /// Read a file that isn't open again, in case it changed on disk
+    /// since it was parsed
+    pub fn refresh(&mut self, url: &Url) {
+        if self.open.contains_key(url) {
+            return;
+        }
+        let Some(content) = self.resolver.read(url) else { return };
+        if self.files.get(url).is_some_and(|(_, old)| *old == content) {
+            return;
+        }
+        utils::forget_scopes(url);
+        self.completion_cache = None;
+        self.files.insert(url.clone(), (Parsed::new(&content), content));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use lsp_types::{request::GotoDefinition, *};
+    use serde_json::json;
+
+    fn labels(completions: Option<Vec<CompletionItem>>) -> Vec<String> {
+        let mut labels: Vec<String> = completions.unwrap_or_default().into_iter().map(|item| item.label).collect();
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn follows_imports() {
+        let (mut app, client) = testing::app_with_files(&json!({}), &[("lib.nix", "{\n  foo = 1;\n  fob = 2;\n}")]);
+        let position = app.open_at("default.nix", "let lib = import ./lib.nix; in lib.fo$0");
+        assert_eq!(labels(app.completions(&position)), ["fob", "foo"]);
+        let position = app.open_at("default.nix", "let lib = import ./lib.nix; in lib.foo$0");
+        let definition = testing::request::<GotoDefinition>(&mut app, &client, position);
+        assert_eq!(definition["uri"], testing::uri("lib.nix").as_str());
+        assert_eq!(definition["range"]["start"], json!({ "line": 1, "character": 2 }));
+    }
+    #[test]
+    fn open_files_come_first() {
+        let (mut app, _client) = testing::app_with_files(&json!({}), &[("lib.nix", "{ foo = 1; }")]);
+        let lib = app.open_file("lib.nix", "{ bar = 1; }");
+        assert_eq!(app.read(&lib).as_deref(), Some("{ bar = 1; }"));
+        let position = app.open_at("default.nix", "(import ./lib.nix).$0");
+        assert_eq!(labels(app.completions(&position)), ["bar"]);
+
+        // Once closed, the file is read from disk again
+        app.open.remove(&lib);
+        app.refresh(&lib);
+        assert_eq!(app.read(&lib).as_deref(), Some("{ foo = 1; }"));
+        assert_eq!(labels(app.completions(&position)), ["foo"]);
+    }
+    #[test]
+    fn missing_imports() {
+        let (mut app, _client) = testing::app_with_files(&json!({}), &[]);
+        let position = app.open_at("default.nix", "(import ./lib.nix).$0");
+        assert_eq!(labels(app.completions(&position)), Vec::<String>::new());
+    }
+}
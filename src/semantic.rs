@@ -0,0 +1,412 @@
+use crate::{App, builtins, nixlib, utils::{self, Encoding, VarKind}};
+use lsp_types::Url;
+use serde_json::{json, Value};
+use rnix::{
+    types::*,
+    NodeOrToken,
+    SyntaxKind::{
+        self,
+        NODE_PAT_BIND,
+        TOKEN_ADD, TOKEN_AND, TOKEN_ASSERT, TOKEN_COMMENT, TOKEN_CONCAT, TOKEN_DIV, TOKEN_ELSE, TOKEN_EQUAL,
+        TOKEN_FLOAT, TOKEN_IDENT, TOKEN_IF, TOKEN_IMPLICATION, TOKEN_IN, TOKEN_INHERIT, TOKEN_INTEGER,
+        TOKEN_INVERT, TOKEN_LESS, TOKEN_LESS_OR_EQ, TOKEN_LET, TOKEN_MORE, TOKEN_MORE_OR_EQ, TOKEN_MUL,
+        TOKEN_NOT_EQUAL, TOKEN_OR, TOKEN_PATH, TOKEN_QUESTION, TOKEN_REC, TOKEN_STRING_CONTENT,
+        TOKEN_STRING_END, TOKEN_STRING_START, TOKEN_SUB, TOKEN_THEN, TOKEN_UPDATE, TOKEN_URI, TOKEN_WITH,
+    },
+    SyntaxNode,
+    SyntaxToken,
+    TextRange,
+    TextUnit,
+};
+use std::{collections::HashMap, rc::Rc};
+
+/// The token types we report, in the order of their indices
+pub const TOKEN_TYPES: &[&str] = &[
+    "comment",
+    "keyword",
+    "string",
+    "number",
+    "operator",
+    "variable",
+    "parameter",
+    "property",
+    "function",
+];
+/// The token modifiers we report, in the order of their bits
+pub const TOKEN_MODIFIERS: &[&str] = &["declaration", "defaultLibrary", "deprecated", "readonly"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TokenType {
+    Comment,
+    Keyword,
+    String,
+    Number,
+    Operator,
+    Variable,
+    Parameter,
+    Property,
+    Function,
+}
+
+const DECLARATION: u32 = 1;
+const DEFAULT_LIBRARY: u32 = 1 << 1;
+const DEPRECATED: u32 = 1 << 2;
+/// Bindings of a `let`, unlike the ones of `rec` sets, can't be changed
+/// from outside with `//` or overrides
+const READONLY: u32 = 1 << 3;
+
+/// Semantic tokens of a file, encoded relative to each other like the LSP
+/// wants. If a range is given, only tokens overlapping it are included.
+pub fn tokens(file: &Rc<Url>, root: &SyntaxNode, code: &str, encoding: Encoding, range: Option<TextRange>) -> Vec<u32> {
+    let mut encoder = Encoder {
+        code,
+        encoding,
+        offset: 0,
+        line: 0,
+        col: 0,
+        prev: (0, 0),
+        data: Vec::new(),
+    };
+    let mut scopes = Scopes {
+        file,
+        cache: HashMap::new(),
+    };
+    collect(&mut scopes, &mut encoder, root, range);
+    encoder.data
+}
+
+/// The variables each node brings into scope, so that looking up every
+/// identifier doesn't have to go through the same bindings again
+struct Scopes<'a> {
+    file: &'a Rc<Url>,
+    cache: HashMap<SyntaxNode, Option<HashMap<String, VarKind>>>,
+}
+impl Scopes<'_> {
+    /// What the variable with this name at the node is bound by, if
+    /// anything. Behaves like `utils::scope_for`.
+    fn lookup(&mut self, node: &SyntaxNode, name: &str) -> Option<VarKind> {
+        for node in node.ancestors() {
+            let file = self.file;
+            let vars = self.cache.entry(node.clone()).or_insert_with(|| {
+                let mut scope = HashMap::new();
+                utils::populate_node(file, &mut scope, &node)?;
+                Some(scope.into_iter().map(|(name, var)| (name, var.kind)).collect())
+            });
+            if let Some(kind) = vars.as_ref()?.get(name) {
+                return Some(kind.clone());
+            }
+        }
+        None
+    }
+}
+
+fn collect(scopes: &mut Scopes, encoder: &mut Encoder, node: &SyntaxNode, range: Option<TextRange>) {
+    for child in node.children_with_tokens() {
+        // Whole tokens are included, even if they only partly overlap
+        if range.is_some_and(|range| !overlaps(range, child.text_range())) {
+            continue;
+        }
+        match child {
+            NodeOrToken::Node(node) => collect(scopes, encoder, &node, range),
+            NodeOrToken::Token(token) => if let Some((kind, modifiers)) = classify(scopes, &token) {
+                encoder.push(token.text_range(), kind, modifiers);
+            },
+        }
+    }
+}
+fn overlaps(a: TextRange, b: TextRange) -> bool {
+    a.start() < b.end() && b.start() < a.end()
+}
+
+fn classify(scopes: &mut Scopes, token: &SyntaxToken) -> Option<(TokenType, u32)> {
+    let kind = match token.kind() {
+        TOKEN_COMMENT => TokenType::Comment,
+        TOKEN_ASSERT | TOKEN_ELSE | TOKEN_IF | TOKEN_IN | TOKEN_INHERIT | TOKEN_LET | TOKEN_REC | TOKEN_THEN
+        | TOKEN_WITH => TokenType::Keyword,
+        TOKEN_STRING_START | TOKEN_STRING_CONTENT | TOKEN_STRING_END | TOKEN_PATH | TOKEN_URI => TokenType::String,
+        TOKEN_INTEGER | TOKEN_FLOAT => TokenType::Number,
+        TOKEN_IDENT => return Ident::cast(token.parent()).map(|ident| classify_ident(scopes, &ident)),
+        kind if is_operator(kind) => TokenType::Operator,
+        _ => return None,
+    };
+    Some((kind, 0))
+}
+fn is_operator(kind: SyntaxKind) -> bool {
+    matches!(
+        kind,
+        TOKEN_CONCAT | TOKEN_INVERT | TOKEN_UPDATE | TOKEN_ADD | TOKEN_SUB | TOKEN_MUL | TOKEN_DIV | TOKEN_AND
+        | TOKEN_EQUAL | TOKEN_IMPLICATION | TOKEN_LESS | TOKEN_LESS_OR_EQ | TOKEN_MORE | TOKEN_MORE_OR_EQ
+        | TOKEN_NOT_EQUAL | TOKEN_OR | TOKEN_QUESTION
+    )
+}
+/// Whether a set or let with these bindings makes them variables, rather
+/// than just attributes
+fn defines_variables(holder: Option<SyntaxNode>) -> bool {
+    match holder {
+        Some(node) => match AttrSet::cast(node.clone()) {
+            Some(set) => utils::is_recursive(&set),
+            None => is_let(&node),
+        },
+        None => false,
+    }
+}
+fn is_let(node: &SyntaxNode) -> bool {
+    LetIn::cast(node.clone()).is_some() || LegacyLet::cast(node.clone()).is_some()
+}
+/// The modifiers of a variable defined by a set or let
+fn declaration_modifiers(holder: Option<&SyntaxNode>) -> u32 {
+    if holder.is_some_and(is_let) { DECLARATION | READONLY } else { DECLARATION }
+}
+fn builtin_modifiers(builtin: &builtins::Builtin) -> u32 {
+    if builtin.deprecated.is_some() { DEFAULT_LIBRARY | DEPRECATED } else { DEFAULT_LIBRARY }
+}
+fn classify_ident(scopes: &mut Scopes, ident: &Ident) -> (TokenType, u32) {
+    let Some(parent) = ident.node().parent() else { return (TokenType::Variable, 0) };
+    let name = ident.as_str();
+
+    if let Some(key) = Key::cast(parent.clone()) {
+        let is_first = key.path().next().as_ref() == Some(ident.node());
+        let holder = key.node().parent().and_then(|entry| entry.parent());
+        return if is_first && defines_variables(holder.clone()) {
+            (TokenType::Variable, declaration_modifiers(holder.as_ref()))
+        } else {
+            (TokenType::Property, DECLARATION)
+        };
+    }
+    if let Some(inherit) = Inherit::cast(parent.clone()) {
+        let holder = inherit.node().parent();
+        return if defines_variables(holder.clone()) {
+            (TokenType::Variable, declaration_modifiers(holder.as_ref()))
+        } else {
+            (TokenType::Property, DECLARATION)
+        };
+    }
+    if let Some(select) = Select::cast(parent.clone()) {
+        if select.index().as_ref() == Some(ident.node()) {
+            // `builtins.foo`, unless `builtins` is shadowed
+            let set = select.set().and_then(Ident::cast);
+            if let Some(set) = set.filter(|set| set.as_str() == "builtins") {
+                let bound = scopes.lookup(set.node(), "builtins").is_some();
+                if let Some(builtin) = builtins::get(name).filter(|_| !bound) {
+                    return (TokenType::Function, builtin_modifiers(builtin));
+                }
+            }
+            // `lib.mkIf` or `lib.strings.hasPrefix`, where `lib` is an
+            // argument like `nixlib::is_lib` assumes
+            let path = utils::select_path(select.node().clone()).unwrap_or_default();
+            if let Some((base, rest)) = path.split_first().filter(|(base, _)| *base == "lib") {
+                let rest: Vec<&str> = rest.iter().map(String::as_str).collect();
+                if nixlib::get(&rest).is_some() && scopes.lookup(select.node(), base) == Some(VarKind::Param) {
+                    return (TokenType::Function, DEFAULT_LIBRARY);
+                }
+            }
+            return (TokenType::Property, 0);
+        }
+    }
+    let is_param = match PatEntry::cast(parent.clone()) {
+        // Not the default value in `{ a ? b }`
+        Some(entry) => entry.name().is_some_and(|name| name.node() == ident.node()),
+        None => parent.kind() == NODE_PAT_BIND
+            || Lambda::cast(parent).is_some_and(|lambda| lambda.arg().as_ref() == Some(ident.node())),
+    };
+    if is_param {
+        return (TokenType::Parameter, DECLARATION);
+    }
+
+    match scopes.lookup(ident.node(), name) {
+        Some(VarKind::Param) => (TokenType::Parameter, 0),
+        Some(VarKind::Let) => (TokenType::Variable, READONLY),
+        Some(_) => (TokenType::Variable, 0),
+        None => match builtins::get(name).filter(|builtin| builtin.global) {
+            Some(_) if matches!(name, "true" | "false" | "null") => (TokenType::Keyword, DEFAULT_LIBRARY),
+            Some(builtin) if name == "builtins" => (TokenType::Variable, builtin_modifiers(builtin)),
+            Some(builtin) => (TokenType::Function, builtin_modifiers(builtin)),
+            None if nixlib::get(&[name]).is_some() && nixlib::in_with_lib(scopes.file, ident.node()) => {
+                (TokenType::Function, DEFAULT_LIBRARY)
+            },
+            None => (TokenType::Variable, 0),
+        },
+    }
+}
+
+/// Keeps track of the position in the file while tokens are added in
+/// order, so positions don't have to be counted from the start each time
+struct Encoder<'a> {
+    code: &'a str,
+    encoding: Encoding,
+    offset: usize,
+    line: u32,
+    col: u32,
+    /// Line and column of the previous token
+    prev: (u32, u32),
+    data: Vec<u32>,
+}
+impl Encoder<'_> {
+    fn advance(&mut self, offset: usize) {
+        for c in self.code[self.offset..offset].chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.col = 0;
+            } else {
+                self.col += self.encoding.len(c);
+            }
+        }
+        self.offset = offset;
+    }
+    fn push(&mut self, range: TextRange, kind: TokenType, modifiers: u32) {
+        // Tokens can't span multiple lines, so split comments and strings
+        let mut start = range.start().to_usize();
+        for line in self.code[start..range.end().to_usize()].split('\n') {
+            self.advance(start);
+            let len = line.chars().map(|c| self.encoding.len(c)).sum::<u32>();
+            if len > 0 {
+                let (prev_line, prev_col) = self.prev;
+                let delta_line = self.line - prev_line;
+                let delta_col = if delta_line == 0 { self.col - prev_col } else { self.col };
+                self.data.extend_from_slice(&[delta_line, delta_col, len, kind as u32, modifiers]);
+                self.prev = (self.line, self.col);
+            }
+            start += line.len() + 1;
+        }
+    }
+}
+
+/// The span of a requested range, in offsets
+pub fn span(code: &str, range: lsp_types::Range, encoding: Encoding) -> Option<TextRange> {
+    let start = utils::lookup_pos(code, range.start, encoding)?;
+    let end = utils::lookup_pos(code, range.end, encoding).unwrap_or(code.len());
+    Some(TextRange::from_to(TextUnit::from_usize(start), TextUnit::from_usize(end.max(start))))
+}
+
+/// The smallest single edit that turns `old` into `new`, as the start,
+/// the number of deleted integers and the inserted ones. Only whole
+/// tokens are replaced, which some clients expect.
+fn diff<'a>(old: &[u32], new: &'a [u32]) -> (usize, usize, &'a [u32]) {
+    let prefix = old.chunks(5).zip(new.chunks(5)).take_while(|(a, b)| a == b).count() * 5;
+    let suffix = old[prefix..].rchunks(5).zip(new[prefix..].rchunks(5)).take_while(|(a, b)| a == b).count() * 5;
+    (prefix, old.len() - prefix - suffix, &new[prefix..new.len() - suffix])
+}
+
+impl App {
+    fn semantic_result(&mut self, uri: Url, data: Vec<u32>) -> String {
+        self.next_result_id += 1;
+        let result_id = self.next_result_id.to_string();
+        self.semantic_tokens.insert(uri, (result_id.clone(), data));
+        result_id
+    }
+    /// All semantic tokens of a file, remembered for later deltas
+    pub fn semantic_tokens_full(&mut self, uri: Url) -> Value {
+        // Like diagnostics, skipped for large files
+        let Some((ast, code)) = self.files.get(&uri).filter(|(_, code)| !self.is_large(code)) else {
+            self.semantic_tokens.remove(&uri);
+            return json!({ "data": [] });
+        };
+        let data = tokens(&Rc::new(uri.clone()), &ast.node(), code, self.encoding, None);
+        let result_id = self.semantic_result(uri, data.clone());
+        json!({ "resultId": result_id, "data": data })
+    }
+    /// The changes to the semantic tokens since the result with the
+    /// given id. If we don't have it anymore, all tokens are sent again.
+    pub fn semantic_tokens_delta(&mut self, uri: Url, previous: &str) -> Value {
+        let old = match self.semantic_tokens.remove(&uri) {
+            Some((result_id, old)) if result_id == previous => old,
+            _ => return self.semantic_tokens_full(uri),
+        };
+        let Some((ast, code)) = self.files.get(&uri).filter(|(_, code)| !self.is_large(code)) else {
+            return json!({ "data": [] });
+        };
+        let new = tokens(&Rc::new(uri.clone()), &ast.node(), code, self.encoding, None);
+        let (start, delete_count, data) = diff(&old, &new);
+        let edits = if delete_count == 0 && data.is_empty() {
+            json!([])
+        } else {
+            json!([{ "start": start, "deleteCount": delete_count, "data": data }])
+        };
+        let result_id = self.semantic_result(uri, new);
+        json!({ "resultId": result_id, "edits": edits })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TOKEN_MODIFIERS, TOKEN_TYPES};
+    use crate::testing;
+    use serde_json::{json, Value};
+
+    /// The results of the full, delta and range requests for a file
+    fn all_tokens(options: &Value, code: &str) -> [Value; 3] {
+        let (mut app, client) = testing::app(options);
+        let uri = app.open_file("default.nix", code);
+        let document = json!({ "uri": uri });
+        let full = testing::raw_request(&mut app, &client, "textDocument/semanticTokens/full", json!({ "textDocument": document }));
+        let previous = full["resultId"].clone();
+        let delta = testing::raw_request(&mut app, &client, "textDocument/semanticTokens/full/delta", json!({
+            "textDocument": document,
+            "previousResultId": previous,
+        }));
+        let range = testing::raw_request(&mut app, &client, "textDocument/semanticTokens/range", json!({
+            "textDocument": document,
+            "range": { "start": { "line": 0, "character": 0 }, "end": { "line": 1, "character": 0 } },
+        }));
+        [full, delta, range]
+    }
+
+    #[test]
+    fn skipped_for_large_files() {
+        let code = "let a = 1; in a";
+        let [full, delta, range] = all_tokens(&json!({}), code);
+        assert_ne!(full["data"], json!([]));
+        assert_eq!(delta["edits"], json!([]));
+        assert_ne!(range["data"], json!([]));
+        for tokens in all_tokens(&json!({ "maxFileSize": 10 }), code) {
+            assert_eq!(tokens["data"], json!([]));
+        }
+    }
+
+    /// The text, type and modifiers of each token in one line of code
+    fn classified(code: &str) -> Vec<(String, &'static str, Vec<&'static str>)> {
+        let [full, ..] = all_tokens(&json!({}), code);
+        let data: Vec<u32> = serde_json::from_value(full["data"].clone()).unwrap();
+        let mut column = 0;
+        data.chunks(5)
+            .map(|token| {
+                assert_eq!(token[0], 0, "{code} has more than one line");
+                column += token[1] as usize;
+                let text = code[column..column + token[2] as usize].to_owned();
+                let modifiers = (0..TOKEN_MODIFIERS.len())
+                    .filter(|bit| token[4] & (1 << bit) != 0)
+                    .map(|bit| TOKEN_MODIFIERS[bit])
+                    .collect();
+                (text, TOKEN_TYPES[token[3] as usize], modifiers)
+            })
+            .collect()
+    }
+    /// The type and modifiers of the first token with a text
+    fn classify(code: &str, text: &str) -> (&'static str, Vec<&'static str>) {
+        let tokens = classified(code);
+        let (_, kind, modifiers) = tokens.into_iter().find(|(token, ..)| token == text).unwrap_or_else(|| panic!("no {} in {}", text, code));
+        (kind, modifiers)
+    }
+
+    #[test]
+    fn modifiers() {
+        let code = "let a = 1; in a";
+        assert_eq!(classify(code, "a"), ("variable", vec!["declaration", "readonly"]));
+        assert_eq!(classified(code).last().unwrap().2, ["readonly"]);
+
+        let code = "rec { a = 1; b = a; }";
+        assert_eq!(classify(code, "a"), ("variable", vec!["declaration"]));
+        assert_eq!(classify(code, "b"), ("variable", vec!["declaration"]));
+
+        assert_eq!(classify("{ a = 1; }", "a"), ("property", vec!["declaration"]));
+        assert_eq!(classify("x: x", "x"), ("parameter", vec!["declaration"]));
+        assert_eq!(classify("map", "map"), ("function", vec!["defaultLibrary"]));
+        assert_eq!(classify("isNull", "isNull"), ("function", vec!["defaultLibrary", "deprecated"]));
+        assert_eq!(classify("builtins.toPath", "toPath"), ("function", vec!["defaultLibrary", "deprecated"]));
+        assert_eq!(classify("{ lib }: lib.mkIf", "mkIf"), ("function", vec!["defaultLibrary"]));
+        // Not the library when `lib` is something else
+        assert_eq!(classify("let lib = { }; in lib.mkIf", "mkIf"), ("property", vec![]));
+        assert_eq!(classify("{ lib }: with lib; mkIf", "mkIf"), ("function", vec!["defaultLibrary"]));
+        assert_eq!(classify("let map = 1; in map", "map"), ("variable", vec!["declaration", "readonly"]));
+    }
+}
@@ -0,0 +1,429 @@
+use crate::utils::{self, Encoding};
+use lsp_types::*;
+use rnix::{
+    types::*,
+    value::Value as ParsedValue,
+    StrPart,
+    SyntaxNode,
+    TextRange,
+};
+use std::{convert::TryFrom, rc::Rc};
+
+/// Outputs of a flake which are namespaces of their own, like
+/// `packages.<system>.<name>`
+const FLAKE_OUTPUTS: &[&str] = &[
+    "apps",
+    "checks",
+    "darwinConfigurations",
+    "darwinModules",
+    "devShells",
+    "formatter",
+    "homeConfigurations",
+    "homeModules",
+    "legacyPackages",
+    "lib",
+    "nixosConfigurations",
+    "nixosModules",
+    "overlays",
+    "packages",
+    "templates",
+];
+
+/// How many characters the detail of a symbol may have, so the outline
+/// stays readable
+const MAX_DETAIL: usize = 50;
+
+struct Outline<'a> {
+    file: Rc<Url>,
+    code: &'a str,
+    encoding: Encoding,
+    /// The kind given to set-valued entries
+    set_kind: SymbolKind,
+}
+
+pub fn document_symbols(uri: &Url, root: &SyntaxNode, code: &str, encoding: Encoding) -> Vec<DocumentSymbol> {
+    let Some(expr) = Root::cast(root.clone()).and_then(|root| root.inner()) else { return Vec::new() };
+    let is_flake = uri.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .is_some_and(|name| name == "flake.nix");
+    if is_flake {
+        if let Some(set) = AttrSet::cast(expr.clone()) {
+            return flake_symbols(uri, code, encoding, &set);
+        }
+    }
+    Outline { file: Rc::new(uri.clone()), code, encoding, set_kind: SymbolKind::Object }.expr(&expr)
+}
+
+/// Flatten an outline for clients that don't support nested symbols. The
+/// symbol each one is nested in is kept as its `containerName`.
+pub fn flatten(uri: &Url, symbols: Vec<DocumentSymbol>) -> Vec<SymbolInformation> {
+    fn visit(uri: &Url, symbols: Vec<DocumentSymbol>, container: Option<&str>, flat: &mut Vec<SymbolInformation>) {
+        for symbol in symbols {
+            flat.push(SymbolInformation {
+                name: symbol.name.clone(),
+                kind: symbol.kind,
+                deprecated: symbol.deprecated,
+                location: Location::new(uri.clone(), symbol.range),
+                container_name: container.map(String::from),
+            });
+            visit(uri, symbol.children.unwrap_or_default(), Some(&symbol.name), flat);
+        }
+    }
+    let mut flat = Vec::new();
+    visit(uri, symbols, None, &mut flat);
+    flat
+}
+
+/// An outline of a flake, with `inputs` and the well-known `outputs` as
+/// namespaces
+fn flake_symbols(uri: &Url, code: &str, encoding: Encoding, set: &AttrSet) -> Vec<DocumentSymbol> {
+    let file = Rc::new(uri.clone());
+    let generic = Outline { file: Rc::clone(&file), code, encoding, set_kind: SymbolKind::Object };
+    let namespaces = Outline { file, code, encoding, set_kind: SymbolKind::Namespace };
+
+    let mut symbols = Vec::new();
+    for entry in set.entries() {
+        let first = entry.key().and_then(|key| key.path().next()).map(|node| key_name(&node));
+        match first.as_deref() {
+            Some("inputs") => {
+                namespaces.entry(&mut symbols, &entry, SymbolKind::Field);
+                if let Some(inputs) = symbols.iter_mut().find(|symbol| symbol.name == "inputs") {
+                    inputs.kind = SymbolKind::Namespace;
+                    for input in inputs.children.iter_mut().flatten() {
+                        input.kind = SymbolKind::Package;
+                    }
+                }
+            },
+            Some("outputs") => {
+                generic.entry(&mut symbols, &entry, SymbolKind::Function);
+                let outputs = entry.value().and_then(Lambda::cast).and_then(|lambda| lambda.body());
+                let body = outputs.as_ref().and_then(|body| unwrap_set(body.clone()));
+                if let (Some(body), Some(symbol)) = (body, symbols.last_mut()) {
+                    // The function returns a set we can see, so give
+                    // well-known outputs a more structured outline
+                    let mut children = Vec::new();
+                    for output in body.entries() {
+                        let name = output.key().and_then(|key| key.path().next()).map(|node| key_name(&node));
+                        if name.is_some_and(|name| FLAKE_OUTPUTS.contains(&name.as_str())) {
+                            namespaces.entry(&mut children, &output, SymbolKind::Namespace);
+                        } else {
+                            generic.entry(&mut children, &output, SymbolKind::Field);
+                        }
+                    }
+                    symbol.children = Some(children);
+                }
+            },
+            _ => generic.entry(&mut symbols, &entry, SymbolKind::Field),
+        }
+    }
+    symbols
+}
+
+/// Skip through expressions that wrap a set without changing its
+/// structure, like `let ... in { ... }`
+fn unwrap_set(mut node: SyntaxNode) -> Option<AttrSet> {
+    loop {
+        node = match ParsedType::try_from(node).ok()? {
+            ParsedType::AttrSet(set) => return Some(set),
+            ParsedType::LetIn(let_in) => let_in.body()?,
+            ParsedType::With(with) => with.body()?,
+            ParsedType::Paren(paren) => paren.inner()?,
+            ParsedType::Assert(assert) => assert.body()?,
+            _ => return None,
+        };
+    }
+}
+
+fn key_name(node: &SyntaxNode) -> String {
+    if let Some(ident) = Ident::cast(node.clone()) {
+        return ident.as_str().into();
+    }
+    if let Some(string) = Str::cast(node.clone()) {
+        if let [StrPart::Literal(literal)] = &*string.parts() {
+            return literal.clone();
+        }
+    }
+    node.text().to_string()
+}
+
+/// The arguments of a function and the functions it returns, like
+/// `{ stdenv, src ? … }: name: …`
+fn signature(lambda: &Lambda) -> String {
+    let mut args = Vec::new();
+    let mut lambda = Some(lambda.clone());
+    while let Some(arg) = lambda.as_ref().and_then(Lambda::arg) {
+        let arg = match Pattern::cast(arg.clone()) {
+            Some(pattern) => {
+                let mut entries: Vec<String> = pattern.entries()
+                    .filter_map(|entry| {
+                        let name = entry.name()?;
+                        Some(if entry.default().is_some() { format!("{} ? …", name.as_str()) } else { name.as_str().to_owned() })
+                    })
+                    .collect();
+                if pattern.ellipsis() {
+                    entries.push("...".into());
+                }
+                let set = if entries.is_empty() { "{ }".into() } else { format!("{{ {} }}", entries.join(", ")) };
+                let bind = pattern.node().children().find_map(PatBind::cast);
+                match bind.as_ref().and_then(|bind| Some((bind, bind.name()?))) {
+                    // `{ ... }@args` or `args@{ ... }`
+                    Some((bind, name)) if bind.node().text_range().start() == pattern.node().text_range().start() => format!("{}@{set}", name.as_str()),
+                    Some((_, name)) => format!("{set}@{}", name.as_str()),
+                    None => set,
+                }
+            },
+            None => arg.text().to_string(),
+        };
+        args.push(arg);
+        lambda = lambda.and_then(|lambda| lambda.body()).and_then(Lambda::cast);
+    }
+    format!("{}: …", args.join(": "))
+}
+
+/// The first line of a text, cut to `MAX_DETAIL` characters
+fn shorten(text: &str) -> String {
+    let mut lines = text.lines();
+    let first = lines.next().unwrap_or_default();
+    if first.chars().count() > MAX_DETAIL {
+        format!("{}…", first.chars().take(MAX_DETAIL - 1).collect::<String>())
+    } else if lines.next().is_some() {
+        format!("{first} …")
+    } else {
+        first.to_owned()
+    }
+}
+
+impl Outline<'_> {
+    fn range(&self, range: TextRange) -> Range {
+        utils::range(self.code, range, self.encoding)
+    }
+    /// The symbols defined by an expression, such as the entries of a set
+    fn expr(&self, node: &SyntaxNode) -> Vec<DocumentSymbol> {
+        let mut symbols = Vec::new();
+        match ParsedType::try_from(node.clone()) {
+            Ok(ParsedType::AttrSet(set)) => self.entries(&mut symbols, &set, SymbolKind::Field),
+            Ok(ParsedType::LetIn(let_in)) => {
+                self.entries(&mut symbols, &let_in, SymbolKind::Variable);
+                if let Some(body) = let_in.body() {
+                    symbols.extend(self.expr(&body));
+                }
+            },
+            Ok(ParsedType::Lambda(lambda)) => if let Some(body) = lambda.body() {
+                symbols.extend(self.expr(&body));
+            },
+            Ok(ParsedType::With(with)) => if let Some(body) = with.body() {
+                symbols.extend(self.expr(&body));
+            },
+            Ok(ParsedType::Assert(assert)) => if let Some(body) = assert.body() {
+                symbols.extend(self.expr(&body));
+            },
+            Ok(ParsedType::Paren(paren)) => if let Some(inner) = paren.inner() {
+                symbols.extend(self.expr(&inner));
+            },
+            // Calls like `mkDerivation { ... }`
+            Ok(ParsedType::Apply(apply)) => if let Some(value) = apply.value() {
+                symbols.extend(self.expr(&value));
+            },
+            _ => (),
+        }
+        symbols
+    }
+    fn entries<T: EntryHolder>(&self, symbols: &mut Vec<DocumentSymbol>, holder: &T, default_kind: SymbolKind) {
+        for entry in holder.entries() {
+            self.entry(symbols, &entry, default_kind);
+        }
+        for inherit in holder.inherits() {
+            for ident in inherit.idents() {
+                let range = self.range(ident.node().text_range());
+                symbols.push(DocumentSymbol {
+                    name: ident.as_str().into(),
+                    detail: None,
+                    kind: default_kind,
+                    deprecated: None,
+                    range,
+                    selection_range: range,
+                    children: None,
+                });
+            }
+        }
+    }
+    /// Add the symbol for an entry, nesting it below its path for
+    /// entries like `a.b.c = ...;`
+    fn entry(&self, symbols: &mut Vec<DocumentSymbol>, entry: &KeyValue, default_kind: SymbolKind) {
+        let (Some(key), Some(value)) = (entry.key(), entry.value()) else { return };
+        let path: Vec<SyntaxNode> = key.path().collect();
+        let Some((last, parents)) = path.split_last() else { return };
+        let range = self.range(entry.node().text_range());
+
+        let children = self.expr(&value);
+        let symbol = DocumentSymbol {
+            name: key_name(last),
+            detail: self.detail(&value),
+            kind: self.kind(&value, default_kind),
+            deprecated: None,
+            range,
+            selection_range: self.range(last.text_range()),
+            children: if children.is_empty() { None } else { Some(children) },
+        };
+
+        let mut symbols = symbols;
+        for parent in parents {
+            let name = key_name(parent);
+            let index = if let Some(index) = symbols.iter().position(|symbol| symbol.name == name) {
+                let existing = &mut symbols[index];
+                existing.range.end = existing.range.end.max(range.end);
+                index
+            } else {
+                symbols.push(DocumentSymbol {
+                    name,
+                    detail: None,
+                    kind: self.set_kind,
+                    deprecated: None,
+                    range,
+                    selection_range: self.range(parent.text_range()),
+                    children: None,
+                });
+                symbols.len() - 1
+            };
+            symbols = symbols[index].children.get_or_insert_with(Vec::new);
+        }
+        symbols.push(symbol);
+    }
+    /// A one-line summary of a value: the arguments of a function, the
+    /// value of a literal, or how many elements a set or list has
+    fn detail(&self, value: &SyntaxNode) -> Option<String> {
+        let detail = match ParsedType::try_from(value.clone()).ok()? {
+            ParsedType::Lambda(lambda) => signature(&lambda),
+            ParsedType::AttrSet(set) => {
+                let mut names: Vec<String> = set.entries()
+                    .filter_map(|entry| entry.key()?.path().next())
+                    .map(|part| key_name(&part))
+                    .chain(set.inherits().flat_map(|inherit| inherit.idents()).map(|ident| ident.as_str().to_owned()))
+                    .collect();
+                names.sort();
+                names.dedup();
+                match names.len() {
+                    0 => "empty set".into(),
+                    1 => "1 attribute".into(),
+                    count => format!("{count} attributes"),
+                }
+            },
+            ParsedType::List(list) => utils::list_summary(&self.file, &list),
+            ParsedType::Str(_) | ParsedType::Value(_) => value.text().to_string(),
+            ParsedType::Ident(_) if utils::literal_type(&self.file, value.clone()).is_some() => value.text().to_string(),
+            _ => return None,
+        };
+        Some(shorten(&detail))
+    }
+    fn kind(&self, value: &SyntaxNode, default_kind: SymbolKind) -> SymbolKind {
+        match ParsedType::try_from(value.clone()) {
+            Ok(ParsedType::AttrSet(_)) => self.set_kind,
+            Ok(ParsedType::Lambda(_)) => SymbolKind::Function,
+            Ok(ParsedType::List(_)) => SymbolKind::Array,
+            Ok(ParsedType::Str(_)) => SymbolKind::String,
+            Ok(ParsedType::Value(value)) => match value.to_value() {
+                Ok(ParsedValue::Integer(_) | ParsedValue::Float(_)) => SymbolKind::Number,
+                Ok(ParsedValue::Path(..)) => SymbolKind::File,
+                Ok(ParsedValue::String(_)) => SymbolKind::String,
+                Err(_) => default_kind,
+            },
+            Ok(ParsedType::Ident(ident)) => match ident.as_str() {
+                "true" | "false" => SymbolKind::Boolean,
+                "null" => SymbolKind::Null,
+                _ => default_kind,
+            },
+            _ => default_kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use lsp_types::{request::DocumentSymbolRequest, *};
+    use serde_json::{json, Value};
+
+    const CODE: &str = "{\n  a.b = 1;\n  c = { d = x: x; };\n}\n";
+
+    fn symbols(options: &Value, hierarchical: Option<bool>) -> Value {
+        let (mut app, client) = testing::app(options);
+        app.client.text_document = Some(TextDocumentClientCapabilities {
+            document_symbol: Some(DocumentSymbolCapability {
+                hierarchical_document_symbol_support: hierarchical,
+                ..DocumentSymbolCapability::default()
+            }),
+            ..TextDocumentClientCapabilities::default()
+        });
+        let uri = app.open_file("default.nix", CODE);
+        testing::request::<DocumentSymbolRequest>(&mut app, &client, DocumentSymbolParams { text_document: TextDocumentIdentifier { uri } })
+    }
+
+    #[test]
+    fn nested_symbols() {
+        let symbols: Vec<DocumentSymbol> = serde_json::from_value(symbols(&json!({}), Some(true))).unwrap();
+        let names: Vec<&str> = symbols.iter().map(|symbol| symbol.name.as_str()).collect();
+        assert_eq!(names, ["a", "c"]);
+        let b = &symbols[0].children.as_ref().unwrap()[0];
+        assert_eq!((b.name.as_str(), b.kind, b.detail.as_deref()), ("b", SymbolKind::Number, Some("1")));
+        let d = &symbols[1].children.as_ref().unwrap()[0];
+        assert_eq!((d.name.as_str(), d.kind), ("d", SymbolKind::Function));
+    }
+    #[test]
+    fn flat_symbols() {
+        for hierarchical in [None, Some(false)] {
+            let symbols: Vec<SymbolInformation> = serde_json::from_value(symbols(&json!({}), hierarchical)).unwrap();
+            let names: Vec<(&str, Option<&str>)> = symbols.iter()
+                .map(|symbol| (symbol.name.as_str(), symbol.container_name.as_deref()))
+                .collect();
+            assert_eq!(names, [("a", None), ("b", Some("a")), ("c", None), ("d", Some("c"))]);
+            assert!(symbols.iter().all(|symbol| symbol.location.uri == testing::uri("default.nix")));
+            // Nested in `a`, but it's where `b` is
+            assert_eq!(symbols[1].location.range, Range::new(Position::new(1, 2), Position::new(1, 10)));
+        }
+    }
+
+    #[test]
+    fn skipped_for_large_files() {
+        for hierarchical in [None, Some(true)] {
+            assert_eq!(symbols(&json!({ "maxFileSize": 10 }), hierarchical), json!([]));
+        }
+    }
+
+    /// The detail of the symbol for `x` in a set, with `value` as its value
+    fn detail(value: &str) -> Option<String> {
+        let code = format!("{{ x = {value}; }}");
+        let symbols = super::document_symbols(&testing::uri("default.nix"), &rnix::parse(&code).node(), &code, crate::utils::Encoding::Utf16);
+        symbols.into_iter().find(|symbol| symbol.name == "x").unwrap().detail
+    }
+
+    #[test]
+    fn details() {
+        assert_eq!(detail("1").as_deref(), Some("1"));
+        assert_eq!(detail("1.5").as_deref(), Some("1.5"));
+        assert_eq!(detail("./a.nix").as_deref(), Some("./a.nix"));
+        assert_eq!(detail("\"a\"").as_deref(), Some("\"a\""));
+        assert_eq!(detail("true").as_deref(), Some("true"));
+        assert_eq!(detail("null").as_deref(), Some("null"));
+        assert_eq!(detail("y").as_deref(), None);
+        assert_eq!(detail("f y").as_deref(), None);
+
+        assert_eq!(detail("{ }").as_deref(), Some("empty set"));
+        assert_eq!(detail("{ a = 1; }").as_deref(), Some("1 attribute"));
+        assert_eq!(detail("{ a.b = 1; a.c = 2; inherit b; }").as_deref(), Some("2 attributes"));
+
+        assert_eq!(detail("[ ]").as_deref(), Some("empty list"));
+        assert_eq!(detail("[ 1 ]").as_deref(), Some("list of int (1 element)"));
+        assert_eq!(detail("[ 1 \"a\" ]").as_deref(), Some("list (mixed, 2 elements)"));
+        assert_eq!(detail("[ 1 y ]").as_deref(), Some("list (2 elements)"));
+
+        assert_eq!(detail("a: b: a").as_deref(), Some("a: b: …"));
+        assert_eq!(detail("{ a, b ? 1, ... }@args: a").as_deref(), Some("{ a, b ? …, ... }@args: …"));
+        assert_eq!(detail("args@{ }: args").as_deref(), Some("args@{ }: …"));
+
+        // Long and multi-line values are cut
+        let long = format!("\"{}\"", "a".repeat(60));
+        let expected = format!("\"{}…", "a".repeat(super::MAX_DETAIL - 2));
+        assert_eq!(detail(&long), Some(expected));
+        assert_eq!(detail("''\n  a\n''").as_deref(), Some("'' …"));
+    }
+}
@@ -0,0 +1,121 @@
+//! Helpers for tests, which talk to an `App` over channels instead of
+//! standard input and output
+
+use crate::{config::Config, resolver::FileResolver, utils::Encoding, App};
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::{notification::{DidChangeTextDocument, Notification as _}, *};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+
+/// Where the cursor is in the code of a test
+const CURSOR: &str = "$0";
+
+/// Files that only exist in memory, so tests don't need real ones
+#[derive(Default)]
+pub struct Memory {
+    files: HashMap<Url, String>,
+}
+impl FileResolver for Memory {
+    fn read(&self, url: &Url) -> Option<String> {
+        self.files.get(url).cloned()
+    }
+}
+
+/// A server with the given settings, and the editor's end of its
+/// connection, which has to be kept around for the server to send to
+pub fn app(options: &serde_json::Value) -> (App, Connection) {
+    let (server, client) = Connection::memory();
+    let config = Config::from_options(Some(options));
+    (App::new(server, config, ClientCapabilities::default(), Encoding::Utf16, Vec::new()), client)
+}
+/// Like `app`, with files that can be read from disk, by name
+pub fn app_with_files(options: &Value, files: &[(&str, &str)]) -> (App, Connection) {
+    let (mut app, client) = app(options);
+    let mut memory = Memory::default();
+    for &(name, code) in files {
+        memory.files.insert(uri(name), code.into());
+    }
+    app.resolver = Arc::new(memory);
+    (app, client)
+}
+pub fn uri(name: &str) -> Url {
+    Url::parse(&format!("file:///test/{name}")).unwrap()
+}
+/// Code without the `$0` marking the cursor, and the position of it
+pub fn cursor(code: &str) -> (String, Position) {
+    let offset = code.find(CURSOR).expect("no cursor in code");
+    let code = code.replacen(CURSOR, "", 1);
+    let position = crate::utils::offset_to_pos(&code, offset, Encoding::Utf16);
+    (code, position)
+}
+/// Send a request to the server, and return the result it replies with
+pub fn request<R>(app: &mut App, client: &Connection, params: R::Params) -> Value
+where
+    R: request::Request,
+    R::Params: serde::Serialize,
+{
+    raw_request(app, client, R::METHOD, serde_json::to_value(params).unwrap())
+}
+/// Like `request`, for requests lsp-types doesn't know about
+pub fn raw_request(app: &mut App, client: &Connection, method: &str, params: Value) -> Value {
+    app.handle_request(Request::new(RequestId::from(0), method.into(), params)).unwrap();
+    let response = client.receiver.try_iter()
+        .find_map(|msg| match msg {
+            Message::Response(response) => Some(response),
+            _ => None,
+        })
+        .expect("no response");
+    assert!(response.error.is_none(), "{:?}", response.error);
+    response.result.unwrap_or(Value::Null)
+}
+/// The responses the server sent, skipping notifications like
+/// diagnostics
+pub fn responses(client: &Connection) -> Vec<Response> {
+    client.receiver.try_iter()
+        .filter_map(|msg| match msg {
+            Message::Response(response) => Some(response),
+            _ => None,
+        })
+        .collect()
+}
+impl App {
+    /// Open a file in the editor
+    pub fn open_file(&mut self, name: &str, code: &str) -> Url {
+        let uri = uri(name);
+        self.open.insert(uri.clone(), 0);
+        self.update_file(uri.clone(), code.into()).unwrap();
+        uri
+    }
+    /// Change an open file, like typing in it does
+    pub fn change_file(&mut self, uri: &Url, code: &str) {
+        let params = DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier { uri: uri.clone(), version: Some(self.open[uri] + 1) },
+            content_changes: vec![TextDocumentContentChangeEvent { range: None, range_length: None, text: code.into() }],
+        };
+        let notification = Notification::new(DidChangeTextDocument::METHOD.into(), params);
+        self.handle_notification(notification).unwrap();
+    }
+    /// Open a file with a cursor marked by `$0`, and return the position
+    /// of it
+    pub fn open_at(&mut self, name: &str, code: &str) -> TextDocumentPositionParams {
+        let (code, position) = cursor(code);
+        let uri = self.open_file(name, &code);
+        TextDocumentPositionParams { text_document: TextDocumentIdentifier { uri }, position }
+    }
+}
+/// Code with edits applied, like an editor would
+pub fn apply(code: &str, edits: &[TextEdit]) -> String {
+    let mut edits: Vec<(usize, usize, &str)> = edits.iter()
+        .map(|edit| {
+            let start = crate::utils::lookup_pos(code, edit.range.start, Encoding::Utf16).unwrap();
+            let end = crate::utils::lookup_pos(code, edit.range.end, Encoding::Utf16).unwrap();
+            (start, end, edit.new_text.as_str())
+        })
+        .collect();
+    edits.sort_by_key(|&(start, end, _)| (start, end));
+    let mut code = code.to_owned();
+    for &(start, end, text) in edits.iter().rev() {
+        code.replace_range(start..end, text);
+    }
+    code
+}
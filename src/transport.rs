@@ -0,0 +1,188 @@
+use crate::Error;
+use log::info;
+use lsp_server::{Connection, Message};
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    thread::{self, JoinHandle},
+};
+
+/// What the server talks to the editor over, chosen on the command line
+#[derive(Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Standard input and output, with `--stdio` or by default
+    Stdio,
+    /// A TCP connection, with `--socket <port>`. The editor listens on
+    /// the port, on localhost unless it's an address like
+    /// `192.168.0.2:5007`, and the server connects to it.
+    Socket(SocketAddr),
+    /// A TCP connection the other way around, with `--listen <port>`. The
+    /// server listens on the port and talks to the first editor
+    /// connecting to it.
+    Listen(SocketAddr),
+    /// A Unix domain socket the editor listens on, with `--pipe <path>`
+    Pipe(PathBuf),
+}
+impl Transport {
+    /// Parse the command line, without the name of the program. Both
+    /// `--socket 5007` and `--socket=5007` work, since editors pass either.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self, Error> {
+        let mut transport = Self::Stdio;
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_owned(), Some(value.to_owned())),
+                None => (arg, None),
+            };
+            let mut value = |flag: &str| value.clone().or_else(|| args.next()).ok_or_else(|| format!("`{flag}` needs a value"));
+            transport = match flag.as_str() {
+                "--stdio" => Self::Stdio,
+                "--socket" => Self::Socket(address(&value("--socket")?)?),
+                "--listen" => Self::Listen(address(&value("--listen")?)?),
+                "--pipe" => Self::Pipe(PathBuf::from(value("--pipe")?)),
+                _ => return Err(format!(
+                    "unknown argument `{flag}`, expected `--stdio`, `--socket <port>`, `--listen <port>` or `--pipe <path>`"
+                ).into()),
+            };
+        }
+        Ok(transport)
+    }
+    /// Set up the connection to the editor, waiting for it to connect
+    /// first when listening on a socket
+    pub fn connect(&self) -> Result<(Connection, IoThreads), Error> {
+        match self {
+            Self::Stdio => {
+                let (connection, threads) = Connection::stdio();
+                Ok((connection, IoThreads::Stdio(threads)))
+            },
+            Self::Socket(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                info!("Connected to {addr}");
+                let writer = stream.try_clone()?;
+                Ok(stream_transport(stream, writer, |stream| stream.shutdown(Shutdown::Both)))
+            },
+            Self::Listen(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                info!("Listening on {}", listener.local_addr()?);
+                let (stream, client) = listener.accept()?;
+                info!("Connected to {client}");
+                let writer = stream.try_clone()?;
+                Ok(stream_transport(stream, writer, |stream| stream.shutdown(Shutdown::Both)))
+            },
+            #[cfg(unix)]
+            Self::Pipe(path) => {
+                use std::os::unix::net::UnixStream;
+                let stream = UnixStream::connect(path)?;
+                info!("Connected to {}", path.display());
+                let writer = stream.try_clone()?;
+                Ok(stream_transport(stream, writer, |stream| stream.shutdown(Shutdown::Both)))
+            },
+            #[cfg(not(unix))]
+            Self::Pipe(_) => Err("`--pipe` is only supported on Unix".into()),
+        }
+    }
+}
+
+/// The address of `--socket` or `--listen`, which is a port on localhost
+/// unless it's a whole address
+fn address(value: &str) -> Result<SocketAddr, Error> {
+    match value.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from(([127, 0, 0, 1], port))),
+        Err(_) => value.parse().map_err(|_| format!("`{value}` is neither a port nor an address like `127.0.0.1:5007`").into()),
+    }
+}
+
+/// The threads reading and writing the messages of a connection
+pub enum IoThreads {
+    Stdio(lsp_server::IoThreads),
+    Stream {
+        reader: JoinHandle<io::Result<()>>,
+        writer: JoinHandle<io::Result<()>>,
+    },
+}
+impl IoThreads {
+    /// Wait for the threads to finish, which they do once the editor
+    /// sent `exit` or went away, and the server dropped its connection
+    pub fn join(self) -> io::Result<()> {
+        match self {
+            Self::Stdio(threads) => threads.join(),
+            Self::Stream { reader, writer } => {
+                let reader = reader.join().expect("reader panicked");
+                writer.join().expect("writer panicked")?;
+                reader
+            },
+        }
+    }
+}
+
+/// Messages to and from a stream, one half of which `reader` and the
+/// other `writer` is. Like `Connection::stdio`, reading stops after
+/// `exit`. Once the server drops its end of the connection, the last
+/// messages are written and `close` shuts the stream down, so the editor
+/// sees it closed even if the server exits on its own.
+fn stream_transport<R, W, F>(reader: R, writer: W, close: F) -> (Connection, IoThreads)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+    F: FnOnce(&W) -> io::Result<()> + Send + 'static,
+{
+    let (server, client) = Connection::memory();
+    let Connection { sender, receiver } = client;
+    let writer = thread::spawn(move || {
+        let mut writer = writer;
+        for msg in receiver {
+            msg.write(&mut writer)?;
+        }
+        close(&writer)
+    });
+    let reader = thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        while let Some(msg) = Message::read(&mut reader)? {
+            let is_exit = matches!(&msg, Message::Notification(notification) if notification.method == "exit");
+            // The server stopped listening, after `shutdown` for example
+            if sender.send(msg).is_err() || is_exit {
+                break;
+            }
+        }
+        Ok(())
+    });
+    (server, IoThreads::Stream { reader, writer })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Transport;
+    use lsp_server::Message;
+    use std::{io::Write, net::{SocketAddr, TcpListener}, path::PathBuf};
+
+    fn parse(args: &[&str]) -> Result<Transport, String> {
+        Transport::from_args(args.iter().map(|&arg| arg.to_owned())).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn args() {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 5007));
+        assert_eq!(parse(&[]), Ok(Transport::Stdio));
+        assert_eq!(parse(&["--socket", "5007"]), Ok(Transport::Socket(localhost)));
+        assert_eq!(parse(&["--socket=5007"]), Ok(Transport::Socket(localhost)));
+        assert_eq!(parse(&["--listen=0.0.0.0:5007"]), Ok(Transport::Listen(SocketAddr::from(([0, 0, 0, 0], 5007)))));
+        assert_eq!(parse(&["--pipe", "/tmp/lsp"]), Ok(Transport::Pipe(PathBuf::from("/tmp/lsp"))));
+        assert!(parse(&["--socket"]).is_err());
+        assert!(parse(&["--socket", "port"]).is_err());
+        assert!(parse(&["--tcp"]).is_err());
+    }
+    #[test]
+    fn socket_connects_to_editor() {
+        let editor = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (connection, threads) = Transport::Socket(editor.local_addr().unwrap()).connect().unwrap();
+        let (mut stream, _) = editor.accept().unwrap();
+        let body = r#"{"jsonrpc":"2.0","method":"exit","params":null}"#;
+        write!(stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        match connection.receiver.recv().unwrap() {
+            Message::Notification(notification) => assert_eq!(notification.method, "exit"),
+            msg => panic!("expected exit, got {:?}", msg),
+        }
+        drop(connection);
+        threads.join().unwrap();
+    }
+}
@@ -1,25 +1,84 @@
 use lsp_types::*;
 use rnix::{
     types::*,
+    value::{StrPart, Value as ParsedValue},
+    SyntaxKind::{
+        NODE_INHERIT, NODE_KEY, NODE_PAT_BIND, NODE_PAT_ENTRY, NODE_STRING_INTERPOL, TOKEN_COMMENT, TOKEN_DOT,
+        TOKEN_INHERIT, TOKEN_REC, TOKEN_WHITESPACE,
+    },
+    NodeOrToken,
     SyntaxNode,
+    SyntaxToken,
     TextRange,
     TextUnit,
     TokenAtOffset,
 };
 use std::{
-    collections::HashMap,
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap},
     convert::TryFrom,
+    hash::{Hash, Hasher},
     path::PathBuf,
     rc::Rc,
 };
 
+/// Words that look like identifiers, but can't be used as one
+const KEYWORDS: &[&str] = &["assert", "else", "if", "in", "inherit", "let", "rec", "then", "with"];
+/// How many scopes `scope_for` keeps before starting over
+const MAX_CACHED_SCOPES: usize = 256;
+
+type Scope = HashMap<String, Var>;
+type ScopeKey = (Rc<Url>, SyntaxNode);
+thread_local! {
+    /// The scopes found by `scope_for`, by file and node. Nodes are equal
+    /// if they're at the same offset of the same subtree, which a newer
+    /// version of the file shares where it didn't change, so they're
+    /// forgotten with `forget_scopes` when it does.
+    static SCOPES: RefCell<HashMap<ScopeKey, Option<Scope>>> = RefCell::new(HashMap::new());
+}
+
 pub fn uri_path(uri: &Url) -> Option<PathBuf> {
     if uri.scheme() != "file" || uri.has_host() {
         return None;
     }
     Some(PathBuf::from(uri.path()))
 }
-pub fn lookup_pos(code: &str, pos: Position) -> Option<usize> {
+/// How the characters of a `Position` are counted, as negotiated with
+/// the client. LSP used to only allow UTF-16.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16,
+    Utf32,
+}
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "utf-8" => Some(Self::Utf8),
+            "utf-16" => Some(Self::Utf16),
+            "utf-32" => Some(Self::Utf32),
+            _ => None,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf16 => "utf-16",
+            Self::Utf32 => "utf-32",
+        }
+    }
+    /// How many units a character counts as
+    // Characters are at most 4 bytes long, so this can't truncate
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn len(self, c: char) -> u32 {
+        match self {
+            Self::Utf8 => c.len_utf8() as u32,
+            Self::Utf16 => c.len_utf16() as u32,
+            Self::Utf32 => 1,
+        }
+    }
+}
+pub fn lookup_pos(code: &str, pos: Position, encoding: Encoding) -> Option<usize> {
     let mut lines = code.split('\n');
 
     let mut offset = 0;
@@ -29,28 +88,39 @@ pub fn lookup_pos(code: &str, pos: Position) -> Option<usize> {
         offset += line.len() + 1;
     }
 
-    lines.next()
-        .and_then(|line| {
-            Some(
-                offset +
-                    line.chars()
-                    .take(usize::try_from(pos.character).ok()?)
-                    .map(char::len_utf8)
-                        .sum::<usize>()
-            )
-        })
+    let line = lines.next()?;
+    if encoding == Encoding::Utf8 {
+        // Positions inside a character are rounded down
+        let mut end = usize::try_from(pos.character).map_or(line.len(), |character| character.min(line.len()));
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        return Some(offset + end);
+    }
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        units += u64::from(encoding.len(c));
+        if units > pos.character {
+            return Some(offset + i);
+        }
+    }
+    Some(offset + line.len())
 }
-pub fn offset_to_pos(code: &str, offset: usize) -> Position {
+pub fn offset_to_pos(code: &str, offset: usize, encoding: Encoding) -> Position {
     let start_of_line = code[..offset].rfind('\n').map_or(0, |n| n+1);
+    let line = &code[start_of_line..offset];
     Position {
         line: code[..start_of_line].chars().filter(|&c| c == '\n').count() as u64,
-        character: code[start_of_line..offset].chars().map(|c| c.len_utf16() as u64).sum()
+        character: match encoding {
+            Encoding::Utf8 => line.len() as u64,
+            _ => line.chars().map(|c| u64::from(encoding.len(c))).sum(),
+        },
     }
 }
-pub fn range(code: &str, range: TextRange) -> Range {
+pub fn range(code: &str, range: TextRange, encoding: Encoding) -> Range {
     Range {
-        start: offset_to_pos(code, range.start().to_usize()),
-        end: offset_to_pos(code, range.end().to_usize()),
+        start: offset_to_pos(code, range.start().to_usize(), encoding),
+        end: offset_to_pos(code, range.end().to_usize(), encoding),
     }
 }
 pub struct CursorInfo {
@@ -74,13 +144,14 @@ pub fn ident_at(root: &SyntaxNode, offset: usize) -> Option<CursorInfo> {
                 });
             }
 
-            path.push(Ident::cast(item)?.as_str().into());
+            path.push(static_name(&item)?);
         }
-        panic!("identifier at cursor is somehow not a child of its parent");
+        // Only reachable if the tree is malformed
+        None
     } else if let Some(mut index) = parent.and_then(Select::cast) {
         let mut path = Vec::new();
         while let Some(new) = Select::cast(index.set()?) {
-            path.push(Ident::cast(new.index()?)?.as_str().into());
+            path.push(static_name(&new.index()?)?);
             index = new;
         }
         if index.set()? != *ident.node() {
@@ -102,100 +173,660 @@ pub fn ident_at(root: &SyntaxNode, offset: usize) -> Option<CursorInfo> {
         })
     }
 }
+/// The attribute being typed at an offset when `ident_at` can't tell
+/// its path: right after the dot, like `a.b.`, where there's no name
+/// yet, or after an expression that isn't a name, like
+/// `(import ./file.nix).a`
+pub fn select_at(root: &SyntaxNode, offset: usize) -> Option<(Select, Option<Ident>)> {
+    let token = root.token_at_offset(TextUnit::from_usize(offset)).left_biased()?;
+    if token.kind() == TOKEN_DOT {
+        return Some((Select::cast(token.parent())?, None));
+    }
+    let ident = Ident::cast(token.parent())?;
+    let select = ident.node().parent().and_then(Select::cast)?;
+    if select.index()? != *ident.node() {
+        return None;
+    }
+    Some((select, Some(ident)))
+}
+/// The type of a value, if it's a literal whose type is obvious from its
+/// syntax alone, named like `int` or `attrs`
+pub fn literal_type(file: &Rc<Url>, mut node: SyntaxNode) -> Option<&'static str> {
+    while let Some(paren) = Paren::cast(node.clone()) {
+        node = paren.inner()?;
+    }
+    match ParsedType::try_from(node.clone()).ok()? {
+        ParsedType::AttrSet(_) => Some("attrs"),
+        ParsedType::Lambda(_) => Some("function"),
+        ParsedType::List(_) => Some("list"),
+        ParsedType::Str(_) => Some("string"),
+        ParsedType::Value(value) => match value.to_value().ok()? {
+            ParsedValue::Integer(_) => Some("int"),
+            ParsedValue::Float(_) => Some("float"),
+            ParsedValue::Path(..) => Some("path"),
+            // URIs like `https://example.com`
+            ParsedValue::String(_) => Some("string"),
+        },
+        ParsedType::Ident(ident) => {
+            let typ = match ident.as_str() {
+                "true" | "false" => "bool",
+                "null" => "null",
+                _ => return None,
+            };
+            let shadowed = scope_for(file, node)?.contains_key(ident.as_str());
+            (!shadowed).then_some(typ)
+        },
+        _ => None,
+    }
+}
+/// A short description of a list, with the type of its elements if
+/// they're all literals of the same type, like `list of string (3 elements)`
+pub fn list_summary(file: &Rc<Url>, list: &List) -> String {
+    let types: Vec<Option<&str>> = list.items().map(|item| literal_type(file, item)).collect();
+    let count = types.len();
+    let elements = if count == 1 { "element" } else { "elements" };
+    match types.split_first() {
+        None => "empty list".into(),
+        Some((&Some(first), rest)) if rest.iter().all(|&typ| typ == Some(first)) => format!("list of {first} ({count} {elements})"),
+        _ if types.iter().all(Option::is_some) => format!("list (mixed, {count} {elements})"),
+        // Some types aren't known without evaluating
+        _ => format!("list ({count} {elements})"),
+    }
+}
+/// The names of an attribute path, like `a.b.c` to `["a", "b", "c"]`
+pub fn select_path(mut node: SyntaxNode) -> Option<Vec<String>> {
+    let mut path = Vec::new();
+    while let Some(select) = Select::cast(node.clone()) {
+        path.push(static_name(&select.index()?)?);
+        node = select.set()?;
+    }
+    path.push(Ident::cast(node)?.as_str().into());
+    path.reverse();
+    Some(path)
+}
+
+/// The syntactic position of an identifier that is being completed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionContext {
+    /// `inherit a;`, inheriting from the surrounding scope
+    Inherit,
+    /// `inherit (set) a;`
+    InheritFrom,
+    /// The key of an entry, `{ a = ...; }`
+    Key,
+    /// An attribute of a set, `set.a`
+    Select,
+    /// An interpolation in a string, `"${a}"`
+    Interpolation,
+    /// Any other expression
+    Value,
+}
+pub fn classify_completion(info: &CursorInfo) -> CompletionContext {
+    let parent = info.ident.node().parent();
+    if let Some(inherit) = parent.clone().and_then(Inherit::cast) {
+        if inherit.from().is_some() {
+            CompletionContext::InheritFrom
+        } else {
+            CompletionContext::Inherit
+        }
+    } else if parent.clone().and_then(Key::cast).is_some() {
+        CompletionContext::Key
+    } else if !info.path.is_empty() {
+        CompletionContext::Select
+    } else if parent.is_some_and(|parent| parent.kind() == NODE_STRING_INTERPOL) {
+        CompletionContext::Interpolation
+    } else {
+        CompletionContext::Value
+    }
+}
+/// Returns the `inherit` the cursor is in, if it's at a position where a
+/// new identifier could be typed: `inherit a <cursor>;`
+pub fn inherit_at(root: &SyntaxNode, offset: usize) -> Option<Inherit> {
+    let token = root.token_at_offset(TextUnit::from_usize(offset)).left_biased()?;
+    if token.kind() != TOKEN_WHITESPACE && token.kind() != TOKEN_INHERIT {
+        return None;
+    }
+    let inherit = Inherit::cast(token.parent())?;
+    // Not before or inside the source: `inherit <cursor> (set);`
+    if inherit.from().is_some_and(|from| from.node().text_range().start().to_usize() >= offset) {
+        return None;
+    }
+    Some(inherit)
+}
+
+/// Where a variable was brought into scope
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VarKind {
+    /// A `let` binding
+    Let,
+    /// A binding in a `rec` set
+    Rec,
+    /// A function parameter
+    Param,
+    /// An attribute of a set reached by selecting or importing it
+    Attr,
+    /// Provided by the `with` expression with this namespace
+    With(SyntaxNode),
+}
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Var {
     pub file: Rc<Url>,
     pub set: SyntaxNode,
     pub key: SyntaxNode,
-    pub value: Option<SyntaxNode>
+    pub value: Option<SyntaxNode>,
+    pub kind: VarKind,
 }
 pub fn populate<T: EntryHolder>(
     file: &Rc<Url>,
     scope: &mut HashMap<String, Var>,
-    set: &T
+    set: &T,
+    kind: &VarKind,
 ) -> Option<()> {
+    // All entries are visible to each other, so an unfinished one while
+    // typing shouldn't hide the ones after it
     for entry in set.entries() {
-        let attr = entry.key()?;
-        let mut path = attr.path();
-        if let Some(ident) = path.next().and_then(Ident::cast) {
+        let (Some(attr), Some(value)) = (entry.key(), entry.value()) else { continue };
+        let Some(key) = attr.path().next() else { continue };
+        if let Some(name) = static_name(&key) {
+            scope.entry(name).or_insert_with(|| Var {
+                file: Rc::clone(file),
+                set: set.node().to_owned(),
+                key,
+                value: Some(value),
+                kind: kind.clone(),
+            });
+        }
+    }
+    for inherit in set.inherits() {
+        for ident in inherit.idents() {
             if !scope.contains_key(ident.as_str()) {
+                // The value has to be looked up elsewhere, see
+                // `App::resolve_var`
                 scope.insert(ident.as_str().into(), Var {
                     file: Rc::clone(file),
                     set: set.node().to_owned(),
                     key: ident.node().to_owned(),
-                    value: Some(entry.value()?.to_owned())
+                    value: None,
+                    kind: kind.clone(),
                 });
             }
         }
     }
     Some(())
 }
-pub fn scope_for(file: &Rc<Url>, node: SyntaxNode) -> Option<HashMap<String, Var>> {
-    let mut scope = HashMap::new();
+/// Like `AttrSet::recursive`, but without looking through all entries,
+/// since `rec` can only be at the start
+pub fn is_recursive(set: &AttrSet) -> bool {
+    set.node().first_token().is_some_and(|token| token.kind() == TOKEN_REC)
+}
+/// Whether a node brings variables into scope for its children, see
+/// `populate_node`
+fn binds(node: &SyntaxNode) -> bool {
+    match ParsedType::try_from(node.clone()) {
+        Ok(ParsedType::LetIn(_) | ParsedType::LegacyLet(_) | ParsedType::Lambda(_)) => true,
+        Ok(ParsedType::AttrSet(set)) => is_recursive(&set),
+        _ => false,
+    }
+}
+/// The variables in scope at a node. Completion, hover and highlighting
+/// look up the same scopes over and over again, so they're cached by the
+/// innermost node that binds variables.
+pub fn scope_for(file: &Rc<Url>, node: SyntaxNode) -> Option<Scope> {
+    let Some(owner) = std::iter::successors(Some(node), SyntaxNode::parent).find(binds) else { return Some(HashMap::new()) };
+    let key = (Rc::clone(file), owner);
+    if let Some(scope) = SCOPES.with(|scopes| scopes.borrow().get(&key).cloned()) {
+        return scope;
+    }
+
+    let mut scope = Some(HashMap::new());
+    let mut current = Some(key.1.clone());
+    while let (Some(node), Some(vars)) = (current, &mut scope) {
+        if populate_node(file, vars, &node).is_none() {
+            scope = None;
+            break;
+        }
+        current = node.parent();
+    }
 
-    let mut current = Some(node);
-    while let Some(node) = current {
-        match ParsedType::try_from(node.clone()) {
-            Ok(ParsedType::LetIn(let_in)) => { populate(&file, &mut scope, &let_in); },
-            Ok(ParsedType::LegacyLet(let_)) => { populate(&file, &mut scope, &let_); },
-            Ok(ParsedType::AttrSet(set)) => if set.recursive() {
-                populate(&file, &mut scope, &set);
+    SCOPES.with(|scopes| {
+        let mut scopes = scopes.borrow_mut();
+        if scopes.len() >= MAX_CACHED_SCOPES {
+            scopes.clear();
+        }
+        scopes.insert(key, scope.clone());
+    });
+    scope
+}
+/// Forget the cached scopes of a file. This has to be done whenever it
+/// changes, since the parts of the tree a change didn't touch are reused
+/// and their nodes are equal to the old ones.
+pub fn forget_scopes(file: &Url) {
+    SCOPES.with(|scopes| scopes.borrow_mut().retain(|(uri, _), _| **uri != *file));
+}
+/// Add the variables a single node brings into scope for its children,
+/// if it's a `let`, `rec` set or function
+pub fn populate_node(file: &Rc<Url>, scope: &mut HashMap<String, Var>, node: &SyntaxNode) -> Option<()> {
+    match ParsedType::try_from(node.clone()) {
+        Ok(ParsedType::LetIn(let_in)) => { populate(file, scope, &let_in, &VarKind::Let); },
+        Ok(ParsedType::LegacyLet(let_)) => { populate(file, scope, &let_, &VarKind::Let); },
+        Ok(ParsedType::AttrSet(set)) if is_recursive(&set) => {
+            populate(file, scope, &set, &VarKind::Rec);
+        },
+        Ok(ParsedType::Lambda(lambda)) => match ParsedType::try_from(lambda.arg()?) {
+            Ok(ParsedType::Ident(ident)) if !scope.contains_key(ident.as_str()) => {
+                scope.insert(ident.as_str().into(), Var {
+                    file: Rc::clone(file),
+                    set: lambda.node().clone(),
+                    key: ident.node().clone(),
+                    value: None,
+                    kind: VarKind::Param,
+                });
             },
-            Ok(ParsedType::Lambda(lambda)) => match ParsedType::try_from(lambda.arg()?) {
-                Ok(ParsedType::Ident(ident)) => if !scope.contains_key(ident.as_str()) {
-                    scope.insert(ident.as_str().into(), Var {
-                        file: Rc::clone(&file),
-                        set: lambda.node().clone(),
-                        key: ident.node().clone(),
-                        value: None
-                    });
-                },
-                Ok(ParsedType::Pattern(pattern)) => {
-                    for entry in pattern.entries() {
-                        let ident = entry.name()?;
-                        if !scope.contains_key(ident.as_str()) {
-                            scope.insert(ident.as_str().into(), Var {
-                                file: Rc::clone(&file),
-                                set: lambda.node().to_owned(),
-                                key: ident.node().to_owned(),
-                                value: None
-                            });
-                        }
+            Ok(ParsedType::Pattern(pattern)) => {
+                for entry in pattern.entries() {
+                    let Some(ident) = entry.name() else { continue };
+                    if !scope.contains_key(ident.as_str()) {
+                        scope.insert(ident.as_str().into(), Var {
+                            file: Rc::clone(file),
+                            set: lambda.node().to_owned(),
+                            key: ident.node().to_owned(),
+                            value: None,
+                            kind: VarKind::Param,
+                        });
                     }
-                },
-                _ => ()
+                }
             },
             _ => ()
+        },
+        _ => ()
+    }
+    Some(())
+}
+/// The name of an attribute, if it's known without evaluating anything,
+/// like `a`, `"a.b"` or `${"a"}` but not `${a}`
+pub fn static_name(part: &SyntaxNode) -> Option<String> {
+    if let Some(ident) = Ident::cast(part.clone()) {
+        return Some(ident.as_str().to_owned());
+    }
+    let string = match Dynamic::cast(part.clone()) {
+        Some(dynamic) => Str::cast(dynamic.inner()?)?,
+        None => Str::cast(part.clone())?,
+    };
+    match string.parts().as_slice() {
+        [StrPart::Literal(name)] => Some(name.clone()),
+        _ => None,
+    }
+}
+/// The quoted attribute name at the cursor, like `"a b"` in `set."a b"`
+/// or `{ "a b" = 1; }`
+pub fn quoted_name_at(root: &SyntaxNode, offset: usize) -> Option<SyntaxNode> {
+    let token = root.token_at_offset(TextUnit::from_usize(offset)).right_biased()?;
+    token.parent().ancestors()
+        .find(|node| node.parent().is_some_and(|parent| {
+            parent.kind() == NODE_KEY || Select::cast(parent).is_some_and(|select| select.index().as_ref() == Some(node))
+        }))
+        .filter(|node| Ident::cast(node.clone()).is_none() && static_name(node).is_some())
+}
+/// Whether a name can be written as an identifier, without quotes
+pub fn is_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'))
+        && !KEYWORDS.contains(&name)
+}
+/// Escape text to be the content of a `"..."` string
+pub fn escape_string(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace("${", "\\${")
+}
+/// A name as it's written as an attribute key or after a `.`, quoted if
+/// it isn't a valid identifier, like `"foo.bar"`
+pub fn escape_key(name: &str) -> String {
+    if is_ident(name) {
+        name.to_owned()
+    } else {
+        format!("\"{}\"", escape_string(name))
+    }
+}
+/// Escape text to be inserted as a snippet, like a quoted key that may
+/// contain `$`
+pub fn escape_snippet(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('$', "\\$").replace('}', "\\}")
+}
+/// Whether an identifier is used as a value, and not just as the name of
+/// a binding or attribute
+pub fn is_reference(ident: &Ident) -> bool {
+    let Some(parent) = ident.node().parent() else { return false };
+    if let Some(select) = Select::cast(parent.clone()) {
+        return select.index().as_ref() != Some(ident.node());
+    }
+    if let Some(lambda) = Lambda::cast(parent.clone()) {
+        return lambda.arg().as_ref() != Some(ident.node());
+    }
+    if let Some(inherit) = Inherit::cast(parent.clone()) {
+        // `inherit a;` uses the `a` from the surrounding scope
+        return inherit.from().is_none();
+    }
+    !matches!(parent.kind(), NODE_KEY | NODE_INHERIT | NODE_PAT_ENTRY | NODE_PAT_BIND)
+}
+/// Returns whether the identifier refers to a builtin: `Some(true)` if
+/// it is accessed through an unshadowed `builtins.`, `Some(false)` if
+/// it is a bare identifier used as a value and not bound by any scope,
+/// `None` otherwise. Keys like `map` in `{ map = 1; }` are neither.
+pub fn builtin_path(file: &Rc<Url>, info: &CursorInfo) -> Option<bool> {
+    let scope = scope_for(file, info.ident.node().clone())?;
+    match &*info.path {
+        [] if is_reference(&info.ident) && !scope.contains_key(info.ident.as_str()) => Some(false),
+        [base] if base == "builtins" && !scope.contains_key("builtins") => Some(true),
+        _ => None,
+    }
+}
+/// Whether a node is only part of a longer chain, like `a.b` in `a.b.c`,
+/// `f a` in `f a b` or `a + b` in `a + b + c`. Selecting it on the way
+/// out would split something that reads as one unit.
+fn partial_chain(node: &SyntaxNode) -> bool {
+    let parent = match node.parent() {
+        Some(parent) if parent.kind() == node.kind() => parent,
+        _ => return false,
+    };
+    let operator = |node: &SyntaxNode| {
+        node.children_with_tokens()
+            .filter_map(NodeOrToken::into_token)
+            .find(|token| token.kind() != TOKEN_WHITESPACE && token.kind() != TOKEN_COMMENT)
+            .map(|token| token.kind())
+    };
+    match ParsedType::try_from(node.clone()) {
+        Ok(ParsedType::Select(_) | ParsedType::Apply(_)) => parent.first_child().as_ref() == Some(node),
+        Ok(ParsedType::BinOp(_)) => operator(node) == operator(&parent),
+        _ => false,
+    }
+}
+/// The ranges to expand a selection through, from the innermost
+/// expression outwards. Parts of chains and nodes covering the same text
+/// as their child are skipped, so `c` in `x = a.b.c;` expands to `a.b.c`,
+/// the entry, and then the set around it.
+pub fn selection_ranges(root: &SyntaxNode, content: &str, pos: Position, encoding: Encoding) -> Option<SelectionRange> {
+    let pos = lookup_pos(content, pos, encoding)?;
+    let node = root.token_at_offset(TextUnit::from_usize(pos)).left_biased()?;
+
+    let mut ranges: Vec<TextRange> = Vec::new();
+    for parent in node.ancestors().filter(|parent| !partial_chain(parent)) {
+        let text_range = parent.text_range();
+        if ranges.last() != Some(&text_range) {
+            ranges.push(text_range);
         }
-        current = node.parent();
+    }
+    if ranges.is_empty() {
+        // Everything was part of a chain, which only happens in broken
+        // code, so fall back to every ancestor
+        ranges = node.ancestors().map(|parent| parent.text_range()).collect();
+        ranges.dedup();
     }
 
-    Some(scope)
+    ranges.into_iter().rev().fold(None, |parent, text_range| {
+        Some(SelectionRange {
+            range: range(content, text_range, encoding),
+            parent: parent.map(Box::new),
+        })
+    })
+}
+
+/// The comment right before a definition, like `# The version` above
+/// `version = "1.0";`, without the `#` or `/* */`. Comments after code
+/// on the same line are about that code, and a blank line ends them.
+pub fn doc_comment(key: &SyntaxNode) -> Option<String> {
+    let parent = key.parent()?;
+    let definition = match parent.kind() {
+        NODE_KEY => parent.parent()?,
+        NODE_INHERIT | NODE_PAT_ENTRY => parent,
+        _ => return None,
+    };
+    let mut lines = Vec::new();
+    let mut element = definition.prev_sibling_or_token();
+    while let Some(NodeOrToken::Token(token)) = element {
+        match token.kind() {
+            TOKEN_WHITESPACE if token.text().matches('\n').count() <= 1 => (),
+            TOKEN_COMMENT => {
+                // Not `a = 1; # about a`
+                let starts_line = token.prev_token().is_none_or( |prev| prev.kind() == TOKEN_WHITESPACE && prev.text().contains('\n'));
+                let text = token.text().as_str();
+                if !starts_line || (text.starts_with("/*") && !lines.is_empty()) {
+                    break;
+                }
+                if let Some(block) = text.strip_prefix("/*") {
+                    lines.push(block_comment_text(block));
+                    break;
+                }
+                let line = text.trim_start_matches('#');
+                lines.push(line.strip_prefix(' ').unwrap_or(line).trim_end().to_owned());
+            },
+            _ => break,
+        }
+        element = token.prev_sibling_or_token();
+    }
+    lines.reverse();
+    let comment = lines.join("\n");
+    let comment = comment.trim();
+    (!comment.is_empty()).then(|| comment.to_owned())
+}
+/// The text of a `/* */` comment, without the stars many put at the
+/// start of every line
+fn block_comment_text(block: &str) -> String {
+    let block = block.strip_suffix("*/").unwrap_or(block).trim_start_matches('*');
+    block.lines()
+        .map(|line| {
+            let line = line.trim();
+            line.strip_prefix('*').map_or(line, |rest| rest.strip_prefix(' ').unwrap_or(rest))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+/// The tokens of a node that affect its meaning, so everything except
+/// whitespace and comments
+fn significant_tokens(node: &SyntaxNode) -> impl Iterator<Item = SyntaxToken> {
+    node.descendants_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+        .filter(|token| token.kind() != TOKEN_WHITESPACE && token.kind() != TOKEN_COMMENT)
+}
+/// Whether two expressions are the same apart from formatting, like
+/// `f  x` and `f /* arg */ x`
+pub fn normalized_eq(a: &SyntaxNode, b: &SyntaxNode) -> bool {
+    if a.kind() != b.kind() {
+        return false;
+    }
+    let (mut a, mut b) = (significant_tokens(a), significant_tokens(b));
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return true,
+            (Some(a), Some(b)) if a.kind() == b.kind() && a.text() == b.text() => (),
+            _ => return false,
+        }
+    }
+}
+/// A hash that's equal for nodes that are `normalized_eq`
+pub fn structural_hash(node: &SyntaxNode) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    node.kind().hash(&mut hasher);
+    for token in significant_tokens(node) {
+        token.kind().hash(&mut hasher);
+        token.text().as_str().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+/// The expression inside any parentheses around a node, like `a` for `((a))`
+pub fn unwrap_parens(mut node: SyntaxNode) -> SyntaxNode {
+    while let Some(inner) = Paren::cast(node.clone()).and_then(|paren| paren.inner()) {
+        node = inner;
+    }
+    node
 }
-pub fn selection_ranges(root: &SyntaxNode, content: &str, pos: Position) -> Option<SelectionRange> {
-    let pos = lookup_pos(content, pos)?;
-    let node = root.token_at_offset(TextUnit::from_usize(pos)).left_biased()?;
 
-    let mut root = None;
-    let mut cursor = &mut root;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut last = None;
-    for parent in node.ancestors() {
-        // De-duplicate
-        if last.as_ref() == Some(&parent) {
-            continue;
+    /// An astral plane character, which is two units in UTF-16, and `é`
+    /// written as an `e` with a combining accent, which is two characters
+    const CODE: &str = "let\n  a = \"😀\"; b = \"e\u{301}\";\n  c = 1;\n";
+
+    fn round_trip(encoding: Encoding) {
+        for offset in (0..=CODE.len()).filter(|&offset| CODE.is_char_boundary(offset)) {
+            let pos = offset_to_pos(CODE, offset, encoding);
+            assert_eq!(lookup_pos(CODE, pos, encoding), Some(offset), "{encoding:?} at {offset}");
         }
+    }
+    fn pos(line: u64, character: u64) -> Position {
+        Position { line, character }
+    }
 
-        let text_range = parent.text_range();
-        *cursor = Some(Box::new(SelectionRange {
-            range: range(content, text_range),
-            parent: None,
-        }));
-        cursor = &mut cursor.as_mut().unwrap().parent;
+    #[test]
+    fn utf8() {
+        round_trip(Encoding::Utf8);
+        let b = CODE.find('b').unwrap();
+        assert_eq!(offset_to_pos(CODE, b, Encoding::Utf8), pos(1, 14));
+        // Inside the emoji, which is rounded down to its start
+        let emoji = CODE.find('😀').unwrap();
+        assert_eq!(lookup_pos(CODE, pos(1, 9), Encoding::Utf8), Some(emoji));
+    }
+    #[test]
+    fn utf16() {
+        round_trip(Encoding::Utf16);
+        let b = CODE.find('b').unwrap();
+        assert_eq!(offset_to_pos(CODE, b, Encoding::Utf16), pos(1, 12));
+        let emoji = CODE.find('😀').unwrap();
+        assert_eq!(lookup_pos(CODE, pos(1, 7), Encoding::Utf16), Some(emoji));
+        // Between the surrogates
+        assert_eq!(lookup_pos(CODE, pos(1, 8), Encoding::Utf16), Some(emoji));
+        let accent = CODE.find('\u{301}').unwrap();
+        assert_eq!(offset_to_pos(CODE, accent, Encoding::Utf16), pos(1, 18));
+        assert_eq!(offset_to_pos(CODE, accent + 2, Encoding::Utf16), pos(1, 19));
+    }
+    #[test]
+    fn utf32() {
+        round_trip(Encoding::Utf32);
+        let b = CODE.find('b').unwrap();
+        assert_eq!(offset_to_pos(CODE, b, Encoding::Utf32), pos(1, 11));
+        let accent = CODE.find('\u{301}').unwrap();
+        assert_eq!(offset_to_pos(CODE, accent, Encoding::Utf32), pos(1, 17));
+    }
+    #[test]
+    fn past_the_end() {
+        for encoding in [Encoding::Utf8, Encoding::Utf16, Encoding::Utf32] {
+            // Characters past the end of a line are at its end
+            assert_eq!(lookup_pos(CODE, pos(0, 100), encoding), Some(3));
+            assert_eq!(lookup_pos(CODE, pos(4, 0), encoding), None);
+        }
+    }
+    /// The scope of the first function in an open file
+    fn function_scope(app: &crate::App, uri: &Url) -> (SyntaxNode, Scope) {
+        let lambda = app.files[uri].0.node().descendants().find(|node| Lambda::cast(node.clone()).is_some()).unwrap();
+        let scope = scope_for(&Rc::new(uri.clone()), lambda.clone()).unwrap();
+        (lambda, scope)
+    }
+
+    #[test]
+    fn scopes_after_edits() {
+        let (mut app, _client) = crate::testing::app(&serde_json::json!({}));
+        let uri = app.open_file("default.nix", "rec {\n  f = x: a;\n  a = { };\n}");
+        let (old, scope) = function_scope(&app, &uri);
+        assert_eq!(scope["a"].value.as_ref().unwrap().text(), "{ }");
+
+        // Only the set of `a` is parsed again, so the function is the
+        // same node as before
+        app.change_file(&uri, "rec {\n  f = x: a;\n  a = { b = 1; };\n}");
+        let (new, scope) = function_scope(&app, &uri);
+        assert_eq!(new, old);
+        assert_eq!(scope["a"].value.as_ref().unwrap().text(), "{ b = 1; }");
 
-        last = Some(parent);
+        app.change_file(&uri, "rec {\n  f = x: a;\n  a = { b = 1; };\n  c = 2;\n}");
+        let (_, scope) = function_scope(&app, &uri);
+        let mut names: Vec<&str> = scope.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["a", "c", "f", "x"]);
     }
 
-    root.map(|b| *b)
+    /// Where the variable at `$0` is defined, and its value
+    fn definition(code: &str) -> (usize, Option<String>) {
+        let (code, pos) = crate::testing::cursor(code);
+        let offset = lookup_pos(&code, pos, Encoding::Utf16).unwrap();
+        let file = Rc::new(Url::parse("file:///test/default.nix").unwrap());
+        let ident = ident_at(&rnix::parse(&code).node(), offset).unwrap().ident;
+        let mut scope = scope_for(&file, ident.node().clone()).unwrap();
+        // Nothing may be left in the cache once the thread ends
+        forget_scopes(&file);
+        let var = scope.remove(ident.as_str()).expect("not in scope");
+        (var.key.text_range().start().to_usize(), var.value.map(|value| value.text().to_string()))
+    }
+
+    #[test]
+    fn let_forward_references() {
+        assert_eq!(definition("let a = b$0; b = 1; in a"), (11, Some("1".into())));
+        assert_eq!(definition("let a = 1; b = a$0; in b"), (4, Some("1".into())));
+        // `rec` sets are the same
+        assert_eq!(definition("rec { a = b$0; b = 1; }"), (13, Some("1".into())));
+    }
+    #[test]
+    fn let_self_references() {
+        assert_eq!(definition("let a = a$0; in a"), (4, Some("a".into())));
+        assert_eq!(definition("let f = n: f$0 n; in f"), (4, Some("n: f n".into())));
+        assert_eq!(definition("let xs = [ 1 ] ++ xs$0; in xs"), (4, Some("[ 1 ] ++ xs".into())));
+    }
+
+    fn expr(code: &str) -> SyntaxNode {
+        rnix::parse(code).root().inner().unwrap()
+    }
+    fn same(a: &str, b: &str) -> bool {
+        let (a, b) = (expr(a), expr(b));
+        let eq = normalized_eq(&a, &b);
+        if eq {
+            assert_eq!(structural_hash(&a), structural_hash(&b), "{a} and {b} have different hashes");
+        }
+        eq
+    }
+
+    #[test]
+    fn formatting_differences() {
+        assert!(same("f x", "f  x"));
+        assert!(same("f x", "f /* arg */ x"));
+        assert!(same("{ a = 1; b = [ 2 3 ]; }", "{\n  a = 1; # one\n  b = [\n    2\n    3\n  ];\n}"));
+        assert!(same("let a = 1; in a", "let\n\ta=1;\nin\n\ta"));
+        assert!(same("x: y: x + y", "x:\ny:\n  x+y"));
+    }
+    #[test]
+    fn other_differences() {
+        assert!(!same("f x", "f y"));
+        assert!(!same("f x y", "f (x y)"));
+        assert!(!same("{ a = 1; }", "rec { a = 1; }"));
+        assert!(!same("[ 1 2 ]", "[ 2 1 ]"));
+        // Whitespace in strings is part of the value
+        assert!(!same("\"a b\"", "\"a  b\""));
+        assert!(!same("\"${a}\"", "\"${ a }.\""));
+        assert_ne!(structural_hash(&expr("f x")), structural_hash(&expr("f y")));
+    }
+
+    /// The text of each selection the one at `$0` expands through
+    fn selections(code: &str) -> Vec<String> {
+        let (code, pos) = crate::testing::cursor(code);
+        let root = rnix::parse(&code).node();
+        let mut selection = selection_ranges(&root, &code, pos, Encoding::Utf16);
+        let mut texts = Vec::new();
+        while let Some(current) = selection {
+            let start = lookup_pos(&code, current.range.start, Encoding::Utf16).unwrap();
+            let end = lookup_pos(&code, current.range.end, Encoding::Utf16).unwrap();
+            texts.push(code[start..end].to_owned());
+            selection = current.parent.map(|parent| *parent);
+        }
+        texts
+    }
+
+    #[test]
+    fn selections_through_chains() {
+        let set = "{ x = a.b.c; }";
+        assert_eq!(selections("{ x = a.b.c$0; }"), ["c", "a.b.c", "x = a.b.c;", set]);
+        assert_eq!(selections("{ x = a.b$0.c; }"), ["b", "a.b.c", "x = a.b.c;", set]);
+        assert_eq!(selections("{ x = a$0.b.c; }"), ["a", "a.b.c", "x = a.b.c;", set]);
+        assert_eq!(selections("f a$0 b"), ["a", "f a b"]);
+        assert_eq!(selections("1 + 2$0 + 3 * 4"), ["2", "1 + 2 + 3 * 4"]);
+        assert_eq!(selections("1 + 2 * 3$0"), ["3", "2 * 3", "1 + 2 * 3"]);
+    }
 }
@@ -0,0 +1,460 @@
+use crate::{App, format::{self, Formatter}, parse::Parsed, resolver::FileResolver, utils::{self, Encoding}};
+use lsp_server::{Message, Notification, Request, RequestId};
+use lsp_types::{
+    notification::{Notification as _, Progress, ShowMessage},
+    request::{ApplyWorkspaceEdit, Request as _, WorkDoneProgressCreate},
+    *,
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+/// How many files to parse per workspace folder, so opening something
+/// like all of nixpkgs doesn't take forever
+const MAX_INDEXED_FILES: usize = 10_000;
+
+pub const FORMAT_COMMAND: &str = "rnix-lsp.formatWorkspace";
+
+/// The `.nix` files in a directory and its subdirectories
+pub struct NixFiles {
+    dirs: Vec<PathBuf>,
+    entries: Option<fs::ReadDir>,
+}
+pub fn nix_files(dir: PathBuf) -> NixFiles {
+    NixFiles { dirs: vec![dir], entries: None }
+}
+impl Iterator for NixFiles {
+    type Item = PathBuf;
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            let Some(entries) = &mut self.entries else {
+                self.entries = fs::read_dir(self.dirs.pop()?).ok();
+                continue;
+            };
+            let Some(entry) = entries.next() else {
+                self.entries = None;
+                continue;
+            };
+            // Hidden folders are things like `.git` and `.direnv`, and
+            // symlinks like `result` point into the store
+            let Ok(entry) = entry else { continue };
+            let Ok(kind) = entry.file_type() else { continue };
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            if kind.is_dir() {
+                self.dirs.push(path);
+            } else if kind.is_file() && path.extension().is_some_and(|extension| extension == "nix") {
+                return Some(path);
+            }
+        }
+    }
+}
+
+/// The folders open in the editor. Clients that don't support multiple
+/// folders only send `rootUri`, or `rootPath` if they're really old.
+pub fn roots(params: &InitializeParams) -> Vec<Url> {
+    if let Some(folders) = params.workspace_folders.as_ref().filter(|folders| !folders.is_empty()) {
+        return folders.iter().map(|folder| folder.uri.clone()).collect();
+    }
+    #[allow(deprecated)]
+    let path = params.root_path.as_ref();
+    params.root_uri.clone()
+        .or_else(|| path.and_then(|path| Url::from_directory_path(path).ok()))
+        .into_iter()
+        .collect()
+}
+/// Whether a file is inside a folder, which may or may not have a
+/// trailing slash
+pub fn contains(folder: &Url, uri: &Url) -> bool {
+    if folder.scheme() != uri.scheme() || folder.host() != uri.host() {
+        return false;
+    }
+    let dir = folder.path().trim_end_matches('/');
+    uri.path().strip_prefix(dir).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+impl App {
+    /// The workspace folder a file belongs to. Folders can be nested in
+    /// each other, in which case the innermost one is used.
+    pub fn root_for(&self, uri: &Url) -> Option<&Url> {
+        self.roots.iter()
+            .filter(|root| contains(root, uri))
+            .max_by_key(|root| root.path().trim_end_matches('/').len())
+    }
+    /// Parse the `.nix` files in a workspace folder, so they're known
+    /// without being opened or imported first
+    pub fn index(&mut self, root: &Url) {
+        let Some(dir) = utils::uri_path(root) else { return };
+        let mut count = 0;
+        for path in nix_files(dir) {
+            if count == MAX_INDEXED_FILES {
+                self.log(MessageType::Warning, format!("{root} has more than {MAX_INDEXED_FILES} files, only indexing some of them"));
+                return;
+            }
+            count += 1;
+            let Ok(uri) = Url::from_file_path(&path) else { continue };
+            if self.files.contains_key(&uri) {
+                continue;
+            }
+            let Some(content) = self.read(&uri) else { continue };
+            if self.is_large(&content) {
+                continue;
+            }
+            self.files.insert(uri, (Parsed::new(&content), content));
+        }
+        self.log(MessageType::Info, format!("Indexed {count} files in {root}"));
+    }
+    /// Update the workspace folders. Files that were only known because
+    /// of a removed folder are forgotten, the ones still open or in
+    /// another folder are kept.
+    pub fn change_folders(&mut self, params: DidChangeWorkspaceFoldersParams) {
+        for folder in params.event.removed {
+            self.roots.retain(|root| *root != folder.uri);
+            let removed: Vec<Url> = self.files.keys()
+                .filter(|uri| contains(&folder.uri, uri) && !self.open.contains_key(*uri) && self.root_for(uri).is_none())
+                .cloned()
+                .collect();
+            for uri in removed {
+                self.files.remove(&uri);
+                self.semantic_tokens.remove(&uri);
+                utils::forget_scopes(&uri);
+                self.completion_cache = None;
+            }
+        }
+        for folder in params.event.added {
+            if !self.roots.contains(&folder.uri) {
+                self.roots.push(folder.uri.clone());
+                self.index(&folder.uri);
+            }
+        }
+    }
+    /// Resolve a path from the settings. Relative paths are relative to
+    /// the first workspace folder, since settings apply to all of them.
+    pub fn resolve_path(&self, path: &Path) -> PathBuf {
+        match self.roots.first().and_then(utils::uri_path) {
+            Some(root) if path.is_relative() => root.join(path),
+            _ => path.to_owned(),
+        }
+    }
+    /// Format every file in the workspace and send the edits to the
+    /// client. This is done on another thread, so the server can keep
+    /// answering requests in the meantime.
+    pub fn format_workspace(&mut self, params: &ExecuteCommandParams) {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        let mut token = params.work_done_progress_params.work_done_token.clone();
+        if token.is_none() && self.client.window.as_ref().and_then(|window| window.work_done_progress) == Some(true) {
+            let created = NumberOrString::String(format!("{FORMAT_COMMAND}/{id}"));
+            self.conn.sender.send(Message::Request(Request::new(
+                RequestId::from(format!("{FORMAT_COMMAND}/{id}/progress")),
+                WorkDoneProgressCreate::METHOD.into(),
+                WorkDoneProgressCreateParams { token: created.clone() },
+            ))).unwrap();
+            token = Some(created);
+        }
+        // The editor's version of open files is what the edits apply to
+        let open = self.open.keys()
+            .filter_map(|uri| Some((uri.clone(), self.read(uri)?)))
+            .collect();
+        let job = FormatJob {
+            id,
+            roots: self.roots.clone(),
+            ignore: self.config.format_ignore.clone(),
+            formatter: self.config.formatter,
+            max_file_size: self.config.max_file_size,
+            max_width: self.config.max_width,
+            encoding: self.encoding,
+            open,
+            resolver: Arc::clone(&self.resolver),
+            token,
+        };
+        let sender = self.conn.sender.clone();
+        thread::spawn(move || job.run(&|msg| {
+            // The client is gone if this fails, so nobody would care
+            let _ = sender.send(msg);
+        }));
+    }
+}
+
+/// Everything needed to format the workspace without access to the `App`
+struct FormatJob {
+    id: u64,
+    roots: Vec<Url>,
+    ignore: Vec<String>,
+    formatter: Formatter,
+    max_file_size: usize,
+    max_width: usize,
+    encoding: Encoding,
+    open: HashMap<Url, String>,
+    resolver: Arc<dyn FileResolver>,
+    token: Option<ProgressToken>,
+}
+impl FormatJob {
+    fn run(self, send: &dyn Fn(Message)) {
+        let progress = |value| if let Some(token) = &self.token {
+            send(Message::Notification(Notification::new(Progress::METHOD.into(), ProgressParams {
+                token: token.clone(),
+                value: ProgressParamsValue::WorkDone(value),
+            })));
+        };
+        progress(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: "Formatting".into(),
+            ..WorkDoneProgressBegin::default()
+        }));
+
+        let mut files = Vec::new();
+        for root in self.roots.iter().filter_map(utils::uri_path) {
+            let paths = nix_files(root.clone()).filter(|path| !self.is_ignored(&root, path));
+            files.extend(paths.map(|path| (path, root.clone())));
+        }
+        // Folders can be nested in each other
+        files.sort();
+        files.dedup_by(|(a, _), (b, _)| a == b);
+
+        let options = FormattingOptions {
+            tab_size: 2,
+            insert_spaces: true,
+            properties: HashMap::new(),
+            trim_trailing_whitespace: None,
+            insert_final_newline: None,
+            trim_final_newlines: None,
+        };
+        let mut changes = HashMap::new();
+        let mut broken = 0;
+        let mut failed = Vec::new();
+        for (done, (path, root)) in files.iter().enumerate() {
+            let Ok(uri) = Url::from_file_path(path) else { continue };
+            let Some(content) = self.open.get(&uri).cloned().or_else(|| self.resolver.read(&uri)) else { continue };
+            if content.len() > self.max_file_size {
+                continue;
+            }
+            let ast = Parsed::new(&content);
+            // Formatting code with errors can make things worse
+            if !ast.errors().is_empty() {
+                broken += 1;
+                continue;
+            }
+            // The same formatter as for a single file, which is
+            // nixpkgs-fmt outside of flakes
+            let flake = match self.formatter {
+                Formatter::NixFmt => format::flake_dir(path, Some(root)),
+                Formatter::NixpkgsFmt | Formatter::None => None,
+            };
+            let edits = match flake {
+                Some(flake) => match format::nix_fmt(&flake, path, &content, &mut || false) {
+                    Ok(formatted) if formatted == content => Vec::new(),
+                    Ok(formatted) => vec![format::replace_changed(&content, &formatted, self.encoding)],
+                    Err(err) => {
+                        failed.push(err);
+                        continue;
+                    },
+                },
+                None => format::format(&ast, &content, &options, self.max_width, self.encoding),
+            };
+            if !edits.is_empty() {
+                changes.insert(uri, edits);
+            }
+            progress(WorkDoneProgress::Report(WorkDoneProgressReport {
+                message: Some(format!("{}/{} files", done + 1, files.len())),
+                percentage: Some(percentage(done + 1, files.len())),
+                ..WorkDoneProgressReport::default()
+            }));
+        }
+
+        let mut message = vec![format!("Formatted {} of {} files", changes.len(), files.len())];
+        if broken > 0 {
+            message.push(format!("skipped {broken} with syntax errors"));
+        }
+        if let Some(err) = failed.first() {
+            message.push(format!("nix fmt failed on {} ({err})", failed.len()));
+        }
+        let message = message.join(", ");
+        if !changes.is_empty() {
+            send(Message::Request(Request::new(
+                RequestId::from(format!("{FORMAT_COMMAND}/{}/edit", self.id)),
+                ApplyWorkspaceEdit::METHOD.into(),
+                ApplyWorkspaceEditParams {
+                    edit: WorkspaceEdit {
+                        changes: Some(changes),
+                        ..WorkspaceEdit::default()
+                    },
+                },
+            )));
+        }
+        progress(WorkDoneProgress::End(WorkDoneProgressEnd { message: Some(message.clone()) }));
+        send(Message::Notification(Notification::new(ShowMessage::METHOD.into(), ShowMessageParams {
+            typ: if failed.is_empty() { MessageType::Info } else { MessageType::Warning },
+            message,
+        })));
+    }
+    /// Whether a file matches one of the ignored patterns, relative to the
+    /// workspace folder
+    fn is_ignored(&self, root: &Path, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else { return false };
+        let parts: Vec<String> = relative.iter().map(|part| part.to_string_lossy().into_owned()).collect();
+        let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+        self.ignore.iter().any(|pattern| {
+            if pattern.contains('/') {
+                let pattern: Vec<&str> = pattern.trim_matches('/').split('/').collect();
+                // Directories match everything inside them
+                (1..=parts.len()).any(|len| glob(&pattern, &parts[..len]))
+            } else {
+                parts.iter().any(|part| wildcard(pattern, part))
+            }
+        })
+    }
+}
+fn percentage(done: usize, total: usize) -> f64 {
+    let done = f64::from(u32::try_from(done).unwrap_or(u32::MAX));
+    let total = f64::from(u32::try_from(total).unwrap_or(u32::MAX));
+    done / total * 100.0
+}
+/// Match path components, where `**` matches any number of them
+fn glob(pattern: &[&str], parts: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => parts.is_empty(),
+        Some((&"**", rest)) => (0..=parts.len()).any(|skip| glob(rest, &parts[skip..])),
+        Some((first, rest)) => parts.split_first().is_some_and(|(part, parts)| wildcard(first, part) && glob(rest, parts)),
+    }
+}
+/// Match a name, where `*` matches any number of characters
+fn wildcard(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => name.strip_prefix(prefix).is_some_and(|name| {
+            name.char_indices().map(|(i, _)| i).chain(Some(name.len())).any(|i| wildcard(rest, &name[i..]))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FormatJob;
+    use crate::{format::Formatter, resolver, utils::Encoding};
+    use lsp_server::Message;
+    use lsp_types::*;
+    use std::{cell::RefCell, collections::HashMap, fs, path::PathBuf, process, sync::Arc};
+
+    /// A folder with files in it, which is removed again at the end
+    struct Folder(PathBuf);
+    impl Folder {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!("rnix-lsp-{name}-{}", process::id()));
+            for (path, code) in files {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, code).unwrap();
+            }
+            Self(dir)
+        }
+        fn uri(&self, path: &str) -> Url {
+            Url::from_file_path(self.0.join(path)).unwrap()
+        }
+    }
+    impl Drop for Folder {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn job(folder: &Folder, formatter: Formatter, ignore: &[&str]) -> FormatJob {
+        FormatJob {
+            id: 0,
+            roots: vec![Url::from_directory_path(&folder.0).unwrap()],
+            ignore: ignore.iter().map(|&pattern| pattern.into()).collect(),
+            formatter,
+            max_file_size: usize::MAX,
+            max_width: 80,
+            encoding: Encoding::Utf16,
+            open: HashMap::new(),
+            resolver: Arc::new(resolver::Disk),
+            token: None,
+        }
+    }
+    /// Run a job, and return the files it changed and the message it
+    /// showed at the end
+    fn run(job: FormatJob) -> (Vec<Url>, String) {
+        let messages = RefCell::new(Vec::new());
+        job.run(&|msg| messages.borrow_mut().push(msg));
+        let mut changed = Vec::new();
+        let mut shown = None;
+        for msg in messages.into_inner() {
+            match msg {
+                Message::Request(req) => {
+                    let params: ApplyWorkspaceEditParams = serde_json::from_value(req.params).unwrap();
+                    changed.extend(params.edit.changes.unwrap().into_keys());
+                },
+                Message::Notification(notification) => {
+                    shown = notification.params["message"].as_str().map(String::from);
+                },
+                Message::Response(_) => (),
+            }
+        }
+        changed.sort();
+        (changed, shown.expect("no message shown"))
+    }
+
+    #[test]
+    fn nix_fmt_in_flakes() {
+        // The flake has no `formatter`, so `nix fmt` fails even where
+        // it's installed
+        let folder = Folder::new("nix-fmt", &[
+            ("flake/flake.nix", "{ outputs = _: { }; }\n"),
+            ("flake/a.nix", "{a=1;}\n"),
+            ("b.nix", "{b=1;}\n"),
+        ]);
+        let (changed, message) = run(job(&folder, Formatter::NixFmt, &[]));
+        // Not laid out by nixpkgs-fmt instead
+        assert_eq!(changed, [folder.uri("b.nix")]);
+        assert!(message.starts_with("Formatted 1 of 3 files, nix fmt failed on 2 ("), "{}", message);
+
+        let (changed, message) = run(job(&folder, Formatter::NixpkgsFmt, &[]));
+        assert_eq!(changed, [folder.uri("b.nix"), folder.uri("flake/a.nix"), folder.uri("flake/flake.nix")]);
+        assert_eq!(message, "Formatted 3 of 3 files");
+    }
+
+    #[test]
+    fn ignore_patterns() {
+        let folder = Folder::new("ignore", &[]);
+        let ignored = |patterns: &[&str], path: &str| job(&folder, Formatter::NixpkgsFmt, patterns).is_ignored(&folder.0, &folder.0.join(path));
+
+        // Names without a `/` match any part of the path
+        assert!(ignored(&["*.gen.nix"], "a.gen.nix"));
+        assert!(ignored(&["*.gen.nix"], "pkgs/b/c.gen.nix"));
+        assert!(!ignored(&["*.gen.nix"], "a.nix"));
+        assert!(ignored(&["generated"], "pkgs/generated/a.nix"));
+        assert!(!ignored(&["generated"], "pkgs/generated.nix"));
+        // Paths with a `/` are from the workspace folder, and directories
+        // match everything inside them
+        assert!(ignored(&["vendor/"], "vendor/a/b.nix"));
+        assert!(!ignored(&["vendor/"], "pkgs/vendor/a.nix"));
+        assert!(ignored(&["pkgs/*/default.nix"], "pkgs/hello/default.nix"));
+        assert!(!ignored(&["pkgs/*/default.nix"], "pkgs/hello/world/default.nix"));
+        // `**` is any number of directories, including none
+        assert!(ignored(&["**/tests/*.nix"], "tests/a.nix"));
+        assert!(ignored(&["**/tests/*.nix"], "pkgs/a/tests/b.nix"));
+        assert!(!ignored(&["**/tests/*.nix"], "pkgs/a/b.nix"));
+        assert!(ignored(&["a.nix", "b.nix"], "b.nix"));
+    }
+
+    #[test]
+    fn summary_of_skipped_files() {
+        let folder = Folder::new("summary", &[
+            ("a.nix", "{a=1;}\n"),
+            ("b.nix", "{ b = 1; }\n"),
+            ("broken.nix", "{ a = ; }\n"),
+            ("generated/c.nix", "{c=1;}\n"),
+        ]);
+        let (changed, message) = run(job(&folder, Formatter::NixpkgsFmt, &["generated/"]));
+        assert_eq!(changed, [folder.uri("a.nix")]);
+        // Ignored files aren't counted at all
+        assert_eq!(message, "Formatted 1 of 3 files, skipped 1 with syntax errors");
+    }
+}